@@ -0,0 +1,96 @@
+//! Packets/sec of the sniffglue json parser
+//!
+//! Set `SPYTRAP_CORPUS` to a file recorded with `sniffglue --json`, otherwise a
+//! synthetic corpus is used that looks like a phone downloading an app update.
+use rand::prelude::*;
+use spytrap_wifi::json;
+use std::env;
+use std::fs;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ROUNDS: usize = 5;
+
+fn tcp(src: &str, dst: &str, sport: u16, dport: u16, flags: &str, payload: &str) -> String {
+    format!(r#"{{"Ether":[{{"source_mac":[1,2,3,4,5,6],"dest_mac":[6,5,4,3,2,1],"ethertype":"IPv4"}},{{"IPv4":[{{"version":4,"ihl":20,"tos":0,"length":1500,"id":1,"flags":2,"fragment_offset":0,"ttl":64,"protocol":"TCP","chksum":1337,"source_addr":"{}","dest_addr":"{}"}},{{"TCP":[{{"source_port":{},"dest_port":{},"sequence_no":1337,"ack_no":1,"data_offset":8,"reserved":0,"flag_urg":false,"flag_ack":true,"flag_psh":false,{},"window":504,"checksum":1337,"urgent_pointer":0,"options":null}},{}]}}]}}]}}"#,
+        src, dst, sport, dport, flags, payload)
+}
+
+fn udp(src: &str, dst: &str, sport: u16, dport: u16, payload: &str) -> String {
+    format!(r#"{{"Ether":[{{"source_mac":[1,2,3,4,5,6],"dest_mac":[6,5,4,3,2,1],"ethertype":"IPv4"}},{{"IPv4":[{{"version":4,"ihl":20,"tos":0,"length":1280,"id":0,"flags":2,"fragment_offset":0,"ttl":64,"protocol":"UDP","chksum":1337,"source_addr":"{}","dest_addr":"{}"}},{{"UDP":[{{"source_port":{},"dest_port":{},"length":1280,"checksum":1337}},{}]}}]}}]}}"#,
+        src, dst, sport, dport, payload)
+}
+
+fn binary(rng: &mut StdRng, len: usize) -> String {
+    let bytes = (0..len).map(|_| rng.gen::<u8>().to_string()).collect::<Vec<_>>();
+    format!(r#"{{"Binary":[{}]}}"#, bytes.join(","))
+}
+
+fn synthetic() -> Vec<String> {
+    const DATA: &str = r#""flag_rst":false,"flag_syn":false,"flag_fin":false"#;
+    const SYN: &str = r#""flag_rst":false,"flag_syn":true,"flag_fin":false"#;
+    const SNI: &str = r#"{"TLS":{"ClientHello":{"version":771,"session_id":null,"cipher":null,"compression":null,"next_protocol":null,"hostname":"example.com"}}}"#;
+    const DNS: &str = r#"{"DNS":{"Request":{"id":1337,"recursion_desired":true,"questions":[["A","example.com"]],"answers":[],"nameservers":[],"additional":[]}}}"#;
+
+    let mut rng = StdRng::seed_from_u64(1337);
+    (0..20_000)
+        .map(|i| match i % 100 {
+            // the download itself
+            0..=69 => tcp("151.101.1.1", "192.168.1.3", 443, 50000, DATA, &binary(&mut rng, 1400)),
+            // acks for the download
+            70..=89 => tcp("192.168.1.3", "151.101.1.1", 50000, 443, DATA, r#""Empty""#),
+            // quic traffic of other apps
+            90..=94 => udp("142.250.102.138", "192.168.1.3", 443, 50001, &binary(&mut rng, 1200)),
+            95 => tcp("192.168.1.3", "151.101.1.1", 50000, 443, SYN, r#""Empty""#),
+            96 => tcp("192.168.1.3", "151.101.1.1", 50000, 443, DATA, SNI),
+            97 => udp("192.168.1.3", "192.168.1.1", 50002, 53, DNS),
+            _ => tcp("192.168.1.3", "151.101.1.1", 50000, 443, DATA, &binary(&mut rng, 100)),
+        })
+        .collect()
+}
+
+fn bench<F: FnMut(&mut [u8])>(name: &str, lines: &[String], mut f: F) {
+    let mut best = Duration::MAX;
+    for _ in 0..ROUNDS {
+        // parse_fast modifies the line, every round starts with a fresh copy
+        let mut lines = lines.iter().map(|line| line.clone().into_bytes()).collect::<Vec<_>>();
+        let start = Instant::now();
+        for line in &mut lines {
+            f(line);
+        }
+        best = best.min(start.elapsed());
+    }
+    let rate = lines.len() as f64 / best.as_secs_f64();
+    println!("{:<32} {:>12.0} pkts/s", name, rate);
+}
+
+fn main() {
+    let lines = match env::var("SPYTRAP_CORPUS") {
+        Ok(path) => fs::read_to_string(&path)
+            .expect("Failed to read corpus")
+            .lines()
+            .map(String::from)
+            .collect(),
+        Err(_) => synthetic(),
+    };
+    let bytes = lines.iter().map(|line| line.len()).sum::<usize>();
+    println!("corpus: {} lines, {} bytes", lines.len(), bytes);
+
+    bench("json::parse", &lines, |line| {
+        black_box(json::parse(line).ok());
+    });
+    bench("json::parse_fast", &lines, |line| {
+        black_box(json::parse_fast(line).ok());
+    });
+    // packets are owned once they're sent to the detector
+    bench("json::parse_fast + into_owned", &lines, |line| {
+        black_box(json::parse_fast(line).ok().flatten().map(|pkt| pkt.into_owned()));
+    });
+    // the payload is only decoded if the reassembler still needs it
+    bench("json::parse_fast + payload", &lines, |line| {
+        if let Ok(Some(pkt)) = json::parse_fast(line) {
+            black_box(pkt.get_tcp_segment().map(|segment| segment.payload.len()));
+            black_box(pkt.get_udp_payload().map(|payload| payload.len()));
+        }
+    });
+}
@@ -5,11 +5,12 @@
 if [[ $2 == "AP-STA-CONNECTED" ]]
 then
   echo "someone has connected with mac id $3 on $1"
+  /spytrap/bin send -S /run/spytrap.sock "connected $3"
 fi
 
 if [[ $2 == "AP-STA-DISCONNECTED" ]]
 then
   echo "someone has disconnected with mac id $3 on $1"
   # this causes a password reset and hostapd restart
-  /spytrap/bin send -S /run/spytrap.sock reset
+  /spytrap/bin send -S /run/spytrap.sock "disconnected $3"
 fi
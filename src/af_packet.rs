@@ -0,0 +1,539 @@
+use crate::errors::*;
+use crate::pcap::{self, Frame};
+use caps::{CapSet, Capability};
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr;
+use std::sync::atomic::{self, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::unix::AsyncFd;
+
+// from linux/if_packet.h, not exported by every libc version
+const PACKET_RX_RING: libc::c_int = 5;
+const PACKET_VERSION: libc::c_int = 10;
+const TPACKET_V3: libc::c_int = 2;
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+
+const SO_ATTACH_FILTER: libc::c_int = 26;
+
+const RING_BLOCK_SIZE: u32 = 1 << 18;
+const RING_BLOCK_NR: u32 = 8;
+const RING_FRAME_SIZE: u32 = 1 << 11;
+/// Hand blocks to userspace after this many milliseconds, even if they aren't full
+const RING_BLOCK_TIMEOUT: u32 = 100;
+
+const SNAPLEN: usize = 0x40000;
+
+// classic bpf opcodes, from linux/bpf_common.h
+const BPF_LD_H_ABS: u16 = 0x28;
+const BPF_LD_B_ABS: u16 = 0x30;
+const BPF_LD_H_IND: u16 = 0x48;
+const BPF_LD_B_IND: u16 = 0x50;
+const BPF_LDX_B_MSH: u16 = 0xb1;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_JSET_K: u16 = 0x45;
+const BPF_RET_K: u16 = 0x06;
+
+const fn stmt(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter { code, jt: 0, jf: 0, k }
+}
+
+const fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+/// Only pass dns, http, tls and quic, and tcp handshakes for the unexplained destination check
+///
+/// Equivalent to `tcpdump -dd 'port 53 or port 80 or port 443 or tcp[tcpflags] & tcp-syn != 0'`,
+/// ipv6 is only matched without extension headers.
+const FILTER: &[libc::sock_filter] = &[
+    // 0: ethertype
+    stmt(BPF_LD_H_ABS, 12),
+    jump(BPF_JEQ_K, 0x86dd, 19, 0),
+    jump(BPF_JEQ_K, 0x0800, 0, 34),
+    // 3: ipv4 protocol
+    stmt(BPF_LD_B_ABS, 23),
+    jump(BPF_JEQ_K, 6, 1, 0),
+    jump(BPF_JEQ_K, 17, 0, 31),
+    // 6: drop fragments without transport header
+    stmt(BPF_LD_H_ABS, 20),
+    jump(BPF_JSET_K, 0x1fff, 29, 0),
+    stmt(BPF_LDX_B_MSH, 14),
+    // 9: source port
+    stmt(BPF_LD_H_IND, 14),
+    jump(BPF_JEQ_K, 53, 25, 0),
+    jump(BPF_JEQ_K, 80, 24, 0),
+    jump(BPF_JEQ_K, 443, 23, 0),
+    // 13: destination port
+    stmt(BPF_LD_H_IND, 16),
+    jump(BPF_JEQ_K, 53, 21, 0),
+    jump(BPF_JEQ_K, 80, 20, 0),
+    jump(BPF_JEQ_K, 443, 19, 0),
+    // 17: tcp syn
+    stmt(BPF_LD_B_ABS, 23),
+    jump(BPF_JEQ_K, 6, 0, 18),
+    stmt(BPF_LD_B_IND, 27),
+    jump(BPF_JSET_K, 0x02, 15, 16),
+    // 21: ipv6 next header
+    stmt(BPF_LD_B_ABS, 20),
+    jump(BPF_JEQ_K, 6, 1, 0),
+    jump(BPF_JEQ_K, 17, 0, 13),
+    // 24: source port
+    stmt(BPF_LD_H_ABS, 54),
+    jump(BPF_JEQ_K, 53, 10, 0),
+    jump(BPF_JEQ_K, 80, 9, 0),
+    jump(BPF_JEQ_K, 443, 8, 0),
+    // 28: destination port
+    stmt(BPF_LD_H_ABS, 56),
+    jump(BPF_JEQ_K, 53, 6, 0),
+    jump(BPF_JEQ_K, 80, 5, 0),
+    jump(BPF_JEQ_K, 443, 4, 0),
+    // 32: tcp syn
+    stmt(BPF_LD_B_ABS, 20),
+    jump(BPF_JEQ_K, 6, 0, 3),
+    stmt(BPF_LD_B_ABS, 67),
+    jump(BPF_JSET_K, 0x02, 0, 1),
+    // 36: accept
+    stmt(BPF_RET_K, SNAPLEN as u32),
+    // 37: drop
+    stmt(BPF_RET_K, 0),
+];
+
+#[repr(C)]
+struct TpacketReq3 {
+    tp_block_size: u32,
+    tp_block_nr: u32,
+    tp_frame_size: u32,
+    tp_frame_nr: u32,
+    tp_retire_blk_tov: u32,
+    tp_sizeof_priv: u32,
+    tp_feature_req_word: u32,
+}
+
+fn check(ret: libc::c_int, msg: &'static str) -> Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error()).context(msg)
+    } else {
+        Ok(())
+    }
+}
+
+fn setsockopt<T>(fd: &OwnedFd, level: libc::c_int, name: libc::c_int, value: &T, msg: &'static str) -> Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(fd.as_raw_fd(), level, name, value as *const T as *const libc::c_void, mem::size_of::<T>() as libc::socklen_t)
+    };
+    check(ret, msg)
+}
+
+/// The memory mapped TPACKET_V3 receive ring
+struct Ring {
+    map: *mut u8,
+    len: usize,
+    current: u32,
+}
+
+// the mapping is only accessed through &mut self
+unsafe impl Send for Ring {}
+
+impl Ring {
+    fn setup(fd: &OwnedFd) -> Result<Ring> {
+        setsockopt(fd, libc::SOL_PACKET, PACKET_VERSION, &TPACKET_V3, "Failed to select TPACKET_V3")?;
+
+        let req = TpacketReq3 {
+            tp_block_size: RING_BLOCK_SIZE,
+            tp_block_nr: RING_BLOCK_NR,
+            tp_frame_size: RING_FRAME_SIZE,
+            tp_frame_nr: RING_BLOCK_SIZE / RING_FRAME_SIZE * RING_BLOCK_NR,
+            tp_retire_blk_tov: RING_BLOCK_TIMEOUT,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        setsockopt(fd, libc::SOL_PACKET, PACKET_RX_RING, &req, "Failed to setup receive ring")?;
+
+        let len = (RING_BLOCK_SIZE * RING_BLOCK_NR) as usize;
+        let map = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd.as_raw_fd(), 0)
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error()).context("Failed to map receive ring");
+        }
+
+        Ok(Ring {
+            map: map as *mut u8,
+            len,
+            current: 0,
+        })
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        debug_assert!(offset + 4 <= self.len);
+        unsafe { ptr::read_volatile(self.map.add(offset) as *const u32) }
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        debug_assert!(offset + 2 <= self.len);
+        unsafe { ptr::read_volatile(self.map.add(offset) as *const u16) }
+    }
+
+    /// Copy all frames of the current block if it has been handed to userspace
+    fn read_block(&mut self, frames: &mut VecDeque<Frame>) -> bool {
+        let block = (self.current * RING_BLOCK_SIZE) as usize;
+        // struct tpacket_block_desc, the header starts at offset 8
+        let status = self.read_u32(block + 8);
+        if status & TP_STATUS_USER == 0 {
+            return false;
+        }
+        atomic::fence(Ordering::Acquire);
+
+        let num_pkts = self.read_u32(block + 12);
+        let mut offset = block + self.read_u32(block + 16) as usize;
+        let end = block + RING_BLOCK_SIZE as usize;
+        for _ in 0..num_pkts {
+            if offset + 28 > end {
+                warn!("Corrupted packet in receive ring, skipping block");
+                break;
+            }
+            // struct tpacket3_hdr
+            let next = self.read_u32(offset) as usize;
+            let sec = self.read_u32(offset + 4);
+            let nsec = self.read_u32(offset + 8);
+            let snaplen = self.read_u32(offset + 12) as usize;
+            let mac = self.read_u16(offset + 24) as usize;
+
+            let start = offset + mac;
+            if start + snaplen > end {
+                warn!("Corrupted packet in receive ring, skipping block");
+                break;
+            }
+            let data = unsafe { std::slice::from_raw_parts(self.map.add(start), snaplen) };
+            frames.push_back(Frame {
+                interface: 0,
+                name: None,
+                linktype: pcap::LINKTYPE_ETHERNET,
+                timestamp: Duration::new(sec as u64, nsec),
+                data: data.to_vec(),
+            });
+
+            if next == 0 {
+                break;
+            }
+            offset += next;
+        }
+
+        // hand the block back to the kernel
+        atomic::fence(Ordering::Release);
+        unsafe { ptr::write_volatile(self.map.add(block + 8) as *mut u32, TP_STATUS_KERNEL) };
+        self.current = (self.current + 1) % RING_BLOCK_NR;
+        true
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map as *mut libc::c_void, self.len) };
+    }
+}
+
+/// A filtered AF_PACKET socket bound to a single interface
+pub struct Socket {
+    fd: OwnedFd,
+    ring: Option<Ring>,
+}
+
+impl Socket {
+    /// Open the socket, this needs CAP_NET_RAW
+    pub fn open(dev: &str, ring: bool) -> Result<Socket> {
+        let name = CString::new(dev)
+            .context("Interface name contains a nul byte")?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error()).with_context(|| anyhow!("Failed to find interface {:?}", dev));
+        }
+
+        // don't receive anything until the filter is attached
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) };
+        check(fd, "Failed to open packet socket")?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let prog = libc::sock_fprog {
+            len: FILTER.len() as u16,
+            filter: FILTER.as_ptr() as *mut libc::sock_filter,
+        };
+        setsockopt(&fd, libc::SOL_SOCKET, SO_ATTACH_FILTER, &prog, "Failed to attach bpf filter")?;
+
+        let ring = if ring {
+            Some(Ring::setup(&fd)?)
+        } else {
+            None
+        };
+
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+        addr.sll_ifindex = ifindex as i32;
+        let ret = unsafe {
+            libc::bind(fd.as_raw_fd(), &addr as *const _ as *const libc::sockaddr, mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t)
+        };
+        check(ret, "Failed to bind packet socket")?;
+
+        info!("Opened packet socket on {:?} (ring: {})", dev, ring.is_some());
+        Ok(Socket {
+            fd,
+            ring,
+        })
+    }
+
+    /// Register the socket with the runtime, the socket is handed back on error so it can be retried
+    pub fn reader(self) -> std::result::Result<Reader, (Socket, Error)> {
+        // the OwnedFd is only closed once the AsyncFd is dropped
+        match unsafe { AsyncFd::register(self.fd) } {
+            Ok(fd) => Ok(Reader {
+                fd,
+                ring: self.ring,
+                pending: VecDeque::new(),
+                buf: vec![0u8; SNAPLEN],
+            }),
+            Err(err) => {
+                let (fd, err) = err.into_parts();
+                let err = Error::from(err).context("Failed to register packet socket");
+                Err((Socket { fd, ring: self.ring }, err))
+            }
+        }
+    }
+}
+
+pub struct Reader {
+    fd: AsyncFd<OwnedFd>,
+    ring: Option<Ring>,
+    pending: VecDeque<Frame>,
+    buf: Vec<u8>,
+}
+
+impl Reader {
+    pub async fn next(&mut self) -> Result<Frame> {
+        loop {
+            if let Some(frame) = self.pending.pop_front() {
+                return Ok(frame);
+            }
+
+            let mut guard = self.fd.readable().await?;
+            if let Some(ring) = &mut self.ring {
+                if !ring.read_block(&mut self.pending) {
+                    guard.clear_ready();
+                }
+                continue;
+            }
+
+            let buf = &mut self.buf;
+            let res = guard.try_io(|fd| {
+                let n = unsafe {
+                    libc::recv(fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0)
+                };
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            });
+
+            match res {
+                Ok(Ok(n)) => {
+                    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                    return Ok(Frame {
+                        interface: 0,
+                        name: None,
+                        linktype: pcap::LINKTYPE_ETHERNET,
+                        timestamp,
+                        data: self.buf[..n].to_vec(),
+                    });
+                }
+                Ok(Err(err)) => return Err(err).context("Failed to receive from packet socket"),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// Drop the capabilities that are only needed to open packet sockets
+///
+/// Capabilities are per-thread, this needs to be called before any other thread is started.
+pub fn drop_capabilities() -> Result<()> {
+    let setpcap = caps::has_cap(None, CapSet::Effective, Capability::CAP_SETPCAP)
+        .context("Failed to read capabilities")?;
+
+    for cap in &[Capability::CAP_NET_RAW, Capability::CAP_NET_ADMIN] {
+        // the bounding set prevents child processes from getting them back
+        if setpcap {
+            caps::drop(None, CapSet::Bounding, *cap)
+                .with_context(|| anyhow!("Failed to drop {} from bounding set", cap))?;
+        }
+        for set in &[CapSet::Effective, CapSet::Permitted, CapSet::Inheritable] {
+            caps::drop(None, *set, *cap)
+                .with_context(|| anyhow!("Failed to drop {}", cap))?;
+        }
+    }
+
+    debug!("Dropped capabilities for packet capture");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal classic bpf interpreter, only supporting the instructions used by FILTER
+    fn run(prog: &[libc::sock_filter], pkt: &[u8]) -> u32 {
+        let load = |offset: usize, len: usize| -> Option<u32> {
+            let b = pkt.get(offset..offset + len)?;
+            Some(b.iter().fold(0, |acc, b| (acc << 8) | *b as u32))
+        };
+
+        let (mut a, mut x, mut pc) = (0u32, 0u32, 0usize);
+        loop {
+            let insn = &prog[pc];
+            pc += 1;
+            let k = insn.k as usize;
+            let value = match insn.code {
+                BPF_LD_H_ABS => load(k, 2),
+                BPF_LD_B_ABS => load(k, 1),
+                BPF_LD_H_IND => load(x as usize + k, 2),
+                BPF_LD_B_IND => load(x as usize + k, 1),
+                BPF_LDX_B_MSH => {
+                    x = (load(k, 1).unwrap_or(0) & 0xf) * 4;
+                    continue;
+                }
+                BPF_JEQ_K | BPF_JSET_K => {
+                    let taken = if insn.code == BPF_JEQ_K { a == insn.k } else { a & insn.k != 0 };
+                    let offset = if taken { insn.jt } else { insn.jf };
+                    pc += offset as usize;
+                    continue;
+                }
+                BPF_RET_K => return insn.k,
+                code => panic!("unsupported opcode: {:#x}", code),
+            };
+            // out of bounds loads drop the packet
+            match value {
+                Some(value) => a = value,
+                None => return 0,
+            }
+        }
+    }
+
+    fn ipv4(protocol: u8, transport: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0; 12];
+        buf.extend([0x08, 0x00, 0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, protocol, 0, 0, 10, 0, 0, 2, 10, 0, 0, 1]);
+        buf.extend(transport);
+        buf
+    }
+
+    fn ipv6(next: u8, transport: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0; 12];
+        buf.extend([0x86, 0xdd, 0x60, 0, 0, 0, 0, 0, next, 64]);
+        buf.extend([0; 32]);
+        buf.extend(transport);
+        buf
+    }
+
+    fn tcp(sport: u16, dport: u16, flags: u8) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(sport.to_be_bytes());
+        buf.extend(dport.to_be_bytes());
+        buf.extend([0; 8]);
+        buf.extend([0x50, flags, 0, 0, 0, 0, 0, 0]);
+        buf
+    }
+
+    fn udp(sport: u16, dport: u16) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(sport.to_be_bytes());
+        buf.extend(dport.to_be_bytes());
+        buf.extend([0, 8, 0, 0]);
+        buf
+    }
+
+    #[test]
+    fn filter_accepts() {
+        for pkt in &[
+            ipv4(17, &udp(1337, 53)),
+            ipv4(17, &udp(53, 1337)),
+            ipv4(17, &udp(1337, 443)),
+            ipv4(6, &tcp(1337, 443, 0x18)),
+            ipv4(6, &tcp(80, 1337, 0x18)),
+            ipv4(6, &tcp(1337, 22, 0x02)),
+            ipv6(17, &udp(1337, 53)),
+            ipv6(6, &tcp(443, 1337, 0x10)),
+            ipv6(6, &tcp(1337, 5228, 0x02)),
+        ] {
+            assert_eq!(run(FILTER, pkt), SNAPLEN as u32, "{:?}", pkt);
+        }
+    }
+
+    #[test]
+    fn filter_drops() {
+        let mut fragment = ipv4(17, &udp(1337, 53));
+        fragment[20] = 0x00;
+        fragment[21] = 0x10;
+
+        for pkt in &[
+            ipv4(17, &udp(1337, 123)),
+            ipv4(6, &tcp(1337, 22, 0x18)),
+            ipv4(1, &[8, 0, 0, 0]),
+            ipv6(17, &udp(1337, 5353)),
+            ipv6(58, &[128, 0, 0, 0]),
+            fragment,
+            // arp
+            [&[0; 12][..], &[0x08, 0x06], &[0; 28]].concat(),
+        ] {
+            assert_eq!(run(FILTER, pkt), 0, "{:?}", pkt);
+        }
+    }
+
+    #[test]
+    fn filter_ipv4_options() {
+        let mut pkt = ipv4(17, &[]);
+        // ihl of 6 words, with 4 bytes of options
+        pkt[14] = 0x46;
+        pkt.extend([1, 1, 1, 0]);
+        pkt.extend(udp(1337, 53));
+        assert_eq!(run(FILTER, &pkt), SNAPLEN as u32);
+    }
+
+    #[test]
+    fn filter_jumps_in_bounds() {
+        for (i, insn) in FILTER.iter().enumerate().filter(|(_, insn)| insn.code & 0x07 == 0x05) {
+            assert!(i + 1 + (insn.jt as usize) < FILTER.len());
+            assert!(i + 1 + (insn.jf as usize) < FILTER.len());
+        }
+    }
+
+    async fn capture_loopback(ring: bool) {
+        let mut reader = Socket::open("lo", ring).unwrap().reader().map_err(|(_, err)| err).unwrap();
+
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        // filtered out
+        sock.send_to(b"ntp", "127.0.0.1:123").unwrap();
+        sock.send_to(b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x06google\x03com\x00\x00\x01\x00\x01", "127.0.0.1:53").unwrap();
+
+        let frame = tokio::time::timeout(Duration::from_secs(5), reader.next()).await
+            .expect("timeout")
+            .unwrap();
+        let pkt = crate::dissect::frame(frame.linktype, &frame.data).unwrap();
+        assert_eq!(pkt.get_questions(), vec![("A".to_string(), "google.com".to_string())]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs CAP_NET_RAW"]
+    async fn loopback() {
+        capture_loopback(false).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs CAP_NET_RAW"]
+    async fn loopback_ring() {
+        capture_loopback(true).await;
+    }
+}
@@ -1,4 +1,5 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Parser)]
 pub struct Args {
@@ -9,25 +10,144 @@ pub struct Args {
 #[derive(Debug, Parser)]
 pub enum SubCommand {
     Start(Start),
+    Analyze(Analyze),
+    Resolver(Resolver),
     Send(Send),
     Sniff(Sniff),
     Stream(Stream),
     Screen(Screen),
     Hotspot(Hotspot),
+    History(History),
+    Report(Report),
+    Record(Record),
+    Replay(Replay),
+    Config(Config),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// Spawn `sniffglue --json`
+    Sniffglue,
+    /// Spawn `tcpdump -w -` and dissect in-process
+    Tcpdump,
+    /// Read from an AF_PACKET socket and dissect in-process, linux only
+    AfPacket,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Output of `sniffglue --json`
+    Sniffglue,
+    /// Suricata eve.json
+    Suricata,
+    /// Zeek dns, ssl, http and conn logs, tsv or json
+    Zeek,
+    /// dnsmasq `log-queries` output, also used by pihole.log
+    Dnsmasq,
+    /// unbound `log-queries` output
+    Unbound,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    /// A single html file that can be opened without network access
+    Html,
+    Json,
+    /// Printable, only latin1 text is supported
+    Pdf,
 }
 
 #[derive(Debug, Parser)]
 pub struct Start {
-    #[clap(short, default_value="hostapd.conf")]
+    /// Config file with the settings of the daemon and the sinks events are sent to
+    #[clap(long)]
+    pub config: Option<String>,
+    #[clap(flatten)]
+    pub overrides: Overrides,
+}
+
+/// Flags that take precedence over the config file
+#[derive(Debug, Default, Parser)]
+pub struct Overrides {
+    /// The hostapd config that is written for every new password
+    #[clap(short)]
+    pub file: Option<String>,
+    /// Interfaces to capture on, can be used multiple times
+    #[clap(short='i')]
+    pub devices: Vec<String>,
+    /// How packets are captured
+    #[clap(long, value_enum)]
+    pub capture: Option<Backend>,
+    /// Use a TPACKET_V3 ring with the af-packet backend
+    #[clap(long)]
+    pub ring: bool,
+    #[clap(short='x')]
+    pub screen: Option<String>,
+    #[clap(short='S')]
+    pub socket: Option<String>,
+    /// Store sessions and findings in this sqlite database, e.g. /spytrap/history.db
+    #[clap(long)]
+    pub history: Option<String>,
+    #[clap(flatten)]
+    pub rule_files: RuleFiles,
+}
+
+/// The files the detections are loaded from, shared by every command that runs them
+#[derive(Debug, Default, Parser)]
+pub struct RuleFiles {
+    /// Known stalkerware in the stalkerware-indicators format, ./ioc.yaml if not set
+    #[clap(short, long)]
+    pub rules: Option<String>,
+    /// Additional ip ranges that may be contacted without a dns lookup
+    #[clap(long)]
+    pub allowlist: Option<String>,
+    /// Ip ranges of the phones besides the private ones, e.g. the global ipv6 prefix of the lan
+    #[clap(long)]
+    pub local_networks: Option<String>,
+    /// Custom detection rules, in addition to the iocs
+    #[clap(long)]
+    pub custom_rules: Option<String>,
+}
+
+/// Run the detections on a pcap or pcapng file
+#[derive(Debug, Parser)]
+pub struct Analyze {
     pub file: String,
-    #[clap(short='i', default_value="en0")]
-    pub device: String,
-    #[clap(short='x', default_value="cat")]
-    pub screen: String,
-    #[clap(short='S', default_value="foo.sock")]
-    pub socket: String,
-    #[clap(short, long, default_value="ioc.yaml")]
-    pub rules: String,
+    #[clap(flatten)]
+    pub rule_files: RuleFiles,
+    /// Write an html, json and pdf report of the capture into this directory
+    #[clap(long)]
+    pub report: Option<String>,
+}
+
+/// Run the detections on resolver query logs and flow exports instead of captured packets
+#[derive(Debug, Parser)]
+pub struct Resolver {
+    /// Follow a dnsmasq log file with `log-queries` enabled, also works for pihole.log
+    #[clap(long)]
+    pub dnsmasq: Option<String>,
+    /// Follow an unbound log file with `log-queries` enabled
+    #[clap(long)]
+    pub unbound: Option<String>,
+    /// Poll the pihole FTL query database
+    #[clap(long)]
+    pub pihole: Option<String>,
+    /// Also read the queries that were logged before the start, by default only new ones are read
+    #[clap(long)]
+    pub backfill: bool,
+    /// Listen for dnstap frames on this unix socket
+    #[clap(long)]
+    pub dnstap: Option<String>,
+    /// Group of the dnstap socket, the resolver needs to be a member to connect to it
+    #[clap(long)]
+    pub dnstap_group: Option<String>,
+    /// Collect netflow v5/v9 and ipfix on this udp address, e.g. 0.0.0.0:2055
+    #[clap(long)]
+    pub netflow: Option<String>,
+    #[clap(flatten)]
+    pub rule_files: RuleFiles,
 }
 
 #[derive(Debug, Parser)]
@@ -39,14 +159,25 @@ pub struct Send {
 
 #[derive(Debug, Parser)]
 pub struct Sniff {
+    /// Interfaces to capture on, can be used multiple times
     #[clap(short='i', default_value="en0")]
-    pub device: String,
+    pub devices: Vec<String>,
+    /// How packets are captured
+    #[clap(long, value_enum, default_value="sniffglue")]
+    pub capture: Backend,
+    /// Use a TPACKET_V3 ring with the af-packet backend
+    #[clap(long)]
+    pub ring: bool,
 }
 
+/// Run the detections on lines from stdin, e.g. sniffglue or another tool's logs
 #[derive(Debug, Parser)]
 pub struct Stream {
-    #[clap(short, long, default_value="./ioc.yaml")]
-    pub rules: String,
+    /// Format of the lines read from stdin
+    #[clap(long, value_enum, default_value="sniffglue")]
+    pub format: Format,
+    #[clap(flatten)]
+    pub rule_files: RuleFiles,
 }
 
 #[derive(Debug, Parser)]
@@ -57,6 +188,119 @@ pub struct Screen {
 
 #[derive(Debug, Parser)]
 pub struct Hotspot {
-    #[clap(short, default_value="hostapd.conf")]
-    pub file: String,
+    /// Config file with the hotspot settings
+    #[clap(long)]
+    pub config: Option<String>,
+    /// The hostapd config that is written for every new password
+    #[clap(short)]
+    pub file: Option<String>,
+}
+
+/// Query past scans from the history database
+#[derive(Debug, Parser)]
+pub struct History {
+    #[clap(long, default_value="/spytrap/history.db")]
+    pub db: String,
+    #[clap(subcommand)]
+    pub command: HistoryCommand,
+}
+
+#[derive(Debug, Parser)]
+pub enum HistoryCommand {
+    /// List all sessions, the most recent one first
+    List,
+    /// Show the findings and observed names of a session
+    Show {
+        id: String,
+    },
+    /// Export sessions as json, all of them if no id is given
+    Export {
+        id: Option<String>,
+    },
+}
+
+/// Render the report of a past scan
+#[derive(Debug, Parser)]
+pub struct Report {
+    pub id: String,
+    #[clap(long, default_value="/spytrap/history.db")]
+    pub db: String,
+    #[clap(long, value_enum, default_value="html")]
+    pub format: ReportFormat,
+    /// Write the report to this file instead of stdout
+    #[clap(short, long)]
+    pub output: Option<String>,
+}
+
+/// Write the raw capture input to compressed, rotating files to replay it later
+#[derive(Debug, Parser)]
+pub struct Record {
+    /// Directory the recording is written to, it needs to be empty
+    #[clap(short, long)]
+    pub output: String,
+    /// Interfaces to capture on, can be used multiple times
+    #[clap(short='i', default_value="en0")]
+    pub devices: Vec<String>,
+    /// How packets are captured
+    #[clap(long, value_enum, default_value="sniffglue")]
+    pub capture: Backend,
+    /// Use a TPACKET_V3 ring with the af-packet backend
+    #[clap(long)]
+    pub ring: bool,
+    /// Record lines of this format from stdin instead of capturing
+    #[clap(long, value_enum)]
+    pub stdin: Option<Format>,
+    /// Also record the client updates of the hostapd hook from this rpc socket, replays then start a session per phone
+    #[clap(short='S', long)]
+    pub socket: Option<String>,
+    /// Start a new file after this many megabytes, before compression
+    #[clap(long, default_value="64")]
+    pub max_size: u64,
+    /// Delete the oldest files if there are more than this, 0 keeps all of them
+    #[clap(long, default_value="0")]
+    pub max_files: usize,
+}
+
+/// Run the detections on a recording with its original timing
+#[derive(Debug, Parser)]
+pub struct Replay {
+    /// A recording directory or a single file of it
+    pub path: String,
+    /// Replay this many times faster than real time
+    #[clap(long, default_value="1", value_parser=parse_speed)]
+    pub speed: f64,
+    /// Replay as fast as possible
+    #[clap(long)]
+    pub fast: bool,
+    #[clap(flatten)]
+    pub rule_files: RuleFiles,
+    /// Send the events to the sinks of this config file instead of stdout
+    #[clap(long)]
+    pub config: Option<String>,
+}
+
+fn parse_speed(s: &str) -> Result<f64, String> {
+    let speed = s.parse::<f64>().map_err(|err| err.to_string())?;
+    if !speed.is_finite() || speed < crate::recording::MIN_SPEED {
+        return Err(format!("needs to be at least {}, use --fast to replay as fast as possible", crate::recording::MIN_SPEED));
+    }
+    Ok(speed)
+}
+
+/// Inspect the config file of the daemon
+#[derive(Debug, Parser)]
+pub struct Config {
+    #[clap(subcommand)]
+    pub command: ConfigCommand,
+}
+
+#[derive(Debug, Parser)]
+pub enum ConfigCommand {
+    /// Validate the config and print the settings that are used, including flags and defaults
+    Check {
+        #[clap(long)]
+        config: Option<String>,
+        #[clap(flatten)]
+        overrides: Overrides,
+    },
 }
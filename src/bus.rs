@@ -0,0 +1,406 @@
+use crate::errors::*;
+use crate::event::Event;
+use crate::sink::{DropPolicy, Registry, Sink, SinkConfig};
+use crate::supervisor::{Policy, Restarts};
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::{Stream, StreamExt};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// Events that are waiting for a sink, this never blocks the publisher
+#[derive(Debug)]
+struct Queue {
+    events: Mutex<VecDeque<Event>>,
+    capacity: usize,
+    policy: DropPolicy,
+    notify: Notify,
+    closed: AtomicBool,
+    dropped: AtomicUsize,
+}
+
+impl Queue {
+    fn new(capacity: usize, policy: DropPolicy) -> Queue {
+        Queue {
+            events: Mutex::new(VecDeque::new()),
+            capacity: capacity.max(1),
+            policy,
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns false if an event had to be dropped
+    fn push(&self, event: Event) -> bool {
+        let mut events = self.events.lock().unwrap();
+        let accepted = if events.len() < self.capacity {
+            events.push_back(event);
+            true
+        } else {
+            if self.policy == DropPolicy::Oldest {
+                events.pop_front();
+                events.push_back(event);
+            }
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            false
+        };
+        drop(events);
+        self.notify.notify_one();
+        accepted
+    }
+
+    /// Wait for the next event, returns None once the bus is gone and the queue is empty
+    async fn pop(&self) -> Option<Event> {
+        loop {
+            let notified = self.notify.notified();
+            if let Some(event) = self.events.lock().unwrap().pop_front() {
+                return Some(event);
+            }
+            if self.closed.load(Ordering::Acquire) {
+                return None;
+            }
+            notified.await;
+        }
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.notify.notify_one();
+    }
+}
+
+struct Subscriber {
+    name: String,
+    observations: bool,
+    queue: Arc<Queue>,
+    task: JoinHandle<()>,
+}
+
+/// How long the sinks may take to deliver their queue once the events ended
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Broadcast events to all sinks, every sink has its own buffer so a slow
+/// or crashed sink doesn't block the detections or any other sink
+pub struct Bus {
+    policy: Policy,
+    drain_timeout: Duration,
+    subscribers: Vec<Subscriber>,
+    failures_tx: UnboundedSender<Event>,
+    failures_rx: mpsc::UnboundedReceiver<Event>,
+}
+
+impl Default for Bus {
+    fn default() -> Bus {
+        let (failures_tx, failures_rx) = mpsc::unbounded();
+        Bus {
+            policy: Policy::default(),
+            drain_timeout: DRAIN_TIMEOUT,
+            subscribers: Vec::new(),
+            failures_tx,
+            failures_rx,
+        }
+    }
+}
+
+impl Bus {
+    pub fn new() -> Bus {
+        Bus::default()
+    }
+
+    /// Subscribe every configured sink, they are created with the factories of the registry
+    pub fn with_sinks(sinks: &[SinkConfig], registry: Arc<Registry>) -> Result<Bus> {
+        let mut bus = Bus::new();
+        for config in sinks {
+            if !registry.contains(&config.kind) {
+                bail!("Unknown sink type: {:?}", config.kind);
+            }
+            let registry = registry.clone();
+            let sink = config.clone();
+            bus.subscribe(config, move || registry.build(&sink));
+        }
+        Ok(bus)
+    }
+
+    pub fn len(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.is_empty()
+    }
+
+    pub fn policy(mut self, policy: Policy) -> Bus {
+        self.policy = policy;
+        self
+    }
+
+    pub fn drain_timeout(mut self, timeout: Duration) -> Bus {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Start a task that delivers events to this sink, the sink is created
+    /// again with `factory` if it fails, e.g. if the screen has crashed
+    pub fn subscribe<F>(&mut self, config: &SinkConfig, mut factory: F)
+    where
+        F: FnMut() -> Result<Box<dyn Sink>> + Send + 'static,
+    {
+        let name = config.name().to_string();
+        let queue = Arc::new(Queue::new(config.buffer, config.drop));
+
+        let rx = queue.clone();
+        let mut failures = self.failures_tx.clone();
+        let mut restarts = Restarts::new(self.policy);
+        let task_name = name.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let started = Instant::now();
+                let err = match factory() {
+                    Ok(mut sink) => loop {
+                        let event = match rx.pop().await {
+                            Some(event) => event,
+                            None => return,
+                        };
+                        if let Err(err) = sink.send(&event).await {
+                            break err;
+                        }
+                    },
+                    Err(err) => err,
+                };
+
+                // events are still queued while the sink is restarting, the other sinks are told about the failure
+                let msg = match restarts.failed(started, Instant::now()) {
+                    Some(delay) => {
+                        error!("Sink {:?} failed, restarting in {:?}: {:#}", task_name, delay, err);
+                        futures::SinkExt::send(&mut failures, Event::error(format!("sink {} failed, restarting in {:?}: {:#}", task_name, delay, err))).await.ok();
+                        tokio::time::sleep(delay).await;
+                        continue;
+                    }
+                    None => format!("sink {} failed too often, giving up: {:#}", task_name, err),
+                };
+                error!("{}", msg);
+                rx.close();
+                futures::SinkExt::send(&mut failures, Event::error(msg)).await.ok();
+                break;
+            }
+        });
+
+        self.subscribers.push(Subscriber {
+            name,
+            observations: config.observations,
+            queue,
+            task,
+        });
+    }
+
+    /// Queue an event for every sink that is interested in it
+    pub fn publish(&self, event: &Event) {
+        for sub in &self.subscribers {
+            if sub.queue.closed.load(Ordering::Acquire) {
+                continue;
+            }
+            if event.is_observation() && !sub.observations {
+                continue;
+            }
+            if !sub.queue.push(event.clone()) && sub.queue.dropped.load(Ordering::Relaxed) == 1 {
+                warn!("Sink {:?} can't keep up, dropping events", sub.name);
+            }
+        }
+    }
+
+    /// Number of events that were dropped for each sink
+    pub fn dropped(&self) -> Vec<(&str, usize)> {
+        self.subscribers.iter()
+            .map(|sub| (sub.name.as_str(), sub.queue.dropped.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Publish everything from this stream until it ends, then wait for the sinks to deliver their queue
+    pub async fn run<R: Stream<Item=Event> + Unpin>(mut self, rx: R) -> Result<()> {
+        let mut rx = rx.fuse();
+        loop {
+            let event = futures::select! {
+                event = rx.next() => event,
+                failure = self.failures_rx.next() => failure,
+            };
+            match event {
+                Some(event) => self.publish(&event),
+                None => break,
+            }
+        }
+        self.drain().await;
+        Ok(())
+    }
+
+    /// Sinks that are stuck, e.g. a screen that doesn't read its stdin, are given up on after the drain timeout
+    async fn drain(&mut self) {
+        for sub in &self.subscribers {
+            sub.queue.close();
+        }
+        let tasks = self.subscribers.iter_mut().map(|sub| &mut sub.task);
+        if tokio::time::timeout(self.drain_timeout, futures::future::join_all(tasks)).await.is_err() {
+            for sub in self.subscribers.iter().filter(|sub| !sub.task.is_finished()) {
+                warn!("Sink {:?} didn't deliver all events within {:?}", sub.name, self.drain_timeout);
+            }
+        }
+    }
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        // sinks finish their queue and then stop
+        for sub in &self.subscribers {
+            sub.queue.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::BoxFuture;
+    use futures::FutureExt;
+
+    fn status(n: usize) -> Event {
+        Event::status(n.to_string())
+    }
+
+    fn drain(queue: &Queue) -> Vec<Event> {
+        queue.events.lock().unwrap().drain(..).collect()
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let queue = Queue::new(2, DropPolicy::Oldest);
+        assert!(queue.push(status(1)));
+        assert!(queue.push(status(2)));
+        assert!(!queue.push(status(3)));
+        assert_eq!(drain(&queue), vec![status(2), status(3)]);
+        assert_eq!(queue.dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn drop_newest() {
+        let queue = Queue::new(2, DropPolicy::Newest);
+        assert!(queue.push(status(1)));
+        assert!(queue.push(status(2)));
+        assert!(!queue.push(status(3)));
+        assert_eq!(drain(&queue), vec![status(1), status(2)]);
+    }
+
+    struct Collect(Arc<Mutex<Vec<Event>>>);
+
+    impl Sink for Collect {
+        fn send<'a>(&'a mut self, event: &'a Event) -> BoxFuture<'a, Result<()>> {
+            async move {
+                self.0.lock().unwrap().push(event.clone());
+                Ok(())
+            }.boxed()
+        }
+    }
+
+    struct Fail;
+
+    impl Sink for Fail {
+        fn send<'a>(&'a mut self, _event: &'a Event) -> BoxFuture<'a, Result<()>> {
+            async move { bail!("broken pipe") }.boxed()
+        }
+    }
+
+    /// Never finishes sending, like a screen that doesn't read its stdin
+    struct Stuck;
+
+    impl Sink for Stuck {
+        fn send<'a>(&'a mut self, _event: &'a Event) -> BoxFuture<'a, Result<()>> {
+            futures::future::pending().boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_sinks_are_isolated() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut bus = Bus::new().policy(Policy {
+            initial: Duration::from_secs(10),
+            budget: 1,
+            ..Policy::default()
+        }).drain_timeout(Duration::from_millis(100));
+        bus.subscribe(&SinkConfig::new("fail"), || Ok(Box::new(Fail)));
+        bus.subscribe(&SinkConfig::new("stuck"), || Ok(Box::new(Stuck)));
+        let collect = events.clone();
+        bus.subscribe(&SinkConfig {
+            buffer: 256,
+            ..SinkConfig::new("collect")
+        }, move || Ok(Box::new(Collect(collect.clone()))));
+
+        let (tx, rx) = mpsc::unbounded();
+        let run = tokio::spawn(bus.run(rx));
+        for n in 0..100 {
+            tx.unbounded_send(status(n)).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(tx);
+        run.await.unwrap().unwrap();
+
+        let events = events.lock().unwrap();
+        let statuses = events.iter()
+            .filter(|event| matches!(event, Event::Status { .. }))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(statuses, (0..100).map(status).collect::<Vec<_>>());
+        assert!(events.contains(&Event::error("sink fail failed, restarting in 10s: broken pipe")));
+    }
+
+    #[tokio::test]
+    async fn skip_observations() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut bus = Bus::new();
+        let collect = events.clone();
+        bus.subscribe(&SinkConfig::new("collect"), move || Ok(Box::new(Collect(collect.clone()))));
+
+        let observed = Event::Observation(crate::event::Observed {
+            kind: "dns".to_string(),
+            name: "example.com".to_string(),
+            origin: Default::default(),
+        });
+        bus.publish(&observed);
+        bus.publish(&status(1));
+        drop(bus);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(*events.lock().unwrap(), vec![status(1)]);
+    }
+
+    #[tokio::test]
+    async fn restart_sinks() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut bus = Bus::new().policy(Policy {
+            initial: Duration::from_millis(1),
+            ..Policy::default()
+        });
+        let collect = events.clone();
+        let mut attempts = 0;
+        bus.subscribe(&SinkConfig::new("screen"), move || {
+            attempts += 1;
+            if attempts == 1 {
+                bail!("not started yet");
+            }
+            Ok(Box::new(Collect(collect.clone())))
+        });
+
+        let (tx, rx) = mpsc::unbounded();
+        tx.unbounded_send(status(1)).unwrap();
+        drop(tx);
+        bus.run(rx).await.unwrap();
+
+        // the failure may or may not be delivered before the bus stops
+        let statuses = events.lock().unwrap().iter()
+            .filter(|event| matches!(event, Event::Status { .. }))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(statuses, vec![status(1)]);
+    }
+}
@@ -0,0 +1,152 @@
+use crate::errors::*;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cidr> {
+        let (addr, prefix) = if let Some((addr, prefix)) = s.split_once('/') {
+            (addr, Some(prefix))
+        } else {
+            (s, None)
+        };
+
+        let addr = addr.parse::<IpAddr>()
+            .with_context(|| anyhow!("Invalid ip address: {:?}", addr))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = if let Some(prefix) = prefix {
+            let prefix = prefix.parse::<u8>()
+                .with_context(|| anyhow!("Invalid prefix length: {:?}", prefix))?;
+            if prefix > max {
+                bail!("Prefix length is too long: /{}", prefix);
+            }
+            prefix
+        } else {
+            max
+        };
+
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CidrSet {
+    list: Vec<Cidr>,
+}
+
+impl CidrSet {
+    #[inline]
+    pub fn new() -> CidrSet {
+        CidrSet::default()
+    }
+
+    pub fn insert(&mut self, cidr: Cidr) {
+        self.list.push(cidr);
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.list.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Read one range per line, empty lines and `#` comments are ignored
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let buf = fs::read_to_string(path)?;
+        self.parse(&buf)
+    }
+
+    fn parse(&mut self, buf: &str) -> Result<()> {
+        for (i, line) in buf.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let cidr = line.parse()
+                .with_context(|| anyhow!("Failed to parse line {}", i + 1))?;
+            self.insert(cidr);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contains_v4() {
+        let cidr = "17.0.0.0/8".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(&"17.253.144.10".parse().unwrap()));
+        assert!(!cidr.contains(&"18.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn contains_v6() {
+        let cidr = "2a00:1450::/32".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(&"2a00:1450:4001:82b::200e".parse().unwrap()));
+        assert!(!cidr.contains(&"2a00:1451::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn contains_mixed() {
+        let cidr = "0.0.0.0/0".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(&"1.2.3.4".parse().unwrap()));
+        assert!(!cidr.contains(&"::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn single_address() {
+        let cidr = "8.8.8.8".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(&"8.8.8.8".parse().unwrap()));
+        assert!(!cidr.contains(&"8.8.4.4".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_prefix() {
+        assert!("1.2.3.4/33".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn parse_list() {
+        let mut set = CidrSet::new();
+        set.parse("# apple\n17.0.0.0/8\n\n2a00:1450::/32 # google\n").unwrap();
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&"17.1.2.3".parse().unwrap()));
+        assert!(!set.contains(&"1.1.1.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,433 @@
+use crate::args::{Backend, Overrides, RuleFiles};
+use crate::errors::*;
+use crate::sink::{Registry, SinkConfig};
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Settings of the `start` daemon, every section is optional and flags take precedence
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Store sessions and findings in this sqlite database
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history: Option<String>,
+    pub capture: Capture,
+    pub hotspot: Hotspot,
+    pub rules: Rules,
+    pub screen: Screen,
+    pub rpc: Rpc,
+    /// Where events are sent to, the screen is used if this is empty
+    #[serde(rename = "sink", skip_serializing_if = "Vec::is_empty")]
+    pub sinks: Vec<SinkConfig>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Capture {
+    #[serde(deserialize_with = "interfaces")]
+    pub interfaces: Vec<String>,
+    pub backend: Backend,
+    /// Use a TPACKET_V3 ring with the af-packet backend
+    pub ring: bool,
+}
+
+impl Default for Capture {
+    fn default() -> Capture {
+        Capture {
+            interfaces: vec!["en0".to_string()],
+            backend: Backend::Sniffglue,
+            ring: false,
+        }
+    }
+}
+
+/// The access point, hostapd is restarted with a new password for every scan
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hotspot {
+    /// The hostapd config that is written for every new password
+    pub config: String,
+    #[serde(deserialize_with = "interface")]
+    pub interface: String,
+    #[serde(deserialize_with = "ssid")]
+    pub ssid: String,
+    #[serde(deserialize_with = "country_code")]
+    pub country_code: String,
+    /// 1-14 are 2.4GHz, anything above is 5GHz
+    #[serde(deserialize_with = "channel")]
+    pub channel: u8,
+}
+
+impl Default for Hotspot {
+    fn default() -> Hotspot {
+        Hotspot {
+            config: "hostapd.conf".to_string(),
+            interface: "wlan1".to_string(),
+            ssid: "Starbucks WiFi".to_string(),
+            country_code: "DE".to_string(),
+            channel: 11,
+        }
+    }
+}
+
+impl Hotspot {
+    pub fn hw_mode(&self) -> &'static str {
+        if self.channel <= 14 {
+            "g"
+        } else {
+            "a"
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+    pub iocs: String,
+    /// Additional ip ranges that may be contacted without a dns lookup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowlist: Option<String>,
+    /// Ip ranges of the phones besides the private ones, e.g. the global ipv6 prefix of the lan
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_networks: Option<String>,
+    /// Custom detection rules, in addition to the iocs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom_rules: Option<String>,
+}
+
+impl Default for Rules {
+    fn default() -> Rules {
+        Rules {
+            iocs: "ioc.yaml".to_string(),
+            allowlist: None,
+            local_networks: None,
+            custom_rules: None,
+        }
+    }
+}
+
+impl Rules {
+    fn apply(&mut self, args: &RuleFiles) {
+        if let Some(rules) = &args.rules {
+            self.iocs = rules.clone();
+        }
+        if let Some(allowlist) = &args.allowlist {
+            self.allowlist = Some(allowlist.clone());
+        }
+        if let Some(local_networks) = &args.local_networks {
+            self.local_networks = Some(local_networks.clone());
+        }
+        if let Some(custom_rules) = &args.custom_rules {
+            self.custom_rules = Some(custom_rules.clone());
+        }
+    }
+}
+
+/// Commands that don't read a config file only use the flags
+impl From<&RuleFiles> for Rules {
+    fn from(args: &RuleFiles) -> Rules {
+        let mut rules = Rules::default();
+        rules.apply(args);
+        rules
+    }
+}
+
+/// The screen that's used if no sinks are configured
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Screen {
+    pub command: String,
+}
+
+impl Default for Screen {
+    fn default() -> Screen {
+        Screen {
+            command: "cat".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rpc {
+    /// Unix socket for the hostapd hook
+    pub socket: String,
+}
+
+impl Default for Rpc {
+    fn default() -> Rpc {
+        Rpc {
+            socket: "foo.sock".to_string(),
+        }
+    }
+}
+
+/// Names end up in the hostapd config, anything that could add a line is rejected
+fn valid_interface(name: &str) -> bool {
+    (1..=15).contains(&name.len())
+        && name != "."
+        && name != ".."
+        && !name.chars().any(|c| c.is_whitespace() || c.is_control() || c == '/')
+}
+
+fn valid_ssid(ssid: &str) -> bool {
+    (1..=32).contains(&ssid.len()) && !ssid.chars().any(char::is_control)
+}
+
+fn valid_channel(channel: u8) -> bool {
+    match channel {
+        1..=14 => true,
+        36..=64 | 100..=144 => channel.is_multiple_of(4),
+        149..=165 => channel % 4 == 1,
+        _ => false,
+    }
+}
+
+/// Errors from here are reported by toml with the location of the value
+fn check<'de, D, T, F>(deserializer: D, valid: F, msg: &str) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+    F: FnOnce(&T) -> bool,
+{
+    let value = T::deserialize(deserializer)?;
+    if valid(&value) {
+        Ok(value)
+    } else {
+        Err(de::Error::custom(msg))
+    }
+}
+
+fn interfaces<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    check(deserializer, |names: &Vec<String>| !names.is_empty() && names.iter().all(|name| valid_interface(name)),
+        "expected a list of interface names, at least one is needed")
+}
+
+fn interface<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    check(deserializer, |name: &String| valid_interface(name), "invalid interface name")
+}
+
+fn ssid<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    check(deserializer, |ssid: &String| valid_ssid(ssid), "ssid needs to be 1 to 32 bytes, without control characters")
+}
+
+fn country_code<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    check(deserializer, |code: &String| code.len() == 2 && code.chars().all(|c| c.is_ascii_uppercase()),
+        "expected a two letter country code, e.g. \"DE\"")
+}
+
+fn channel<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    check(deserializer, |channel: &u8| valid_channel(*channel), "not a valid wifi channel")
+}
+
+impl Config {
+    /// Flags from the command line replace the settings of the file
+    pub fn apply(&mut self, args: &Overrides) {
+        if let Some(file) = &args.file {
+            self.hotspot.config = file.clone();
+        }
+        if !args.devices.is_empty() {
+            self.capture.interfaces = args.devices.clone();
+        }
+        if let Some(backend) = args.capture {
+            self.capture.backend = backend;
+        }
+        if args.ring {
+            self.capture.ring = true;
+        }
+        if let Some(screen) = &args.screen {
+            self.screen.command = screen.clone();
+        }
+        if let Some(socket) = &args.socket {
+            self.rpc.socket = socket.clone();
+        }
+        if let Some(history) = &args.history {
+            self.history = Some(history.clone());
+        }
+        self.rules.apply(&args.rule_files);
+    }
+
+    /// Checks that need the whole config, values from flags are checked again
+    pub fn validate(&self, registry: &Registry) -> Result<()> {
+        if self.capture.interfaces.is_empty() {
+            bail!("capture.interfaces: at least one interface is needed");
+        }
+        if let Some(name) = self.capture.interfaces.iter().find(|name| !valid_interface(name)) {
+            bail!("capture.interfaces: invalid interface name {:?}", name);
+        }
+        if !valid_interface(&self.hotspot.interface) {
+            bail!("hotspot.interface: invalid interface name {:?}", self.hotspot.interface);
+        }
+
+        for (i, sink) in self.sinks.iter().enumerate() {
+            if !registry.contains(&sink.kind) {
+                bail!("sink #{}: unknown sink type {:?}", i + 1, sink.kind);
+            }
+            let missing = match sink.kind.as_str() {
+                "screen" if sink.command.is_none() => Some("command"),
+                "file" | "socket" | "report" if sink.path.is_none() => Some("path"),
+                _ => None,
+            };
+            if let Some(field) = missing {
+                bail!("sink #{} ({}): missing {:?}", i + 1, sink.name(), field);
+            }
+        }
+        Ok(())
+    }
+
+    /// The configured sinks, or the screen if there are none, and the history database
+    pub fn sinks(&self) -> Vec<SinkConfig> {
+        let mut sinks = if self.sinks.is_empty() {
+            vec![SinkConfig::screen(&self.screen.command)]
+        } else {
+            self.sinks.clone()
+        };
+        if let Some(path) = &self.history {
+            sinks.push(SinkConfig::history(path));
+        }
+        sinks
+    }
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Config> {
+    let buf = fs::read_to_string(path)?;
+    parse(&buf)
+}
+
+pub fn parse(buf: &str) -> Result<Config> {
+    let config = toml::from_str(buf)?;
+    Ok(config)
+}
+
+/// The config file, if any, with the flags applied on top
+pub fn effective(path: Option<&str>, args: &Overrides) -> Result<Config> {
+    let mut config = match path {
+        Some(path) => load(path)
+            .with_context(|| anyhow!("Failed to load config from {:?}", path))?,
+        None => Config::default(),
+    };
+    config.apply(args);
+    config.validate(&Registry::default())?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{DropPolicy, Format};
+
+    #[test]
+    fn parse_sinks() {
+        let config = parse(r#"
+[[sink]]
+type = "screen"
+command = "/spytrap/screen.py"
+buffer = 10
+
+[[sink]]
+type = "file"
+path = "/spytrap/events.jsonl"
+format = "json"
+drop = "newest"
+observations = true
+"#).unwrap();
+        assert_eq!(config.sinks, vec![
+            SinkConfig {
+                buffer: 10,
+                ..SinkConfig::screen("/spytrap/screen.py")
+            },
+            SinkConfig {
+                path: Some("/spytrap/events.jsonl".to_string()),
+                format: Format::Json,
+                drop: DropPolicy::Newest,
+                observations: true,
+                ..SinkConfig::new("file")
+            },
+        ]);
+    }
+
+    #[test]
+    fn reject_unknown_fields() {
+        assert!(parse("[[sink]]\ntype = \"stdout\"\nbuffr = 10\n").is_err());
+    }
+
+    #[test]
+    fn parse_sections() {
+        let config = parse(r#"
+history = "/spytrap/history.db"
+
+[capture]
+interfaces = ["wlan1", "usb0"]
+backend = "af-packet"
+
+[hotspot]
+config = "/etc/hostapd/hostapd.conf"
+ssid = "Free WiFi"
+channel = 36
+
+[rules]
+iocs = "/spytrap/ioc.yaml"
+"#).unwrap();
+        assert_eq!(config.capture.interfaces, vec!["wlan1", "usb0"]);
+        assert_eq!(config.capture.backend, Backend::AfPacket);
+        assert_eq!(config.hotspot.ssid, "Free WiFi");
+        assert_eq!(config.hotspot.hw_mode(), "a");
+        assert_eq!(config.hotspot.interface, "wlan1");
+        assert_eq!(config.rules.iocs, "/spytrap/ioc.yaml");
+        assert_eq!(config.rpc, Rpc::default());
+        assert_eq!(config.sinks(), vec![
+            SinkConfig::screen("cat"),
+            SinkConfig::history("/spytrap/history.db"),
+        ]);
+    }
+
+    #[test]
+    fn error_locations() {
+        let err = parse("[hotspot]\nssid = \"Free WiFi\"\nchannel = 15\n").unwrap_err();
+        let err = format!("{:#}", err);
+        assert!(err.contains("line 3"), "{}", err);
+        assert!(err.contains("not a valid wifi channel"), "{}", err);
+
+        let err = parse("[hotspot]\nssid = \"a\\nwpa=0\"\n").unwrap_err();
+        assert!(format!("{:#}", err).contains("line 2"));
+        assert!(parse("[capture]\ninterfaces = []\n").is_err());
+        assert!(parse("[hotspot]\ncountry_code = \"de\"\n").is_err());
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let mut config = parse("[capture]\ninterfaces = [\"wlan1\"]\n[rpc]\nsocket = \"/run/spytrap.sock\"\n").unwrap();
+        config.apply(&Overrides {
+            devices: vec!["wlan0".to_string()],
+            history: Some("h.db".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(config.capture.interfaces, vec!["wlan0"]);
+        assert_eq!(config.rpc.socket, "/run/spytrap.sock");
+        assert_eq!(config.history.as_deref(), Some("h.db"));
+
+        config.apply(&Overrides {
+            devices: vec!["wlan0\nwpa=0".to_string()],
+            ..Default::default()
+        });
+        assert!(config.validate(&Registry::default()).is_err());
+    }
+
+    #[test]
+    fn validate_sinks() {
+        let registry = Registry::default();
+        let config = parse("[[sink]]\ntype = \"matrix\"\n").unwrap();
+        assert_eq!(config.validate(&registry).unwrap_err().to_string(), "sink #1: unknown sink type \"matrix\"");
+        let config = parse("[[sink]]\ntype = \"stdout\"\n[[sink]]\ntype = \"file\"\n").unwrap();
+        assert_eq!(config.validate(&registry).unwrap_err().to_string(), "sink #2 (file): missing \"path\"");
+    }
+
+    #[test]
+    fn print_effective() {
+        let config = parse("[[sink]]\ntype = \"stdout\"\n").unwrap();
+        let toml = toml::to_string_pretty(&config).unwrap();
+        assert_eq!(parse(&toml).unwrap(), config);
+    }
+}
@@ -0,0 +1,586 @@
+//! The matching engine of spytrap, usable without the daemon
+//!
+//! ```
+//! use spytrap_wifi::detector::Detector;
+//! use spytrap_wifi::ioc;
+//! use spytrap_wifi::observation::Observation;
+//!
+//! let iocs = ioc::parse(b"- name: ExampleSpy\n  type: stalkerware\n  c2:\n    domains:\n    - c2.example.com\n").unwrap();
+//! let mut detector = Detector::builder().iocs(iocs).build();
+//!
+//! let findings = detector.findings(Observation::Dns {
+//!     flow: None,
+//!     qtype: "A".to_string(),
+//!     name: "api.c2.example.com".to_string(),
+//! });
+//! assert_eq!(findings[0].family(), Some("ExampleSpy"));
+//! ```
+
+use crate::bus::Bus;
+use crate::cidr::CidrSet;
+use crate::errors::*;
+use crate::event::{Detection, Event, Level, Observed, Origin, Target};
+use crate::ioc::{self, Iocs};
+use crate::json::{Flow, Pkt, Source, HTTP};
+use crate::observation::{Labeled, Observation};
+use crate::quic::QuicTracker;
+use crate::reassembly::Reassembler;
+use crate::rules::{self, Rules};
+use crate::session::Provenance;
+use crate::sink::{Registry, SinkConfig};
+use crate::tls;
+use crate::tunnel::TunnelDetector;
+use crate::unexplained::{self, Unexplained};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::watch;
+
+/// Configures the iocs, rules and sinks of a `Detector`
+pub struct Builder {
+    iocs: Iocs,
+    iocs_path: Option<String>,
+    allowlist: CidrSet,
+    local_networks: CidrSet,
+    rules: Rules,
+    rules_path: Option<String>,
+    sinks: Vec<SinkConfig>,
+    registry: Registry,
+    sessions: Option<watch::Receiver<Option<String>>>,
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder {
+            iocs: Iocs::default(),
+            iocs_path: None,
+            allowlist: unexplained::default_allowlist(),
+            local_networks: unexplained::default_local_networks(),
+            rules: Rules::new(),
+            rules_path: None,
+            sinks: Vec::new(),
+            registry: Registry::default(),
+            sessions: None,
+        }
+    }
+}
+
+impl Builder {
+    pub fn iocs(mut self, iocs: Iocs) -> Builder {
+        self.iocs = iocs;
+        self
+    }
+
+    /// Load iocs in the stalkerware-indicators format, the path is mentioned in sessions
+    pub fn load_iocs<P: AsRef<Path>>(mut self, path: P) -> Result<Builder> {
+        let path = path.as_ref();
+        self.iocs = ioc::load(path)
+            .with_context(|| anyhow!("Failed to load iocs from {:?}", path))?;
+        info!("Loaded {} known IOCs ({} c2 ips)", self.iocs.len(), self.iocs.ips.len());
+        self.iocs_path = Some(path.to_string_lossy().into_owned());
+        Ok(self)
+    }
+
+    /// Additional ip ranges that may be contacted without a dns lookup, on top of the built-in ones
+    pub fn load_allowlist<P: AsRef<Path>>(mut self, path: P) -> Result<Builder> {
+        let path = path.as_ref();
+        self.allowlist.load(path)
+            .with_context(|| anyhow!("Failed to load allowlist from {:?}", path))?;
+        info!("Loaded {} allowlisted ip ranges", self.allowlist.len());
+        Ok(self)
+    }
+
+    /// Ranges of the phones in addition to the private ones, e.g. the global ipv6 prefix of the lan.
+    /// Only connections from them to elsewhere are checked for unexplained destinations
+    pub fn load_local_networks<P: AsRef<Path>>(mut self, path: P) -> Result<Builder> {
+        let path = path.as_ref();
+        self.local_networks.load(path)
+            .with_context(|| anyhow!("Failed to load local networks from {:?}", path))?;
+        info!("Loaded {} local networks", self.local_networks.len());
+        Ok(self)
+    }
+
+    /// Custom detection rules, in addition to the iocs
+    pub fn rules(mut self, rules: Rules) -> Builder {
+        self.rules = rules;
+        self
+    }
+
+    pub fn load_rules<P: AsRef<Path>>(mut self, path: P) -> Result<Builder> {
+        let path = path.as_ref();
+        self.rules = rules::load(path)
+            .with_context(|| anyhow!("Failed to load custom rules from {:?}", path))?;
+        info!("Loaded {} custom rules", self.rules.len());
+        self.rules_path = Some(path.to_string_lossy().into_owned());
+        Ok(self)
+    }
+
+    /// Where `Detector::run` delivers events to
+    pub fn sinks(mut self, sinks: Vec<SinkConfig>) -> Builder {
+        self.sinks = sinks;
+        self
+    }
+
+    /// Sink types that are available to `sinks`, to add types that aren't built-in
+    pub fn registry(mut self, registry: Registry) -> Builder {
+        self.registry = registry;
+        self
+    }
+
+    /// The id of the current session, the detector is reset whenever it changes
+    pub fn sessions(mut self, sessions: watch::Receiver<Option<String>>) -> Builder {
+        self.sessions = Some(sessions);
+        self
+    }
+
+    pub fn build(self) -> Detector {
+        let provenance = Provenance {
+            path: self.iocs_path.unwrap_or_default(),
+            sha256: self.iocs.sha256.clone(),
+            iocs: self.iocs.len(),
+            custom_rules: self.rules_path,
+        };
+        Detector {
+            iocs: self.iocs,
+            provenance,
+            rules: self.rules,
+            unexplained: Unexplained::new(self.allowlist, self.local_networks),
+            tunnel: TunnelDetector::new(),
+            quic: QuicTracker::new(),
+            reassembler: Reassembler::new(),
+            sinks: self.sinks,
+            registry: self.registry,
+            sessions: self.sessions,
+        }
+    }
+}
+
+/// Matches observations against the iocs and rules
+///
+/// Observations have to be passed in the order they were made, some detections
+/// depend on earlier ones (dns answers, tcp streams, time windows for dns tunnels).
+pub struct Detector {
+    iocs: Iocs,
+    provenance: Provenance,
+    rules: Rules,
+    unexplained: Unexplained,
+    tunnel: TunnelDetector,
+    quic: QuicTracker,
+    reassembler: Reassembler,
+    sinks: Vec<SinkConfig>,
+    registry: Registry,
+    sessions: Option<watch::Receiver<Option<String>>>,
+}
+
+impl Detector {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// Which iocs and rules are used, this is sent as the first event by `stream`
+    pub fn provenance(&self) -> &Provenance {
+        &self.provenance
+    }
+
+    /// Forget the dns answers and everything that was reported so far, so the next phone
+    /// is checked from scratch. Repeated detections within a session are merged by the session tracker
+    pub fn reset(&mut self) {
+        self.unexplained.reset();
+        self.tunnel.reset();
+    }
+
+    fn detect_name(&self, src: &Source, name: &str, events: &mut Vec<Event>) {
+        if let Some(ioc) = self.iocs.lookup(name) {
+            warn!("detected({}): {:?} ({}, {})", src.as_str(), name, ioc.family, ioc.section.as_str());
+            let detection = Detection::new(Level::Detected, src.as_str(), Target::Name(name.to_string()));
+            events.push(detection.ioc(ioc).into());
+        } else {
+            debug!("observed({}): {:?}", src.as_str(), name);
+            events.push(Event::Observation(Observed {
+                kind: src.as_str().to_string(),
+                name: name.to_string(),
+                origin: Origin::default(),
+            }));
+        }
+    }
+
+    fn detect_fingerprint(&self, params: &tls::HelloParams, transport: tls::Transport, sni: &str, events: &mut Vec<Event>) {
+        let ja3 = params.ja3();
+        let ja4 = params.ja4(transport);
+        debug!("fingerprint(tls): {:?} (ja3: {}, ja4: {})", sni, ja3, ja4);
+        self.detect_hashes(&[&ja3, &ja4], sni, events);
+    }
+
+    fn detect_hashes(&self, fps: &[&str], sni: &str, events: &mut Vec<Event>) {
+        for fp in fps {
+            if let Some(rule) = self.rules.match_fingerprint(fp) {
+                warn!("detected(tls/fingerprint): {:?} (rule: {:?}, sni: {:?})", fp, rule, sni);
+                let detection = Detection::new(Level::Detected, "tls/fingerprint", Target::Fingerprint {
+                    hash: fp.to_string(),
+                    sni: sni.to_string(),
+                });
+                events.push(detection.rule(rule).into());
+                break;
+            }
+        }
+    }
+
+    fn detect_question(&mut self, qtype: &str, name: &str, now: Instant, events: &mut Vec<Event>) {
+        if let Some(rule) = self.rules.match_dns(qtype, name) {
+            warn!("detected(dns/{}): {:?} (rule: {:?})", qtype, name, rule);
+            let detection = Detection::new(Level::Detected, format!("dns/{}", qtype), Target::Name(name.to_string()));
+            events.push(detection.rule(rule).into());
+        }
+
+        for suspicious in self.tunnel.check(qtype, name, now) {
+            info!("suspicious(dns): {:?} ({}, query: {:?})", suspicious.parent, suspicious.reason, name);
+            let detection = Detection::new(Level::Suspicious, "dns", Target::Tunnel {
+                parent: suspicious.parent,
+                reason: suspicious.reason.to_string(),
+                names: suspicious.names,
+            });
+            events.push(detection.into());
+        }
+    }
+
+    fn detect_http(&self, http: &HTTP, events: &mut Vec<Event>) {
+        if let Some(rule) = self.rules.match_http(http) {
+            let line = http.request_line();
+            warn!("detected(http): {:?} (rule: {:?}, host: {:?}, agent: {:?})", line, rule, http.host, http.agent);
+            let detection = Detection::new(Level::Detected, "http", Target::Request(line));
+            events.push(detection.rule(rule).into());
+        }
+    }
+
+    fn check_flow(&mut self, flow: &Flow, events: &mut Vec<Event>) {
+        if let Some(ioc) = self.iocs.lookup_ip(&flow.dst.ip()) {
+            warn!("detected(ip/{}): {} ({})", flow.proto.as_str(), flow.dst, ioc.family);
+            let detection = Detection::new(Level::Detected, format!("ip/{}", flow.proto.as_str()), Target::Addr(flow.dst));
+            events.push(detection.ioc(ioc).into());
+        } else if self.unexplained.check(flow) {
+            info!("unexplained({}): {}", flow.proto.as_str(), flow.dst);
+            let detection = Detection::new(Level::Unexplained, flow.proto.as_str(), Target::Addr(flow.dst));
+            events.push(detection.into());
+        }
+    }
+
+    fn detect(&mut self, obs: &Observation, now: Instant, events: &mut Vec<Event>) {
+        match obs {
+            Observation::Packet(pkt) => self.process_pkt(pkt, now, events),
+            Observation::Dns { qtype, name, .. } => {
+                self.detect_name(&Source::DNS, name, events);
+                self.detect_question(qtype, name, now, events);
+            }
+            Observation::Query { qtype, name, .. } => {
+                self.detect_name(&Source::DNS, name, events);
+                self.detect_question(qtype, name, now, events);
+            }
+            Observation::Resolved { addr, .. } => self.unexplained.resolved(*addr),
+            Observation::Tls { sni, ja3, ja4, .. } => {
+                let sni = sni.as_deref().unwrap_or_default();
+                if !sni.is_empty() {
+                    self.detect_name(&Source::TLS, sni, events);
+                }
+                let fps = ja3.iter().chain(ja4.iter())
+                    .map(|fp| fp.as_str())
+                    .collect::<Vec<_>>();
+                self.detect_hashes(&fps, sni, events);
+            }
+            Observation::Http { http, .. } => {
+                for (src, name) in http.get_names() {
+                    self.detect_name(&src, &name, events);
+                }
+                self.detect_http(http, events);
+            }
+            Observation::Connection(_) => (),
+        }
+
+        if let Some(flow) = obs.flow() {
+            self.check_flow(&flow, events);
+        }
+    }
+
+    fn process_pkt(&mut self, pkt: &Pkt, now: Instant, events: &mut Vec<Event>) {
+        let names = pkt.get_names();
+        for (src, name) in names {
+            self.detect_name(&src, &name, events);
+        }
+
+        for (qtype, name) in pkt.get_questions() {
+            self.detect_question(&qtype, &name, now, events);
+        }
+
+        if let Some(http) = pkt.get_http() {
+            self.detect_http(http, events);
+        }
+
+        if let Some(ch) = pkt.get_client_hello() {
+            if !ch.params.is_empty() {
+                self.detect_fingerprint(&ch.params, tls::Transport::TCP, &ch.hostname, events);
+            }
+        }
+
+        // ClientHellos that are split across multiple segments
+        let segment = match (pkt.get_flow(), pkt.get_tcp_header()) {
+            // there's nothing left to find in this stream, don't decode the payload
+            (Some(flow), Some(hdr)) if self.reassembler.is_done(&flow) => Some(hdr.flags()),
+            _ => pkt.get_tcp_segment(),
+        };
+        if let (Some(flow), Some(segment)) = (pkt.get_flow(), segment) {
+            match self.reassembler.process(&flow, &segment) {
+                Ok(Some(hello)) => {
+                    let sni = hello.sni.unwrap_or_default();
+                    if !sni.is_empty() {
+                        self.detect_name(&Source::TLS, &sni, events);
+                    }
+                    self.detect_fingerprint(&hello.params, tls::Transport::TCP, &sni, events);
+                }
+                Ok(None) => (),
+                Err(err) => trace!("Failed to parse tcp stream: {:#}", err),
+            }
+        }
+
+        if let (Some(flow), Some(payload)) = (pkt.get_flow(), pkt.get_udp_payload()) {
+            if flow.dst.port() == 443 {
+                match self.quic.process(payload) {
+                    Ok(Some(hello)) => {
+                        let sni = hello.sni.unwrap_or_default();
+                        if !sni.is_empty() {
+                            self.detect_name(&Source::QUIC, &sni, events);
+                        }
+                        self.detect_fingerprint(&hello.params, tls::Transport::QUIC, &sni, events);
+                    }
+                    Ok(None) => (),
+                    Err(err) => trace!("Failed to parse quic packet: {:#}", err),
+                }
+            }
+        }
+
+        for (_name, addr) in pkt.get_answers() {
+            self.unexplained.resolved(addr);
+        }
+    }
+
+    /// Detections and observed names, tagged with the client and interface. This never fails,
+    /// observations without a time are processed as if they were just seen
+    pub fn process(&mut self, item: &Labeled) -> Vec<Event> {
+        let changed = match &mut self.sessions {
+            Some(sessions) if sessions.has_changed().unwrap_or(false) => {
+                let session = sessions.borrow_and_update();
+                debug!("Resetting detections for session {:?}", session.as_deref());
+                true
+            }
+            _ => false,
+        };
+        if changed {
+            self.reset();
+        }
+
+        let mut events = Vec::new();
+        let now = item.time.unwrap_or_else(Instant::now);
+        self.detect(&item.observation, now, &mut events);
+
+        let origin = Origin {
+            client: match &item.observation {
+                // there's no flow for resolver logs, the log mentions the client instead
+                Observation::Query { client, .. } => Some(*client),
+                obs => obs.flow().and_then(|flow| self.unexplained.local_addr(&flow)),
+            },
+            interface: item.interface.as_deref().map(String::from),
+            session: None,
+        };
+        for event in &mut events {
+            event.set_origin(&origin);
+        }
+        events
+    }
+
+    /// Only the detections of an observation, names that didn't match anything are skipped
+    pub fn findings(&mut self, observation: Observation) -> Vec<Detection> {
+        self.process(&Labeled::from(observation))
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Detection(detection) => Some(detection),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Process observations until the stream ends, the provenance is sent first
+    pub async fn stream<R, S>(&mut self, mut rx: R, tx: &mut S) -> Result<()>
+    where
+        R: Stream<Item=Labeled> + Unpin,
+        S: Sink<Event> + Unpin,
+    {
+        let send_err = |_| anyhow!("sink error");
+        tx.send(Event::IocsLoaded(self.provenance.clone())).await.map_err(send_err)?;
+        while let Some(item) = rx.next().await {
+            for event in self.process(&item) {
+                tx.send(event).await.map_err(send_err)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Process observations and deliver the events to the configured sinks, like the daemon does.
+    /// Returns once the sinks delivered everything, sinks that are stuck are given up on after a few seconds
+    pub async fn run<R: Stream<Item=Labeled> + Unpin>(mut self, rx: R) -> Result<()> {
+        let registry = Arc::new(std::mem::take(&mut self.registry));
+        let bus = Bus::with_sinks(&self.sinks, registry)?;
+        let (mut tx, events) = futures::channel::mpsc::channel(256);
+        let detect = async move {
+            self.stream(rx, &mut tx).await?;
+            tx.close_channel();
+            Ok(())
+        };
+        futures::future::try_join(detect, bus.run(events)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::Proto;
+    use crate::sink::Format;
+
+    fn detector() -> Detector {
+        let iocs = ioc::parse(br#"---
+- name: ExampleSpy
+  type: stalkerware
+  websites:
+  - examplespy.com
+  c2:
+    ips:
+    - 185.212.128.12
+    domains:
+    - c2.example.com
+"#).unwrap();
+        Detector::builder().iocs(iocs).build()
+    }
+
+    fn dns(name: &str) -> Observation {
+        Observation::Dns {
+            flow: None,
+            qtype: "A".to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    fn connection(dst: &str) -> Observation {
+        Observation::Connection(Flow {
+            proto: Proto::TCP,
+            src: "192.168.1.3:1337".parse().unwrap(),
+            dst: dst.parse().unwrap(),
+        })
+    }
+
+    #[test]
+    fn findings() {
+        let mut detector = detector();
+        assert_eq!(detector.provenance().iocs, 3);
+
+        let findings = detector.findings(dns("api.c2.example.com"));
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].level, Level::Detected);
+        assert_eq!(findings[0].target, Target::Name("api.c2.example.com".to_string()));
+        assert_eq!(findings[0].family(), Some("ExampleSpy"));
+        assert_eq!(detector.findings(dns("example.org")), vec![]);
+
+        // c2 connections are merged by the session tracker, unexplained destinations are only reported once
+        assert_eq!(detector.findings(connection("185.212.128.12:443")).len(), 1);
+        assert_eq!(detector.findings(connection("185.212.128.12:443")).len(), 1);
+        assert_eq!(detector.findings(connection("93.184.216.34:443"))[0].level, Level::Unexplained);
+        assert_eq!(detector.findings(connection("93.184.216.34:443")), vec![]);
+    }
+
+    #[test]
+    fn reset_for_sessions() {
+        let (sessions, rx) = watch::channel(None);
+        let mut detector = Detector::builder().sessions(rx).build();
+        let resolved = Observation::Resolved {
+            name: "example.com".to_string(),
+            addr: "93.184.216.34".parse().unwrap(),
+        };
+
+        sessions.send_replace(Some("a".to_string()));
+        detector.findings(resolved);
+        assert_eq!(detector.findings(connection("93.184.216.34:443")), vec![]);
+        assert_eq!(detector.findings(connection("45.33.32.156:443")).len(), 1);
+        assert_eq!(detector.findings(connection("45.33.32.156:443")), vec![]);
+
+        // the dns answers and reports of the previous phone don't count
+        sessions.send_replace(Some("b".to_string()));
+        assert_eq!(detector.findings(connection("93.184.216.34:443")).len(), 1);
+        assert_eq!(detector.findings(connection("45.33.32.156:443")).len(), 1);
+    }
+
+    #[test]
+    fn labels() {
+        let mut detector = detector();
+        let events = detector.process(&Labeled {
+            interface: Some(Arc::from("wlan1")),
+            time: Some(Instant::now()),
+            observation: Observation::Query {
+                client: "10.0.0.2".parse().unwrap(),
+                qtype: "A".to_string(),
+                name: "examplespy.com".to_string(),
+            },
+        });
+        let detection = events[0].detection().unwrap();
+        assert_eq!(detection.origin.interface.as_deref(), Some("wlan1"));
+        assert_eq!(detection.origin.client, Some("10.0.0.2".parse().unwrap()));
+
+        // names without a match are observations
+        let events = detector.process(&Labeled::from(dns("example.org")));
+        assert!(events[0].is_observation());
+
+        // captures know the phone from the flow
+        let findings = detector.findings(connection("185.212.128.12:443"));
+        assert_eq!(findings[0].origin.client, Some("192.168.1.3".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn stream() {
+        let mut detector = detector();
+        let observations = futures::stream::iter(vec![
+            Labeled::from(dns("example.org")),
+            Labeled::from(dns("c2.example.com")),
+        ]);
+        let mut events = Vec::new();
+        detector.stream(observations, &mut events).await.unwrap();
+
+        assert_eq!(events[0], Event::IocsLoaded(detector.provenance().clone()));
+        let detections = events.iter().filter_map(Event::detection).collect::<Vec<_>>();
+        assert_eq!(detections.len(), 1);
+        assert_eq!(detections[0].target, Target::Name("c2.example.com".to_string()));
+    }
+
+    #[tokio::test]
+    async fn run_with_sinks() {
+        let path = std::env::temp_dir().join(format!("spytrap-detector-{}.jsonl", std::process::id()));
+        let detector = Detector::builder()
+            .iocs(detector().iocs)
+            .sinks(vec![SinkConfig {
+                path: Some(path.to_string_lossy().into_owned()),
+                format: Format::Json,
+                ..SinkConfig::new("file")
+            }])
+            .build();
+        detector.run(futures::stream::iter(vec![Labeled::from(dns("c2.example.com"))])).await.unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert!(log.contains(r#""event":"detection""#), "{}", log);
+    }
+
+    #[test]
+    fn unknown_sinks() {
+        let detector = Detector::builder()
+            .sinks(vec![SinkConfig::new("matrix")])
+            .build();
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        assert!(rt.block_on(detector.run(futures::stream::empty())).is_err());
+    }
+}
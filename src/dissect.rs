@@ -0,0 +1,490 @@
+use crate::dns;
+use crate::errors::*;
+use crate::json::*;
+use crate::pcap;
+use crate::reader::Reader;
+use crate::tls;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+
+const HTTP_METHODS: &[&str] = &["GET", "POST", "PUT", "HEAD", "DELETE", "OPTIONS", "PATCH", "CONNECT", "TRACE"];
+
+/// Dissect a frame of the given pcap link type
+///
+/// Frames without an ethernet header are still returned as `Pkt::Ether`.
+pub fn frame(linktype: u32, data: &[u8]) -> Result<Pkt<'static>> {
+    let ip = match linktype {
+        pcap::LINKTYPE_ETHERNET => return ethernet(data),
+        pcap::LINKTYPE_RAW | pcap::LINKTYPE_DLT_RAW | pcap::LINKTYPE_IPV4 | pcap::LINKTYPE_IPV6 => ip(data)?,
+        pcap::LINKTYPE_LINUX_SLL => {
+            let mut r = Reader::new(data);
+            // packet type, arphrd type, address length and address
+            r.skip(14)?;
+            let ethertype = r.u16()?;
+            network(ethertype, r.rest())?
+        }
+        pcap::LINKTYPE_LINUX_SLL2 => {
+            let mut r = Reader::new(data);
+            let ethertype = r.u16()?;
+            // reserved, interface index, arphrd type, packet type, address length and address
+            r.skip(18)?;
+            network(ethertype, r.rest())?
+        }
+        pcap::LINKTYPE_NULL => {
+            let mut r = Reader::new(data);
+            // the address family is in host byte order of the capturing machine
+            r.skip(4)?;
+            ip(r.rest())?
+        }
+        _ => bail!("Unsupported link type: {}", linktype),
+    };
+    Ok(Pkt::Ether((Dummy {}, ip)))
+}
+
+/// Dissect an ethernet frame into the same structure sniffglue emits
+pub fn ethernet(frame: &[u8]) -> Result<Pkt<'static>> {
+    let mut r = Reader::new(frame);
+    // destination and source mac
+    r.skip(12)?;
+    let mut ethertype = r.u16()?;
+    while ethertype == ETHERTYPE_VLAN || ethertype == ETHERTYPE_QINQ {
+        // tag control information
+        r.skip(2)?;
+        ethertype = r.u16()?;
+    }
+
+    let ip = network(ethertype, r.rest())?;
+    Ok(Pkt::Ether((Dummy {}, ip)))
+}
+
+fn network(ethertype: u16, buf: &[u8]) -> Result<IP<'static>> {
+    match ethertype {
+        ETHERTYPE_IPV4 => ipv4(buf),
+        ETHERTYPE_IPV6 => ipv6(buf),
+        _ => bail!("Unsupported ethertype: {:#06x}", ethertype),
+    }
+}
+
+/// Dissect an ip packet without link layer
+pub fn ip(buf: &[u8]) -> Result<IP<'static>> {
+    match buf.first().map(|b| b >> 4) {
+        Some(4) => ipv4(buf),
+        Some(6) => ipv6(buf),
+        Some(version) => bail!("Unsupported ip version: {}", version),
+        None => bail!("Ip packet is empty"),
+    }
+}
+
+fn ipv4(buf: &[u8]) -> Result<IP<'static>> {
+    let mut r = Reader::new(buf);
+    let ihl = (r.u8()? & 0x0f) as usize * 4;
+    // tos
+    r.skip(1)?;
+    let total_len = r.u16()? as usize;
+    // identification
+    r.skip(2)?;
+    let fragment = r.u16()?;
+    // ttl
+    r.skip(1)?;
+    let protocol = r.u8()?;
+    // checksum
+    r.skip(2)?;
+    let source_addr = Ipv4Addr::from(r.u32()?);
+    let dest_addr = Ipv4Addr::from(r.u32()?);
+
+    if fragment & 0x1fff != 0 {
+        bail!("Ipv4 packet is not the first fragment");
+    }
+    if ihl < 20 || total_len < ihl {
+        bail!("Invalid ipv4 header length");
+    }
+    // ethernet frames may be padded
+    let payload = buf.get(ihl..total_len.min(buf.len()))
+        .context("Ipv4 packet is truncated")?;
+
+    let hdr = IPv4Header {
+        source_addr,
+        dest_addr,
+    };
+    let ipv4 = match protocol {
+        PROTO_TCP => IPv4::TCP(tcp(payload)?),
+        PROTO_UDP => IPv4::UDP(udp(payload)?),
+        _ => bail!("Unsupported ip protocol: {}", protocol),
+    };
+    Ok(IP::IPv4((hdr, ipv4)))
+}
+
+fn ipv6(buf: &[u8]) -> Result<IP<'static>> {
+    let mut r = Reader::new(buf);
+    // version, traffic class and flow label
+    r.skip(4)?;
+    let payload_len = r.u16()? as usize;
+    let mut next = r.u8()?;
+    // hop limit
+    r.skip(1)?;
+    let mut addr = [0u8; 16];
+    addr.copy_from_slice(r.take(16)?);
+    let source_addr = Ipv6Addr::from(addr);
+    addr.copy_from_slice(r.take(16)?);
+    let dest_addr = Ipv6Addr::from(addr);
+
+    let len = payload_len.min(r.remaining());
+    let mut r = Reader::new(r.take(len)?);
+    loop {
+        match next {
+            // hop-by-hop, routing and destination options
+            0 | 43 | 60 => {
+                next = r.u8()?;
+                let len = r.u8()? as usize;
+                r.skip(6 + len * 8)?;
+            }
+            // fragment
+            44 => {
+                next = r.u8()?;
+                r.skip(1)?;
+                let offset = r.u16()? >> 3;
+                r.skip(4)?;
+                if offset != 0 {
+                    bail!("Ipv6 packet is not the first fragment");
+                }
+            }
+            _ => break,
+        }
+    }
+
+    let hdr = IPv6Header {
+        source_addr,
+        dest_addr,
+    };
+    let ipv6 = match next {
+        PROTO_TCP => IPv6::TCP(tcp(r.rest())?),
+        PROTO_UDP => IPv6::UDP(udp(r.rest())?),
+        _ => bail!("Unsupported ip protocol: {}", next),
+    };
+    Ok(IP::IPv6((hdr, ipv6)))
+}
+
+fn tcp(buf: &[u8]) -> Result<(TCPHeader, TCP<'static>)> {
+    let mut r = Reader::new(buf);
+    let source_port = r.u16()?;
+    let dest_port = r.u16()?;
+    let sequence_no = r.u32()?;
+    // ack number
+    r.skip(4)?;
+    let data_offset = (r.u8()? >> 4) as usize * 4;
+    let flags = r.u8()?;
+
+    if data_offset < 20 {
+        bail!("Invalid tcp header length");
+    }
+    let payload = buf.get(data_offset..)
+        .context("Tcp segment is truncated")?;
+
+    let hdr = TCPHeader {
+        source_port,
+        dest_port,
+        sequence_no,
+        flag_syn: flags & 0x02 != 0,
+        flag_fin: flags & 0x01 != 0,
+        flag_rst: flags & 0x04 != 0,
+    };
+    Ok((hdr, tcp_payload(payload)))
+}
+
+fn tcp_payload(payload: &[u8]) -> TCP<'static> {
+    if payload.is_empty() {
+        return TCP::Empty;
+    }
+
+    // hellos that need more than one segment are left to the reassembler
+    if let Ok(Some(hello)) = tls::parse_records(payload) {
+        return TCP::TLS(TLS::ClientHello(ClientHello {
+            hostname: hello.sni.unwrap_or_default().into(),
+            params: hello.params,
+        }));
+    }
+
+    if let Some(http) = http(payload) {
+        return TCP::HTTP(http);
+    }
+
+    TCP::Binary(payload.to_vec().into())
+}
+
+/// Parse the head of an http/1 request
+fn http(payload: &[u8]) -> Option<HTTP> {
+    let method = payload.split(|b| *b == b' ').next()?;
+    if !HTTP_METHODS.iter().any(|m| m.as_bytes() == method) {
+        return None;
+    }
+
+    let head = String::from_utf8_lossy(payload);
+    let head = head.split("\r\n\r\n").next()?;
+    let mut lines = head.split("\r\n");
+
+    let mut request = lines.next()?.split(' ');
+    let method = request.next()?.to_string();
+    let uri = request.next()?.to_string();
+    let version = request.next()?.strip_prefix("HTTP/")?.to_string();
+    if request.next().is_some() {
+        return None;
+    }
+
+    let mut http = HTTP {
+        method,
+        uri,
+        version,
+        host: None,
+        agent: None,
+        referer: None,
+        cookies: None,
+    };
+
+    for line in lines {
+        let (key, value) = match line.split_once(':') {
+            Some(header) => header,
+            None => continue,
+        };
+        let value = Some(value.trim().to_string());
+        match key.trim().to_ascii_lowercase().as_str() {
+            "host" => http.host = value,
+            "user-agent" => http.agent = value,
+            "referer" => http.referer = value,
+            "cookie" => http.cookies = value,
+            _ => (),
+        }
+    }
+
+    Some(http)
+}
+
+fn udp(buf: &[u8]) -> Result<(UDPHeader, UDP<'static>)> {
+    let mut r = Reader::new(buf);
+    let source_port = r.u16()?;
+    let dest_port = r.u16()?;
+    let len = r.u16()? as usize;
+    // checksum
+    r.skip(2)?;
+
+    let payload = r.rest();
+    let payload = if len >= 8 {
+        &payload[..(len - 8).min(payload.len())]
+    } else {
+        payload
+    };
+
+    let hdr = UDPHeader {
+        source_port,
+        dest_port,
+    };
+    let udp = if source_port == 53 || dest_port == 53 {
+        match dns::parse(payload) {
+            Ok(dns) => UDP::DNS(dns),
+            Err(_) => UDP::Binary(payload.to_vec().into()),
+        }
+    } else {
+        UDP::Binary(payload.to_vec().into())
+    };
+    Ok((hdr, udp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::tests;
+    use std::net::IpAddr;
+
+    const DNS_QUERY: &[u8] = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x06google\x03com\x00\x00\x01\x00\x01";
+
+    fn ether(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![70, 80, 90, 100, 110, 120, 10, 20, 30, 40, 50, 60];
+        buf.extend(ethertype.to_be_bytes());
+        buf.extend(payload);
+        buf
+    }
+
+    fn ipv4(src: &str, dst: &str, protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let src = src.parse::<Ipv4Addr>().unwrap();
+        let dst = dst.parse::<Ipv4Addr>().unwrap();
+        let mut buf = vec![0x45, 0];
+        buf.extend((20 + payload.len() as u16).to_be_bytes());
+        buf.extend([0, 0, 0x40, 0, 64, protocol, 0, 0]);
+        buf.extend(src.octets());
+        buf.extend(dst.octets());
+        buf.extend(payload);
+        ether(ETHERTYPE_IPV4, &buf)
+    }
+
+    fn ipv6(src: &str, dst: &str, next: u8, payload: &[u8]) -> Vec<u8> {
+        let src = src.parse::<Ipv6Addr>().unwrap();
+        let dst = dst.parse::<Ipv6Addr>().unwrap();
+        let mut buf = vec![0x60, 0, 0, 0];
+        buf.extend((payload.len() as u16).to_be_bytes());
+        buf.extend([next, 64]);
+        buf.extend(src.octets());
+        buf.extend(dst.octets());
+        buf.extend(payload);
+        ether(ETHERTYPE_IPV6, &buf)
+    }
+
+    fn tcp(sport: u16, dport: u16, seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(sport.to_be_bytes());
+        buf.extend(dport.to_be_bytes());
+        buf.extend(seq.to_be_bytes());
+        buf.extend(1337u32.to_be_bytes());
+        buf.extend([0x50, flags, 0x01, 0xf8, 0, 0, 0, 0]);
+        buf.extend(payload);
+        buf
+    }
+
+    fn udp(sport: u16, dport: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(sport.to_be_bytes());
+        buf.extend(dport.to_be_bytes());
+        buf.extend((8 + payload.len() as u16).to_be_bytes());
+        buf.extend([0, 0]);
+        buf.extend(payload);
+        buf
+    }
+
+    fn client_hello(sni: &str) -> Vec<u8> {
+        let mut name = vec![0];
+        name.extend((sni.len() as u16).to_be_bytes());
+        name.extend(sni.as_bytes());
+        let mut ext = vec![0, 0];
+        ext.extend((name.len() as u16 + 2).to_be_bytes());
+        ext.extend((name.len() as u16).to_be_bytes());
+        ext.extend(name);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend([0u8; 32]);
+        body.extend([0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend((ext.len() as u16).to_be_bytes());
+        body.extend(ext);
+
+        let mut handshake = vec![1];
+        handshake.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(body);
+
+        let mut record = vec![22, 3, 1];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn dissect_dns() {
+        let frame = ipv4("192.168.1.3", "192.168.1.1", PROTO_UDP, &udp(1337, 53, DNS_QUERY));
+        assert_eq!(ethernet(&frame).unwrap(), tests::dns_request());
+    }
+
+    #[test]
+    fn dissect_sni() {
+        let frame = ipv4("192.168.1.3", "142.250.102.138", PROTO_TCP, &tcp(1337, 443, 1337, 0x18, &client_hello("google.com")));
+        let params = tls::HelloParams {
+            legacy_version: 0x0303,
+            cipher_suites: vec![0x1301],
+            extensions: vec![0],
+            ..Default::default()
+        };
+        assert_eq!(ethernet(&frame).unwrap(), tests::sni(params));
+    }
+
+    #[test]
+    fn dissect_http() {
+        let req = b"GET / HTTP/1.1\r\nHost: google.com\r\nUser-Agent: curl/7.72.0\r\nAccept: */*\r\n\r\n";
+        let frame = ipv4("192.168.1.3", "142.250.102.138", PROTO_TCP, &tcp(1337, 80, 1337, 0x18, req));
+        assert_eq!(ethernet(&frame).unwrap(), tests::http());
+    }
+
+    #[test]
+    fn dissect_dns_response() {
+        let msg = b"\x12\x34\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\
+            \x06github\x03com\x00\x00\x01\x00\x01\
+            \xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\x8c\x52\x79\x04";
+        let frame = ipv4("192.168.1.1", "192.168.1.3", PROTO_UDP, &udp(53, 1337, msg));
+        let pkt = ethernet(&frame).unwrap();
+        assert_eq!(pkt.get_names(), vec![]);
+        assert_eq!(pkt.get_answers(), vec![
+            ("github.com".to_string(), "140.82.121.4".parse::<IpAddr>().unwrap()),
+        ]);
+    }
+
+    #[test]
+    fn dissect_ipv6_syn() {
+        let frame = ipv6("fd00::3", "2a00:1450:4001:82b::200e", PROTO_TCP, &tcp(1337, 443, 1337, 0x02, &[]));
+        let pkt = ethernet(&frame).unwrap();
+        assert_eq!(pkt.get_flow(), Some(Flow {
+            proto: Proto::TCP,
+            src: "[fd00::3]:1337".parse().unwrap(),
+            dst: "[2a00:1450:4001:82b::200e]:443".parse().unwrap(),
+        }));
+        let segment = pkt.get_tcp_segment().unwrap();
+        assert!(segment.syn);
+        assert!(segment.payload.is_empty());
+    }
+
+    #[test]
+    fn dissect_udp_binary_padded() {
+        let mut frame = ipv4("192.168.1.3", "142.250.102.138", PROTO_UDP, &udp(1337, 443, &[192, 0, 0, 0]));
+        // ethernet pads short frames to 60 bytes
+        frame.resize(60, 0);
+        let pkt = ethernet(&frame).unwrap();
+        assert_eq!(pkt.get_udp_payload(), Some(&[192, 0, 0, 0][..]));
+    }
+
+    #[test]
+    fn dissect_vlan() {
+        let frame = ipv4("192.168.1.3", "192.168.1.1", PROTO_UDP, &udp(1337, 53, DNS_QUERY));
+        let mut tagged = frame[..12].to_vec();
+        tagged.extend([0x81, 0x00, 0x00, 0x2a]);
+        tagged.extend(&frame[12..]);
+        assert_eq!(ethernet(&tagged).unwrap(), tests::dns_request());
+    }
+
+    #[test]
+    fn partial_hello_is_binary() {
+        let hello = client_hello("google.com");
+        let frame = ipv4("192.168.1.3", "142.250.102.138", PROTO_TCP, &tcp(1337, 443, 1337, 0x18, &hello[..20]));
+        let pkt = ethernet(&frame).unwrap();
+        assert_eq!(pkt.get_client_hello(), None);
+        assert_eq!(pkt.get_tcp_segment().unwrap().payload, &hello[..20]);
+    }
+
+    #[test]
+    fn dissect_linux_sll() {
+        let eth = ipv4("192.168.1.3", "192.168.1.1", PROTO_UDP, &udp(1337, 53, DNS_QUERY));
+        let mut sll = vec![0, 4, 0, 1, 0, 6, 10, 20, 30, 40, 50, 60, 0, 0];
+        sll.extend(&eth[12..]);
+        assert_eq!(frame(pcap::LINKTYPE_LINUX_SLL, &sll).unwrap(), tests::dns_request());
+    }
+
+    #[test]
+    fn dissect_linux_sll2() {
+        let eth = ipv4("192.168.1.3", "192.168.1.1", PROTO_UDP, &udp(1337, 53, DNS_QUERY));
+        let mut sll2 = eth[12..14].to_vec();
+        sll2.extend([0, 0, 0, 0, 0, 3, 0, 1, 4, 6, 10, 20, 30, 40, 50, 60, 0, 0]);
+        sll2.extend(&eth[14..]);
+        assert_eq!(frame(pcap::LINKTYPE_LINUX_SLL2, &sll2).unwrap(), tests::dns_request());
+    }
+
+    #[test]
+    fn dissect_raw_ip() {
+        let eth = ipv4("192.168.1.3", "192.168.1.1", PROTO_UDP, &udp(1337, 53, DNS_QUERY));
+        assert_eq!(frame(pcap::LINKTYPE_RAW, &eth[14..]).unwrap(), tests::dns_request());
+    }
+
+    #[test]
+    fn not_http() {
+        assert_eq!(http(b"GETTING / HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(http(b"GET /\r\n\r\n"), None);
+    }
+}
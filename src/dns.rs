@@ -0,0 +1,162 @@
+use crate::errors::*;
+use crate::json::{DNS, DNSRequest, DNSResponse, Opaque, Record};
+use crate::reader::Reader;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Compression pointers can loop, limit how often we follow them
+const MAX_POINTERS: usize = 32;
+
+pub fn qtype_str(qtype: u16) -> String {
+    let s = match qtype {
+        1 => "A",
+        2 => "NS",
+        5 => "CNAME",
+        6 => "SOA",
+        10 => "NULL",
+        12 => "PTR",
+        15 => "MX",
+        16 => "TXT",
+        28 => "AAAA",
+        33 => "SRV",
+        64 => "SVCB",
+        65 => "HTTPS",
+        255 => "ANY",
+        _ => return format!("TYPE{}", qtype),
+    };
+    s.to_string()
+}
+
+/// Read a possibly compressed name, `r` is advanced past the name
+fn read_name<'a>(msg: &'a [u8], r: &mut Reader<'a>) -> Result<String> {
+    let mut labels = Vec::new();
+    let mut cursor = r.clone();
+    let mut jumped = false;
+    let mut pointers = 0;
+
+    loop {
+        let len = cursor.u8()?;
+        match len & 0xc0 {
+            0x00 if len == 0 => break,
+            0x00 => {
+                let label = cursor.take(len as usize)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+            }
+            0xc0 => {
+                let offset = (((len & 0x3f) as usize) << 8) | cursor.u8()? as usize;
+                if !jumped {
+                    *r = cursor.clone();
+                    jumped = true;
+                }
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    bail!("Too many compression pointers in dns name");
+                }
+                let target = msg.get(offset..)
+                    .context("Dns compression pointer is out of bounds")?;
+                cursor = Reader::new(target);
+            }
+            _ => bail!("Unsupported dns label type: {:#x}", len),
+        }
+    }
+
+    if !jumped {
+        *r = cursor;
+    }
+    Ok(labels.join("."))
+}
+
+/// Parse a dns message in wire format
+pub fn parse(msg: &[u8]) -> Result<DNS<'static>> {
+    let mut r = Reader::new(msg);
+    let _id = r.u16()?;
+    let flags = r.u16()?;
+    let qdcount = r.u16()?;
+    let ancount = r.u16()?;
+    let _nscount = r.u16()?;
+    let _arcount = r.u16()?;
+
+    let mut questions = Vec::new();
+    for _ in 0..qdcount {
+        let name = read_name(msg, &mut r)?;
+        let qtype = r.u16()?;
+        let _qclass = r.u16()?;
+        questions.push((qtype_str(qtype).into(), name.into()));
+    }
+
+    // the QR bit is not set for queries
+    if flags & 0x8000 == 0 {
+        return Ok(DNS::Request(DNSRequest {
+            questions,
+        }));
+    }
+
+    let mut answers = Vec::new();
+    for _ in 0..ancount {
+        let name = read_name(msg, &mut r)?;
+        let rtype = r.u16()?;
+        let _class = r.u16()?;
+        let _ttl = r.u32()?;
+        let mut data = r.prefixed(|r| Ok(r.u16()? as usize))?;
+
+        let record = match rtype {
+            1 => {
+                let b = data.take(4)?;
+                Record::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+            }
+            28 => {
+                let mut b = [0u8; 16];
+                b.copy_from_slice(data.take(16)?);
+                Record::AAAA(Ipv6Addr::from(b))
+            }
+            5 => Record::CNAME(read_name(msg, &mut data)?.into()),
+            2 => Record::NS(read_name(msg, &mut data)?.into()),
+            12 => Record::PTR(read_name(msg, &mut data)?.into()),
+            16 => Record::TXT(Opaque),
+            _ => Record::Unknown(Opaque),
+        };
+        answers.push((name.into(), record));
+    }
+
+    Ok(DNS::Response(DNSResponse {
+        answers,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_query() {
+        let msg = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x06google\x03com\x00\x00\x01\x00\x01";
+        assert_eq!(parse(msg).unwrap(), DNS::Request(DNSRequest {
+            questions: vec![("A".into(), "google.com".into())],
+        }));
+    }
+
+    #[test]
+    fn parse_response_compressed() {
+        let msg = b"\x12\x34\x81\x80\x00\x01\x00\x02\x00\x00\x00\x00\
+            \x03www\x06github\x03com\x00\x00\x01\x00\x01\
+            \xc0\x0c\x00\x05\x00\x01\x00\x00\x0e\x10\x00\x02\xc0\x10\
+            \xc0\x10\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\x8c\x52\x79\x04";
+        assert_eq!(parse(msg).unwrap(), DNS::Response(DNSResponse {
+            answers: vec![
+                ("www.github.com".into(), Record::CNAME("github.com".into())),
+                ("github.com".into(), Record::A("140.82.121.4".parse().unwrap())),
+            ],
+        }));
+    }
+
+    #[test]
+    fn pointer_loop() {
+        let msg = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\xc0\x0c\x00\x01\x00\x01";
+        assert!(parse(msg).is_err());
+    }
+
+    #[test]
+    fn unknown_qtype() {
+        assert_eq!(qtype_str(65), "HTTPS");
+        assert_eq!(qtype_str(4242), "TYPE4242");
+    }
+}
@@ -0,0 +1,345 @@
+use crate::dns;
+use crate::errors::*;
+use crate::json::DNS;
+use crate::observation::Observation;
+use crate::reader::Reader;
+use crate::sink;
+use futures::channel::mpsc::Sender;
+use futures::{Sink, SinkExt};
+use std::convert::TryInto;
+use std::fs::Permissions;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UnixListener;
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+// frame streams control frames
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FINISH: u32 = 0x05;
+const FIELD_CONTENT_TYPE: u32 = 0x01;
+const MAX_CONTROL_LEN: u32 = 512;
+const MAX_FRAME_LEN: u32 = 1024 * 1024;
+
+// dnstap message types
+const CLIENT_QUERY: u64 = 5;
+const CLIENT_RESPONSE: u64 = 6;
+
+/// Protobuf field values, only the wire types used by dnstap
+#[derive(Debug)]
+enum Field<'a> {
+    Varint(u64),
+    Fixed,
+    Bytes(&'a [u8]),
+}
+
+fn varint(r: &mut Reader) -> Result<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let b = r.u8()?;
+        n |= u64::from(b & 0x7f) << shift;
+        if b & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    bail!("Protobuf varint is too long")
+}
+
+/// Decode a protobuf message into (field number, value)
+fn fields(buf: &[u8]) -> Result<Vec<(u64, Field<'_>)>> {
+    let mut r = Reader::new(buf);
+    let mut fields = Vec::new();
+    while !r.is_empty() {
+        let key = varint(&mut r)?;
+        let value = match key & 0x07 {
+            0 => Field::Varint(varint(&mut r)?),
+            1 => {
+                r.skip(8)?;
+                Field::Fixed
+            }
+            2 => {
+                let len = varint(&mut r)?;
+                Field::Bytes(r.take(len as usize)?)
+            }
+            5 => {
+                r.skip(4)?;
+                Field::Fixed
+            }
+            wire => bail!("Unsupported protobuf wire type: {}", wire),
+        };
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+fn address(buf: &[u8]) -> Option<IpAddr> {
+    match buf.len() {
+        4 => {
+            let b: [u8; 4] = buf.try_into().ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(b)))
+        }
+        16 => {
+            let b: [u8; 16] = buf.try_into().ok()?;
+            Some(IpAddr::V6(Ipv6Addr::from(b)))
+        }
+        _ => None,
+    }
+}
+
+/// Decode a dnstap frame, only queries and responses between clients and the resolver are used
+pub fn parse(frame: &[u8]) -> Result<Vec<Observation>> {
+    let message = fields(frame)?.into_iter()
+        .find_map(|(n, value)| match (n, value) {
+            (14, Field::Bytes(msg)) => Some(msg),
+            _ => None,
+        });
+    let message = match message {
+        Some(message) => message,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut kind = None;
+    let mut client = None;
+    let mut query = None;
+    let mut response = None;
+    for (n, value) in fields(message)? {
+        match (n, value) {
+            (1, Field::Varint(v)) => kind = Some(v),
+            (4, Field::Bytes(b)) => client = address(b),
+            (10, Field::Bytes(b)) => query = Some(b),
+            (14, Field::Bytes(b)) => response = Some(b),
+            _ => (),
+        }
+    }
+
+    let mut observations = Vec::new();
+    match (kind, client, query, response) {
+        (Some(CLIENT_QUERY), Some(client), Some(query), _) => {
+            if let DNS::Request(req) = dns::parse(query)? {
+                for (qtype, name) in req.questions {
+                    observations.push(Observation::Query {
+                        client,
+                        qtype: qtype.into_owned(),
+                        name: name.into_owned(),
+                    });
+                }
+            }
+        }
+        (Some(CLIENT_RESPONSE), _, _, Some(response)) => {
+            let resp = dns::parse(response)?;
+            for (name, addr) in resp.get_answers() {
+                observations.push(Observation::Resolved { name, addr });
+            }
+        }
+        _ => (),
+    }
+
+    Ok(observations)
+}
+
+async fn write_control<W: AsyncWrite + Unpin>(w: &mut W, control: u32, content_type: bool) -> Result<()> {
+    let mut buf = Vec::new();
+    buf.extend(&control.to_be_bytes());
+    if content_type {
+        buf.extend(&FIELD_CONTENT_TYPE.to_be_bytes());
+        buf.extend(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        buf.extend(CONTENT_TYPE);
+    }
+
+    w.write_all(&0u32.to_be_bytes()).await?;
+    w.write_all(&(buf.len() as u32).to_be_bytes()).await?;
+    w.write_all(&buf).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+/// Read the next frame, control frames are returned with their type
+async fn read_frame<R: AsyncRead + Unpin>(r: &mut R) -> Result<(Option<u32>, Vec<u8>)> {
+    let len = r.read_u32().await?;
+    if len == 0 {
+        let len = r.read_u32().await?;
+        if !(4..=MAX_CONTROL_LEN).contains(&len) {
+            bail!("Invalid control frame length: {}", len);
+        }
+        let control = r.read_u32().await?;
+        let mut buf = vec![0; len as usize - 4];
+        r.read_exact(&mut buf).await?;
+        Ok((Some(control), buf))
+    } else {
+        if len > MAX_FRAME_LEN {
+            bail!("Frame is too large: {}", len);
+        }
+        let mut buf = vec![0; len as usize];
+        r.read_exact(&mut buf).await?;
+        Ok((None, buf))
+    }
+}
+
+/// Run the frame streams protocol on a connection, both the bidirectional
+/// handshake and unidirectional streams are supported
+async fn handle<S: Sink<Observation> + Unpin, T: AsyncRead + AsyncWrite + Unpin>(mut sink: S, mut stream: T) -> Result<()> {
+    info!("got dnstap connection");
+    loop {
+        match read_frame(&mut stream).await? {
+            (Some(CONTROL_READY), _) => write_control(&mut stream, CONTROL_ACCEPT, true).await?,
+            (Some(CONTROL_START), _) => debug!("dnstap stream started"),
+            (Some(CONTROL_STOP), _) => {
+                debug!("dnstap stream stopped");
+                write_control(&mut stream, CONTROL_FINISH, false).await.ok();
+                return Ok(());
+            }
+            (Some(control), _) => trace!("Ignoring frame streams control frame: {}", control),
+            (None, frame) => match parse(&frame) {
+                Ok(observations) => {
+                    for obs in observations {
+                        sink.send(obs).await.map_err(|_| anyhow!("sink error"))?;
+                    }
+                }
+                Err(err) => trace!("Failed to parse dnstap frame: {:#}", err),
+            },
+        }
+    }
+}
+
+pub async fn spawn(path: &str, group: Option<&str>, tx: Sender<Observation>) -> Result<()> {
+    let path = Path::new(path);
+    if path.exists() {
+        fs::remove_file(&path)
+            .await
+            .context("Failed to remove old dnstap socket")?;
+    }
+
+    info!("Binding dnstap socket: {:?}", path.display());
+    let listener = UnixListener::bind(path)?;
+
+    // the resolver usually runs as a different user, it needs to be in the group to send queries
+    if let Some(group) = group {
+        std::os::unix::fs::chown(path, None, Some(sink::group_id(group)?))
+            .with_context(|| anyhow!("Failed to change the group of {:?}", path))?;
+    }
+    fs::set_permissions(&path, Permissions::from_mode(0o660))
+        .await
+        .context("Failed to make dnstap socket 0660")?;
+
+    loop {
+        let (stream, _addr) = listener.accept().await?;
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(tx, stream).await {
+                warn!("dnstap connection failed: {:#}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn encode_varint(buf: &mut Vec<u8>, mut n: u64) {
+        while n >= 0x80 {
+            buf.push((n as u8) | 0x80);
+            n >>= 7;
+        }
+        buf.push(n as u8);
+    }
+
+    fn encode_bytes(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+        encode_varint(buf, (field << 3) | 2);
+        encode_varint(buf, value.len() as u64);
+        buf.extend(value);
+    }
+
+    fn dnstap(kind: u64, client: &[u8], field: u64, msg: &[u8]) -> Vec<u8> {
+        let mut message = Vec::new();
+        encode_varint(&mut message, 1 << 3);
+        encode_varint(&mut message, kind);
+        encode_varint(&mut message, 2 << 3);
+        encode_varint(&mut message, 1);
+        encode_bytes(&mut message, 4, client);
+        encode_varint(&mut message, 6 << 3);
+        encode_varint(&mut message, 53022);
+        // query_time_nsec is a fixed32
+        encode_varint(&mut message, (9 << 3) | 5);
+        message.extend(&[0x00, 0xca, 0x9a, 0x3b]);
+        encode_bytes(&mut message, field, msg);
+
+        let mut frame = Vec::new();
+        encode_bytes(&mut frame, 1, b"pihole");
+        encode_varint(&mut frame, 15 << 3);
+        encode_varint(&mut frame, 1);
+        encode_bytes(&mut frame, 14, &message);
+        frame
+    }
+
+    const QUERY: &[u8] = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x06google\x03com\x00\x00\x10\x00\x01";
+    const RESPONSE: &[u8] = b"\x12\x34\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\
+        \x06github\x03com\x00\x00\x01\x00\x01\
+        \xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\x8c\x52\x79\x04";
+
+    #[test]
+    fn parse_client_query() {
+        let frame = dnstap(CLIENT_QUERY, &[10, 38, 73, 100], 10, QUERY);
+        assert_eq!(parse(&frame).unwrap(), vec![Observation::Query {
+            client: "10.38.73.100".parse().unwrap(),
+            qtype: "TXT".to_string(),
+            name: "google.com".to_string(),
+        }]);
+    }
+
+    #[test]
+    fn parse_client_response() {
+        let frame = dnstap(CLIENT_RESPONSE, &[10, 38, 73, 100], 14, RESPONSE);
+        assert_eq!(parse(&frame).unwrap(), vec![Observation::Resolved {
+            name: "github.com".to_string(),
+            addr: "140.82.121.4".parse().unwrap(),
+        }]);
+    }
+
+    #[test]
+    fn ignore_forwarder_query() {
+        let frame = dnstap(7, &[10, 38, 73, 1], 10, QUERY);
+        assert_eq!(parse(&frame).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let frame = dnstap(CLIENT_QUERY, &[10, 38, 73, 100], 10, QUERY);
+        assert!(parse(&frame[..frame.len() - 4]).is_err());
+    }
+
+    #[tokio::test]
+    async fn bidirectional_handshake() {
+        let (mut client, server) = tokio::net::UnixStream::pair().unwrap();
+        let (tx, mut rx) = futures::channel::mpsc::channel(16);
+        let server = tokio::spawn(handle(tx, server));
+
+        write_control(&mut client, CONTROL_READY, true).await.unwrap();
+        let (control, payload) = read_frame(&mut client).await.unwrap();
+        assert_eq!(control, Some(CONTROL_ACCEPT));
+        assert!(payload.ends_with(CONTENT_TYPE));
+
+        write_control(&mut client, CONTROL_START, true).await.unwrap();
+        let frame = dnstap(CLIENT_QUERY, &[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x13, 0x37], 10, QUERY);
+        client.write_all(&(frame.len() as u32).to_be_bytes()).await.unwrap();
+        client.write_all(&frame).await.unwrap();
+        write_control(&mut client, CONTROL_STOP, false).await.unwrap();
+
+        let (control, _) = read_frame(&mut client).await.unwrap();
+        assert_eq!(control, Some(CONTROL_FINISH));
+        server.await.unwrap().unwrap();
+
+        assert_eq!(rx.next().await, Some(Observation::Query {
+            client: "fd00::1337".parse().unwrap(),
+            qtype: "TXT".to_string(),
+            name: "google.com".to_string(),
+        }));
+    }
+}
@@ -0,0 +1,366 @@
+use crate::ioc::Indicator;
+use crate::session::{Provenance, Session};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+/// How sure we are that something is wrong
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    /// An ioc or custom rule matched
+    Detected,
+    /// Looks like dns tunnelling
+    Suspicious,
+    /// A connection to an address that was never resolved
+    Unexplained,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Detected => "detected",
+            Level::Suspicious => "suspicious",
+            Level::Unexplained => "unexplained",
+        }
+    }
+
+    /// Everything that isn't an ioc match is a weaker signal, so it's marked with [?] instead of [!]
+    pub fn sigil(&self) -> char {
+        match self {
+            Level::Detected => '!',
+            _ => '?',
+        }
+    }
+}
+
+/// What a detection is about
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    Name(String),
+    Fingerprint { hash: String, sni: String },
+    Request(String),
+    Addr(SocketAddr),
+    Tunnel {
+        parent: String,
+        reason: String,
+        /// The queries that looked like a tunnel
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        names: Vec<String>,
+    },
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Name(name) => write!(f, "{:?}", name),
+            Target::Fingerprint { hash, sni } => write!(f, "{:?} ({})", hash, sni),
+            Target::Request(line) => write!(f, "{:?}", line),
+            Target::Addr(addr) => write!(f, "{}", addr),
+            Target::Tunnel { parent, reason, names } => {
+                write!(f, "{:?} ({}", parent, reason)?;
+                match names.as_slice() {
+                    [] => (),
+                    [name] => write!(f, ", query: {:?}", name)?,
+                    [name, rest @ ..] => write!(f, ", queries: {:?} and {} more", name, rest.len())?,
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// Where an observation or detection was seen
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Origin {
+    /// The phone's address, from the resolver log or the local side of the flow
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    /// The scan this was seen in, set once the event passed the session tracker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(client) = &self.client {
+            write!(f, " from {}", client)?;
+        }
+        if let Some(interface) = &self.interface {
+            write!(f, " on {}", interface)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Detection {
+    pub level: Level,
+    /// e.g. `dns`, `dns/TXT`, `tls/fingerprint` or `ip/tcp`
+    pub kind: String,
+    pub target: Target,
+    /// Name of the custom rule that matched, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+    /// The ioc entry that matched, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ioc: Option<Indicator>,
+    #[serde(flatten)]
+    pub origin: Origin,
+    /// When this was reported, set by the session tracker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<DateTime<Utc>>,
+    /// Identical detections within a session are merged into the first one
+    #[serde(default = "one", skip_serializing_if = "is_one")]
+    pub count: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+fn one() -> u64 {
+    1
+}
+
+fn is_one(n: &u64) -> bool {
+    *n == 1
+}
+
+impl Detection {
+    pub fn new(level: Level, kind: impl Into<String>, target: Target) -> Detection {
+        Detection {
+            level,
+            kind: kind.into(),
+            target,
+            rule: None,
+            ioc: None,
+            origin: Origin::default(),
+            time: None,
+            count: 1,
+            last_seen: None,
+        }
+    }
+
+    pub fn rule(mut self, rule: &str) -> Detection {
+        self.rule = Some(rule.to_string());
+        self
+    }
+
+    pub fn ioc(mut self, ioc: &Indicator) -> Detection {
+        self.ioc = Some(ioc.clone());
+        self
+    }
+
+    /// The app this is about, either from the iocs or the name of the custom rule
+    pub fn family(&self) -> Option<&str> {
+        self.ioc.as_ref().map(|ioc| ioc.family.as_str())
+            .or(self.rule.as_deref())
+    }
+}
+
+impl fmt::Display for Detection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] {}({}): {}{}", self.level.sigil(), self.level.as_str(), self.kind, self.target, self.origin)
+    }
+}
+
+/// A name that was seen on the network without matching anything
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Observed {
+    /// e.g. `dns`, `tls`, `quic` or `http`
+    pub kind: String,
+    pub name: String,
+    #[serde(flatten)]
+    pub origin: Origin,
+}
+
+impl fmt::Display for Observed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[ ] observed({}): {:?}{}", self.kind, self.name, self.origin)
+    }
+}
+
+/// Everything that is passed from the detections and the hotspot to the screen, stdout and other sinks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Detection(Detection),
+    Observation(Observed),
+    /// The hotspot was (re)started with a new password
+    Credentials {
+        ssid: String,
+        password: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+    },
+    ClientConnected {
+        mac: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+    },
+    ClientDisconnected {
+        mac: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+    },
+    SessionStarted(Session),
+    /// Contains all findings of the session
+    SessionEnded(Session),
+    IocsLoaded(Provenance),
+    Status {
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+    },
+    Error {
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+    },
+}
+
+impl Event {
+    pub fn status(message: impl Into<String>) -> Event {
+        Event::Status { message: message.into(), session: None }
+    }
+
+    pub fn error(message: impl Into<String>) -> Event {
+        Event::Error { message: message.into(), session: None }
+    }
+
+    pub fn detection(&self) -> Option<&Detection> {
+        match self {
+            Event::Detection(detection) => Some(detection),
+            _ => None,
+        }
+    }
+
+    /// Observations are only interesting for logs and history, not for the screen
+    pub fn is_observation(&self) -> bool {
+        matches!(self, Event::Observation(_))
+    }
+
+    /// Set the client and interface for detections and observations
+    pub fn set_origin(&mut self, origin: &Origin) {
+        match self {
+            Event::Detection(detection) => detection.origin = origin.clone(),
+            Event::Observation(observed) => observed.origin = origin.clone(),
+            _ => (),
+        }
+    }
+
+    pub fn session(&self) -> Option<&str> {
+        match self {
+            Event::Detection(detection) => detection.origin.session.as_deref(),
+            Event::Observation(observed) => observed.origin.session.as_deref(),
+            Event::SessionStarted(session) | Event::SessionEnded(session) => Some(&session.id),
+            Event::IocsLoaded(_) => None,
+            Event::Credentials { session, .. }
+            | Event::ClientConnected { session, .. }
+            | Event::ClientDisconnected { session, .. }
+            | Event::Status { session, .. }
+            | Event::Error { session, .. } => session.as_deref(),
+        }
+    }
+
+    /// Attach the event to a session, the ioc list is loaded independently of any session
+    pub fn set_session(&mut self, id: &str) {
+        let id = Some(id.to_string());
+        match self {
+            Event::Detection(detection) => detection.origin.session = id,
+            Event::Observation(observed) => observed.origin.session = id,
+            Event::SessionStarted(_) | Event::SessionEnded(_) | Event::IocsLoaded(_) => (),
+            Event::Credentials { session, .. }
+            | Event::ClientConnected { session, .. }
+            | Event::ClientDisconnected { session, .. }
+            | Event::Status { session, .. }
+            | Event::Error { session, .. } => *session = id,
+        }
+    }
+}
+
+impl From<Detection> for Event {
+    fn from(detection: Detection) -> Event {
+        Event::Detection(detection)
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Detection(detection) => write!(f, "{}", detection),
+            Event::Observation(observed) => write!(f, "{}", observed),
+            Event::Credentials { ssid, password, .. } => write!(f, "[+] {:?} (pw: {})", ssid, password),
+            Event::ClientConnected { mac, .. } => write!(f, "[+] connected: {}", mac),
+            Event::ClientDisconnected { mac, .. } => write!(f, "[+] disconnected: {}", mac),
+            Event::SessionStarted(session) | Event::SessionEnded(session) => write!(f, "[+] {}", session),
+            Event::IocsLoaded(provenance) => write!(f, "[#] loaded {} iocs", provenance.iocs),
+            Event::Status { message, .. } => write!(f, "[#] {}", message),
+            Event::Error { message, .. } => write!(f, "[-] {}", message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_detections() {
+        let event = Event::from(Detection::new(Level::Detected, "dns", Target::Name("example.com".to_string())));
+        assert_eq!(event.to_string(), r#"[!] detected(dns): "example.com""#);
+
+        let event = Event::from(Detection::new(Level::Detected, "tls/fingerprint", Target::Fingerprint {
+            hash: "t13d1516h2_8daaf6152771_e5627efa2ab1".to_string(),
+            sni: "example.com".to_string(),
+        }));
+        assert_eq!(event.to_string(), r#"[!] detected(tls/fingerprint): "t13d1516h2_8daaf6152771_e5627efa2ab1" (example.com)"#);
+
+        let event = Event::from(Detection::new(Level::Unexplained, "tcp", Target::Addr("93.184.216.34:443".parse().unwrap())));
+        assert_eq!(event.to_string(), "[?] unexplained(tcp): 93.184.216.34:443");
+    }
+
+    #[test]
+    fn render_origin() {
+        let mut event = Event::from(Detection::new(Level::Detected, "dns/TXT", Target::Name("example.com".to_string())));
+        event.set_origin(&Origin {
+            client: Some("10.38.73.100".parse().unwrap()),
+            interface: Some("wlan1".to_string()),
+            session: None,
+        });
+        assert_eq!(event.to_string(), r#"[!] detected(dns/TXT): "example.com" from 10.38.73.100 on wlan1"#);
+    }
+
+    #[test]
+    fn serialize_detection() {
+        let mut event = Event::from(Detection::new(Level::Suspicious, "dns", Target::Tunnel {
+            parent: "example.com".to_string(),
+            reason: "TXT query".to_string(),
+            names: vec!["x.example.com".to_string()],
+        }));
+        event.set_origin(&Origin {
+            client: None,
+            interface: Some("wlan1".to_string()),
+            session: None,
+        });
+        assert_eq!(event.to_string(), r#"[?] suspicious(dns): "example.com" (TXT query, query: "x.example.com") on wlan1"#);
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(json, r#"{"event":"detection","level":"suspicious","kind":"dns","target":{"tunnel":{"parent":"example.com","reason":"TXT query","names":["x.example.com"]}},"interface":"wlan1"}"#);
+        assert_eq!(serde_json::from_str::<Event>(&json).unwrap(), event);
+    }
+
+    #[test]
+    fn serialize_credentials() {
+        let event = Event::Credentials {
+            ssid: "Starbucks WiFi".to_string(),
+            password: "abcdefghij".to_string(),
+            session: None,
+        };
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(json, r#"{"event":"credentials","ssid":"Starbucks WiFi","password":"abcdefghij"}"#);
+        assert_eq!(event.to_string(), r#"[+] "Starbucks WiFi" (pw: abcdefghij)"#);
+    }
+}
@@ -0,0 +1,437 @@
+use crate::errors::*;
+use crate::event::{Detection, Event, Observed};
+use crate::session::Session;
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, Row};
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const DEFAULT_PATH: &str = "/spytrap/history.db";
+
+/// Every entry upgrades the schema by one version, existing entries must never be changed
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE sessions (
+        id TEXT PRIMARY KEY,
+        started TEXT NOT NULL,
+        ended TEXT,
+        reason TEXT,
+        ssid TEXT,
+        password TEXT,
+        clients TEXT NOT NULL,
+        provenance TEXT
+    );
+    CREATE TABLE detections (
+        id INTEGER PRIMARY KEY,
+        session TEXT,
+        time TEXT NOT NULL,
+        level TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX detections_session ON detections(session);
+    CREATE TABLE observations (
+        session TEXT NOT NULL DEFAULT '',
+        kind TEXT NOT NULL,
+        name TEXT NOT NULL,
+        count INTEGER NOT NULL,
+        first_seen TEXT NOT NULL,
+        last_seen TEXT NOT NULL,
+        PRIMARY KEY (session, kind, name)
+    );",
+];
+
+/// How often a name was seen during a session, individual observations are not stored
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Summary {
+    pub kind: String,
+    pub name: String,
+    pub count: u64,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// A session as it was stored, findings are loaded from the detections table
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Scan {
+    #[serde(flatten)]
+    pub session: Session,
+    pub observations: Vec<Summary>,
+}
+
+fn time(s: String) -> Result<DateTime<Utc>> {
+    let time = DateTime::parse_from_rfc3339(&s)
+        .with_context(|| anyhow!("Invalid timestamp in history: {:?}", s))?;
+    Ok(time.with_timezone(&Utc))
+}
+
+fn json<T: serde::de::DeserializeOwned>(s: String) -> Result<T> {
+    serde_json::from_str(&s)
+        .with_context(|| anyhow!("Invalid json in history: {:?}", s))
+}
+
+/// Sessions include the hotspot password, only the owner of the database may read it
+fn restrict(path: &Path) -> Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| anyhow!("Failed to create history database {:?}", path))?;
+
+    // sqlite creates the -wal and -shm files with the mode of the database, fix the ones of an older version
+    for suffix in ["", "-wal", "-shm"] {
+        let mut file = path.as_os_str().to_owned();
+        file.push(suffix);
+        let file = PathBuf::from(file);
+        if file.exists() {
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o600))
+                .with_context(|| anyhow!("Failed to restrict permissions of {:?}", file))?;
+        }
+    }
+    Ok(())
+}
+
+fn session_from_row(row: &Row) -> Result<Session> {
+    Ok(Session {
+        id: row.get(0)?,
+        started: time(row.get(1)?)?,
+        ended: row.get::<_, Option<String>>(2)?.map(time).transpose()?,
+        reason: row.get::<_, Option<String>>(3)?
+            .map(|reason| serde_json::from_value(serde_json::Value::String(reason)))
+            .transpose()?,
+        ssid: row.get(4)?,
+        password: row.get(5)?,
+        clients: json(row.get(6)?)?,
+        provenance: row.get::<_, Option<String>>(7)?.map(json).transpose()?,
+        findings: Vec::new(),
+    })
+}
+
+/// Sessions, detections and observation summaries of past scans
+pub struct History {
+    db: Connection,
+}
+
+impl History {
+    /// Open or create the database and upgrade it to the latest schema
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<History> {
+        let path = path.as_ref();
+        restrict(path)?;
+        let db = Connection::open(path)
+            .with_context(|| anyhow!("Failed to open history database {:?}", path))?;
+        History::setup(db)
+    }
+
+    /// Open an existing database to query it, it's neither created nor migrated
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<History> {
+        let path = path.as_ref();
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let db = Connection::open_with_flags(path, flags)
+            .with_context(|| anyhow!("Failed to open history database {:?}", path))?;
+        db.busy_timeout(Duration::from_secs(5))?;
+
+        let history = History { db };
+        let version = history.version()?;
+        if version != MIGRATIONS.len() {
+            bail!("History database has schema version {}, expected {}", version, MIGRATIONS.len());
+        }
+        Ok(history)
+    }
+
+    pub fn open_in_memory() -> Result<History> {
+        History::setup(Connection::open_in_memory()?)
+    }
+
+    fn setup(db: Connection) -> Result<History> {
+        // the journal makes writes atomic if power is lost, every commit is synced to the sd card
+        db.pragma_update(None, "journal_mode", "WAL")?;
+        db.pragma_update(None, "synchronous", "FULL")?;
+        db.busy_timeout(Duration::from_secs(5))?;
+
+        let mut history = History { db };
+        history.migrate()?;
+        Ok(history)
+    }
+
+    pub fn version(&self) -> Result<usize> {
+        let version = self.db.pragma_query_value(None, "user_version", |row| row.get::<_, i64>(0))?;
+        Ok(version as usize)
+    }
+
+    fn migrate(&mut self) -> Result<()> {
+        let version = self.version()?;
+        if version > MIGRATIONS.len() {
+            bail!("History database has schema version {}, only {} is supported", version, MIGRATIONS.len());
+        }
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            info!("Migrating history database to version {}", i + 1);
+            let tx = self.db.transaction()?;
+            tx.execute_batch(migration)
+                .with_context(|| anyhow!("Failed to migrate history database to version {}", i + 1))?;
+            tx.pragma_update(None, "user_version", (i + 1) as i64)?;
+            tx.commit()?;
+        }
+        Ok(())
+    }
+
+    fn save_session(&self, session: &Session) -> Result<()> {
+        self.db.execute("INSERT INTO sessions (id, started, ended, reason, ssid, password, clients, provenance)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            ON CONFLICT (id) DO UPDATE SET
+                ended = excluded.ended,
+                reason = excluded.reason,
+                clients = excluded.clients,
+                provenance = excluded.provenance", (
+            &session.id,
+            session.started.to_rfc3339(),
+            session.ended.map(|time| time.to_rfc3339()),
+            session.reason.map(|reason| reason.as_str()),
+            &session.ssid,
+            &session.password,
+            serde_json::to_string(&session.clients)?,
+            session.provenance.as_ref().map(serde_json::to_string).transpose()?,
+        ))?;
+        Ok(())
+    }
+
+    fn save_detection(db: &Connection, detection: &Detection, now: DateTime<Utc>) -> Result<()> {
+        let time = detection.time.unwrap_or(now);
+        db.execute("INSERT INTO detections (session, time, level, kind, data) VALUES (?1, ?2, ?3, ?4, ?5)", (
+            &detection.origin.session,
+            time.to_rfc3339(),
+            detection.level.as_str(),
+            &detection.kind,
+            serde_json::to_string(detection)?,
+        ))?;
+        Ok(())
+    }
+
+    fn save_observation(&self, observed: &Observed, now: DateTime<Utc>) -> Result<()> {
+        self.db.execute("INSERT INTO observations (session, kind, name, count, first_seen, last_seen)
+            VALUES (?1, ?2, ?3, 1, ?4, ?4)
+            ON CONFLICT (session, kind, name) DO UPDATE SET
+                count = count + 1,
+                last_seen = excluded.last_seen", (
+            observed.origin.session.as_deref().unwrap_or(""),
+            &observed.kind,
+            &observed.name,
+            now.to_rfc3339(),
+        ))?;
+        Ok(())
+    }
+
+    /// Replace the detections of a session with the merged findings, including their counts
+    fn save_findings(&mut self, session: &Session, now: DateTime<Utc>) -> Result<()> {
+        let tx = self.db.transaction()?;
+        tx.execute("DELETE FROM detections WHERE session = ?1", [&session.id])?;
+        for detection in &session.findings {
+            History::save_detection(&tx, detection, now)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Store everything that should survive a restart, every event is written in its own transaction
+    pub fn record(&mut self, event: &Event, now: DateTime<Utc>) -> Result<()> {
+        match event {
+            Event::SessionStarted(session) => self.save_session(session),
+            Event::SessionEnded(session) => {
+                self.save_session(session)?;
+                self.save_findings(session, now)
+            }
+            Event::Detection(detection) => History::save_detection(&self.db, detection, now),
+            Event::Observation(observed) => self.save_observation(observed, now),
+            _ => Ok(()),
+        }
+    }
+
+    fn findings(&self, session: &str) -> Result<Vec<Detection>> {
+        let mut stmt = self.db.prepare_cached("SELECT data FROM detections WHERE session = ?1 ORDER BY id")?;
+        let mut rows = stmt.query([session])?;
+        let mut findings = Vec::new();
+        while let Some(row) = rows.next()? {
+            findings.push(json(row.get(0)?)?);
+        }
+        Ok(findings)
+    }
+
+    fn observations(&self, session: &str) -> Result<Vec<Summary>> {
+        let mut stmt = self.db.prepare_cached("SELECT kind, name, count, first_seen, last_seen FROM observations
+            WHERE session = ?1 ORDER BY count DESC, name")?;
+        let mut rows = stmt.query([session])?;
+        let mut observations = Vec::new();
+        while let Some(row) = rows.next()? {
+            observations.push(Summary {
+                kind: row.get(0)?,
+                name: row.get(1)?,
+                count: row.get::<_, i64>(2)? as u64,
+                first_seen: time(row.get(3)?)?,
+                last_seen: time(row.get(4)?)?,
+            });
+        }
+        Ok(observations)
+    }
+
+    /// All sessions with their findings, the most recent one first
+    pub fn sessions(&self) -> Result<Vec<Session>> {
+        let mut stmt = self.db.prepare("SELECT id, started, ended, reason, ssid, password, clients, provenance
+            FROM sessions ORDER BY started DESC")?;
+        let mut rows = stmt.query([])?;
+        let mut sessions = Vec::new();
+        while let Some(row) = rows.next()? {
+            let mut session = session_from_row(row)?;
+            session.findings = self.findings(&session.id)?;
+            sessions.push(session);
+        }
+        Ok(sessions)
+    }
+
+    pub fn scan(&self, id: &str) -> Result<Option<Scan>> {
+        let session = self.db.query_row("SELECT id, started, ended, reason, ssid, password, clients, provenance
+            FROM sessions WHERE id = ?1", [id], |row| Ok(session_from_row(row)))
+            .optional()?
+            .transpose()?;
+        let mut session = match session {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+        session.findings = self.findings(id)?;
+        let observations = self.observations(id)?;
+        Ok(Some(Scan {
+            session,
+            observations,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{Level, Origin, Target};
+    use crate::session::{EndReason, Provenance};
+
+    fn time(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    fn session() -> Session {
+        Session {
+            id: "20231114-221320-0001".to_string(),
+            started: time(0),
+            ended: None,
+            reason: None,
+            ssid: Some("Starbucks WiFi".to_string()),
+            password: Some("abcdefghij".to_string()),
+            clients: vec!["aa:bb:cc:dd:ee:ff".to_string()],
+            provenance: Some(Provenance {
+                path: "ioc.yaml".to_string(),
+                sha256: "00".repeat(32),
+                iocs: 3,
+                custom_rules: None,
+            }),
+            findings: Vec::new(),
+        }
+    }
+
+    fn observed(name: &str, session: &str) -> Event {
+        Event::Observation(Observed {
+            kind: "dns".to_string(),
+            name: name.to_string(),
+            origin: Origin {
+                session: Some(session.to_string()),
+                ..Default::default()
+            },
+        })
+    }
+
+    #[test]
+    fn migrate_twice() {
+        let dir = std::env::temp_dir().join(format!("spytrap-history-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.db");
+
+        let mut history = History::open(&path).unwrap();
+        assert_eq!(history.version().unwrap(), MIGRATIONS.len());
+        history.record(&Event::SessionStarted(session()), time(0)).unwrap();
+        drop(history);
+
+        let history = History::open(&path).unwrap();
+        assert_eq!(history.sessions().unwrap().len(), 1);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn private_database() {
+        let dir = std::env::temp_dir().join(format!("spytrap-history-private-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.db");
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        // queries don't create a missing database
+        assert!(History::open_read_only(&path).is_err());
+        assert!(!path.exists());
+
+        let mut history = History::open(&path).unwrap();
+        history.record(&Event::SessionStarted(session()), time(0)).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(mode(&dir.join("history.db-wal")), 0o600);
+
+        let mut reader = History::open_read_only(&path).unwrap();
+        assert_eq!(reader.sessions().unwrap().len(), 1);
+        assert!(reader.record(&Event::SessionStarted(session()), time(1)).is_err());
+        drop(reader);
+        drop(history);
+
+        // the wal is gone once the daemon closed the database
+        assert!(!dir.join("history.db-wal").exists());
+        let reader = History::open_read_only(&path).unwrap();
+        assert_eq!(reader.sessions().unwrap().len(), 1);
+        drop(reader);
+
+        // a database of an older version is restricted when the daemon opens it
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        History::open(&path).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn record_session() {
+        let mut history = History::open_in_memory().unwrap();
+        let mut session = session();
+        history.record(&Event::SessionStarted(session.clone()), time(0)).unwrap();
+
+        let mut detection = Detection::new(Level::Detected, "dns", Target::Name("example.com".to_string()));
+        detection.origin.session = Some(session.id.clone());
+        detection.time = Some(time(5));
+        history.record(&detection.clone().into(), time(5)).unwrap();
+        history.record(&observed("example.org", &session.id), time(6)).unwrap();
+        history.record(&observed("example.org", &session.id), time(7)).unwrap();
+        history.record(&Event::status("not stored"), time(8)).unwrap();
+
+        // the session ends with the merged findings, they replace what was recorded before
+        detection.count = 3;
+        detection.last_seen = Some(time(9));
+        session.ended = Some(time(10));
+        session.reason = Some(EndReason::Disconnected);
+        session.findings = vec![detection];
+        history.record(&Event::SessionEnded(session.clone()), time(10)).unwrap();
+
+        let scan = history.scan(&session.id).unwrap().unwrap();
+        assert_eq!(scan.session, session);
+        assert_eq!(scan.observations, vec![Summary {
+            kind: "dns".to_string(),
+            name: "example.org".to_string(),
+            count: 2,
+            first_seen: time(6),
+            last_seen: time(7),
+        }]);
+        assert_eq!(history.sessions().unwrap(), vec![session]);
+        assert_eq!(history.scan("unknown").unwrap(), None);
+    }
+}
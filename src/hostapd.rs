@@ -1,8 +1,30 @@
+use crate::config::Hotspot;
 use crate::errors::*;
 use rand::Rng;
 use tokio::process::Command;
 use tokio::fs;
 
+/// Messages on the rpc socket, client updates are sent by hostapd-hook.sh
+#[derive(Debug, PartialEq, Eq)]
+pub enum Signal {
+    Connected(String),
+    /// The phone left the network, this also resets the password
+    Disconnected(String),
+    Reset,
+}
+
+impl Signal {
+    /// Anything that isn't a client update resets the password
+    pub fn parse(line: &str) -> Signal {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some("connected"), Some(mac)) => Signal::Connected(mac.to_string()),
+            (Some("disconnected"), Some(mac)) => Signal::Disconnected(mac.to_string()),
+            _ => Signal::Reset,
+        }
+    }
+}
+
 // TODO: this should just pick a random word
 pub fn pwgen() -> String {
     /*
@@ -22,8 +44,8 @@ pub fn pwgen() -> String {
         .collect()
 }
 
-// TODO: all arguments are unsafe
-fn mkconfig(interface: &str, ssid: &str, password: &str) -> String {
+/// Settings are validated when the config is loaded, so they can't add lines
+fn mkconfig(settings: &Hotspot, password: &str) -> String {
 	format!("
 interface={}
 
@@ -36,9 +58,9 @@ ctrl_interface=/run/hostapd
 ctrl_interface_group=0
 
 ssid={}
-country_code=DE
-hw_mode=g
-channel=11
+country_code={}
+hw_mode={}
+channel={}
 beacon_int=100
 dtim_period=2
 max_num_sta=255
@@ -80,12 +102,12 @@ wpa=2
 wpa_passphrase={}
 wpa_key_mgmt=WPA-PSK
 rsn_pairwise=CCMP
-", interface, ssid, password)
+", settings.interface, settings.ssid, settings.country_code, settings.hw_mode(), settings.channel, password)
 }
 
-pub async fn write_config(path: &str, interface: &str, ssid: &str, password: &str) -> Result<()> {
-	let config = mkconfig(interface, ssid, password);
-	fs::write(path, config.as_bytes()).await?;
+pub async fn write_config(settings: &Hotspot, password: &str) -> Result<()> {
+	let config = mkconfig(settings, password);
+	fs::write(&settings.config, config.as_bytes()).await?;
 	Ok(())
 }
 
@@ -97,3 +119,27 @@ pub async fn restart() -> Result<()> {
 		.wait().await?;
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Signal::parse("connected 02:00:00:00:00:01"), Signal::Connected("02:00:00:00:00:01".to_string()));
+        assert_eq!(Signal::parse("disconnected 02:00:00:00:00:01"), Signal::Disconnected("02:00:00:00:00:01".to_string()));
+        assert_eq!(Signal::parse("reset"), Signal::Reset);
+        assert_eq!(Signal::parse("connected"), Signal::Reset);
+    }
+
+    #[test]
+    fn hotspot_settings() {
+        let config = mkconfig(&Hotspot {
+            channel: 36,
+            ..Hotspot::default()
+        }, "abcdefghij");
+        assert!(config.contains("\ninterface=wlan1\n"));
+        assert!(config.contains("\nssid=Starbucks WiFi\ncountry_code=DE\nhw_mode=a\nchannel=36\n"));
+        assert!(config.contains("\nwpa_passphrase=abcdefghij\n"));
+    }
+}
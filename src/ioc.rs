@@ -1,37 +1,139 @@
 use crate::errors::*;
 use crate::suffix::SuffixTree;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::fs;
+use std::net::IpAddr;
 use std::path::Path;
 
-pub fn load<P: AsRef<Path>>(path: P) -> Result<SuffixTree<String>> {
+/// Which list of an ioc entry a domain or ip is from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Section {
+    /// The vendor's website, e.g. where the app is bought
+    Website,
+    /// Where the app is downloaded from
+    Distribution,
+    /// Servers the app uploads collected data to
+    C2,
+}
+
+impl Section {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Section::Website => "website",
+            Section::Distribution => "distribution",
+            Section::C2 => "c2",
+        }
+    }
+}
+
+/// The ioc entry a domain or ip belongs to
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Indicator {
+    /// Name of the app, e.g. `OwnSpy`
+    pub family: String,
+    /// e.g. `stalkerware` or `watchware`
+    pub category: String,
+    pub section: Section,
+}
+
+#[derive(Debug, Default)]
+pub struct Iocs {
+    pub domains: SuffixTree<String>,
+    /// Every domain with the entry it's listed in, subdomains are matched with the suffix tree
+    pub indicators: HashMap<String, Indicator>,
+    pub ips: HashMap<IpAddr, Indicator>,
+    /// Hash of the file the iocs were loaded from
+    pub sha256: String,
+}
+
+impl Iocs {
+    pub fn len(&self) -> usize {
+        self.domains.len() + self.ips.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn matches(&self, domain: &str) -> bool {
+        self.domains.matches(domain)
+    }
+
+    pub fn matches_ip(&self, ip: &IpAddr) -> bool {
+        self.ips.contains_key(ip)
+    }
+
+    /// Returns the entry of the most specific listed domain this name is part of
+    pub fn lookup(&self, domain: &str) -> Option<&Indicator> {
+        if !self.matches(domain) {
+            return None;
+        }
+        let mut domain = domain.trim_end_matches('.');
+        loop {
+            if let Some(indicator) = self.indicators.get(domain) {
+                return Some(indicator);
+            }
+            domain = domain.split_once('.')?.1;
+        }
+    }
+
+    pub fn lookup_ip(&self, ip: &IpAddr) -> Option<&Indicator> {
+        self.ips.get(ip)
+    }
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Iocs> {
     let list = fs::read(path)?;
-    parse_domain_iocs(&list)
+    parse(&list)
 }
 
-fn parse_domain_iocs(buf: &[u8]) -> Result<SuffixTree<String>> {
+/// Parse an ioc list in the stalkerware-indicators format
+pub fn parse(buf: &[u8]) -> Result<Iocs> {
     let mut tree = SuffixTree::new();
+    let mut indicators = HashMap::new();
+    let mut ips = HashMap::new();
     let list = stalkerware_indicators::parse_from_buf(buf)?;
 
     for item in list {
-        for domain in item.websites {
-            debug!("Loaded ioc (website): {:?}", domain);
-            tree.insert(&domain);
-        }
+        let indicator = |section| Indicator {
+            family: item.name.clone(),
+            category: item.r#type.clone(),
+            section,
+        };
 
-        for domain in item.distribution {
-            debug!("Loaded ioc (distribution): {:?}", domain);
-            tree.insert(&domain);
+        let domains = [
+            (Section::Website, &item.websites),
+            (Section::Distribution, &item.distribution),
+            (Section::C2, &item.c2.domains),
+        ];
+        for (section, list) in domains {
+            for domain in list {
+                debug!("Loaded ioc ({}): {:?}", section.as_str(), domain);
+                tree.insert(domain);
+                indicators.entry(domain.to_string()).or_insert_with(|| indicator(section));
+            }
         }
 
-        for domain in item.c2.domains {
-            debug!("Loaded ioc (c2): {:?}", domain);
-            tree.insert(&domain);
+        for ip in &item.c2.ips {
+            match ip.to_string().parse() {
+                Ok(ip) => {
+                    debug!("Loaded ioc (c2 ip): {:?}", ip);
+                    ips.entry(ip).or_insert_with(|| indicator(Section::C2));
+                }
+                Err(_) => warn!("Invalid c2 ip address: {:?}", ip),
+            }
         }
-
-        // TODO: ip addresses are not matched yet
     }
 
-    Ok(tree)
+    Ok(Iocs {
+        domains: tree,
+        indicators,
+        ips,
+        sha256: format!("{:x}", Sha256::digest(buf)),
+    })
 }
 
 #[cfg(test)]
@@ -66,10 +168,12 @@ mod tests {
   - 6287970dd9.era3000.com
   - c9db9bbc8d.era3000.com
   c2:
+    ips:
+    - 185.212.128.12
     domains:
     - user.ownspy.es
 "#;
-        let iocs = parse_domain_iocs(buf).unwrap();
+        let iocs = parse(buf).unwrap();
 
         let expected = &[
             "mobileinnova.net",
@@ -86,6 +190,18 @@ mod tests {
         let expected = expected.iter()
             .map(|s| String::from(*s))
             .collect::<SuffixTree<_>>();
-        assert_eq!(iocs, expected);
+        assert_eq!(iocs.domains, expected);
+        assert!(iocs.matches_ip(&"185.212.128.12".parse().unwrap()));
+        assert!(!iocs.matches_ip(&"185.212.128.13".parse().unwrap()));
+
+        let indicator = iocs.lookup("www.user.ownspy.es").unwrap();
+        assert_eq!(indicator, &Indicator {
+            family: "OwnSpy".to_string(),
+            category: "stalkerware".to_string(),
+            section: Section::C2,
+        });
+        assert_eq!(iocs.lookup("panel.ownspy.es").unwrap().section, Section::Website);
+        assert_eq!(iocs.lookup("example.es"), None);
+        assert_eq!(iocs.lookup_ip(&"185.212.128.12".parse().unwrap()).unwrap().section, Section::C2);
     }
 }
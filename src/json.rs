@@ -1,11 +1,20 @@
 use crate::errors::*;
-use serde::Deserialize;
+use crate::reassembly::Segment;
+use crate::tls::HelloParams;
+use memchr::memmem;
+use serde::{Deserialize, Deserializer};
+use serde::de::{self, IgnoredAny, SeqAccess, Visitor};
+use std::borrow::Cow;
+use std::sync::OnceLock;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Source {
     DNS,
     TLS,
     HTTP,
+    QUIC,
 }
 
 impl Source {
@@ -14,47 +23,233 @@ impl Source {
             Source::DNS => "dns",
             Source::TLS => "tls",
             Source::HTTP => "http",
+            Source::QUIC => "quic",
         }
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Proto {
+    TCP,
+    UDP,
+}
+
+impl Proto {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Proto::TCP => "tcp",
+            Proto::UDP => "udp",
+        }
+    }
+}
+
+/// A single tcp/udp packet, as seen from the ip and transport header
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Flow {
+    pub proto: Proto,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+}
+
+/// A packet from sniffglue or the native dissector, names borrow from the json line
+/// until [`Pkt::into_owned`] is called
 #[derive(Debug, PartialEq, Eq, Deserialize)]
-pub enum Pkt {
-    Ether((Dummy, IP)),
+pub enum Pkt<'a> {
+    Ether(#[serde(borrow)] (Dummy, IP<'a>)),
 }
 
-impl Pkt {
+impl<'a> Pkt<'a> {
+    pub fn into_owned(self) -> Pkt<'static> {
+        match self {
+            Pkt::Ether((dummy, ip)) => Pkt::Ether((dummy, ip.into_owned())),
+        }
+    }
+
     pub fn get_names(&self) -> Vec<(Source, String)> {
         match self {
             Pkt::Ether((_, ip)) => ip.get_names(),
         }
     }
+
+    pub fn get_flow(&self) -> Option<Flow> {
+        match self {
+            Pkt::Ether((_, ip)) => Some(ip.get_flow()),
+        }
+    }
+
+    pub fn get_answers(&self) -> Vec<(String, IpAddr)> {
+        match self {
+            Pkt::Ether((_, ip)) => ip.get_answers(),
+        }
+    }
+
+    /// Returns the dns questions as (query type, name)
+    pub fn get_questions(&self) -> Vec<(String, String)> {
+        match self {
+            Pkt::Ether((_, ip)) => ip.get_questions(),
+        }
+    }
+
+    pub fn get_http(&self) -> Option<&HTTP> {
+        match self {
+            Pkt::Ether((_, ip)) => ip.get_http(),
+        }
+    }
+
+    pub fn get_client_hello(&self) -> Option<&ClientHello<'a>> {
+        match self {
+            Pkt::Ether((_, ip)) => ip.get_client_hello(),
+        }
+    }
+
+    /// Returns the udp payload if sniffglue didn't recognize the protocol
+    pub fn get_udp_payload(&self) -> Option<&[u8]> {
+        match self {
+            Pkt::Ether((_, ip)) => ip.get_udp_payload(),
+        }
+    }
+
+    /// Returns the tcp segment, the payload is only set if sniffglue didn't recognize the protocol
+    pub fn get_tcp_segment(&self) -> Option<Segment<'_>> {
+        match self {
+            Pkt::Ether((_, ip)) => ip.get_tcp_segment(),
+        }
+    }
+
+    pub fn get_tcp_header(&self) -> Option<&TCPHeader> {
+        match self {
+            Pkt::Ether((_, ip)) => ip.get_tcp_header(),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize)]
 pub struct Dummy {}
 
+/// Any value we don't care about, regardless of its type
+#[derive(Debug, PartialEq, Eq)]
+pub struct Opaque;
+
+impl<'de> Deserialize<'de> for Opaque {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        IgnoredAny::deserialize(deserializer)?;
+        Ok(Opaque)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Deserialize)]
-pub enum IP {
-    IPv4((Dummy, IPv4)),
+pub enum IP<'a> {
+    IPv4(#[serde(borrow)] (IPv4Header, IPv4<'a>)),
+    IPv6(#[serde(borrow)] (IPv6Header, IPv6<'a>)),
 }
 
-impl IP {
+impl<'a> IP<'a> {
+    pub fn into_owned(self) -> IP<'static> {
+        match self {
+            IP::IPv4((hdr, ipv4)) => IP::IPv4((hdr, ipv4.into_owned())),
+            IP::IPv6((hdr, ipv6)) => IP::IPv6((hdr, ipv6.into_owned())),
+        }
+    }
+
     #[inline(always)]
     pub fn get_names(&self) -> Vec<(Source, String)> {
         match self {
             IP::IPv4((_, ipv4)) => ipv4.get_names(),
+            IP::IPv6((_, ipv6)) => ipv6.get_names(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_flow(&self) -> Flow {
+        match self {
+            IP::IPv4((hdr, ipv4)) => ipv4.get_flow(hdr.source_addr.into(), hdr.dest_addr.into()),
+            IP::IPv6((hdr, ipv6)) => ipv6.get_flow(hdr.source_addr.into(), hdr.dest_addr.into()),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_answers(&self) -> Vec<(String, IpAddr)> {
+        match self {
+            IP::IPv4((_, ipv4)) => ipv4.get_answers(),
+            IP::IPv6((_, ipv6)) => ipv6.get_answers(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_questions(&self) -> Vec<(String, String)> {
+        match self {
+            IP::IPv4((_, ipv4)) => ipv4.get_questions(),
+            IP::IPv6((_, ipv6)) => ipv6.get_questions(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_http(&self) -> Option<&HTTP> {
+        match self {
+            IP::IPv4((_, ipv4)) => ipv4.get_http(),
+            IP::IPv6((_, ipv6)) => ipv6.get_http(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_client_hello(&self) -> Option<&ClientHello<'a>> {
+        match self {
+            IP::IPv4((_, ipv4)) => ipv4.get_client_hello(),
+            IP::IPv6((_, ipv6)) => ipv6.get_client_hello(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_udp_payload(&self) -> Option<&[u8]> {
+        match self {
+            IP::IPv4((_, ipv4)) => ipv4.get_udp_payload(),
+            IP::IPv6((_, ipv6)) => ipv6.get_udp_payload(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_tcp_segment(&self) -> Option<Segment<'_>> {
+        match self {
+            IP::IPv4((_, ipv4)) => ipv4.get_tcp_segment(),
+            IP::IPv6((_, ipv6)) => ipv6.get_tcp_segment(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_tcp_header(&self) -> Option<&TCPHeader> {
+        match self {
+            IP::IPv4((_, ipv4)) => ipv4.get_tcp_header(),
+            IP::IPv6((_, ipv6)) => ipv6.get_tcp_header(),
         }
     }
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize)]
-pub enum IPv4 {
-    TCP((Dummy, TCP)),
-    UDP((Dummy, UDP)),
+pub struct IPv4Header {
+    pub source_addr: Ipv4Addr,
+    pub dest_addr: Ipv4Addr,
 }
 
-impl IPv4 {
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct IPv6Header {
+    pub source_addr: Ipv6Addr,
+    pub dest_addr: Ipv6Addr,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub enum IPv4<'a> {
+    TCP(#[serde(borrow)] (TCPHeader, TCP<'a>)),
+    UDP(#[serde(borrow)] (UDPHeader, UDP<'a>)),
+}
+
+impl<'a> IPv4<'a> {
+    pub fn into_owned(self) -> IPv4<'static> {
+        match self {
+            IPv4::TCP((hdr, tcp)) => IPv4::TCP((hdr, tcp.into_owned())),
+            IPv4::UDP((hdr, udp)) => IPv4::UDP((hdr, udp.into_owned())),
+        }
+    }
+
     #[inline(always)]
     pub fn get_names(&self) -> Vec<(Source, String)> {
         match self {
@@ -62,30 +257,396 @@ impl IPv4 {
             IPv4::UDP((_, udp)) => udp.get_names(),
         }
     }
+
+    #[inline(always)]
+    pub fn get_flow(&self, src: IpAddr, dst: IpAddr) -> Flow {
+        let (proto, sport, dport) = match self {
+            IPv4::TCP((hdr, _)) => (Proto::TCP, hdr.source_port, hdr.dest_port),
+            IPv4::UDP((hdr, _)) => (Proto::UDP, hdr.source_port, hdr.dest_port),
+        };
+        Flow {
+            proto,
+            src: SocketAddr::new(src, sport),
+            dst: SocketAddr::new(dst, dport),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_answers(&self) -> Vec<(String, IpAddr)> {
+        match self {
+            IPv4::TCP(_) => Vec::new(),
+            IPv4::UDP((_, udp)) => udp.get_answers(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_questions(&self) -> Vec<(String, String)> {
+        match self {
+            IPv4::TCP(_) => Vec::new(),
+            IPv4::UDP((_, udp)) => udp.get_questions(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_http(&self) -> Option<&HTTP> {
+        match self {
+            IPv4::TCP((_, TCP::HTTP(http))) => Some(http),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_client_hello(&self) -> Option<&ClientHello<'a>> {
+        match self {
+            IPv4::TCP((_, TCP::TLS(TLS::ClientHello(ch)))) => Some(ch),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_udp_payload(&self) -> Option<&[u8]> {
+        match self {
+            IPv4::UDP((_, UDP::Binary(payload))) => Some(payload.bytes()),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_tcp_segment(&self) -> Option<Segment<'_>> {
+        match self {
+            IPv4::TCP((hdr, tcp)) => Some(hdr.segment(tcp)),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_tcp_header(&self) -> Option<&TCPHeader> {
+        match self {
+            IPv4::TCP((hdr, _)) => Some(hdr),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize)]
-pub enum TCP {
-    TLS(TLS),
+pub enum IPv6<'a> {
+    TCP(#[serde(borrow)] (TCPHeader, TCP<'a>)),
+    UDP(#[serde(borrow)] (UDPHeader, UDP<'a>)),
+}
+
+impl<'a> IPv6<'a> {
+    pub fn into_owned(self) -> IPv6<'static> {
+        match self {
+            IPv6::TCP((hdr, tcp)) => IPv6::TCP((hdr, tcp.into_owned())),
+            IPv6::UDP((hdr, udp)) => IPv6::UDP((hdr, udp.into_owned())),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_names(&self) -> Vec<(Source, String)> {
+        match self {
+            IPv6::TCP((_, tcp)) => tcp.get_names(),
+            IPv6::UDP((_, udp)) => udp.get_names(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_flow(&self, src: IpAddr, dst: IpAddr) -> Flow {
+        let (proto, sport, dport) = match self {
+            IPv6::TCP((hdr, _)) => (Proto::TCP, hdr.source_port, hdr.dest_port),
+            IPv6::UDP((hdr, _)) => (Proto::UDP, hdr.source_port, hdr.dest_port),
+        };
+        Flow {
+            proto,
+            src: SocketAddr::new(src, sport),
+            dst: SocketAddr::new(dst, dport),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_answers(&self) -> Vec<(String, IpAddr)> {
+        match self {
+            IPv6::TCP(_) => Vec::new(),
+            IPv6::UDP((_, udp)) => udp.get_answers(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_questions(&self) -> Vec<(String, String)> {
+        match self {
+            IPv6::TCP(_) => Vec::new(),
+            IPv6::UDP((_, udp)) => udp.get_questions(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_http(&self) -> Option<&HTTP> {
+        match self {
+            IPv6::TCP((_, TCP::HTTP(http))) => Some(http),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_client_hello(&self) -> Option<&ClientHello<'a>> {
+        match self {
+            IPv6::TCP((_, TCP::TLS(TLS::ClientHello(ch)))) => Some(ch),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_udp_payload(&self) -> Option<&[u8]> {
+        match self {
+            IPv6::UDP((_, UDP::Binary(payload))) => Some(payload.bytes()),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_tcp_segment(&self) -> Option<Segment<'_>> {
+        match self {
+            IPv6::TCP((hdr, tcp)) => Some(hdr.segment(tcp)),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_tcp_header(&self) -> Option<&TCPHeader> {
+        match self {
+            IPv6::TCP((hdr, _)) => Some(hdr),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct TCPHeader {
+    pub source_port: u16,
+    pub dest_port: u16,
+    pub sequence_no: u32,
+    pub flag_syn: bool,
+    pub flag_fin: bool,
+    pub flag_rst: bool,
+}
+
+impl TCPHeader {
+    /// The segment without its payload, e.g. to track the flags of a stream
+    #[inline(always)]
+    pub fn flags(&self) -> Segment<'static> {
+        self.segment(&TCP::Empty)
+    }
+
+    #[inline(always)]
+    pub fn segment<'a>(&self, tcp: &'a TCP<'_>) -> Segment<'a> {
+        let payload = match tcp {
+            TCP::Binary(payload) => payload.bytes(),
+            _ => &[][..],
+        };
+        Segment {
+            seq: self.sequence_no,
+            syn: self.flag_syn,
+            fin: self.flag_fin,
+            rst: self.flag_rst,
+            payload,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct UDPHeader {
+    pub source_port: u16,
+    pub dest_port: u16,
+}
+
+/// Packet data that sniffglue didn't recognize
+///
+/// This is the bulk of most lines, [`parse_fast`] keeps it as text and it's
+/// only decoded if it's actually needed
+#[derive(Debug, Default)]
+pub struct Payload<'a> {
+    text: Option<Cow<'a, str>>,
+    bytes: OnceLock<Vec<u8>>,
+}
+
+impl Payload<'_> {
+    pub fn into_owned(self) -> Payload<'static> {
+        Payload {
+            text: self.text.map(|text| Cow::Owned(text.into_owned())),
+            bytes: self.bytes,
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        self.bytes.get_or_init(|| {
+            let text = self.text.as_deref().unwrap_or_default();
+            decode_numbers(text).unwrap_or_else(|| {
+                trace!("Failed to decode payload: {:?}", text);
+                Vec::new()
+            })
+        })
+    }
+}
+
+impl From<Vec<u8>> for Payload<'_> {
+    fn from(bytes: Vec<u8>) -> Self {
+        Payload {
+            text: None,
+            bytes: OnceLock::from(bytes),
+        }
+    }
+}
+
+impl PartialEq for Payload<'_> {
+    fn eq(&self, other: &Payload<'_>) -> bool {
+        self.bytes() == other.bytes()
+    }
+}
+
+impl Eq for Payload<'_> {}
+
+impl<'de: 'a, 'a> Deserialize<'de> for Payload<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct PayloadVisitor;
+
+        impl<'de> Visitor<'de> for PayloadVisitor {
+            type Value = Payload<'de>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a list of bytes")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Payload<'de>, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(b) = seq.next_element()? {
+                    bytes.push(b);
+                }
+                Ok(Payload::from(bytes))
+            }
+
+            // written by parse_fast, the comma separated numbers of the list
+            fn visit_borrowed_str<E: de::Error>(self, text: &'de str) -> std::result::Result<Payload<'de>, E> {
+                Ok(Payload {
+                    text: Some(Cow::Borrowed(text)),
+                    bytes: OnceLock::new(),
+                })
+            }
+
+            fn visit_str<E: de::Error>(self, text: &str) -> std::result::Result<Payload<'de>, E> {
+                Ok(Payload {
+                    text: Some(Cow::Owned(text.to_string())),
+                    bytes: OnceLock::new(),
+                })
+            }
+        }
+
+        deserializer.deserialize_any(PayloadVisitor)
+    }
+}
+
+/// A string that is borrowed from the line unless json escapes had to be decoded,
+/// serde only borrows `Cow` if it's a field of its own and not inside a list
+struct Text<'a>(Cow<'a, str>);
+
+impl<'de: 'a, 'a> Deserialize<'de> for Text<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct TextVisitor;
+
+        impl<'de> Visitor<'de> for TextVisitor {
+            type Value = Text<'de>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string")
+            }
+
+            fn visit_borrowed_str<E: de::Error>(self, text: &'de str) -> std::result::Result<Text<'de>, E> {
+                Ok(Text(Cow::Borrowed(text)))
+            }
+
+            fn visit_str<E: de::Error>(self, text: &str) -> std::result::Result<Text<'de>, E> {
+                Ok(Text(Cow::Owned(text.to_string())))
+            }
+        }
+
+        deserializer.deserialize_str(TextVisitor)
+    }
+}
+
+fn owned(text: Cow<'_, str>) -> Cow<'static, str> {
+    Cow::Owned(text.into_owned())
+}
+
+/// Decode `22,3,1` into bytes, this is a lot faster than a json array
+fn decode_numbers(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() / 3 + 1);
+    let mut n = None::<u16>;
+    for &b in text.as_bytes() {
+        match b {
+            b'0'..=b'9' => {
+                let v = n.unwrap_or(0) * 10 + u16::from(b - b'0');
+                if v > 255 {
+                    return None;
+                }
+                n = Some(v);
+            }
+            b',' => bytes.push(n.take()? as u8),
+            b' ' if n.is_none() => (),
+            _ => return None,
+        }
+    }
+    if let Some(n) = n {
+        bytes.push(n as u8);
+    } else if !bytes.is_empty() {
+        return None;
+    }
+    Some(bytes)
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub enum TCP<'a> {
+    #[serde(borrow)]
+    TLS(TLS<'a>),
     HTTP(HTTP),
+    Text(Opaque),
+    #[serde(borrow)]
+    Binary(Payload<'a>),
+    Empty,
 }
 
-impl TCP {
+impl TCP<'_> {
+    pub fn into_owned(self) -> TCP<'static> {
+        match self {
+            TCP::TLS(tls) => TCP::TLS(tls.into_owned()),
+            TCP::HTTP(http) => TCP::HTTP(http),
+            TCP::Text(text) => TCP::Text(text),
+            TCP::Binary(payload) => TCP::Binary(payload.into_owned()),
+            TCP::Empty => TCP::Empty,
+        }
+    }
+
     #[inline(always)]
     pub fn get_names(&self) -> Vec<(Source, String)> {
         match self {
             TCP::TLS(tls) => tls.get_names(),
             TCP::HTTP(http) => http.get_names(),
+            _ => Vec::new(),
         }
     }
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize)]
-pub enum TLS {
-    ClientHello(ClientHello),
+pub enum TLS<'a> {
+    #[serde(borrow)]
+    ClientHello(ClientHello<'a>),
 }
 
-impl TLS {
+impl TLS<'_> {
+    pub fn into_owned(self) -> TLS<'static> {
+        match self {
+            TLS::ClientHello(ch) => TLS::ClientHello(ch.into_owned()),
+        }
+    }
+
     #[inline(always)]
     pub fn get_names(&self) -> Vec<(Source, String)> {
         match self {
@@ -95,63 +656,166 @@ impl TLS {
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize)]
-pub struct ClientHello {
-    hostname: String,
+pub struct ClientHello<'a> {
+    #[serde(borrow)]
+    pub hostname: Cow<'a, str>,
+    /// Only available if the capture emits the full ClientHello
+    #[serde(flatten)]
+    pub params: HelloParams,
 }
 
-impl ClientHello {
+impl ClientHello<'_> {
+    pub fn into_owned(self) -> ClientHello<'static> {
+        ClientHello {
+            hostname: owned(self.hostname),
+            params: self.params,
+        }
+    }
+
     #[inline(always)]
     pub fn get_names(&self) -> Vec<(Source, String)> {
-        vec![(Source::TLS, self.hostname.clone())]
+        // the native dissector leaves this empty if there's no sni
+        if self.hostname.is_empty() {
+            return Vec::new();
+        }
+        vec![(Source::TLS, self.hostname.to_string())]
     }
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize)]
 pub struct HTTP {
-    host: String,
+    pub method: String,
+    pub uri: String,
+    pub version: String,
+    pub host: Option<String>,
+    pub agent: Option<String>,
+    pub referer: Option<String>,
+    pub cookies: Option<String>,
 }
 
 impl HTTP {
     #[inline(always)]
     pub fn get_names(&self) -> Vec<(Source, String)> {
-        vec![(Source::HTTP, self.host.clone())]
+        self.host.iter()
+            .map(|host| (Source::HTTP, host.clone()))
+            .collect()
+    }
+
+    pub fn request_line(&self) -> String {
+        format!("{} {} HTTP/{}", self.method, self.uri, self.version)
     }
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize)]
-pub enum UDP {
-    DNS(DNS),
+pub enum UDP<'a> {
+    #[serde(borrow)]
+    DNS(DNS<'a>),
+    Text(Opaque),
+    #[serde(borrow)]
+    Binary(Payload<'a>),
 }
 
-impl UDP {
+impl UDP<'_> {
+    pub fn into_owned(self) -> UDP<'static> {
+        match self {
+            UDP::DNS(dns) => UDP::DNS(dns.into_owned()),
+            UDP::Text(text) => UDP::Text(text),
+            UDP::Binary(payload) => UDP::Binary(payload.into_owned()),
+        }
+    }
+
     #[inline(always)]
     pub fn get_names(&self) -> Vec<(Source, String)> {
         match self {
             UDP::DNS(dns) => dns.get_names(),
+            _ => Vec::new(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_answers(&self) -> Vec<(String, IpAddr)> {
+        match self {
+            UDP::DNS(dns) => dns.get_answers(),
+            _ => Vec::new(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_questions(&self) -> Vec<(String, String)> {
+        match self {
+            UDP::DNS(dns) => dns.get_questions(),
+            _ => Vec::new(),
         }
     }
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize)]
-pub enum DNS {
-    Request(DNSRequest),
+pub enum DNS<'a> {
+    #[serde(borrow)]
+    Request(DNSRequest<'a>),
+    #[serde(borrow)]
+    Response(DNSResponse<'a>),
 }
 
-impl DNS {
+impl DNS<'_> {
+    pub fn into_owned(self) -> DNS<'static> {
+        match self {
+            DNS::Request(req) => DNS::Request(req.into_owned()),
+            DNS::Response(resp) => DNS::Response(resp.into_owned()),
+        }
+    }
+
     #[inline(always)]
     pub fn get_names(&self) -> Vec<(Source, String)> {
         match self {
             DNS::Request(req) => req.get_names(),
+            DNS::Response(_) => Vec::new(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_answers(&self) -> Vec<(String, IpAddr)> {
+        match self {
+            DNS::Request(_) => Vec::new(),
+            DNS::Response(resp) => resp.get_answers(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_questions(&self) -> Vec<(String, String)> {
+        match self {
+            DNS::Request(req) => req.questions.iter()
+                .map(|(qtype, name)| (qtype.to_string(), name.to_string()))
+                .collect(),
+            DNS::Response(_) => Vec::new(),
         }
     }
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize)]
-pub struct DNSRequest {
-    questions: Vec<(String, String)>,
+pub struct DNSRequest<'a> {
+    /// Query type and name
+    #[serde(borrow, deserialize_with = "questions")]
+    pub questions: Vec<(Cow<'a, str>, Cow<'a, str>)>,
+}
+
+/// Query type and name
+type Question<'a> = (Cow<'a, str>, Cow<'a, str>);
+
+fn questions<'de: 'a, 'a, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<Question<'a>>, D::Error> {
+    let questions = Vec::<(Text, Text)>::deserialize(deserializer)?;
+    Ok(questions.into_iter().map(|(qtype, name)| (qtype.0, name.0)).collect())
 }
 
-impl DNSRequest {
+impl DNSRequest<'_> {
+    pub fn into_owned(self) -> DNSRequest<'static> {
+        DNSRequest {
+            questions: self.questions.into_iter()
+                .map(|(qtype, name)| (owned(qtype), owned(name)))
+                .collect(),
+        }
+    }
+
     #[inline(always)]
     pub fn get_names(&self) -> Vec<(Source, String)> {
         self.questions.iter()
@@ -160,92 +824,216 @@ impl DNSRequest {
     }
 }
 
-pub fn parse(line: &[u8]) -> Result<Pkt> {
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct DNSResponse<'a> {
+    #[serde(borrow, deserialize_with = "answers")]
+    pub answers: Vec<(Cow<'a, str>, Record<'a>)>,
+}
+
+fn answers<'de: 'a, 'a, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<(Cow<'a, str>, Record<'a>)>, D::Error> {
+    let answers = Vec::<(Text, Record)>::deserialize(deserializer)?;
+    Ok(answers.into_iter().map(|(name, record)| (name.0, record)).collect())
+}
+
+impl DNSResponse<'_> {
+    pub fn into_owned(self) -> DNSResponse<'static> {
+        DNSResponse {
+            answers: self.answers.into_iter()
+                .map(|(name, record)| (owned(name), record.into_owned()))
+                .collect(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_answers(&self) -> Vec<(String, IpAddr)> {
+        self.answers.iter()
+            .flat_map(|(name, record)| {
+                let addr = match record {
+                    Record::A(addr) => IpAddr::V4(*addr),
+                    Record::AAAA(addr) => IpAddr::V6(*addr),
+                    _ => return None,
+                };
+                Some((name.to_string(), addr))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub enum Record<'a> {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    #[serde(borrow)]
+    CNAME(Cow<'a, str>),
+    #[serde(borrow)]
+    NS(Cow<'a, str>),
+    #[serde(borrow)]
+    PTR(Cow<'a, str>),
+    TXT(Opaque),
+    Unknown(Opaque),
+}
+
+impl Record<'_> {
+    pub fn into_owned(self) -> Record<'static> {
+        match self {
+            Record::A(addr) => Record::A(addr),
+            Record::AAAA(addr) => Record::AAAA(addr),
+            Record::CNAME(name) => Record::CNAME(owned(name)),
+            Record::NS(name) => Record::NS(owned(name)),
+            Record::PTR(name) => Record::PTR(owned(name)),
+            Record::TXT(txt) => Record::TXT(txt),
+            Record::Unknown(data) => Record::Unknown(data),
+        }
+    }
+}
+
+pub fn parse(line: &[u8]) -> Result<Pkt<'_>> {
     let pkt = serde_json::from_slice(line)?;
     Ok(pkt)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+const BINARY: &[u8] = br#"{"Binary":["#;
+const PURE_ACK: &[&[u8]] = &[
+    br#""Empty"]"#,
+    br#""flag_syn":false"#,
+    br#""flag_fin":false"#,
+    br#""flag_rst":false"#,
+];
 
-    #[test]
-    fn parse_dns() {
-        let line = br#"{"Ether":[{"source_mac":[10,20,30,40,50,60],"dest_mac":[70,80,90,100,110,120],"ethertype":"IPv4"},{"IPv4":[{"version":4,"ihl":20,"tos":0,"length":79,"id":14838,"flags":0,"fragment_offset":0,"ttl":64,"protocol":"UDP","chksum":1337,"source_addr":"192.168.1.3","dest_addr":"192.168.1.1"},{"UDP":[{"source_port":1337,"dest_port":53,"length":59,"checksum":1337},{"DNS":{"Request":{"questions":[["A","google.com"]]}}}]}]}]}"#;
-        let pkt = parse(line).unwrap();
-        assert_eq!(
-            pkt,
-            Pkt::Ether((
-                Dummy {},
-                IP::IPv4((
-                    Dummy {},
-                    IPv4::UDP((
-                        Dummy {},
-                        UDP::DNS(DNS::Request(
-                            DNSRequest {
-                                questions: vec![("A".to_string(), "google.com".to_string()),],
-                            }
-                        ))
-                    ))
-                ))
-            ))
-        );
+/// Like [`parse`], but skips lines that can't contain anything we detect
+///
+/// Tcp packets without payload or flags return `None` before any json is
+/// parsed. Payloads are passed to serde as a string instead of a list of
+/// numbers, the list is what makes up most of the line. The brackets of the
+/// list are replaced with quotes in place, so the payload and the names
+/// borrow from the line without copying it.
+pub fn parse_fast(line: &mut [u8]) -> Result<Option<Pkt<'_>>> {
+    if PURE_ACK.iter().all(|needle| memmem::find(line, needle).is_some()) {
+        return Ok(None);
     }
 
-    #[test]
-    fn extract_dns() {
-        let pkt = Pkt::Ether((
+    let start = match memmem::find(line, BINARY) {
+        Some(idx) => idx + BINARY.len() - 1,
+        None => return parse(line).map(Some),
+    };
+    let end = match memchr::memchr(b']', &line[start..]) {
+        Some(idx) => start + idx,
+        None => bail!("Unterminated payload"),
+    };
+
+    line[start] = b'"';
+    line[end] = b'"';
+    parse(line).map(Some)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn dns_request() -> Pkt<'static> {
+        Pkt::Ether((
             Dummy {},
             IP::IPv4((
-                Dummy {},
+                IPv4Header {
+                    source_addr: "192.168.1.3".parse().unwrap(),
+                    dest_addr: "192.168.1.1".parse().unwrap(),
+                },
                 IPv4::UDP((
-                    Dummy {},
+                    UDPHeader {
+                        source_port: 1337,
+                        dest_port: 53,
+                    },
                     UDP::DNS(DNS::Request(
                         DNSRequest {
-                            questions: vec![("A".to_string(), "google.com".to_string()),],
+                            questions: vec![("A".into(), "google.com".into()),],
                         }
                     ))
                 ))
             ))
-        ));
-        assert_eq!(pkt.get_names(), vec![(Source::DNS, "google.com".to_string())]);
+        ))
     }
 
-    #[test]
-    fn parse_sni() {
-        let line = br#"{"Ether":[{"source_mac":[10,20,30,40,50,60],"dest_mac":[70,80,90,100,110,120],"ethertype":"IPv4"},{"IPv4":[{"version":4,"ihl":20,"tos":0,"length":569,"id":2281,"flags":2,"fragment_offset":0,"ttl":64,"protocol":"TCP","chksum":1337,"source_addr":"192.168.1.3","dest_addr":"142.250.102.138"},{"TCP":[{"source_port":1337,"dest_port":443,"sequence_no":1337,"ack_no":1337,"data_offset":8,"reserved":0,"flag_urg":false,"flag_ack":true,"flag_psh":true,"flag_rst":false,"flag_syn":false,"flag_fin":false,"window":504,"checksum":1337,"urgent_pointer":0,"options":null},{"TLS":{"ClientHello":{"version":"tls1.2","session_id":"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=","hostname":"google.com"}}}]}]}]}"#;
-        let pkt = parse(line).unwrap();
-        assert_eq!(
-            pkt,
-            Pkt::Ether((
-                Dummy {},
-                IP::IPv4((
-                    Dummy {},
-                    IPv4::TCP((
-                        Dummy {},
-                        TCP::TLS(TLS::ClientHello(ClientHello {
-                            hostname: "google.com".to_string(),
-                        }))
-                    ))
+    pub(crate) fn sni(params: HelloParams) -> Pkt<'static> {
+        Pkt::Ether((
+            Dummy {},
+            IP::IPv4((
+                IPv4Header {
+                    source_addr: "192.168.1.3".parse().unwrap(),
+                    dest_addr: "142.250.102.138".parse().unwrap(),
+                },
+                IPv4::TCP((
+                    TCPHeader {
+                        source_port: 1337,
+                        dest_port: 443,
+                        sequence_no: 1337,
+                        flag_syn: false,
+                        flag_fin: false,
+                        flag_rst: false,
+                    },
+                    TCP::TLS(TLS::ClientHello(ClientHello {
+                        hostname: "google.com".into(),
+                        params,
+                    }))
                 ))
             ))
-        );
+        ))
     }
 
-    #[test]
-    fn extract_sni() {
-        let pkt = Pkt::Ether((
+    pub(crate) fn http() -> Pkt<'static> {
+        Pkt::Ether((
             Dummy {},
             IP::IPv4((
-                Dummy {},
+                IPv4Header {
+                    source_addr: "192.168.1.3".parse().unwrap(),
+                    dest_addr: "142.250.102.138".parse().unwrap(),
+                },
                 IPv4::TCP((
-                    Dummy {},
-                    TCP::TLS(TLS::ClientHello(ClientHello {
-                        hostname: "google.com".to_string(),
-                    }))
+                    TCPHeader {
+                        source_port: 1337,
+                        dest_port: 80,
+                        sequence_no: 1337,
+                        flag_syn: false,
+                        flag_fin: false,
+                        flag_rst: false,
+                    },
+                    TCP::HTTP(HTTP {
+                        method: "GET".to_string(),
+                        uri: "/".to_string(),
+                        version: "1.1".to_string(),
+                        host: Some("google.com".to_string()),
+                        agent: Some("curl/7.72.0".to_string()),
+                        referer: None,
+                        cookies: None,
+                    })
                 ))
             ))
-        ));
+        ))
+    }
+
+    #[test]
+    fn parse_dns() {
+        let line = br#"{"Ether":[{"source_mac":[10,20,30,40,50,60],"dest_mac":[70,80,90,100,110,120],"ethertype":"IPv4"},{"IPv4":[{"version":4,"ihl":20,"tos":0,"length":79,"id":14838,"flags":0,"fragment_offset":0,"ttl":64,"protocol":"UDP","chksum":1337,"source_addr":"192.168.1.3","dest_addr":"192.168.1.1"},{"UDP":[{"source_port":1337,"dest_port":53,"length":59,"checksum":1337},{"DNS":{"Request":{"questions":[["A","google.com"]]}}}]}]}]}"#;
+        let pkt = parse(line).unwrap();
+        assert_eq!(pkt, dns_request());
+    }
+
+    #[test]
+    fn extract_dns() {
+        let pkt = dns_request();
+        assert_eq!(pkt.get_names(), vec![(Source::DNS, "google.com".to_string())]);
+        assert_eq!(pkt.get_questions(), vec![("A".to_string(), "google.com".to_string())]);
+    }
+
+    #[test]
+    fn parse_sni() {
+        let line = br#"{"Ether":[{"source_mac":[10,20,30,40,50,60],"dest_mac":[70,80,90,100,110,120],"ethertype":"IPv4"},{"IPv4":[{"version":4,"ihl":20,"tos":0,"length":569,"id":2281,"flags":2,"fragment_offset":0,"ttl":64,"protocol":"TCP","chksum":1337,"source_addr":"192.168.1.3","dest_addr":"142.250.102.138"},{"TCP":[{"source_port":1337,"dest_port":443,"sequence_no":1337,"ack_no":1337,"data_offset":8,"reserved":0,"flag_urg":false,"flag_ack":true,"flag_psh":true,"flag_rst":false,"flag_syn":false,"flag_fin":false,"window":504,"checksum":1337,"urgent_pointer":0,"options":null},{"TLS":{"ClientHello":{"version":"tls1.2","session_id":"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=","hostname":"google.com"}}}]}]}]}"#;
+        let pkt = parse(line).unwrap();
+        assert_eq!(pkt, sni(HelloParams::default()));
+    }
+
+    #[test]
+    fn extract_sni() {
+        let pkt = sni(HelloParams::default());
         assert_eq!(pkt.get_names(), vec![(Source::TLS, "google.com".to_string())]);
     }
 
@@ -253,37 +1041,136 @@ mod tests {
     fn parse_http() {
         let line = br#"{"Ether":[{"source_mac":[10,20,30,40,50,60],"dest_mac":[70,80,90,100,110,120],"ethertype":"IPv4"},{"IPv4":[{"version":4,"ihl":20,"tos":0,"length":126,"id":19300,"flags":2,"fragment_offset":0,"ttl":64,"protocol":"TCP","chksum":1337,"source_addr":"192.168.1.3","dest_addr":"142.250.102.138"},{"TCP":[{"source_port":1337,"dest_port":80,"sequence_no":1337,"ack_no":1337,"data_offset":8,"reserved":0,"flag_urg":false,"flag_ack":true,"flag_psh":true,"flag_rst":false,"flag_syn":false,"flag_fin":false,"window":504,"checksum":1337,"urgent_pointer":0,"options":null},{"HTTP":{"method":"GET","uri":"/","version":"1.1","host":"google.com","agent":"curl/7.72.0","referer":null,"auth":null,"cookies":null}}]}]}]}"#;
         let pkt = parse(line).unwrap();
-        assert_eq!(
-            pkt,
-            Pkt::Ether((
-                Dummy {},
-                IP::IPv4((
-                    Dummy {},
-                    IPv4::TCP((
-                        Dummy {},
-                        TCP::HTTP(HTTP {
-                            host: "google.com".to_string(),
-                        })
-                    ))
-                ))
-            ))
-        );
+        assert_eq!(pkt, http());
     }
 
     #[test]
     fn extract_http() {
-        let pkt = Pkt::Ether((
-            Dummy {},
-            IP::IPv4((
-                Dummy {},
-                IPv4::TCP((
-                    Dummy {},
-                    TCP::HTTP(HTTP {
-                        host: "google.com".to_string(),
-                    })
-                ))
-            ))
-        ));
+        let pkt = http();
         assert_eq!(pkt.get_names(), vec![(Source::HTTP, "google.com".to_string())]);
+        assert_eq!(pkt.get_http().map(|http| http.request_line()), Some("GET / HTTP/1.1".to_string()));
+    }
+
+    #[test]
+    fn parse_dns_response() {
+        let line = br#"{"Ether":[{"source_mac":[70,80,90,100,110,120],"dest_mac":[10,20,30,40,50,60],"ethertype":"IPv4"},{"IPv4":[{"version":4,"ihl":20,"tos":0,"length":140,"id":0,"flags":2,"fragment_offset":0,"ttl":64,"protocol":"UDP","chksum":1337,"source_addr":"192.168.1.1","dest_addr":"192.168.1.3"},{"UDP":[{"source_port":53,"dest_port":1337,"length":120,"checksum":1337},{"DNS":{"Response":{"answers":[["www.github.com",{"CNAME":"github.com"}],["github.com",{"A":"140.82.121.4"}],["github.com",{"TXT":[118,61,115,112,102,49]}]]}}}]}]}]}"#;
+        let pkt = parse(line).unwrap();
+        assert_eq!(pkt.get_names(), vec![]);
+        assert_eq!(pkt.get_answers(), vec![
+            ("github.com".to_string(), "140.82.121.4".parse().unwrap()),
+        ]);
+    }
+
+    #[test]
+    fn extract_flow() {
+        let line = br#"{"Ether":[{"source_mac":[10,20,30,40,50,60],"dest_mac":[70,80,90,100,110,120],"ethertype":"IPv6"},{"IPv6":[{"source_addr":"fd00::3","dest_addr":"2a00:1450:4001:82b::200e"},{"TCP":[{"source_port":1337,"dest_port":443,"sequence_no":1337,"ack_no":0,"data_offset":10,"reserved":0,"flag_urg":false,"flag_ack":false,"flag_psh":false,"flag_rst":false,"flag_syn":true,"flag_fin":false,"window":64800,"checksum":1337,"urgent_pointer":0,"options":null},"Empty"]}]}]}"#;
+        let pkt = parse(line).unwrap();
+        assert_eq!(pkt.get_flow(), Some(Flow {
+            proto: Proto::TCP,
+            src: "[fd00::3]:1337".parse().unwrap(),
+            dst: "[2a00:1450:4001:82b::200e]:443".parse().unwrap(),
+        }));
+    }
+
+    #[test]
+    fn parse_sni_with_params() {
+        let line = br#"{"Ether":[{"source_mac":[10,20,30,40,50,60],"dest_mac":[70,80,90,100,110,120],"ethertype":"IPv4"},{"IPv4":[{"version":4,"ihl":20,"tos":0,"length":569,"id":2281,"flags":2,"fragment_offset":0,"ttl":64,"protocol":"TCP","chksum":1337,"source_addr":"192.168.1.3","dest_addr":"142.250.102.138"},{"TCP":[{"source_port":1337,"dest_port":443,"sequence_no":1337,"ack_no":1337,"data_offset":8,"reserved":0,"flag_urg":false,"flag_ack":true,"flag_psh":true,"flag_rst":false,"flag_syn":false,"flag_fin":false,"window":504,"checksum":1337,"urgent_pointer":0,"options":null},{"TLS":{"ClientHello":{"version":"tls1.2","session_id":null,"hostname":"google.com","legacy_version":771,"cipher_suites":[4865,4866],"extensions":[0,16,43],"supported_groups":[29],"alpn_protocols":["h2"],"supported_versions":[772]}}}]}]}]}"#;
+        let pkt = parse(line).unwrap();
+        let ch = pkt.get_client_hello().unwrap();
+        assert_eq!(ch.hostname, "google.com");
+        assert_eq!(ch.params, HelloParams {
+            legacy_version: 771,
+            cipher_suites: vec![4865, 4866],
+            extensions: vec![0, 16, 43],
+            supported_groups: vec![29],
+            alpn_protocols: vec!["h2".to_string()],
+            supported_versions: vec![772],
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn parse_udp_binary() {
+        let line = br#"{"Ether":[{"source_mac":[10,20,30,40,50,60],"dest_mac":[70,80,90,100,110,120],"ethertype":"IPv4"},{"IPv4":[{"version":4,"ihl":20,"tos":0,"length":32,"id":0,"flags":2,"fragment_offset":0,"ttl":64,"protocol":"UDP","chksum":1337,"source_addr":"192.168.1.3","dest_addr":"142.250.102.138"},{"UDP":[{"source_port":1337,"dest_port":443,"length":12,"checksum":1337},{"Binary":[192,0,0,0]}]}]}]}"#;
+        let pkt = parse(line).unwrap();
+        assert_eq!(pkt.get_udp_payload(), Some(&[192, 0, 0, 0][..]));
+        assert_eq!(pkt.get_names(), vec![]);
+    }
+
+    const SYN: &[u8] = br#"{"Ether":[{"source_mac":[10,20,30,40,50,60],"dest_mac":[70,80,90,100,110,120],"ethertype":"IPv6"},{"IPv6":[{"source_addr":"fd00::3","dest_addr":"2a00:1450:4001:82b::200e"},{"TCP":[{"source_port":1337,"dest_port":443,"sequence_no":1337,"ack_no":0,"data_offset":10,"reserved":0,"flag_urg":false,"flag_ack":false,"flag_psh":false,"flag_rst":false,"flag_syn":true,"flag_fin":false,"window":64800,"checksum":1337,"urgent_pointer":0,"options":null},"Empty"]}]}]}"#;
+
+    #[test]
+    fn parse_fast_syn() {
+        let pkt = parse_fast(&mut SYN.to_vec()).unwrap().map(Pkt::into_owned);
+        assert_eq!(pkt, Some(parse(SYN).unwrap()));
+    }
+
+    #[test]
+    fn parse_fast_skip_ack() {
+        let line = String::from_utf8_lossy(SYN).replace(r#""flag_syn":true"#, r#""flag_syn":false"#);
+        assert_eq!(parse_fast(&mut line.into_bytes()).unwrap(), None);
+        let line = String::from_utf8_lossy(SYN).replace(r#""flag_syn":true"#, r#""flag_syn": false"#);
+        assert!(parse_fast(&mut line.into_bytes()).unwrap().is_some());
+    }
+
+    #[test]
+    fn parse_fast_tcp_binary() {
+        let line = String::from_utf8_lossy(SYN)
+            .replace(r#""flag_syn":true"#, r#""flag_syn":false"#)
+            .replace(r#""Empty""#, r#"{"Binary":[22,3,1,0,255]}"#);
+        let mut buf = line.clone().into_bytes();
+        let pkt = parse_fast(&mut buf).unwrap().unwrap();
+        assert_eq!(pkt, parse(line.as_bytes()).unwrap());
+        assert_eq!(pkt.get_tcp_segment().unwrap().payload, &[22, 3, 1, 0, 255]);
+    }
+
+    #[test]
+    fn parse_fast_udp_binary() {
+        let line = br#"{"Ether":[{"source_mac":[10,20,30,40,50,60],"dest_mac":[70,80,90,100,110,120],"ethertype":"IPv4"},{"IPv4":[{"version":4,"ihl":20,"tos":0,"length":32,"id":0,"flags":2,"fragment_offset":0,"ttl":64,"protocol":"UDP","chksum":1337,"source_addr":"192.168.1.3","dest_addr":"142.250.102.138"},{"UDP":[{"source_port":1337,"dest_port":443,"length":12,"checksum":1337},{"Binary":[192,0,0,0]}]}]}]}"#;
+        let pkt = parse_fast(&mut line.to_vec()).unwrap().unwrap().into_owned();
+        assert_eq!(pkt.get_udp_payload(), Some(&[192, 0, 0, 0][..]));
+    }
+
+    #[test]
+    fn parse_fast_invalid_payload() {
+        let line = String::from_utf8_lossy(SYN).replace(r#""Empty""#, r#"{"Binary":[22,3,1337]}"#);
+        let mut buf = line.into_bytes();
+        let pkt = parse_fast(&mut buf).unwrap().unwrap();
+        assert!(pkt.get_tcp_segment().unwrap().payload.is_empty());
+        let line = String::from_utf8_lossy(SYN).replace(r#""Empty"]}]}]}"#, r#"{"Binary":[22,3"#);
+        assert!(parse_fast(&mut line.into_bytes()).is_err());
+    }
+
+    #[test]
+    fn borrow_from_line() {
+        let mut line = String::from_utf8_lossy(SYN)
+            .replace(r#""flag_syn":true"#, r#""flag_syn":false"#)
+            .replace(r#""Empty""#, r#"{"TLS":{"ClientHello":{"hostname":"google.com"}}}"#)
+            .into_bytes();
+        let pkt = parse_fast(&mut line).unwrap().unwrap();
+        let ch = pkt.get_client_hello().unwrap();
+        assert!(matches!(ch.hostname, Cow::Borrowed("google.com")));
+
+        let line = br#"{"Ether":[{},{"IPv4":[{"source_addr":"192.168.1.3","dest_addr":"192.168.1.1"},{"UDP":[{"source_port":1337,"dest_port":53},{"DNS":{"Request":{"questions":[["A","google.com"],["TXT","a\\b.example.com"]]}}}]}]}]}"#;
+        let pkt = parse(line).unwrap();
+        let questions = match &pkt {
+            Pkt::Ether((_, IP::IPv4((_, IPv4::UDP((_, UDP::DNS(DNS::Request(req)))))))) => &req.questions,
+            _ => panic!("not a dns request"),
+        };
+        assert!(matches!(questions[0].1, Cow::Borrowed("google.com")));
+        // names with escapes can't be borrowed, but are still parsed
+        assert!(matches!(&questions[1].1, Cow::Owned(name) if name == "a\\b.example.com"));
+        assert_eq!(pkt.into_owned().get_questions()[1], ("TXT".to_string(), "a\\b.example.com".to_string()));
+    }
+
+    #[test]
+    fn decode_payload_numbers() {
+        assert_eq!(decode_numbers(""), Some(vec![]));
+        assert_eq!(decode_numbers("0,1,255"), Some(vec![0, 1, 255]));
+        assert_eq!(decode_numbers("0, 1"), Some(vec![0, 1]));
+        assert_eq!(decode_numbers("256"), None);
+        assert_eq!(decode_numbers("1,,2"), None);
+        assert_eq!(decode_numbers("1,"), None);
+        assert_eq!(decode_numbers("1 2"), None);
     }
 }
@@ -6,3 +6,34 @@ pub mod ioc;
 pub mod hostapd;
 pub mod rpc;
 pub mod suffix;
+pub mod cidr;
+pub mod unexplained;
+pub mod rules;
+pub mod tunnel;
+pub mod tls;
+pub mod quic;
+pub mod reassembly;
+pub mod dns;
+pub mod dissect;
+pub mod pcap;
+pub mod observation;
+pub mod recording;
+pub mod detector;
+pub mod event;
+pub mod session;
+pub mod history;
+pub mod report;
+pub mod pdf;
+pub mod bus;
+pub mod sink;
+pub mod config;
+pub mod supervisor;
+pub mod suricata;
+pub mod zeek;
+pub mod resolver;
+pub mod pihole;
+pub mod dnstap;
+pub mod netflow;
+#[cfg(target_os = "linux")]
+pub mod af_packet;
+mod reader;
@@ -1,202 +1,753 @@
 use clap::Parser;
 use env_logger::Env;
 use futures::FutureExt;
+use futures::future;
 use futures::select;
+use futures::channel::mpsc::{SendError, Sender};
 use futures::{Sink, SinkExt, Stream, StreamExt};
-use spytrap_wifi::args::{Args, SubCommand};
-use spytrap_wifi::args::Start;
+use spytrap_wifi::args::{self, Args, ConfigCommand, HistoryCommand, Overrides, ReportFormat, SubCommand};
+use spytrap_wifi::args::{Analyze, Backend, Format, Replay, Resolver};
+#[cfg(target_os = "linux")]
+use spytrap_wifi::af_packet;
+use spytrap_wifi::dissect;
+use spytrap_wifi::dnstap;
+use spytrap_wifi::bus::Bus;
+use spytrap_wifi::config::{self, Config};
+use spytrap_wifi::detector::{self, Detector};
 use spytrap_wifi::errors::*;
+use spytrap_wifi::event::{Detection, Event};
+use spytrap_wifi::history;
 use spytrap_wifi::hostapd;
 use spytrap_wifi::json;
-use spytrap_wifi::ioc;
+use spytrap_wifi::netflow;
+use spytrap_wifi::observation::{Captured, Input, Labeled, Observation};
+use spytrap_wifi::pihole;
+use spytrap_wifi::pcap::{PcapReader, Reorder};
+use spytrap_wifi::report::Report;
+use spytrap_wifi::recording::{self, Recorder};
+use spytrap_wifi::resolver;
 use spytrap_wifi::rpc;
+use spytrap_wifi::session::{self, Tracker};
+use spytrap_wifi::sink::{self, DropPolicy, SinkConfig};
 use spytrap_wifi::stdio;
-use spytrap_wifi::suffix::SuffixTree;
+use spytrap_wifi::supervisor::Supervisor;
+use spytrap_wifi::suricata;
+use spytrap_wifi::zeek;
+use chrono::Utc;
+use std::io::Write;
 use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::fs::File;
 use tokio::process::Command;
-use tokio::io::AsyncWriteExt;
 use tokio::io::{BufReader, AsyncBufReadExt};
 
 
-// this function must not error or panic
-async fn process<S: Sink<String> + Unpin>(line: &[u8], iocs: &SuffixTree<String>, sink: &mut S) {
-    if let Ok(pkt) = json::parse(line) {
-        let names = pkt.get_names();
-        for (src, name) in names {
-            if iocs.matches(&name) {
-                warn!("detected({}): {:?}", src.as_str(), name);
-                send(sink, format!("[!] detected({}): {:?}", src.as_str(), name)).await.ok();
-            } else {
-                debug!("observed({}): {:?}", src.as_str(), name);
-            }
-        }
+async fn send<T, S: Sink<T> + Unpin>(sink: &mut S, value: T) -> Result<()> {
+    sink.send(value).await.map_err(|_| anyhow!("sink error"))
+}
+
+fn detector(rules: &config::Rules) -> Result<detector::Builder> {
+    let mut builder = Detector::builder().load_iocs(&rules.iocs)?;
+    if let Some(path) = &rules.allowlist {
+        builder = builder.load_allowlist(path)?;
+    }
+    if let Some(path) = &rules.local_networks {
+        builder = builder.load_local_networks(path)?;
     }
+    if let Some(path) = &rules.custom_rules {
+        builder = builder.load_rules(path)?;
+    }
+    Ok(builder)
 }
 
-async fn send<S: Sink<String> + Unpin>(sink: &mut S, value: String) -> Result<()> {
-    sink.send(value).await.map_err(|_| anyhow!("sink error"))
+async fn stream<R: Stream<Item=Labeled> + Unpin, S: Sink<Event> + Unpin>(rx: R, tx: &mut S, rules: &config::Rules) -> Result<()> {
+    let mut detector = detector(rules)?.build();
+    detector.stream(rx, tx).await
 }
 
-async fn stream<R: Stream<Item=String> + Unpin, S: Sink<String> + Unpin>(mut rx: R, tx: &mut S, path: &str) -> Result<()> {
-    let iocs = ioc::load(path)
-        .with_context(|| anyhow!("Failed to load iocs from {:?}", path))?;
-    info!("Loaded {} known IOCs", iocs.len());
+/// Turns captured lines and frames into observations, input that fails to parse is skipped
+struct Decoder {
+    format: Format,
+    zeek: zeek::Parser,
+}
 
-    while let Some(line) = rx.next().await {
-        process(line.as_bytes(), &iocs, tx).await;
+impl Decoder {
+    fn new(format: Format) -> Decoder {
+        Decoder {
+            format,
+            zeek: zeek::Parser::new(),
+        }
     }
 
-    Ok(())
+    fn decode(&mut self, captured: Captured) -> Vec<Labeled> {
+        let Captured { interface, time, input } = captured;
+        let observations = match input {
+            Input::Line(line) => match self.format {
+                Format::Sniffglue => {
+                    // the line isn't needed afterwards, so it's parsed in place
+                    let mut line = line.into_bytes();
+                    json::parse_fast(&mut line).map(|pkt| pkt.into_iter().map(|pkt| Observation::Packet(pkt.into_owned())).collect())
+                }
+                Format::Suricata => suricata::parse(&line),
+                Format::Zeek => self.zeek.parse(&line),
+                Format::Dnsmasq => Ok(resolver::dnsmasq(&line).into_iter().collect()),
+                Format::Unbound => Ok(resolver::unbound(&line).into_iter().collect()),
+            },
+            Input::Frame { linktype, data } => dissect::frame(linktype, &data).map(|pkt| vec![Observation::Packet(pkt)]),
+        };
+        let observations = observations.unwrap_or_else(|err| {
+            trace!("Failed to decode input: {:#}", err);
+            Vec::new()
+        });
+        observations.into_iter()
+            .map(|observation| Labeled {
+                interface: interface.clone(),
+                time: Some(time),
+                observation,
+            })
+            .collect()
+    }
+}
+
+fn decode<R: Stream<Item=Captured> + Unpin>(rx: R, format: Format) -> impl Stream<Item=Labeled> + Unpin {
+    let mut decoder = Decoder::new(format);
+    rx.flat_map(move |captured| futures::stream::iter(decoder.decode(captured)))
 }
 
-async fn hotspot<R: Stream<Item=String> + Unpin, S: Sink<String> + Unpin>(mut stream: R, mut sink: S, path: &str) -> Result<()> {
+/// Lines from stdin, they are captured when they're read
+fn lines<R: Stream<Item=String> + Unpin>(rx: R) -> impl Stream<Item=Captured> + Unpin {
+    rx.map(|line| Captured::new(None, Input::Line(line)))
+}
+
+async fn hotspot<R: Stream<Item=String> + Unpin, S: Sink<Event> + Unpin>(mut stream: R, mut sink: S, settings: &config::Hotspot) -> Result<()> {
     loop {
-        let ssid = "Starbucks WiFi";
         let pw = hostapd::pwgen();
 
         info!("Writing hostapd config");
-        hostapd::write_config(path, "wlan1", ssid, &pw).await
+        hostapd::write_config(settings, &pw).await
             .context("Failed to write hostapd config")?;
         info!("Restarting hostapd");
         hostapd::restart().await.ok();
 
-        let line = format!("[+] {:?} (pw: {})", ssid, pw);
-        send(&mut sink, line).await?;
-
-        // wait for signal
-        if stream.next().await.is_none() {
-            break;
+        send(&mut sink, Event::Credentials {
+            ssid: settings.ssid.clone(),
+            password: pw,
+            session: None,
+        }).await?;
+
+        // wait for signal, client updates from the hostapd hook are passed on
+        loop {
+            let line = match stream.next().await {
+                Some(line) => line,
+                None => return Ok(()),
+            };
+            match hostapd::Signal::parse(&line) {
+                hostapd::Signal::Connected(mac) => {
+                    send(&mut sink, Event::ClientConnected { mac, session: None }).await?;
+                }
+                hostapd::Signal::Disconnected(mac) => {
+                    send(&mut sink, Event::ClientDisconnected { mac, session: None }).await?;
+                    break;
+                }
+                hostapd::Signal::Reset => break,
+            }
         }
     }
-    Ok(())
 }
 
-async fn screen<S: Stream<Item=String> + Unpin>(mut stream: S, bin: &str) -> Result<()> {
-    info!("Spawn python script");
+/// Run sniffglue until it exits, restarting it is up to the supervisor
+async fn sniff<S: Sink<Input> + Unpin>(mut sink: S, dev: &str) -> Result<()> {
+    info!("Spawning sniffglue");
+    let mut child = Command::new("sniffglue")
+        .args(["--json", dev])
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to spawn sniffglue")?;
+    let stdout = child.stdout.take()
+        .context("Child did not have a handle to stdout")?;
+
+    let mut reader = BufReader::new(stdout).lines();
+    while let Some(line) = reader.next_line().await? {
+        send(&mut sink, Input::Line(line)).await?;
+    }
 
-    let mut cmd = Command::new(bin);
-    cmd.stdin(Stdio::piped());
+    let status = child.wait().await?;
+    bail!("sniffglue has exited: {}", status)
+}
 
-    let mut child = cmd.spawn()
-        .expect("failed to spawn command");
+async fn sniff_tcpdump<S: Sink<Input> + Unpin>(mut sink: S, dev: &str) -> Result<()> {
+    info!("Spawning tcpdump");
+    let mut child = Command::new("tcpdump")
+        .args(["-i", dev, "-n", "-U", "--immediate-mode", "-w", "-"])
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to spawn tcpdump")?;
+    let stdout = child.stdout.take()
+        .context("Child did not have a handle to stdout")?;
+
+    let mut reader = PcapReader::new(stdout).await?;
+    while let Some(frame) = reader.next().await? {
+        send(&mut sink, Input::Frame {
+            linktype: frame.linktype,
+            data: frame.data,
+        }).await?;
+    }
 
-    let mut stdin = child.stdin.take()
-        .expect("child did not have a handle to stdin");
+    let status = child.wait().await?;
+    bail!("tcpdump has exited: {}", status)
+}
 
-    // Ensure the child process is spawned in the runtime so it can
-    // make progress on its own while we await for any output.
-    let join = tokio::spawn(async move {
-        let status = child.wait().await
-            .expect("child process encountered an error");
+#[cfg(target_os = "linux")]
+async fn sniff_af_packet<S: Sink<Input> + Unpin>(mut sink: S, reader: &mut af_packet::Reader) -> Result<()> {
+    loop {
+        let frame = reader.next().await?;
+        send(&mut sink, Input::Frame {
+            linktype: frame.linktype,
+            data: frame.data,
+        }).await?;
+    }
+}
 
-        error!("child status was: {}", status);
-    });
+/// A capture backend, sockets are opened before the runtime is started
+enum Capture {
+    Sniffglue,
+    Tcpdump,
+    /// The socket can't be opened again after privileges were dropped, so the reader is kept across restarts
+    #[cfg(target_os = "linux")]
+    AfPacket {
+        socket: Option<af_packet::Socket>,
+        reader: Option<af_packet::Reader>,
+    },
+}
 
-    while let Some(item) = stream.next().await {
-        info!("Sending to screen: {:?}", item);
-        let line = format!("{}\n", item);
-        stdin.write_all(line.as_bytes()).await?;
+impl Capture {
+    fn open(backend: Backend, dev: &str, ring: bool) -> Result<Capture> {
+        match backend {
+            Backend::Sniffglue => Ok(Capture::Sniffglue),
+            Backend::Tcpdump => Ok(Capture::Tcpdump),
+            #[cfg(target_os = "linux")]
+            Backend::AfPacket => {
+                let socket = af_packet::Socket::open(dev, ring)
+                    .with_context(|| anyhow!("Failed to start capture on {:?}", dev))?;
+                Ok(Capture::AfPacket {
+                    socket: Some(socket),
+                    reader: None,
+                })
+            }
+            #[cfg(not(target_os = "linux"))]
+            Backend::AfPacket => {
+                let _ = (dev, ring);
+                bail!("The af-packet backend is only supported on linux")
+            }
+        }
+    }
+
+    async fn run<S: Sink<Input> + Unpin>(&mut self, sink: S, dev: &str) -> Result<()> {
+        match self {
+            Capture::Sniffglue => sniff(sink, dev).await,
+            Capture::Tcpdump => sniff_tcpdump(sink, dev).await,
+            #[cfg(target_os = "linux")]
+            Capture::AfPacket { socket, reader } => {
+                if let Some(s) = socket.take() {
+                    match s.reader() {
+                        Ok(r) => *reader = Some(r),
+                        Err((s, err)) => {
+                            // keep the socket for the next restart
+                            *socket = Some(s);
+                            return Err(err);
+                        }
+                    }
+                }
+                let reader = reader.as_mut()
+                    .context("Packet socket is gone")?;
+                sniff_af_packet(sink, reader).await
+            }
+        }
     }
 
-    join.await.ok();
+    /// Open a capture for every interface, privileges are dropped once all sockets are open
+    fn open_all(backend: Backend, devices: &[String], ring: bool) -> Result<Vec<(String, Capture)>> {
+        let captures = devices.iter()
+            .map(|dev| Ok((dev.to_string(), Capture::open(backend, dev, ring)?)))
+            .collect::<Result<Vec<_>>>()?;
 
-    Ok(())
+        #[cfg(target_os = "linux")]
+        if backend == Backend::AfPacket {
+            af_packet::drop_capabilities()?;
+        }
+
+        Ok(captures)
+    }
 }
 
-async fn sniff<S: Sink<String> + Unpin>(mut sink: S, dev: &str) -> Result<()> {
-    loop {
-        info!("Spawning sniffglue");
+/// Run every capture as its own component, input is labeled with its interface and decoded later.
+/// A failing capture is restarted and doesn't stop the others
+fn supervise_captures(supervisor: &mut Supervisor, captures: Vec<(String, Capture)>, tx: Sender<Captured>) {
+    for (dev, capture) in captures {
+        let capture = Arc::new(tokio::sync::Mutex::new(capture));
+        let interface = Arc::<str>::from(dev.as_str());
+        let tx = tx.clone();
+        supervisor.spawn(&format!("capture on {}", dev), move || {
+            let capture = capture.clone();
+            let interface = interface.clone();
+            let dev = dev.clone();
+            let sink = tx.clone().with(move |input| future::ok::<_, SendError>(Captured::new(Some(interface.clone()), input)));
+            async move {
+                capture.lock().await.run(sink, &dev).await
+            }
+        });
+    }
+}
 
-        let mut cmd = Command::new("sniffglue");
-        cmd.args(["--json", dev]);
+/// Only print detections, e.g. for the output of `stream`
+fn detections<S: Stream<Item=Event> + Unpin>(events: S) -> impl Stream<Item=Detection> + Unpin {
+    events.filter_map(|event| future::ready(match event {
+        Event::Detection(detection) => Some(detection),
+        _ => None,
+    }))
+}
 
-        // Specify that we want the command's standard output piped back to us.
-        // By default, standard input/output/error will be inherited from the
-        // current process (for example, this means that standard input will
-        // come from the keyboard and standard output/error will go directly to
-        // the terminal if this process is invoked from the command line).
-        cmd.stdout(Stdio::piped());
+/// Frames that are held back to sort the interfaces of a capture file by time
+const REORDER_FRAMES: usize = 1024;
 
-        let mut child = cmd.spawn()
-            .expect("failed to spawn command");
+/// Events of `replay` and `analyze` are printed, nothing is dropped if stdout is slow
+fn stdout_sink() -> SinkConfig {
+    SinkConfig { buffer: 4096, drop: DropPolicy::Newest, ..SinkConfig::new("stdout") }
+}
 
-        let stdout = child.stdout.take()
-            .expect("child did not have a handle to stdout");
+async fn analyze(args: Analyze) -> Result<()> {
+    let mut sinks = vec![stdout_sink()];
+    if let Some(dir) = &args.report {
+        sinks.push(SinkConfig { path: Some(dir.clone()), ..SinkConfig::new("report") });
+    }
+    let bus = Bus::with_sinks(&sinks, Arc::new(sink::Registry::default()))?;
+    let mut detector = detector(&config::Rules::from(&args.rule_files))?.build();
+
+    let file = File::open(&args.file).await
+        .with_context(|| anyhow!("Failed to open capture file {:?}", args.file))?;
+    let mut reader = PcapReader::new(BufReader::new(file)).await
+        .with_context(|| anyhow!("Failed to read capture file {:?}", args.file))?;
+
+    let (mut tx, rx) = futures::channel::mpsc::channel(256);
+    let read = async {
+        // replay the capture timestamps so time windows behave like in live mode
+        let start = Instant::now();
+        let mut first = None;
+        let mut frames = 0;
+        let mut skipped = 0;
+
+        // frames from different interfaces are not necessarily in order
+        let mut reorder = Reorder::new(REORDER_FRAMES);
+        let mut eof = false;
+        loop {
+            let next = if eof { None } else { reader.next().await? };
+            let frame = match next {
+                Some(frame) => match reorder.push(frame) {
+                    Some(frame) => frame,
+                    None => continue,
+                },
+                // flush the frames that are still held back
+                None => {
+                    eof = true;
+                    match reorder.pop() {
+                        Some(frame) => frame,
+                        None => break,
+                    }
+                }
+            };
+            frames += 1;
+
+            let pkt = match dissect::frame(frame.linktype, &frame.data) {
+                Ok(pkt) => pkt,
+                Err(err) => {
+                    trace!("Failed to dissect frame: {:#}", err);
+                    skipped += 1;
+                    continue;
+                }
+            };
+            let first = *first.get_or_insert(frame.timestamp);
+            let events = detector.process(&Labeled {
+                interface: frame.name,
+                time: Some(start + frame.timestamp.saturating_sub(first)),
+                observation: Observation::Packet(pkt),
+            });
+            for event in events {
+                tx.send(event).await?;
+            }
+        }
+        tx.close_channel();
+        Ok::<_, Error>((frames, skipped))
+    };
 
-        let mut reader = BufReader::new(stdout).lines();
+    // the capture is checked as one scan, repeated detections are merged like in the live run
+    let mut tracker = Tracker::new(session::IDLE_TIMEOUT);
+    let started = tracker.start_session(Utc::now());
+    let events = futures::stream::iter(started).chain(session::track(rx, tracker));
+    let ((frames, skipped), ()) = future::try_join(read, bus.run(Box::pin(events))).await?;
 
-        // Ensure the child process is spawned in the runtime so it can
-        // make progress on its own while we await for any output.
-        let join = tokio::spawn(async move {
-            let status = child.wait().await
-                .expect("child process encountered an error");
+    println!("[+] analyzed {} frames from {:?} ({} skipped)", frames, args.file, skipped);
+    Ok(())
+}
 
-            error!("child status was: {}", status);
-        });
+async fn resolver(args: Resolver) -> Result<()> {
+    let (tx1, rx1) = futures::channel::mpsc::channel(256);
+    let (mut tx2, rx2) = futures::channel::mpsc::channel(256);
 
-        while let Some(line) = reader.next_line().await? {
-            send(&mut sink, line).await?;
-        }
+    let mut sources = Vec::new();
+    if let Some(path) = args.dnsmasq {
+        sources.push(resolver::follow(path, resolver::dnsmasq, args.backfill, tx1.clone()).boxed());
+    }
+    if let Some(path) = args.unbound {
+        sources.push(resolver::follow(path, resolver::unbound, args.backfill, tx1.clone()).boxed());
+    }
+    if let Some(path) = args.pihole {
+        sources.push(pihole::follow(path, args.backfill, tx1.clone()).boxed());
+    }
+    if let Some(path) = args.dnstap {
+        let tx = tx1.clone();
+        let group = args.dnstap_group.clone();
+        sources.push(async move { dnstap::spawn(&path, group.as_deref(), tx).await }.boxed());
+    }
+    if let Some(addr) = args.netflow {
+        let tx = tx1.clone();
+        sources.push(async move { netflow::spawn(&addr, tx).await }.boxed());
+    }
+    drop(tx1);
+
+    if sources.is_empty() {
+        bail!("No input given, use --dnsmasq, --unbound, --pihole, --dnstap or --netflow");
+    }
+
+    let rules = config::Rules::from(&args.rule_files);
+    select! {
+        sources = future::try_join_all(sources).fuse() => sources.map(|_| ()),
+        stream = stream(rx1.map(Labeled::from), &mut tx2, &rules).fuse() => stream,
+        stdout = stdio::stdout(detections(rx2)).fuse() => stdout,
+    }
+}
 
-        join.await.ok();
-   }
+/// Setup the sinks from the config file, the screen is used if there are none
+fn setup_bus(config: &Config) -> Result<Bus> {
+    let registry = Arc::new(sink::Registry::default());
+    let bus = Bus::with_sinks(&config.sinks(), registry)?;
+    info!("Sending events to {} sinks", bus.len());
+    Ok(bus)
 }
 
-async fn start(args: Start) -> Result<()> {
-    let (mut screen_tx, screen_rx) = futures::channel::mpsc::channel(0);
+async fn start(config: Config, captures: Vec<(String, Capture)>) -> Result<()> {
+    let bus = setup_bus(&config)?;
+    let (events_tx, events_rx) = futures::channel::mpsc::channel(256);
 
     let (tx1, rx1) = futures::channel::mpsc::channel(256);
     let (tx2, rx2) = futures::channel::mpsc::channel(256);
 
+    // receivers are shared so a restarted component picks up where the old one stopped
+    let rx1 = Arc::new(tokio::sync::Mutex::new(rx1));
+    let rx2 = Arc::new(tokio::sync::Mutex::new(rx2));
+
+    let mut supervisor = Supervisor::new(events_tx.clone());
+
+    let socket = config.rpc.socket.clone();
+    supervisor.spawn("rpc", move || {
+        let socket = socket.clone();
+        let tx = tx1.clone();
+        async move { rpc::spawn(&socket, tx).await }
+    });
+
+    let events = events_tx.clone();
+    let settings = Arc::new(config.hotspot);
+    supervisor.spawn("hotspot", move || {
+        let rx = rx1.clone();
+        let events = events.clone();
+        let settings = settings.clone();
+        async move { hotspot(&mut *rx.lock().await, events, &settings).await }
+    });
+
+    supervise_captures(&mut supervisor, captures, tx2);
+
+    // the detector starts from scratch for every session
+    let (sessions_tx, sessions_rx) = tokio::sync::watch::channel(None);
+    let events = events_tx;
+    let rules = Arc::new(config.rules);
+    supervisor.spawn("stream", move || {
+        let rx = rx2.clone();
+        let mut events = events.clone();
+        let sessions = sessions_rx.clone();
+        let rules = rules.clone();
+        async move {
+            let mut detector = detector(&rules)?
+                .sessions(sessions)
+                .build();
+            detector.stream(decode(&mut *rx.lock().await, Format::Sniffglue), &mut events).await
+        }
+    });
+
+    let events = session::track(events_rx, Tracker::new(session::IDLE_TIMEOUT))
+        .inspect(move |event| {
+            if let Event::SessionStarted(session) = event {
+                sessions_tx.send_replace(Some(session.id.clone()));
+            }
+        });
     select! {
-        rpc = rpc::spawn(&args.socket, tx1).fuse() => rpc,
-        // rpc = stdio::stdin(tx1).fuse() => rpc,
-        hotspot = hotspot(rx1, screen_tx.clone(), &args.file).fuse() => hotspot,
+        bus = bus.run(Box::pin(events)).fuse() => bus,
+        supervisor = supervisor.wait().fuse() => supervisor,
+    }
+}
 
-        sniff = sniff(tx2, &args.device).fuse() => sniff,
-        stream = stream(rx2, &mut screen_tx, &args.rules).fuse() => stream,
+/// Write captured input to disk until the input ends or ctrl-c is pressed
+async fn record(args: args::Record, captures: Vec<(String, Capture)>) -> Result<()> {
+    let format = args.stdin.unwrap_or(Format::Sniffglue);
+    let mut recorder = Recorder::create(&args.output, format, args.max_size.saturating_mul(1024 * 1024), args.max_files)?;
+    info!("Recording to {:?}", args.output);
+
+    // client updates of the hostapd hook mark where sessions start and end
+    let (signals_tx, mut signals_rx) = futures::channel::mpsc::channel(256);
+    let mut rpc = if let Some(socket) = args.socket.clone() {
+        recorder = recorder.with_signals();
+        async move { rpc::spawn(&socket, signals_tx).await }.boxed().fuse()
+    } else {
+        future::pending().boxed().fuse()
+    };
+
+    let (tx, mut rx) = futures::channel::mpsc::channel(1024);
+    // the recording ends with stdin, captures are recorded until all of them failed
+    let mut supervisor = if args.stdin.is_some() {
+        let (lines_tx, lines_rx) = futures::channel::mpsc::channel(256);
+        tokio::spawn(stdio::stdin(lines_tx));
+        tokio::spawn(lines(lines_rx).map(Ok).forward(tx));
+        future::pending().boxed().fuse()
+    } else {
+        let (events_tx, events_rx) = futures::channel::mpsc::channel(256);
+        let mut supervisor = Supervisor::new(events_tx);
+        supervise_captures(&mut supervisor, captures, tx);
+        tokio::spawn(stdio::stdout(events_rx));
+        supervisor.wait().boxed().fuse()
+    };
+    let mut ctrl_c = Box::pin(tokio::signal::ctrl_c().fuse());
+    let mut records = 0;
+    loop {
+        let captured = select! {
+            captured = rx.next() => captured,
+            signal = signals_rx.select_next_some() => {
+                tokio::task::block_in_place(|| recorder.signal(&signal, Instant::now()))?;
+                records += 1;
+                continue;
+            }
+            _ = ctrl_c => None,
+            err = supervisor => {
+                err?;
+                None
+            }
+            err = rpc => {
+                err?;
+                None
+            }
+        };
+        match captured {
+            Some(captured) => tokio::task::block_in_place(|| recorder.write(&captured))?,
+            None => break,
+        }
+        records += 1;
+    }
 
-        screen = screen(screen_rx, &args.screen).fuse() => screen,
+    tokio::task::block_in_place(|| recorder.finish())?;
+    info!("Recorded {} entries to {:?}", records, args.output);
+    Ok(())
+}
+
+async fn replay(args: Replay) -> Result<()> {
+    let speed = if args.fast { None } else { Some(args.speed) };
+    let sinks = match &args.config {
+        Some(path) => config::effective(Some(path), &Overrides::default())?.sinks(),
+        None => vec![stdout_sink()],
+    };
+    let bus = Bus::with_sinks(&sinks, Arc::new(sink::Registry::default()))?;
+
+    let replay = recording::Replay::open(&args.path)?;
+    let format = replay.format();
+    let signals = replay.signals();
+    let detector = detector(&config::Rules::from(&args.rule_files))?.build();
+    let (tx1, rx1) = futures::channel::mpsc::channel(256);
+    let (mut tx2, rx2) = futures::channel::mpsc::channel(256);
+    // sessions follow the recorded hostapd hook, without it the recording is checked as one scan
+    let detect = async {
+        let mut decoder = Decoder::new(format);
+        recording::detect(rx1, |captured| decoder.decode(captured), detector, signals, &mut tx2).await?;
+        tx2.close_channel();
+        Ok(())
+    };
+    future::try_join3(replay.run(speed, tx1), detect, bus.run(rx2)).await?;
+
+    Ok(())
+}
+
+fn history(args: args::History) -> Result<()> {
+    let db = history::History::open_read_only(&args.db)?;
+    match args.command {
+        HistoryCommand::List => {
+            for session in db.sessions()? {
+                let ended = match (session.ended, session.reason) {
+                    (Some(ended), Some(reason)) => format!("{}, {}", ended.format("%H:%M:%S"), reason.as_str()),
+                    _ => "running".to_string(),
+                };
+                println!("{}  {} - {}  {} findings  {}",
+                    session.id,
+                    session.started.format("%Y-%m-%d %H:%M:%S"),
+                    ended,
+                    session.findings.len(),
+                    session.clients.join(", "));
+            }
+        }
+        HistoryCommand::Show { id } => {
+            let scan = db.scan(&id)?
+                .with_context(|| anyhow!("Session not found: {:?}", id))?;
+            let session = &scan.session;
+            println!("session:  {}", session.id);
+            println!("started:  {}", session.started.to_rfc3339());
+            if let (Some(ended), Some(reason)) = (session.ended, session.reason) {
+                println!("ended:    {} ({})", ended.to_rfc3339(), reason.as_str());
+            }
+            if let (Some(ssid), Some(password)) = (&session.ssid, &session.password) {
+                println!("hotspot:  {:?} (pw: {})", ssid, password);
+            }
+            println!("clients:  {}", session.clients.join(", "));
+            if let Some(provenance) = &session.provenance {
+                println!("iocs:     {} iocs from {:?} (sha256: {})", provenance.iocs, provenance.path, provenance.sha256);
+            }
+
+            println!();
+            for detection in &session.findings {
+                let time = detection.time.map(|time| time.format("%H:%M:%S").to_string()).unwrap_or_default();
+                println!("{} {}", time, detection);
+            }
+            for summary in &scan.observations {
+                println!("{} [ ] observed({}): {:?} ({}x)", summary.last_seen.format("%H:%M:%S"), summary.kind, summary.name, summary.count);
+            }
+        }
+        HistoryCommand::Export { id } => {
+            let ids = match id {
+                Some(id) => vec![id],
+                None => db.sessions()?.into_iter().map(|session| session.id).collect(),
+            };
+            let mut scans = Vec::new();
+            for id in ids {
+                let scan = db.scan(&id)?
+                    .with_context(|| anyhow!("Session not found: {:?}", id))?;
+                scans.push(scan);
+            }
+            println!("{}", serde_json::to_string_pretty(&scans)?);
+        }
     }
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn report(args: args::Report) -> Result<()> {
+    let db = history::History::open_read_only(&args.db)?;
+    let scan = db.scan(&args.id)?
+        .with_context(|| anyhow!("Session not found: {:?}", args.id))?;
+    let report = Report::new(&scan.session, Utc::now());
+    let data = match args.format {
+        ReportFormat::Html => report.to_html().into_bytes(),
+        ReportFormat::Json => report.to_json()?.into_bytes(),
+        ReportFormat::Pdf => report.to_pdf(),
+    };
+    match args.output {
+        Some(path) => std::fs::write(&path, data)
+            .with_context(|| anyhow!("Failed to write report to {:?}", path))?,
+        None => std::io::stdout().write_all(&data)?,
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
     env_logger::init_from_env(Env::default()
         .default_filter_or("spytrap=info"));
 
     let args = Args::parse();
+
+    // the daemon is configured before anything is started, capture sockets are opened from the config
+    let config = match &args.subcommand {
+        SubCommand::Start(args) => Some(config::effective(args.config.as_deref(), &args.overrides)?),
+        _ => None,
+    };
+
+    // capabilities are per-thread, so packet sockets need to be opened
+    // and privileges dropped before the runtime starts its worker threads
+    let captures = match (&args.subcommand, &config) {
+        (SubCommand::Start(_), Some(config)) => Capture::open_all(config.capture.backend, &config.capture.interfaces, config.capture.ring)?,
+        (SubCommand::Sniff(args), _) => Capture::open_all(args.capture, &args.devices, args.ring)?,
+        (SubCommand::Record(args), _) if args.stdin.is_none() => Capture::open_all(args.capture, &args.devices, args.ring)?,
+        _ => Vec::new(),
+    };
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(args, config, captures))
+}
+
+async fn run(args: Args, config: Option<Config>, captures: Vec<(String, Capture)>) -> Result<()> {
     match args.subcommand {
-        SubCommand::Start(args) => start(args).await,
+        SubCommand::Start(_) => start(config.context("Config was not loaded")?, captures).await,
+        SubCommand::Analyze(args) => analyze(args).await,
+        SubCommand::Resolver(args) => resolver(args).await,
         SubCommand::Send(args) => rpc::send(&args.socket, args.value).await,
-        SubCommand::Sniff(args) => {
+        SubCommand::Sniff(_) => {
             let (tx, _rx) = futures::channel::mpsc::channel(256);
-            sniff(tx, &args.device).await
+            let (events_tx, events_rx) = futures::channel::mpsc::channel(256);
+            let mut supervisor = Supervisor::new(events_tx);
+            supervise_captures(&mut supervisor, captures, tx);
+            select! {
+                sniff = supervisor.wait().fuse() => sniff,
+                stdout = stdio::stdout(events_rx).fuse() => stdout,
+            }
         }
         SubCommand::Stream(args) => {
             let (tx1, rx1) = futures::channel::mpsc::channel(256);
             let (mut tx2, rx2) = futures::channel::mpsc::channel(256);
-            select! {
-                x = stdio::stdin(tx1).fuse() => x,
-                x = stream(rx1, &mut tx2, &args.rules).fuse() => x,
-                x = stdio::stdout(rx2).fuse() => x,
-            }?;
+            // log files reach eof, so keep going until every line was processed
+            let detect = async {
+                stream(decode(lines(rx1), args.format), &mut tx2, &config::Rules::from(&args.rule_files)).await?;
+                tx2.close_channel();
+                Ok(())
+            };
+            future::try_join3(stdio::stdin(tx1), detect, stdio::stdout(detections(rx2))).await?;
 
             Ok(())
         }
         SubCommand::Screen(args) => {
-            let (tx, rx) = futures::channel::mpsc::channel(256);
-            select! {
-                stdin = stdio::stdin(tx).fuse() => stdin,
-                screen = screen(rx, &args.screen).fuse() => screen,
+            let mut screen = sink::Screen::spawn(&args.screen, sink::Format::Text)?;
+            let stdin = tokio::io::stdin();
+            let mut reader = BufReader::new(stdin).lines();
+            while let Some(line) = reader.next_line().await? {
+                screen.write_line(&line).await?;
             }
+            Ok(())
         }
+        SubCommand::History(args) => history(args),
+        SubCommand::Report(args) => report(args),
+        SubCommand::Record(args) => record(args, captures).await,
+        SubCommand::Config(args) => match args.command {
+            ConfigCommand::Check { config, overrides } => {
+                let config = config::effective(config.as_deref(), &overrides)?;
+                print!("{}", toml::to_string_pretty(&config)?);
+                Ok(())
+            }
+        },
+        SubCommand::Replay(args) => replay(args).await,
         SubCommand::Hotspot(args) => {
+            let config = config::effective(args.config.as_deref(), &Overrides {
+                file: args.file,
+                ..Default::default()
+            })?;
             let (tx1, rx1) = futures::channel::mpsc::channel(256);
             let (tx2, rx2) = futures::channel::mpsc::channel(256);
             select! {
                 _stdin = stdio::stdin(tx1).fuse() => (),
-                _hotspot = hotspot(rx1, tx2, &args.file).fuse() => (),
+                _hotspot = hotspot(rx1, tx2, &config.hotspot).fuse() => (),
                 _stdout = stdio::stdout(rx2).fuse() => (),
             }
             Ok(())
@@ -0,0 +1,533 @@
+use crate::errors::*;
+use crate::json::{Flow, Proto};
+use crate::observation::Observation;
+use crate::reader::Reader;
+use futures::channel::mpsc::Sender;
+use futures::SinkExt;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+
+// information elements, these are the same for netflow v9 and ipfix
+const PROTOCOL: u16 = 4;
+const SRC_PORT: u16 = 7;
+const SRC_IPV4: u16 = 8;
+const DST_PORT: u16 = 11;
+const DST_IPV4: u16 = 12;
+const SRC_IPV6: u16 = 27;
+const DST_IPV6: u16 = 28;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+/// Marks a variable length field in ipfix templates
+const VARIABLE_LENGTH: u16 = 0xffff;
+
+/// Templates that are kept at once, any udp sender can make up new ones
+const MAX_TEMPLATES: usize = 4096;
+/// Templates that are kept per exporter, a real one only uses a handful
+const MAX_TEMPLATES_PER_EXPORTER: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Field {
+    id: u16,
+    len: u16,
+}
+
+type Template = Vec<Field>;
+
+/// Templates are scoped to the exporter and its source id or observation domain
+type TemplateKey = (SocketAddr, u32, u16);
+
+#[derive(Debug)]
+struct Stored {
+    template: Template,
+    /// When it was received, the oldest template is evicted first
+    seq: u64,
+}
+
+/// The fields of a flow record that are needed to build a `Flow`
+#[derive(Debug, Default)]
+struct Record {
+    proto: Option<u8>,
+    src: Option<IpAddr>,
+    sport: Option<u16>,
+    dst: Option<IpAddr>,
+    dport: Option<u16>,
+}
+
+impl Record {
+    fn set(&mut self, id: u16, value: &[u8]) {
+        match (id, value.len()) {
+            (PROTOCOL, 1) => self.proto = Some(value[0]),
+            (SRC_PORT, 2) => self.sport = Some(u16::from_be_bytes([value[0], value[1]])),
+            (DST_PORT, 2) => self.dport = Some(u16::from_be_bytes([value[0], value[1]])),
+            (SRC_IPV4, 4) | (DST_IPV4, 4) | (SRC_IPV6, 16) | (DST_IPV6, 16) => {
+                let addr = ip(value);
+                if id == SRC_IPV4 || id == SRC_IPV6 {
+                    self.src = addr;
+                } else {
+                    self.dst = addr;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn flow(&self) -> Option<Flow> {
+        let proto = match self.proto? {
+            IPPROTO_TCP => Proto::TCP,
+            IPPROTO_UDP => Proto::UDP,
+            _ => return None,
+        };
+        Some(Flow {
+            proto,
+            src: SocketAddr::new(self.src?, self.sport?),
+            dst: SocketAddr::new(self.dst?, self.dport?),
+        })
+    }
+}
+
+fn ip(buf: &[u8]) -> Option<IpAddr> {
+    match buf.len() {
+        4 => {
+            let b: [u8; 4] = buf.try_into().ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(b)))
+        }
+        16 => {
+            let b: [u8; 16] = buf.try_into().ok()?;
+            Some(IpAddr::V6(Ipv6Addr::from(b)))
+        }
+        _ => None,
+    }
+}
+
+/// Decoder for netflow v5, v9 and ipfix packets, v9 and ipfix
+/// records can only be decoded after their template was received
+#[derive(Debug, Default)]
+pub struct Collector {
+    templates: HashMap<TemplateKey, Stored>,
+    seq: u64,
+}
+
+impl Collector {
+    pub fn new() -> Collector {
+        Collector::default()
+    }
+
+    /// Store a template, templates without fields withdraw the previous one
+    fn insert(&mut self, key: TemplateKey, template: Template) {
+        if template.iter().all(|field| field.len == 0) {
+            debug!("Netflow template {} from {} was withdrawn", key.2, key.0);
+            self.templates.remove(&key);
+            return;
+        }
+
+        if !self.templates.contains_key(&key) {
+            let exporter = key.0;
+            let count = self.templates.keys().filter(|(addr, _, _)| *addr == exporter).count();
+            if count >= MAX_TEMPLATES_PER_EXPORTER {
+                self.evict(|(addr, _, _)| *addr == exporter);
+            } else if self.templates.len() >= MAX_TEMPLATES {
+                self.evict(|_| true);
+            }
+        }
+
+        debug!("Received netflow template {} from {}", key.2, key.0);
+        self.seq += 1;
+        self.templates.insert(key, Stored {
+            template,
+            seq: self.seq,
+        });
+    }
+
+    /// Drop the oldest template that matches the filter
+    fn evict<F: Fn(&TemplateKey) -> bool>(&mut self, filter: F) {
+        let oldest = self.templates.iter()
+            .filter(|(key, _)| filter(key))
+            .min_by_key(|(_, stored)| stored.seq)
+            .map(|(key, _)| *key);
+        if let Some(key) = oldest {
+            trace!("Evicting netflow template {} from {}", key.2, key.0);
+            self.templates.remove(&key);
+        }
+    }
+
+    pub fn parse(&mut self, exporter: SocketAddr, buf: &[u8]) -> Result<Vec<Flow>> {
+        let mut r = Reader::new(buf);
+        match r.u16()? {
+            5 => v5(r),
+            9 => self.v9(exporter, r),
+            10 => self.ipfix(exporter, r),
+            version => bail!("Unsupported netflow version: {}", version),
+        }
+    }
+
+    fn v9(&mut self, exporter: SocketAddr, mut r: Reader) -> Result<Vec<Flow>> {
+        // count, sys uptime, unix secs, sequence
+        r.skip(14)?;
+        let source_id = r.u32()?;
+        self.sets(exporter, source_id, r, 0, 1, false)
+    }
+
+    fn ipfix(&mut self, exporter: SocketAddr, mut r: Reader) -> Result<Vec<Flow>> {
+        let len = r.u16()? as usize;
+        if len < 16 {
+            bail!("Invalid ipfix message length: {}", len);
+        }
+        // export time, sequence
+        r.skip(8)?;
+        let domain = r.u32()?;
+        let r = Reader::new(r.take(len - 16)?);
+        self.sets(exporter, domain, r, 2, 3, true)
+    }
+
+    /// Read the flowsets of a v9 or ipfix message, both use the same layout
+    fn sets(&mut self, exporter: SocketAddr, domain: u32, mut r: Reader, template_id: u16, options_id: u16, ipfix: bool) -> Result<Vec<Flow>> {
+        let mut flows = Vec::new();
+        while r.remaining() >= 4 {
+            let id = r.u16()?;
+            let len = r.u16()? as usize;
+            if len < 4 {
+                bail!("Invalid flowset length: {}", len);
+            }
+            let mut set = Reader::new(r.take(len - 4)?);
+
+            if id == template_id {
+                while set.remaining() >= 4 {
+                    let tid = set.u16()?;
+                    let count = set.u16()?;
+                    // an ipfix withdrawal with the set id withdraws every template of the domain
+                    if ipfix && count == 0 && tid == template_id {
+                        debug!("Netflow templates from {} were withdrawn", exporter);
+                        self.templates.retain(|(addr, id, _), _| *addr != exporter || *id != domain);
+                        continue;
+                    }
+                    let mut template = Vec::with_capacity(count as usize);
+                    for _ in 0..count {
+                        let id = set.u16()?;
+                        let len = set.u16()?;
+                        // enterprise specific fields are followed by the enterprise number
+                        if ipfix && id & 0x8000 != 0 {
+                            set.skip(4)?;
+                            template.push(Field { id: 0, len });
+                        } else {
+                            template.push(Field { id, len });
+                        }
+                    }
+                    self.insert((exporter, domain, tid), template);
+                }
+            } else if id == options_id {
+                trace!("Skipping netflow options template");
+            } else if id >= 256 {
+                let template = match self.templates.get(&(exporter, domain, id)) {
+                    Some(stored) => &stored.template,
+                    None => {
+                        trace!("Skipping netflow records for unknown template {}", id);
+                        continue;
+                    }
+                };
+                records(template, set, &mut flows)?;
+            }
+        }
+        Ok(flows)
+    }
+}
+
+fn records(template: &[Field], mut set: Reader, flows: &mut Vec<Flow>) -> Result<()> {
+    let min_len = template.iter()
+        .map(|f| if f.len == VARIABLE_LENGTH { 1 } else { f.len as usize })
+        .sum::<usize>();
+    if min_len == 0 {
+        bail!("Netflow template has no fields");
+    }
+
+    // anything shorter than a record is padding
+    while set.remaining() >= min_len {
+        let mut record = Record::default();
+        for field in template {
+            let len = if field.len == VARIABLE_LENGTH {
+                match set.u8()? {
+                    255 => set.u16()? as usize,
+                    n => n as usize,
+                }
+            } else {
+                field.len as usize
+            };
+            record.set(field.id, set.take(len)?);
+        }
+        flows.extend(record.flow());
+    }
+    Ok(())
+}
+
+fn v5(mut r: Reader) -> Result<Vec<Flow>> {
+    let count = r.u16()?;
+    // sys uptime, unix secs, unix nsecs, sequence, engine type and id, sampling
+    r.skip(20)?;
+
+    let mut flows = Vec::new();
+    for _ in 0..count {
+        let mut rec = Reader::new(r.take(48)?);
+        let src = rec.take(4)?;
+        let dst = rec.take(4)?;
+        // nexthop, input, output, packets, octets, first, last
+        rec.skip(24)?;
+        let sport = rec.u16()?;
+        let dport = rec.u16()?;
+        // pad, tcp flags
+        rec.skip(2)?;
+        let proto = rec.u8()?;
+
+        let record = Record {
+            proto: Some(proto),
+            src: ip(src),
+            sport: Some(sport),
+            dst: ip(dst),
+            dport: Some(dport),
+        };
+        flows.extend(record.flow());
+    }
+    Ok(flows)
+}
+
+pub async fn spawn(addr: &str, tx: Sender<Observation>) -> Result<()> {
+    let socket = UdpSocket::bind(addr).await
+        .with_context(|| anyhow!("Failed to bind netflow collector to {:?}", addr))?;
+    info!("Listening for netflow on {}", socket.local_addr()?);
+    collect(socket, tx).await
+}
+
+async fn collect(socket: UdpSocket, mut tx: Sender<Observation>) -> Result<()> {
+    let mut collector = Collector::new();
+    let mut buf = vec![0; 65535];
+    loop {
+        let (n, exporter) = socket.recv_from(&mut buf).await?;
+        match collector.parse(exporter, &buf[..n]) {
+            Ok(flows) => {
+                for flow in flows {
+                    tx.send(Observation::Connection(flow)).await.map_err(|_| anyhow!("sink error"))?;
+                }
+            }
+            Err(err) => trace!("Failed to parse netflow packet from {}: {:#}", exporter, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn exporter() -> SocketAddr {
+        "192.168.1.1:40000".parse().unwrap()
+    }
+
+    fn flow(proto: Proto, src: &str, dst: &str) -> Flow {
+        Flow {
+            proto,
+            src: src.parse().unwrap(),
+            dst: dst.parse().unwrap(),
+        }
+    }
+
+    fn v5_packet() -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(&[0, 5, 0, 2]);
+        buf.extend(&[0; 20]);
+        for (src, dst, sport, dport, proto) in &[
+            ([10, 38, 73, 100], [93, 184, 216, 34], 51234u16, 443u16, 6u8),
+            ([10, 38, 73, 100], [10, 38, 73, 1], 0, 0, 1),
+        ] {
+            buf.extend(src);
+            buf.extend(dst);
+            buf.extend(&[0; 24]);
+            buf.extend(&sport.to_be_bytes());
+            buf.extend(&dport.to_be_bytes());
+            buf.extend(&[0, 0x12, *proto, 0]);
+            buf.extend(&[0; 8]);
+        }
+        buf
+    }
+
+    fn template_set(set_id: u16, template_id: u16, fields: &[(u16, u16)]) -> Vec<u8> {
+        let mut set = Vec::new();
+        set.extend(&set_id.to_be_bytes());
+        set.extend(&((8 + fields.len() * 4) as u16).to_be_bytes());
+        set.extend(&template_id.to_be_bytes());
+        set.extend(&(fields.len() as u16).to_be_bytes());
+        for (id, len) in fields {
+            set.extend(&id.to_be_bytes());
+            set.extend(&len.to_be_bytes());
+        }
+        set
+    }
+
+    fn data_set(template_id: u16, records: &[u8]) -> Vec<u8> {
+        let mut set = Vec::new();
+        set.extend(&template_id.to_be_bytes());
+        set.extend(&((4 + records.len()) as u16).to_be_bytes());
+        set.extend(records);
+        set
+    }
+
+    fn v9_packet(sets: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = vec![0, 9, 0, sets.len() as u8];
+        buf.extend(&[0; 12]);
+        buf.extend(&1337u32.to_be_bytes());
+        for set in sets {
+            buf.extend(set);
+        }
+        buf
+    }
+
+    fn ipfix_packet(sets: &[Vec<u8>]) -> Vec<u8> {
+        let len = 16 + sets.iter().map(|s| s.len()).sum::<usize>();
+        let mut buf = vec![0, 10];
+        buf.extend(&(len as u16).to_be_bytes());
+        buf.extend(&[0; 8]);
+        buf.extend(&1337u32.to_be_bytes());
+        for set in sets {
+            buf.extend(set);
+        }
+        buf
+    }
+
+    const V4_TEMPLATE: &[(u16, u16)] = &[(SRC_IPV4, 4), (DST_IPV4, 4), (SRC_PORT, 2), (DST_PORT, 2), (PROTOCOL, 1)];
+
+    fn v4_record(src: [u8; 4], dst: [u8; 4], sport: u16, dport: u16, proto: u8) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(&src);
+        buf.extend(&dst);
+        buf.extend(&sport.to_be_bytes());
+        buf.extend(&dport.to_be_bytes());
+        buf.push(proto);
+        buf
+    }
+
+    #[test]
+    fn parse_v5() {
+        let mut collector = Collector::new();
+        let flows = collector.parse(exporter(), &v5_packet()).unwrap();
+        assert_eq!(flows, vec![flow(Proto::TCP, "10.38.73.100:51234", "93.184.216.34:443")]);
+    }
+
+    #[test]
+    fn parse_v9() {
+        let mut collector = Collector::new();
+        let mut records = v4_record([10, 38, 73, 100], [93, 184, 216, 34], 51234, 443, 6);
+        records.extend(v4_record([10, 38, 73, 100], [1, 1, 1, 1], 53022, 53, 17));
+        // padding to a multiple of 4 bytes
+        records.extend(&[0, 0]);
+        let packet = v9_packet(&[template_set(0, 256, V4_TEMPLATE), data_set(256, &records)]);
+
+        let flows = collector.parse(exporter(), &packet).unwrap();
+        assert_eq!(flows, vec![
+            flow(Proto::TCP, "10.38.73.100:51234", "93.184.216.34:443"),
+            flow(Proto::UDP, "10.38.73.100:53022", "1.1.1.1:53"),
+        ]);
+    }
+
+    #[test]
+    fn v9_template_from_previous_packet() {
+        let mut collector = Collector::new();
+        let records = v4_record([10, 38, 73, 100], [93, 184, 216, 34], 51234, 443, 6);
+
+        // records before the template can't be decoded
+        let data = v9_packet(&[data_set(256, &records)]);
+        assert_eq!(collector.parse(exporter(), &data).unwrap(), vec![]);
+
+        let template = v9_packet(&[template_set(0, 256, V4_TEMPLATE)]);
+        assert_eq!(collector.parse(exporter(), &template).unwrap(), vec![]);
+        assert_eq!(collector.parse(exporter(), &data).unwrap(), vec![
+            flow(Proto::TCP, "10.38.73.100:51234", "93.184.216.34:443"),
+        ]);
+
+        // templates are scoped to the exporter
+        assert_eq!(collector.parse("192.168.1.2:40000".parse().unwrap(), &data).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn parse_ipfix_ipv6_variable_length() {
+        let mut collector = Collector::new();
+        let fields = &[(SRC_IPV6, 16), (DST_IPV6, 16), (SRC_PORT, 2), (DST_PORT, 2), (PROTOCOL, 1), (82, VARIABLE_LENGTH)];
+
+        let mut record = Vec::new();
+        record.extend(&"fd00::1337".parse::<Ipv6Addr>().unwrap().octets());
+        record.extend(&"2606:2800:220:1:248:1893:25c8:1946".parse::<Ipv6Addr>().unwrap().octets());
+        record.extend(&51234u16.to_be_bytes());
+        record.extend(&443u16.to_be_bytes());
+        record.push(6);
+        // interfaceName
+        record.push(4);
+        record.extend(b"eth0");
+
+        let packet = ipfix_packet(&[template_set(2, 300, fields), data_set(300, &record)]);
+        assert_eq!(collector.parse(exporter(), &packet).unwrap(), vec![
+            flow(Proto::TCP, "[fd00::1337]:51234", "[2606:2800:220:1:248:1893:25c8:1946]:443"),
+        ]);
+    }
+
+    #[test]
+    fn ipfix_template_withdrawal() {
+        let mut collector = Collector::new();
+        let records = v4_record([10, 38, 73, 100], [93, 184, 216, 34], 51234, 443, 6);
+        let packet = ipfix_packet(&[template_set(2, 256, V4_TEMPLATE), template_set(2, 257, V4_TEMPLATE)]);
+        collector.parse(exporter(), &packet).unwrap();
+
+        // the withdrawn template is gone, the rest of the packet is still decoded
+        let packet = ipfix_packet(&[template_set(2, 256, &[]), data_set(256, &records), data_set(257, &records)]);
+        assert_eq!(collector.parse(exporter(), &packet).unwrap(), vec![
+            flow(Proto::TCP, "10.38.73.100:51234", "93.184.216.34:443"),
+        ]);
+        assert_eq!(collector.templates.len(), 1);
+
+        // withdraw all of them
+        let packet = ipfix_packet(&[template_set(2, 2, &[])]);
+        collector.parse(exporter(), &packet).unwrap();
+        assert!(collector.templates.is_empty());
+    }
+
+    #[test]
+    fn bounded_templates() {
+        let mut collector = Collector::new();
+        for tid in 0..MAX_TEMPLATES_PER_EXPORTER as u16 * 2 {
+            collector.parse(exporter(), &v9_packet(&[template_set(0, 256 + tid, V4_TEMPLATE)])).unwrap();
+        }
+        assert_eq!(collector.templates.len(), MAX_TEMPLATES_PER_EXPORTER);
+        // the oldest ones were evicted
+        assert!(!collector.templates.contains_key(&(exporter(), 1337, 256)));
+        assert!(collector.templates.contains_key(&(exporter(), 1337, 256 + MAX_TEMPLATES_PER_EXPORTER as u16 * 2 - 1)));
+
+        for port in 0..MAX_TEMPLATES as u16 * 2 {
+            let exporter = SocketAddr::new("192.168.1.2".parse().unwrap(), port);
+            collector.parse(exporter, &v9_packet(&[template_set(0, 256, V4_TEMPLATE)])).unwrap();
+        }
+        assert_eq!(collector.templates.len(), MAX_TEMPLATES);
+    }
+
+    #[test]
+    fn truncated_packet() {
+        let mut collector = Collector::new();
+        let packet = v5_packet();
+        assert!(collector.parse(exporter(), &packet[..packet.len() - 10]).is_err());
+        assert!(collector.parse(exporter(), &[0, 7, 0, 0]).is_err());
+    }
+
+    #[tokio::test]
+    async fn replay_to_localhost() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (tx, mut rx) = futures::channel::mpsc::channel(16);
+        let collector = tokio::spawn(collect(socket, tx));
+
+        let exporter = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        exporter.send_to(&v5_packet(), addr).await.unwrap();
+
+        assert_eq!(rx.next().await, Some(Observation::Connection(
+            flow(Proto::TCP, "10.38.73.100:51234", "93.184.216.34:443"),
+        )));
+        collector.abort();
+    }
+}
@@ -0,0 +1,117 @@
+use crate::json::{Flow, Pkt, Proto, HTTP};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Something seen on the network, either a packet or an entry from another tool's logs
+#[derive(Debug, PartialEq, Eq)]
+pub enum Observation {
+    Packet(Pkt<'static>),
+    /// A dns query
+    Dns {
+        flow: Option<Flow>,
+        qtype: String,
+        name: String,
+    },
+    /// A dns query from a resolver log, only the client is known
+    Query {
+        client: IpAddr,
+        qtype: String,
+        name: String,
+    },
+    /// A dns answer that resolved a name to an address
+    Resolved {
+        name: String,
+        addr: IpAddr,
+    },
+    /// A tls handshake, fingerprints are already hashed
+    Tls {
+        flow: Option<Flow>,
+        sni: Option<String>,
+        ja3: Option<String>,
+        ja4: Option<String>,
+    },
+    Http {
+        flow: Option<Flow>,
+        http: HTTP,
+    },
+    /// A connection without any further knowledge about its content
+    Connection(Flow),
+}
+
+impl Observation {
+    pub fn flow(&self) -> Option<Flow> {
+        match self {
+            Observation::Packet(pkt) => pkt.get_flow(),
+            Observation::Dns { flow, .. } => *flow,
+            Observation::Query { .. } => None,
+            Observation::Resolved { .. } => None,
+            Observation::Tls { flow, .. } => *flow,
+            Observation::Http { flow, .. } => *flow,
+            Observation::Connection(flow) => Some(*flow),
+        }
+    }
+}
+
+/// An observation and the interface it was captured on, if any
+#[derive(Debug, PartialEq, Eq)]
+pub struct Labeled {
+    pub interface: Option<Arc<str>>,
+    /// When it was captured, if unknown it's processed as if it was just seen
+    pub time: Option<Instant>,
+    pub observation: Observation,
+}
+
+impl From<Observation> for Labeled {
+    fn from(observation: Observation) -> Labeled {
+        Labeled {
+            interface: None,
+            time: None,
+            observation,
+        }
+    }
+}
+
+/// Captured data before it's decoded, this is what's written to recordings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    /// A line of text, e.g. sniffglue json or a log entry
+    Line(String),
+    /// A link-layer frame from tcpdump or a packet socket
+    Frame {
+        linktype: u32,
+        data: Vec<u8>,
+    },
+}
+
+/// Input with the interface it was captured on and the time it was received
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Captured {
+    pub interface: Option<Arc<str>>,
+    pub time: Instant,
+    pub input: Input,
+}
+
+impl Captured {
+    pub fn new(interface: Option<Arc<str>>, input: Input) -> Captured {
+        Captured {
+            interface,
+            time: Instant::now(),
+            input,
+        }
+    }
+}
+
+/// Build a flow from log fields, if all of them are present
+pub fn flow(proto: Option<&str>, src: Option<IpAddr>, sport: Option<u16>, dst: Option<IpAddr>, dport: Option<u16>) -> Option<Flow> {
+    let proto = match proto?.to_ascii_lowercase().as_str() {
+        "tcp" => Proto::TCP,
+        "udp" => Proto::UDP,
+        _ => return None,
+    };
+    Some(Flow {
+        proto,
+        src: SocketAddr::new(src?, sport?),
+        dst: SocketAddr::new(dst?, dport?),
+    })
+}
@@ -0,0 +1,603 @@
+use crate::errors::*;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const LINKTYPE_NULL: u32 = 0;
+pub const LINKTYPE_ETHERNET: u32 = 1;
+pub const LINKTYPE_RAW: u32 = 101;
+/// Some platforms use the DLT value instead of LINKTYPE_RAW
+pub const LINKTYPE_DLT_RAW: u32 = 12;
+pub const LINKTYPE_LINUX_SLL: u32 = 113;
+pub const LINKTYPE_IPV4: u32 = 228;
+pub const LINKTYPE_IPV6: u32 = 229;
+pub const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const MAGIC_MICROS: u32 = 0xa1b2c3d4;
+const MAGIC_NANOS: u32 = 0xa1b23c4d;
+/// Larger than any snaplen tcpdump would use
+const MAX_PACKET_LEN: usize = 256 * 1024;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0a0d0d0a;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 1;
+const BLOCK_PACKET: u32 = 2;
+const BLOCK_SIMPLE_PACKET: u32 = 3;
+const BLOCK_ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const OPT_END: u16 = 0;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_TSRESOL: u16 = 9;
+const MAX_BLOCK_LEN: usize = MAX_PACKET_LEN + 1024;
+
+/// A captured frame with its link layer header
+#[derive(Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// Index of the capture interface, always 0 for classic pcap
+    pub interface: u32,
+    /// Name of the capture interface, if the pcapng file has one
+    pub name: Option<Arc<str>>,
+    pub linktype: u32,
+    /// Capture time since the unix epoch
+    pub timestamp: Duration,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resolution {
+    /// Units of 10^-n seconds
+    Decimal(u8),
+    /// Units of 2^-n seconds
+    Binary(u8),
+}
+
+impl Resolution {
+    fn from_option(value: u8) -> Resolution {
+        if value & 0x80 == 0 {
+            Resolution::Decimal(value)
+        } else {
+            Resolution::Binary(value & 0x7f)
+        }
+    }
+
+    fn to_duration(self, ts: u64) -> Duration {
+        match self {
+            Resolution::Decimal(n) => {
+                let units = 10u64.saturating_pow(n as u32);
+                let frac = (ts % units) as u128 * 1_000_000_000 / units as u128;
+                Duration::new(ts / units, frac as u32)
+            }
+            Resolution::Binary(n) => {
+                let n = n.min(63);
+                let frac = ((ts & ((1 << n) - 1)) as u128 * 1_000_000_000) >> n;
+                Duration::new(ts >> n, frac as u32)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Interface {
+    linktype: u32,
+    resolution: Resolution,
+    name: Option<Arc<str>>,
+}
+
+#[derive(Debug)]
+enum Format {
+    Pcap {
+        linktype: u32,
+        resolution: Resolution,
+    },
+    Pcapng {
+        interfaces: Vec<Interface>,
+    },
+}
+
+/// Read frames from a pcap or pcapng stream, like `tcpdump -w -` or a capture file
+pub struct PcapReader<R> {
+    reader: R,
+    big_endian: bool,
+    format: Format,
+}
+
+impl<R: AsyncRead + Unpin> PcapReader<R> {
+    pub async fn new(mut reader: R) -> Result<PcapReader<R>> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).await
+            .context("Failed to read capture header")?;
+
+        if u32::from_be_bytes(magic) == BLOCK_SECTION_HEADER {
+            let mut pcap = PcapReader {
+                reader,
+                big_endian: false,
+                format: Format::Pcapng {
+                    interfaces: Vec::new(),
+                },
+            };
+            pcap.read_section_header().await?;
+            return Ok(pcap);
+        }
+
+        let (big_endian, resolution) = match (u32::from_be_bytes(magic), u32::from_le_bytes(magic)) {
+            (MAGIC_MICROS, _) => (true, Resolution::Decimal(6)),
+            (MAGIC_NANOS, _) => (true, Resolution::Decimal(9)),
+            (_, MAGIC_MICROS) => (false, Resolution::Decimal(6)),
+            (_, MAGIC_NANOS) => (false, Resolution::Decimal(9)),
+            _ => bail!("Not a pcap or pcapng stream, unknown magic: {:02x?}", magic),
+        };
+
+        let mut hdr = [0u8; 20];
+        reader.read_exact(&mut hdr).await
+            .context("Failed to read pcap header")?;
+
+        let linktype = [hdr[16], hdr[17], hdr[18], hdr[19]];
+        let linktype = if big_endian {
+            u32::from_be_bytes(linktype)
+        } else {
+            u32::from_le_bytes(linktype)
+        };
+
+        Ok(PcapReader {
+            reader,
+            big_endian,
+            format: Format::Pcap {
+                linktype,
+                resolution,
+            },
+        })
+    }
+
+    fn u16(&self, b: &[u8]) -> u16 {
+        let b = [b[0], b[1]];
+        if self.big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        }
+    }
+
+    fn u32(&self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        if self.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    }
+
+    /// Read the rest of a section header, after the block type
+    async fn read_section_header(&mut self) -> Result<()> {
+        let mut hdr = [0u8; 8];
+        self.reader.read_exact(&mut hdr).await
+            .context("Failed to read pcapng section header")?;
+
+        self.big_endian = match (u32::from_be_bytes([hdr[4], hdr[5], hdr[6], hdr[7]]), u32::from_le_bytes([hdr[4], hdr[5], hdr[6], hdr[7]])) {
+            (BYTE_ORDER_MAGIC, _) => true,
+            (_, BYTE_ORDER_MAGIC) => false,
+            _ => bail!("Invalid pcapng byte order magic"),
+        };
+
+        let len = self.u32(&hdr[0..4]) as usize;
+        if !(12..=MAX_BLOCK_LEN).contains(&len) {
+            bail!("Invalid pcapng section header length: {}", len);
+        }
+        // version, section length, options and trailing length
+        let mut rest = vec![0u8; len - 12];
+        self.reader.read_exact(&mut rest).await?;
+
+        // interfaces are numbered per section
+        self.format = Format::Pcapng {
+            interfaces: Vec::new(),
+        };
+        Ok(())
+    }
+
+    /// Returns None at the end of the stream
+    pub async fn next(&mut self) -> Result<Option<Frame>> {
+        match self.format {
+            Format::Pcap { linktype, resolution } => self.next_pcap(linktype, resolution).await,
+            Format::Pcapng { .. } => self.next_pcapng().await,
+        }
+    }
+
+    /// Read a fixed size header, returns None if the stream ended cleanly before it
+    async fn read_header(&mut self, buf: &mut [u8]) -> Result<bool> {
+        match self.reader.read_exact(buf).await {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn next_pcap(&mut self, linktype: u32, resolution: Resolution) -> Result<Option<Frame>> {
+        let mut hdr = [0u8; 16];
+        if !self.read_header(&mut hdr).await? {
+            return Ok(None);
+        }
+
+        let secs = self.u32(&hdr[0..4]) as u64;
+        let frac = self.u32(&hdr[4..8]) as u64;
+        let caplen = self.u32(&hdr[8..12]) as usize;
+        if caplen > MAX_PACKET_LEN {
+            bail!("Pcap record is too large: {} bytes", caplen);
+        }
+
+        let mut data = vec![0u8; caplen];
+        self.reader.read_exact(&mut data).await
+            .context("Failed to read pcap record")?;
+
+        Ok(Some(Frame {
+            interface: 0,
+            name: None,
+            linktype,
+            timestamp: Duration::from_secs(secs) + resolution.to_duration(frac),
+            data,
+        }))
+    }
+
+    async fn next_pcapng(&mut self) -> Result<Option<Frame>> {
+        loop {
+            let mut hdr = [0u8; 4];
+            if !self.read_header(&mut hdr).await? {
+                return Ok(None);
+            }
+
+            let block_type = self.u32(&hdr);
+            if u32::from_be_bytes(hdr) == BLOCK_SECTION_HEADER {
+                self.read_section_header().await?;
+                continue;
+            }
+
+            self.reader.read_exact(&mut hdr).await
+                .context("Failed to read pcapng block header")?;
+            let len = self.u32(&hdr) as usize;
+            if !(12..=MAX_BLOCK_LEN).contains(&len) || !len.is_multiple_of(4) {
+                bail!("Invalid pcapng block length: {}", len);
+            }
+
+            let mut body = vec![0u8; len - 8];
+            self.reader.read_exact(&mut body).await
+                .context("Failed to read pcapng block")?;
+            // strip the trailing length
+            body.truncate(len - 12);
+
+            match block_type {
+                BLOCK_INTERFACE_DESCRIPTION => {
+                    let interface = self.parse_interface(&body)?;
+                    if let Format::Pcapng { interfaces } = &mut self.format {
+                        interfaces.push(interface);
+                    }
+                }
+                BLOCK_ENHANCED_PACKET => {
+                    if body.len() < 20 {
+                        bail!("Pcapng enhanced packet block is truncated");
+                    }
+                    let interface = self.u32(&body[0..4]);
+                    let ts = (self.u32(&body[4..8]) as u64) << 32 | self.u32(&body[8..12]) as u64;
+                    let caplen = self.u32(&body[12..16]) as usize;
+                    return self.frame(interface, ts, &body[20..], caplen).map(Some);
+                }
+                BLOCK_PACKET => {
+                    if body.len() < 20 {
+                        bail!("Pcapng packet block is truncated");
+                    }
+                    let interface = self.u16(&body[0..2]) as u32;
+                    let ts = (self.u32(&body[4..8]) as u64) << 32 | self.u32(&body[8..12]) as u64;
+                    let caplen = self.u32(&body[12..16]) as usize;
+                    return self.frame(interface, ts, &body[20..], caplen).map(Some);
+                }
+                BLOCK_SIMPLE_PACKET => {
+                    if body.len() < 4 {
+                        bail!("Pcapng simple packet block is truncated");
+                    }
+                    // there's no timestamp and no captured length
+                    let origlen = self.u32(&body[0..4]) as usize;
+                    let caplen = origlen.min(body.len() - 4);
+                    return self.frame(0, 0, &body[4..], caplen).map(Some);
+                }
+                _ => trace!("Skipping pcapng block: {:#x}", block_type),
+            }
+        }
+    }
+
+    fn parse_interface(&self, body: &[u8]) -> Result<Interface> {
+        if body.len() < 8 {
+            bail!("Pcapng interface description block is truncated");
+        }
+        let linktype = self.u16(&body[0..2]) as u32;
+        let mut resolution = Resolution::Decimal(6);
+        let mut name = None;
+
+        let mut options = &body[8..];
+        while options.len() >= 4 {
+            let code = self.u16(&options[0..2]);
+            let len = self.u16(&options[2..4]) as usize;
+            let value = options.get(4..4 + len)
+                .context("Pcapng interface option is truncated")?;
+            match code {
+                OPT_END => break,
+                OPT_IF_TSRESOL if len == 1 => resolution = Resolution::from_option(value[0]),
+                OPT_IF_NAME => name = Some(Arc::from(String::from_utf8_lossy(value).trim_end_matches('\0'))),
+                _ => (),
+            }
+            let padded = (len + 3) & !3;
+            options = options.get(4 + padded..).unwrap_or_default();
+        }
+
+        Ok(Interface {
+            linktype,
+            resolution,
+            name,
+        })
+    }
+
+    fn frame(&self, interface: u32, ts: u64, data: &[u8], caplen: usize) -> Result<Frame> {
+        let iface = match &self.format {
+            Format::Pcapng { interfaces } => interfaces.get(interface as usize),
+            Format::Pcap { .. } => None,
+        }.with_context(|| anyhow!("Packet references unknown interface: {}", interface))?;
+
+        let data = data.get(..caplen)
+            .context("Pcapng packet is truncated")?;
+
+        Ok(Frame {
+            interface,
+            name: iface.name.clone(),
+            linktype: iface.linktype,
+            timestamp: iface.resolution.to_duration(ts),
+            data: data.to_vec(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Pending {
+    timestamp: Duration,
+    seq: u64,
+    frame: Frame,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Pending) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Pending) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Pending) -> Ordering {
+        (self.timestamp, self.seq).cmp(&(other.timestamp, other.seq))
+    }
+}
+
+/// Put frames of different interfaces back in order, capture files interleave them in blocks.
+/// Only `capacity` frames are held back, frames that are further out of order are passed on late
+pub struct Reorder {
+    capacity: usize,
+    seq: u64,
+    pending: BinaryHeap<Reverse<Pending>>,
+}
+
+impl Reorder {
+    pub fn new(capacity: usize) -> Reorder {
+        Reorder {
+            capacity,
+            seq: 0,
+            pending: BinaryHeap::new(),
+        }
+    }
+
+    /// Add a frame, returns the oldest one once more than `capacity` frames are held back
+    pub fn push(&mut self, frame: Frame) -> Option<Frame> {
+        self.pending.push(Reverse(Pending {
+            timestamp: frame.timestamp,
+            seq: self.seq,
+            frame,
+        }));
+        self.seq += 1;
+        if self.pending.len() > self.capacity {
+            self.pop()
+        } else {
+            None
+        }
+    }
+
+    /// Take the oldest frame, e.g. to flush the rest at the end of the capture
+    pub fn pop(&mut self) -> Option<Frame> {
+        self.pending.pop().map(|Reverse(pending)| pending.frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(secs: u32, micros: u32, data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(secs.to_le_bytes());
+        buf.extend(micros.to_le_bytes());
+        buf.extend((data.len() as u32).to_le_bytes());
+        buf.extend((data.len() as u32).to_le_bytes());
+        buf.extend(data);
+        buf
+    }
+
+    fn header(magic: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(magic.to_le_bytes());
+        buf.extend(2u16.to_le_bytes());
+        buf.extend(4u16.to_le_bytes());
+        buf.extend([0; 8]);
+        buf.extend(65535u32.to_le_bytes());
+        buf.extend(LINKTYPE_ETHERNET.to_le_bytes());
+        buf
+    }
+
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let mut body = body.to_vec();
+        while !body.len().is_multiple_of(4) {
+            body.push(0);
+        }
+        let len = body.len() as u32 + 12;
+        let mut buf = Vec::new();
+        buf.extend(block_type.to_le_bytes());
+        buf.extend(len.to_le_bytes());
+        buf.extend(body);
+        buf.extend(len.to_le_bytes());
+        buf
+    }
+
+    fn section_header() -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend(BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend(1u16.to_le_bytes());
+        body.extend(0u16.to_le_bytes());
+        body.extend((-1i64).to_le_bytes());
+        block(BLOCK_SECTION_HEADER, &body)
+    }
+
+    fn interface(linktype: u16, tsresol: Option<u8>, name: Option<&str>) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend(linktype.to_le_bytes());
+        body.extend([0, 0]);
+        body.extend(65535u32.to_le_bytes());
+        if let Some(name) = name {
+            body.extend(OPT_IF_NAME.to_le_bytes());
+            body.extend((name.len() as u16).to_le_bytes());
+            body.extend(name.as_bytes());
+            body.extend(vec![0; (4 - name.len() % 4) % 4]);
+        }
+        if let Some(tsresol) = tsresol {
+            body.extend(OPT_IF_TSRESOL.to_le_bytes());
+            body.extend(1u16.to_le_bytes());
+            body.extend([tsresol, 0, 0, 0]);
+            body.extend([0; 4]);
+        }
+        block(BLOCK_INTERFACE_DESCRIPTION, &body)
+    }
+
+    fn enhanced_packet(interface: u32, ts: u64, data: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend(interface.to_le_bytes());
+        body.extend(((ts >> 32) as u32).to_le_bytes());
+        body.extend((ts as u32).to_le_bytes());
+        body.extend((data.len() as u32).to_le_bytes());
+        body.extend((data.len() as u32).to_le_bytes());
+        body.extend(data);
+        block(BLOCK_ENHANCED_PACKET, &body)
+    }
+
+    #[tokio::test]
+    async fn read_frames() {
+        let mut buf = header(MAGIC_MICROS);
+        buf.extend(record(1600000000, 500, b"hello"));
+        buf.extend(record(1600000001, 0, b"world"));
+
+        let mut pcap = PcapReader::new(&buf[..]).await.unwrap();
+        let frame = pcap.next().await.unwrap().unwrap();
+        assert_eq!(frame.linktype, LINKTYPE_ETHERNET);
+        assert_eq!(frame.data, b"hello");
+        assert_eq!(frame.timestamp, Duration::new(1600000000, 500_000));
+        let frame = pcap.next().await.unwrap().unwrap();
+        assert_eq!(frame.data, b"world");
+        assert_eq!(pcap.next().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn nanosecond_timestamps() {
+        let mut buf = header(MAGIC_NANOS);
+        buf.extend(record(1, 500, b"x"));
+        let mut pcap = PcapReader::new(&buf[..]).await.unwrap();
+        assert_eq!(pcap.next().await.unwrap().unwrap().timestamp, Duration::new(1, 500));
+    }
+
+    #[tokio::test]
+    async fn truncated_record() {
+        let mut buf = header(MAGIC_MICROS);
+        buf.extend(record(1, 0, b"hello"));
+        buf.truncate(buf.len() - 2);
+        let mut pcap = PcapReader::new(&buf[..]).await.unwrap();
+        assert!(pcap.next().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn not_pcap() {
+        assert!(PcapReader::new(&b"{\"Ether\":[{\"source_mac\":[]}]}"[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pcapng_interfaces() {
+        let mut buf = section_header();
+        buf.extend(interface(LINKTYPE_ETHERNET as u16, None, Some("wlan1")));
+        buf.extend(interface(LINKTYPE_RAW as u16, Some(9), None));
+        // unknown blocks are skipped
+        buf.extend(block(0x0bad, b"ignored"));
+        buf.extend(enhanced_packet(0, 1_600_000_000_000_500, b"ether"));
+        buf.extend(enhanced_packet(1, 1_600_000_000_000_000_500, b"raw"));
+
+        let mut pcap = PcapReader::new(&buf[..]).await.unwrap();
+        assert_eq!(pcap.next().await.unwrap(), Some(Frame {
+            interface: 0,
+            name: Some(Arc::from("wlan1")),
+            linktype: LINKTYPE_ETHERNET,
+            timestamp: Duration::new(1600000000, 500_000),
+            data: b"ether".to_vec(),
+        }));
+        assert_eq!(pcap.next().await.unwrap(), Some(Frame {
+            interface: 1,
+            name: None,
+            linktype: LINKTYPE_RAW,
+            timestamp: Duration::new(1600000000, 500),
+            data: b"raw".to_vec(),
+        }));
+        assert_eq!(pcap.next().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn pcapng_new_section_resets_interfaces() {
+        let mut buf = section_header();
+        buf.extend(interface(LINKTYPE_ETHERNET as u16, None, None));
+        buf.extend(section_header());
+        buf.extend(enhanced_packet(0, 0, b"ether"));
+
+        let mut pcap = PcapReader::new(&buf[..]).await.unwrap();
+        assert!(pcap.next().await.is_err());
+    }
+
+    #[test]
+    fn binary_resolution() {
+        assert_eq!(Resolution::from_option(0x80 | 10).to_duration(1024 * 3 + 512), Duration::new(3, 500_000_000));
+        assert_eq!(Resolution::from_option(3).to_duration(1500), Duration::new(1, 500_000_000));
+    }
+
+    #[test]
+    fn reorder_frames() {
+        let frame = |ms: u64, data: &[u8]| Frame {
+            interface: 0,
+            name: None,
+            linktype: LINKTYPE_ETHERNET,
+            timestamp: Duration::from_millis(ms),
+            data: data.to_vec(),
+        };
+        let mut reorder = Reorder::new(2);
+        assert_eq!(reorder.push(frame(20, b"b")), None);
+        assert_eq!(reorder.push(frame(10, b"a")), None);
+        assert_eq!(reorder.push(frame(20, b"c")).unwrap().data, b"a");
+        assert_eq!(reorder.push(frame(30, b"d")).unwrap().data, b"b");
+        // too late to be sorted in, but it's not lost
+        assert_eq!(reorder.push(frame(5, b"e")).unwrap().data, b"e");
+        assert_eq!(reorder.pop().unwrap().data, b"c");
+        assert_eq!(reorder.pop().unwrap().data, b"d");
+        assert_eq!(reorder.pop(), None);
+    }
+}
@@ -0,0 +1,219 @@
+use std::fmt::Write;
+
+const WIDTH: f32 = 595.0;
+const HEIGHT: f32 = 842.0;
+const MARGIN: f32 = 50.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Title,
+    Heading,
+    Text,
+    Bold,
+}
+
+impl Style {
+    fn font(&self) -> &'static str {
+        match self {
+            Style::Text => "F1",
+            _ => "F2",
+        }
+    }
+
+    fn size(&self) -> f32 {
+        match self {
+            Style::Title => 18.0,
+            Style::Heading => 13.0,
+            Style::Text | Style::Bold => 10.0,
+        }
+    }
+
+    fn leading(&self) -> f32 {
+        self.size() * 1.45
+    }
+
+    /// Helvetica is about half as wide as it's high on average, wide enough to never overflow for normal text
+    fn max_chars(&self) -> usize {
+        ((WIDTH - 2.0 * MARGIN) / (self.size() * 0.55)) as usize
+    }
+}
+
+/// Only latin1 can be shown with the standard fonts without embedding one
+fn escape(text: &str, out: &mut Vec<u8>) {
+    for c in text.chars() {
+        match c {
+            '(' | ')' | '\\' => {
+                out.push(b'\\');
+                out.push(c as u8);
+            }
+            ' '..='~' | '\u{a0}'..='\u{ff}' => out.push(c as u32 as u8),
+            _ => out.push(b'?'),
+        }
+    }
+}
+
+fn wrap(text: &str, max: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > max {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+        // words that don't fit on a line at all, e.g. hashes
+        while line.chars().count() > max {
+            let split = line.char_indices().nth(max).map(|(i, _)| i).unwrap_or(line.len());
+            let rest = line.split_off(split);
+            lines.push(std::mem::replace(&mut line, rest));
+        }
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// A minimal pdf writer for text documents, uses the standard fonts so the file doesn't depend on anything else
+#[derive(Debug)]
+pub struct Document {
+    pages: Vec<Vec<u8>>,
+    y: f32,
+}
+
+impl Default for Document {
+    fn default() -> Document {
+        Document {
+            pages: Vec::new(),
+            y: 0.0,
+        }
+    }
+}
+
+impl Document {
+    pub fn new() -> Document {
+        Document::default()
+    }
+
+    fn line(&mut self, style: Style, text: &str) {
+        if self.pages.is_empty() || self.y - style.leading() < MARGIN {
+            self.pages.push(Vec::new());
+            self.y = HEIGHT - MARGIN;
+        }
+        self.y -= style.leading();
+
+        let page = self.pages.last_mut().unwrap();
+        let mut op = String::new();
+        write!(op, "BT /{} {} Tf 1 0 0 1 {} {:.1} Tm (", style.font(), style.size(), MARGIN, self.y).ok();
+        page.extend(op.as_bytes());
+        escape(text, page);
+        page.extend(b") Tj ET\n");
+    }
+
+    /// Add a paragraph, it's wrapped to the width of the page
+    pub fn push(&mut self, style: Style, text: &str) {
+        for line in wrap(text, style.max_chars()) {
+            self.line(style, &line);
+        }
+    }
+
+    pub fn space(&mut self) {
+        self.y -= Style::Text.leading() / 2.0;
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages.len().max(1)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut objects = vec![
+            b"<< /Type /Catalog /Pages 2 0 R >>".to_vec(),
+            Vec::new(),
+            b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>".to_vec(),
+            b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding /WinAnsiEncoding >>".to_vec(),
+        ];
+
+        let empty = vec![Vec::new()];
+        let pages = if self.pages.is_empty() { &empty } else { &self.pages };
+        let mut kids = Vec::new();
+        for content in pages {
+            let page = objects.len() + 1;
+            kids.push(format!("{} 0 R", page));
+            objects.push(format!("<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /Font << /F1 3 0 R /F2 4 0 R >> >> /Contents {} 0 R >>",
+                WIDTH, HEIGHT, page + 1).into_bytes());
+
+            let mut stream = format!("<< /Length {} >>\nstream\n", content.len()).into_bytes();
+            stream.extend(content);
+            stream.extend(b"\nendstream");
+            objects.push(stream);
+        }
+        objects[1] = format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids.join(" "), kids.len()).into_bytes();
+
+        let mut out = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+        let mut offsets = Vec::new();
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(out.len());
+            out.extend(format!("{} 0 obj\n", i + 1).as_bytes());
+            out.extend(object);
+            out.extend(b"\nendobj\n");
+        }
+
+        let xref = out.len();
+        let mut trailer = format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1);
+        for offset in offsets {
+            writeln!(trailer, "{:010} 00000 n ", offset).ok();
+        }
+        write!(trailer, "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n", objects.len() + 1, xref).ok();
+        out.extend(trailer.as_bytes());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_lines() {
+        assert_eq!(wrap("a b c", 3), vec!["a b", "c"]);
+        assert_eq!(wrap("", 3), vec![""]);
+        assert_eq!(wrap("abcdefg h", 3), vec!["abc", "def", "g h"]);
+    }
+
+    #[test]
+    fn escape_text() {
+        let mut out = Vec::new();
+        escape("(a\\b) ü ✓", &mut out);
+        assert_eq!(out, b"\\(a\\\\b\\) \xfc ?");
+    }
+
+    #[test]
+    fn xref_offsets() {
+        let mut doc = Document::new();
+        doc.push(Style::Title, "Report");
+        for i in 0..120 {
+            doc.push(Style::Text, &format!("line {}", i));
+        }
+        assert_eq!(doc.pages(), 3);
+
+        let pdf = doc.to_bytes();
+        assert!(pdf.starts_with(b"%PDF-1.4\n"));
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.contains("/Count 3"));
+        assert!(text.contains("(line 99) Tj"));
+
+        // every object starts where the xref table says it does
+        let xref = text.rsplit("startxref\n").next().unwrap().lines().next().unwrap().parse::<usize>().unwrap();
+        let table = &pdf[xref..];
+        let offsets = String::from_utf8_lossy(table).lines()
+            .filter(|line| line.ends_with(" n "))
+            .map(|line| line[..10].parse::<usize>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(offsets.len(), 4 + 2 * 3);
+        for (i, offset) in offsets.into_iter().enumerate() {
+            assert!(pdf[offset..].starts_with(format!("{} 0 obj", i + 1).as_bytes()));
+        }
+    }
+}
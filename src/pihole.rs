@@ -0,0 +1,145 @@
+use crate::errors::*;
+use crate::observation::Observation;
+use futures::{Sink, SinkExt};
+use rusqlite::{Connection, OpenFlags};
+use std::time::Duration;
+
+const BATCH_SIZE: i64 = 1000;
+
+/// FTL stores the query type as an enum instead of the dns type
+fn qtype_str(qtype: i64) -> String {
+    let s = match qtype {
+        1 => "A",
+        2 => "AAAA",
+        3 => "ANY",
+        4 => "SRV",
+        5 => "SOA",
+        6 => "PTR",
+        7 => "TXT",
+        8 => "NAPTR",
+        9 => "MX",
+        10 => "DS",
+        11 => "RRSIG",
+        12 => "DNSKEY",
+        13 => "NS",
+        15 => "SVCB",
+        16 => "HTTPS",
+        _ => "OTHER",
+    };
+    s.to_string()
+}
+
+/// Read the next batch of queries after `last_id`, returns the id of the last row
+fn fetch(db: &Connection, last_id: i64) -> Result<(i64, Vec<Observation>)> {
+    let mut stmt = db.prepare_cached("SELECT id, type, domain, client FROM queries WHERE id > ?1 ORDER BY id LIMIT ?2")?;
+    let mut rows = stmt.query((last_id, BATCH_SIZE))?;
+
+    let mut last_id = last_id;
+    let mut observations = Vec::new();
+    while let Some(row) = rows.next()? {
+        last_id = row.get(0)?;
+        let qtype = row.get::<_, i64>(1)?;
+        let name = row.get::<_, String>(2)?;
+        let client = row.get::<_, String>(3)?;
+
+        match client.parse() {
+            Ok(client) => observations.push(Observation::Query {
+                client,
+                qtype: qtype_str(qtype),
+                name,
+            }),
+            Err(_) => trace!("Skipping query with invalid client address: {:?}", client),
+        }
+    }
+
+    Ok((last_id, observations))
+}
+
+/// The id of the newest query, or 0 if there are none
+fn latest_id(db: &Connection) -> Result<i64> {
+    let id = db.query_row("SELECT COALESCE(MAX(id), 0) FROM queries", [], |row| row.get(0))?;
+    Ok(id)
+}
+
+/// Poll pihole's FTL database for new queries, with `backfill` the queries that are already stored are read first
+pub async fn follow<S: Sink<Observation> + Unpin>(path: String, backfill: bool, mut sink: S) -> Result<()> {
+    let db = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| anyhow!("Failed to open pihole database {:?}", path))?;
+    info!("Reading queries from {:?}", path);
+
+    let mut last_id = if backfill { 0 } else { latest_id(&db)? };
+    let mut db = Some(db);
+    loop {
+        let conn = db.take().expect("connection is always returned");
+        let (conn, result) = tokio::task::spawn_blocking(move || {
+            let result = fetch(&conn, last_id);
+            (conn, result)
+        }).await?;
+        db = Some(conn);
+
+        let (id, observations) = result?;
+        let idle = id == last_id;
+        last_id = id;
+
+        for obs in observations {
+            sink.send(obs).await.map_err(|_| anyhow!("sink error"))?;
+        }
+
+        // FTL only writes to the database periodically
+        if idle {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db() -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch("
+            CREATE TABLE queries (id INTEGER PRIMARY KEY, timestamp INTEGER NOT NULL, type INTEGER NOT NULL, status INTEGER NOT NULL, domain TEXT NOT NULL, client TEXT NOT NULL, forward TEXT);
+            INSERT INTO queries VALUES (1, 1682942400, 1, 2, 'example.com', '10.38.73.100', '1.1.1.1#53');
+            INSERT INTO queries VALUES (2, 1682942401, 7, 2, 'exfil.example.com', 'fd00::1337', '1.1.1.1#53');
+            INSERT INTO queries VALUES (3, 1682942402, 1, 2, 'example.com', 'localhost', NULL);
+        ").unwrap();
+        db
+    }
+
+    #[test]
+    fn fetch_queries() {
+        let db = db();
+        let (last_id, observations) = fetch(&db, 0).unwrap();
+        assert_eq!(last_id, 3);
+        assert_eq!(observations, vec![
+            Observation::Query {
+                client: "10.38.73.100".parse().unwrap(),
+                qtype: "A".to_string(),
+                name: "example.com".to_string(),
+            },
+            Observation::Query {
+                client: "fd00::1337".parse().unwrap(),
+                qtype: "TXT".to_string(),
+                name: "exfil.example.com".to_string(),
+            },
+        ]);
+    }
+
+    #[test]
+    fn start_after_latest() {
+        let db = db();
+        assert_eq!(latest_id(&db).unwrap(), 3);
+        db.execute_batch("DELETE FROM queries").unwrap();
+        assert_eq!(latest_id(&db).unwrap(), 0);
+    }
+
+    #[test]
+    fn fetch_only_new_queries() {
+        let db = db();
+        let (last_id, observations) = fetch(&db, 2).unwrap();
+        assert_eq!(last_id, 3);
+        assert_eq!(observations, vec![]);
+        assert_eq!(fetch(&db, 3).unwrap(), (3, vec![]));
+    }
+}
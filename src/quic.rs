@@ -0,0 +1,414 @@
+use crate::errors::*;
+use crate::reader::Reader;
+use crate::tls::{self, ClientHello};
+use aes::Aes128;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::cipher::generic_array::GenericArray;
+use aes_gcm::Aes128Gcm;
+use aes_gcm::aead::{Aead, Payload};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::collections::HashMap;
+
+pub const VERSION_1: u32 = 0x0000_0001;
+pub const VERSION_2: u32 = 0x6b33_43cf;
+
+// rfc9001 section 5.2
+const SALT_V1: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17,
+    0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+];
+// rfc9369 section 3.3.1
+const SALT_V2: [u8; 20] = [
+    0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93,
+    0x81, 0xbe, 0x6e, 0x26, 0x9d, 0xcb, 0xf9, 0xbd, 0x2e, 0xd9,
+];
+
+const FRAME_PADDING: u64 = 0x00;
+const FRAME_PING: u64 = 0x01;
+const FRAME_ACK: u64 = 0x02;
+const FRAME_ACK_ECN: u64 = 0x03;
+const FRAME_CRYPTO: u64 = 0x06;
+const FRAME_CONNECTION_CLOSE: u64 = 0x1c;
+
+/// Stop tracking connections that never complete their ClientHello
+const MAX_PENDING: usize = 256;
+/// A ClientHello is usually 2 packets at most, even with post-quantum key shares
+const MAX_CRYPTO_LEN: u64 = 16 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Keys {
+    pub key: [u8; 16],
+    pub iv: [u8; 12],
+    pub hp: [u8; 16],
+}
+
+fn expand_label(hkdf: &Hkdf<Sha256>, label: &str, out: &mut [u8]) {
+    let label = format!("tls13 {}", label);
+    let mut info = Vec::with_capacity(4 + label.len());
+    info.extend(&(out.len() as u16).to_be_bytes());
+    info.push(label.len() as u8);
+    info.extend(label.as_bytes());
+    // empty context
+    info.push(0);
+    hkdf.expand(&info, out)
+        .expect("Output length is always valid for sha256");
+}
+
+/// Derive the keys of the client Initial packets from the destination connection id
+pub fn client_initial_keys(version: u32, dcid: &[u8]) -> Result<Keys> {
+    let (salt, prefix) = match version {
+        VERSION_1 => (&SALT_V1, "quic"),
+        VERSION_2 => (&SALT_V2, "quicv2"),
+        _ => bail!("Unsupported quic version: {:#010x}", version),
+    };
+
+    let (initial, _) = Hkdf::<Sha256>::extract(Some(salt), dcid);
+    let initial = Hkdf::<Sha256>::from_prk(&initial)
+        .expect("Extracted key is always valid");
+    let mut client = [0u8; 32];
+    expand_label(&initial, "client in", &mut client);
+    let client = Hkdf::<Sha256>::from_prk(&client)
+        .expect("Expanded key is always valid");
+
+    let mut keys = Keys {
+        key: [0; 16],
+        iv: [0; 12],
+        hp: [0; 16],
+    };
+    expand_label(&client, &format!("{} key", prefix), &mut keys.key);
+    expand_label(&client, &format!("{} iv", prefix), &mut keys.iv);
+    expand_label(&client, &format!("{} hp", prefix), &mut keys.hp);
+    Ok(keys)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum PacketType {
+    Initial,
+    ZeroRtt,
+    Handshake,
+    Retry,
+}
+
+fn packet_type(version: u32, first: u8) -> Result<PacketType> {
+    let kind = (first & 0x30) >> 4;
+    // quic v2 uses different values on purpose
+    let kind = match version {
+        VERSION_1 => kind,
+        VERSION_2 => kind.wrapping_sub(1) & 0x03,
+        _ => bail!("Unsupported quic version: {:#010x}", version),
+    };
+    Ok(match kind {
+        0 => PacketType::Initial,
+        1 => PacketType::ZeroRtt,
+        2 => PacketType::Handshake,
+        _ => PacketType::Retry,
+    })
+}
+
+/// The decrypted content of a client Initial packet
+#[derive(Debug, PartialEq, Eq)]
+pub struct Initial {
+    pub version: u32,
+    pub dcid: Vec<u8>,
+    /// CRYPTO frames as (offset, data)
+    pub crypto: Vec<(u64, Vec<u8>)>,
+}
+
+fn parse_frames(payload: &[u8]) -> Result<Vec<(u64, Vec<u8>)>> {
+    let mut r = Reader::new(payload);
+    let mut crypto = Vec::new();
+
+    while !r.is_empty() {
+        match r.varint()? {
+            FRAME_PADDING | FRAME_PING => (),
+            kind @ (FRAME_ACK | FRAME_ACK_ECN) => {
+                // largest acknowledged, delay
+                r.varint()?;
+                r.varint()?;
+                let ranges = r.varint()?;
+                // first range
+                r.varint()?;
+                for _ in 0..ranges {
+                    // gap, length
+                    r.varint()?;
+                    r.varint()?;
+                }
+                if kind == FRAME_ACK_ECN {
+                    for _ in 0..3 {
+                        r.varint()?;
+                    }
+                }
+            }
+            FRAME_CRYPTO => {
+                let offset = r.varint()?;
+                let len = r.varint()? as usize;
+                crypto.push((offset, r.take(len)?.to_vec()));
+            }
+            FRAME_CONNECTION_CLOSE => {
+                // error code, frame type, reason phrase
+                r.varint()?;
+                r.varint()?;
+                let len = r.varint()? as usize;
+                r.skip(len)?;
+            }
+            // no other frames are allowed in Initial packets
+            kind => bail!("Unexpected frame in quic Initial packet: {:#x}", kind),
+        }
+    }
+
+    Ok(crypto)
+}
+
+/// Decrypt the long header packet at the start of `buf`, returns the packet and its length
+fn decrypt_packet(buf: &[u8]) -> Result<(Option<Initial>, usize)> {
+    let mut r = Reader::new(buf);
+    let first = r.u8()?;
+    if first & 0x80 == 0 {
+        bail!("Not a quic long header packet");
+    }
+    let version = r.u32()?;
+    let dcid = r.prefixed(|r| Ok(r.u8()? as usize))?.rest().to_vec();
+    let _scid = r.prefixed(|r| Ok(r.u8()? as usize))?;
+
+    match packet_type(version, first)? {
+        PacketType::Initial => (),
+        // retry packets use the rest of the datagram
+        PacketType::Retry => return Ok((None, buf.len())),
+        PacketType::ZeroRtt | PacketType::Handshake => {
+            let len = r.varint()? as usize;
+            let header_len = buf.len() - r.remaining();
+            r.skip(len)?;
+            return Ok((None, header_len + len));
+        }
+    }
+
+    let token_len = r.varint()? as usize;
+    r.skip(token_len)?;
+    let len = r.varint()? as usize;
+    let pn_offset = buf.len() - r.remaining();
+    if r.remaining() < len {
+        bail!("Quic packet is truncated");
+    }
+    // the packet number, the header protection sample and the aead tag have to fit in the packet
+    if len < 20 {
+        bail!("Quic packet is too short for header protection sample");
+    }
+    let packet_len = pn_offset + len;
+
+    let keys = client_initial_keys(version, &dcid)?;
+
+    // remove header protection, the sample assumes a 4 byte packet number
+    let sample = &buf[pn_offset + 4..pn_offset + 20];
+    let mut mask = GenericArray::clone_from_slice(sample);
+    Aes128::new(GenericArray::from_slice(&keys.hp)).encrypt_block(&mut mask);
+
+    let mut header = buf[..pn_offset + 4].to_vec();
+    header[0] ^= mask[0] & 0x0f;
+    let pn_len = (header[0] & 0x03) as usize + 1;
+    header.truncate(pn_offset + pn_len);
+
+    let mut pn = 0u64;
+    for i in 0..pn_len {
+        header[pn_offset + i] ^= mask[1 + i];
+        pn = (pn << 8) | header[pn_offset + i] as u64;
+    }
+
+    let mut nonce = keys.iv;
+    for (n, p) in nonce.iter_mut().rev().zip(pn.to_le_bytes().iter()) {
+        *n ^= *p;
+    }
+
+    let cipher = Aes128Gcm::new(GenericArray::from_slice(&keys.key));
+    let payload = cipher.decrypt(GenericArray::from_slice(&nonce), Payload {
+        msg: &buf[pn_offset + pn_len..packet_len],
+        aad: &header,
+    }).map_err(|_| anyhow!("Failed to decrypt quic Initial packet"))?;
+
+    let crypto = parse_frames(&payload)?;
+    Ok((Some(Initial {
+        version,
+        dcid,
+        crypto,
+    }), packet_len))
+}
+
+/// Decrypt all client Initial packets in a udp datagram
+pub fn parse_initials(datagram: &[u8]) -> Result<Vec<Initial>> {
+    let mut initials = Vec::new();
+    let mut offset = 0;
+    // coalesced packets are all long header packets, except for the last one
+    while offset < datagram.len() && datagram[offset] & 0x80 != 0 {
+        let (initial, len) = decrypt_packet(&datagram[offset..])?;
+        initials.extend(initial);
+        offset += len;
+    }
+    Ok(initials)
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    fragments: Vec<(u64, Vec<u8>)>,
+}
+
+impl Pending {
+    /// Concatenate all data that is contiguous from offset 0
+    fn assemble(&mut self) -> Vec<u8> {
+        self.fragments.sort_by_key(|(offset, _)| *offset);
+        let mut buf = Vec::new();
+        for (offset, data) in &self.fragments {
+            let offset = *offset as usize;
+            if offset > buf.len() {
+                break;
+            }
+            let end = offset + data.len();
+            if end > buf.len() {
+                buf.extend(&data[buf.len() - offset..]);
+            }
+        }
+        buf
+    }
+}
+
+/// Reassemble ClientHellos that span multiple Initial packets
+#[derive(Debug, Default)]
+pub struct QuicTracker {
+    pending: HashMap<Vec<u8>, Pending>,
+}
+
+impl QuicTracker {
+    #[inline]
+    pub fn new() -> QuicTracker {
+        QuicTracker::default()
+    }
+
+    /// Returns the ClientHello once it has been fully received
+    pub fn process(&mut self, datagram: &[u8]) -> Result<Option<ClientHello>> {
+        for initial in parse_initials(datagram)? {
+            if initial.crypto.is_empty() {
+                continue;
+            }
+
+            if !self.pending.contains_key(&initial.dcid) && self.pending.len() >= MAX_PENDING {
+                debug!("Too many pending quic connections, discarding state");
+                self.pending.clear();
+            }
+
+            let pending = self.pending.entry(initial.dcid.clone()).or_default();
+            for (offset, data) in initial.crypto {
+                if offset + data.len() as u64 <= MAX_CRYPTO_LEN {
+                    pending.fragments.push((offset, data));
+                }
+            }
+
+            let buf = pending.assemble();
+            match tls::parse_handshake(&buf) {
+                Ok(Some(hello)) => {
+                    self.pending.remove(&initial.dcid);
+                    return Ok(Some(hello));
+                }
+                Ok(None) => (),
+                Err(err) => {
+                    self.pending.remove(&initial.dcid);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DCID: &[u8] = &[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+    #[test]
+    fn keys_v1() {
+        // rfc9001 appendix A.1
+        let keys = client_initial_keys(VERSION_1, DCID).unwrap();
+        assert_eq!(keys, Keys {
+            key: [0x1f, 0x36, 0x96, 0x13, 0xdd, 0x76, 0xd5, 0x46, 0x77, 0x30, 0xef, 0xcb, 0xe3, 0xb1, 0xa2, 0x2d],
+            iv: [0xfa, 0x04, 0x4b, 0x2f, 0x42, 0xa3, 0xfd, 0x3b, 0x46, 0xfb, 0x25, 0x5c],
+            hp: [0x9f, 0x50, 0x44, 0x9e, 0x04, 0xa0, 0xe8, 0x10, 0x28, 0x3a, 0x1e, 0x99, 0x33, 0xad, 0xed, 0xd2],
+        });
+    }
+
+    #[test]
+    fn keys_v2() {
+        // rfc9369 appendix A.1
+        let keys = client_initial_keys(VERSION_2, DCID).unwrap();
+        assert_eq!(keys, Keys {
+            key: [0x8b, 0x1a, 0x0b, 0xc1, 0x21, 0x28, 0x42, 0x90, 0xa2, 0x9e, 0x09, 0x71, 0xb5, 0xcd, 0x04, 0x5d],
+            iv: [0x91, 0xf7, 0x3e, 0x23, 0x51, 0xd8, 0xfa, 0x91, 0x66, 0x0e, 0x90, 0x9f],
+            hp: [0x45, 0xb9, 0x5e, 0x15, 0x23, 0x5d, 0x6f, 0x45, 0xa6, 0xb1, 0x9c, 0xbc, 0xb0, 0x29, 0x4b, 0xa9],
+        });
+    }
+
+    #[test]
+    fn packet_types() {
+        assert_eq!(packet_type(VERSION_1, 0xc0).unwrap(), PacketType::Initial);
+        assert_eq!(packet_type(VERSION_1, 0xf0).unwrap(), PacketType::Retry);
+        assert_eq!(packet_type(VERSION_2, 0xd0).unwrap(), PacketType::Initial);
+        assert_eq!(packet_type(VERSION_2, 0xc0).unwrap(), PacketType::Retry);
+        assert_eq!(packet_type(VERSION_2, 0xf0).unwrap(), PacketType::Handshake);
+        assert!(packet_type(0xff00_001d, 0xc0).is_err());
+    }
+
+    #[test]
+    fn initial_v1() {
+        let buf = include_bytes!("../fixtures/quic/initial_v1.bin");
+        let hello = QuicTracker::new().process(buf).unwrap().unwrap();
+        assert_eq!(hello.sni.as_deref(), Some("www.example.org"));
+        assert_eq!(hello.params.alpn_protocols, vec!["h3".to_string()]);
+    }
+
+    #[test]
+    fn initial_v2() {
+        let buf = include_bytes!("../fixtures/quic/initial_v2.bin");
+        let hello = QuicTracker::new().process(buf).unwrap().unwrap();
+        assert_eq!(hello.sni.as_deref(), Some("www.example.org"));
+    }
+
+    #[test]
+    fn initial_split() {
+        let mut quic = QuicTracker::new();
+        let buf = include_bytes!("../fixtures/quic/initial_split_1.bin");
+        assert_eq!(quic.process(buf).unwrap(), None);
+        let buf = include_bytes!("../fixtures/quic/initial_split_2.bin");
+        let hello = quic.process(buf).unwrap().unwrap();
+        assert_eq!(hello.sni.as_deref(), Some("www.example.org"));
+        assert!(quic.pending.is_empty());
+    }
+
+    #[test]
+    fn initial_split_reordered() {
+        let mut quic = QuicTracker::new();
+        let buf = include_bytes!("../fixtures/quic/initial_split_2.bin");
+        assert_eq!(quic.process(buf).unwrap(), None);
+        let buf = include_bytes!("../fixtures/quic/initial_split_1.bin");
+        let hello = quic.process(buf).unwrap().unwrap();
+        assert_eq!(hello.sni.as_deref(), Some("www.example.org"));
+    }
+
+    #[test]
+    fn tampered() {
+        let mut buf = include_bytes!("../fixtures/quic/initial_v1.bin").to_vec();
+        buf[100] ^= 0xff;
+        assert!(parse_initials(&buf).is_err());
+    }
+
+    #[test]
+    fn zero_length() {
+        // the Length doesn't even cover the packet number, the rest of the datagram must not be used
+        let buf = include_bytes!("../fixtures/quic/initial_zero_length.bin");
+        assert!(parse_initials(buf).is_err());
+        assert!(QuicTracker::new().process(buf).is_err());
+    }
+
+    #[test]
+    fn short_header() {
+        assert_eq!(parse_initials(&[0x40, 1, 2, 3]).unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::errors::*;
+
+/// Bounds checked big endian reader for binary protocols
+#[derive(Debug, Clone)]
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    #[inline]
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf }
+    }
+
+    #[inline]
+    pub fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    #[inline]
+    pub fn rest(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            bail!("Unexpected end of data, expected {} more bytes", n - self.buf.len());
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub fn skip(&mut self, n: usize) -> Result<()> {
+        self.take(n)?;
+        Ok(())
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub fn u24(&mut self) -> Result<u32> {
+        let b = self.take(3)?;
+        Ok(u32::from_be_bytes([0, b[0], b[1], b[2]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Variable-length integer as used by quic
+    pub fn varint(&mut self) -> Result<u64> {
+        let first = self.u8()?;
+        let len = 1 << (first >> 6);
+        let mut value = (first & 0x3f) as u64;
+        for b in self.take(len - 1)? {
+            value = (value << 8) | *b as u64;
+        }
+        Ok(value)
+    }
+
+    /// Read a length prefixed slice, the length is read with `len`
+    pub fn prefixed<F: FnOnce(&mut Self) -> Result<usize>>(&mut self, len: F) -> Result<Reader<'a>> {
+        let n = len(self)?;
+        Ok(Reader::new(self.take(n)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varints() {
+        // examples from rfc9000 appendix A.1
+        assert_eq!(Reader::new(&[0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8, 0x8c]).varint().unwrap(), 151_288_809_941_952_652);
+        assert_eq!(Reader::new(&[0x9d, 0x7f, 0x3e, 0x7d]).varint().unwrap(), 494_878_333);
+        assert_eq!(Reader::new(&[0x7b, 0xbd]).varint().unwrap(), 15_293);
+        assert_eq!(Reader::new(&[0x25]).varint().unwrap(), 37);
+        assert!(Reader::new(&[0x7b]).varint().is_err());
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let mut r = Reader::new(&[1, 2, 3]);
+        assert_eq!(r.u16().unwrap(), 0x0102);
+        assert!(r.u16().is_err());
+        assert_eq!(r.u8().unwrap(), 3);
+        assert!(r.is_empty());
+    }
+}
@@ -0,0 +1,315 @@
+use crate::errors::*;
+use crate::json::Flow;
+use crate::tls::{self, ClientHello};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Only the start of each stream is reassembled, this fits a ClientHello with post-quantum key shares
+const MAX_STREAM_LEN: usize = 8 * 1024;
+/// Stop tracking streams once this limit is reached
+const MAX_STREAMS: usize = 1024;
+/// Give up on streams without a syn if the start of the stream didn't show up by then
+const MAX_UNSYNCED_SEGMENTS: usize = 4;
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+
+/// A single tcp segment of a client to server stream
+#[derive(Debug, PartialEq, Eq)]
+pub struct Segment<'a> {
+    pub seq: u32,
+    pub syn: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub payload: &'a [u8],
+}
+
+#[derive(Debug, Default)]
+struct Stream {
+    /// Sequence number of the first byte of the stream
+    base: Option<u32>,
+    /// The base is known from the syn, otherwise it's a guess
+    synced: bool,
+    /// Segments as (offset, data), the offset is relative to base
+    segments: Vec<(usize, Vec<u8>)>,
+    /// Either a ClientHello was found or this isn't tls
+    done: bool,
+}
+
+impl Stream {
+    fn insert(&mut self, seq: u32, payload: &[u8]) {
+        let base = *self.base.get_or_insert(seq);
+
+        // the first segment we've seen was not the first one that was sent
+        let offset = seq.wrapping_sub(base) as i32;
+        if offset < 0 {
+            let shift = offset.unsigned_abs() as usize;
+            if self.synced || shift >= MAX_STREAM_LEN {
+                return;
+            }
+            for (offset, _) in &mut self.segments {
+                *offset += shift;
+            }
+            self.segments.retain(|(offset, _)| *offset < MAX_STREAM_LEN);
+            self.base = Some(seq);
+            self.segments.push((0, payload.to_vec()));
+            return;
+        }
+
+        let offset = offset as usize;
+        if offset >= MAX_STREAM_LEN {
+            return;
+        }
+        let len = payload.len().min(MAX_STREAM_LEN - offset);
+        self.segments.push((offset, payload[..len].to_vec()));
+    }
+
+    /// Concatenate all data that is contiguous from the start of the stream
+    fn assemble(&mut self) -> Vec<u8> {
+        self.segments.sort_by_key(|(offset, _)| *offset);
+        let mut buf = Vec::new();
+        for (offset, data) in &self.segments {
+            if *offset > buf.len() {
+                break;
+            }
+            let end = offset + data.len();
+            if end > buf.len() {
+                // retransmissions may overlap with data we already have
+                buf.extend(&data[buf.len() - offset..]);
+            }
+        }
+        buf
+    }
+}
+
+/// Reassemble the start of client to server tcp streams to find ClientHellos
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    streams: HashMap<(SocketAddr, SocketAddr), Stream>,
+}
+
+impl Reassembler {
+    #[inline]
+    pub fn new() -> Reassembler {
+        Reassembler::default()
+    }
+
+    fn make_room(&mut self) {
+        if self.streams.len() < MAX_STREAMS {
+            return;
+        }
+        self.streams.retain(|_, stream| !stream.done);
+        if self.streams.len() >= MAX_STREAMS {
+            debug!("Too many tcp streams, discarding reassembly state");
+            self.streams.clear();
+        }
+    }
+
+    /// There's nothing left to find in this stream, its payload doesn't need to be decoded
+    pub fn is_done(&self, flow: &Flow) -> bool {
+        self.streams.get(&(flow.src, flow.dst))
+            .map(|stream| stream.done)
+            .unwrap_or(false)
+    }
+
+    /// Returns the ClientHello once it has been fully received
+    pub fn process(&mut self, flow: &Flow, segment: &Segment) -> Result<Option<ClientHello>> {
+        let key = (flow.src, flow.dst);
+
+        if segment.rst || segment.fin {
+            self.streams.remove(&key);
+            return Ok(None);
+        }
+
+        if segment.syn {
+            self.make_room();
+            self.streams.insert(key, Stream {
+                // the syn itself counts as one byte
+                base: Some(segment.seq.wrapping_add(1)),
+                synced: true,
+                ..Default::default()
+            });
+            return Ok(None);
+        }
+
+        if segment.payload.is_empty() {
+            return Ok(None);
+        }
+
+        if !self.streams.contains_key(&key) {
+            self.make_room();
+        }
+        let stream = self.streams.entry(key).or_default();
+        if stream.done {
+            return Ok(None);
+        }
+        stream.insert(segment.seq, segment.payload);
+
+        let buf = stream.assemble();
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        if buf[0] != CONTENT_TYPE_HANDSHAKE {
+            // without a syn this might not be the start of the stream yet
+            if stream.synced || stream.segments.len() >= MAX_UNSYNCED_SEGMENTS {
+                stream.done = true;
+            }
+            return Ok(None);
+        }
+
+        match tls::parse_records(&buf) {
+            Ok(Some(hello)) => {
+                stream.done = true;
+                Ok(Some(hello))
+            }
+            Ok(None) => {
+                if buf.len() >= MAX_STREAM_LEN {
+                    stream.done = true;
+                }
+                Ok(None)
+            }
+            Err(err) => {
+                stream.done = true;
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::{self, Proto};
+
+    const HELLO: &[u8] = include_bytes!("../fixtures/tls/client_hello_large.bin");
+    const MSS: usize = 1448;
+
+    fn flow() -> Flow {
+        Flow {
+            proto: Proto::TCP,
+            src: "10.38.73.100:51234".parse().unwrap(),
+            dst: "93.184.216.34:443".parse().unwrap(),
+        }
+    }
+
+    fn syn(seq: u32) -> Segment<'static> {
+        Segment {
+            seq,
+            syn: true,
+            fin: false,
+            rst: false,
+            payload: &[],
+        }
+    }
+
+    fn data(seq: u32, payload: &[u8]) -> Segment<'_> {
+        Segment {
+            seq,
+            syn: false,
+            fin: false,
+            rst: false,
+            payload,
+        }
+    }
+
+    fn split(isn: u32, buf: &[u8], mss: usize) -> Vec<Segment<'_>> {
+        buf.chunks(mss)
+            .enumerate()
+            .map(|(i, chunk)| data(isn.wrapping_add(1 + (i * mss) as u32), chunk))
+            .collect()
+    }
+
+    fn feed(r: &mut Reassembler, segments: &[Segment]) -> Vec<ClientHello> {
+        segments.iter()
+            .flat_map(|segment| r.process(&flow(), segment).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn large_hello_needs_two_segments() {
+        assert!(HELLO.len() > MSS);
+        assert_eq!(tls::parse_records(&HELLO[..MSS]).unwrap(), None);
+    }
+
+    #[test]
+    fn in_order() {
+        let mut r = Reassembler::new();
+        r.process(&flow(), &syn(1000)).unwrap();
+        let hellos = feed(&mut r, &split(1000, HELLO, MSS));
+        assert_eq!(hellos.len(), 1);
+        assert_eq!(hellos[0].sni.as_deref(), Some("split.example.com"));
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut r = Reassembler::new();
+        r.process(&flow(), &syn(1000)).unwrap();
+        let mut segments = split(1000, HELLO, 500);
+        segments.reverse();
+        let hellos = feed(&mut r, &segments);
+        assert_eq!(hellos.len(), 1);
+        assert_eq!(hellos[0].sni.as_deref(), Some("split.example.com"));
+    }
+
+    #[test]
+    fn without_syn_out_of_order() {
+        let mut r = Reassembler::new();
+        let mut segments = split(1000, HELLO, MSS);
+        segments.reverse();
+        let hellos = feed(&mut r, &segments);
+        assert_eq!(hellos.len(), 1);
+    }
+
+    #[test]
+    fn wrapping_sequence_numbers() {
+        let mut r = Reassembler::new();
+        let isn = u32::MAX - 700;
+        r.process(&flow(), &syn(isn)).unwrap();
+        let hellos = feed(&mut r, &split(isn, HELLO, 300));
+        assert_eq!(hellos.len(), 1);
+    }
+
+    #[test]
+    fn retransmission() {
+        let mut r = Reassembler::new();
+        r.process(&flow(), &syn(1000)).unwrap();
+        let segments = vec![
+            data(1001, &HELLO[..800]),
+            // overlaps with the first segment
+            data(1001 + 600, &HELLO[600..1400]),
+            data(1001 + 600, &HELLO[600..1400]),
+            data(1001 + 1400, &HELLO[1400..]),
+        ];
+        let hellos = feed(&mut r, &segments);
+        assert_eq!(hellos.len(), 1);
+    }
+
+    #[test]
+    fn only_report_once() {
+        let mut r = Reassembler::new();
+        r.process(&flow(), &syn(1000)).unwrap();
+        let mut segments = split(1000, HELLO, MSS);
+        segments.push(data(1001, &HELLO[..MSS]));
+        assert_eq!(feed(&mut r, &segments).len(), 1);
+    }
+
+    #[test]
+    fn not_tls() {
+        let mut r = Reassembler::new();
+        r.process(&flow(), &syn(1000)).unwrap();
+        assert_eq!(feed(&mut r, &[data(1001, b"SSH-2.0-OpenSSH_9.0\r\n")]), vec![]);
+        assert!(r.streams[&(flow().src, flow().dst)].done);
+    }
+
+    #[test]
+    fn sniffglue_fixture() {
+        let mut r = Reassembler::new();
+        let mut hellos = Vec::new();
+        for line in include_str!("../fixtures/sniffglue/split_hello.jsonl").lines() {
+            let pkt = json::parse(line.as_bytes()).unwrap();
+            let flow = pkt.get_flow().unwrap();
+            let segment = pkt.get_tcp_segment().unwrap();
+            hellos.extend(r.process(&flow, &segment).unwrap());
+        }
+        assert_eq!(hellos.len(), 1);
+        assert_eq!(hellos[0].sni.as_deref(), Some("split.example.com"));
+    }
+}
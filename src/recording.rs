@@ -0,0 +1,680 @@
+use crate::args::Format;
+use crate::detector::Detector;
+use crate::errors::*;
+use crate::event::Event;
+use crate::hostapd::Signal;
+use crate::observation::{Captured, Input, Labeled};
+use crate::session::{self, EndReason, Tracker};
+use chrono::{DateTime, Utc};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const VERSION: u32 = 1;
+/// Files are rotated after this many bytes, before compression
+pub const MAX_SIZE: u64 = 64 * 1024 * 1024;
+const EXTENSION: &str = ".jsonl.gz";
+/// Compressed data is flushed this often, so a crash only loses the last moment
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// Slower replays would wait for years between records
+pub const MIN_SPEED: f64 = 0.001;
+
+/// The first line of every file, rotated files can be replayed on their own
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    pub recording: u32,
+    pub format: Format,
+    pub started: DateTime<Utc>,
+    /// The hostapd hook was recorded too, sessions are replayed from its signals
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub signals: bool,
+}
+
+/// Something that was received during the recording
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recorded {
+    Input(Input),
+    /// A message of the hostapd hook, e.g. `connected <mac>`
+    Signal(String),
+}
+
+/// Captured input or a signal and how long after the start of the recording it was received
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub offset: Duration,
+    pub interface: Option<String>,
+    pub recorded: Recorded,
+}
+
+/// A line in a recording, frames are hex encoded
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// Microseconds since the recording was started
+    t: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    interface: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    line: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    linktype: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    frame: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signal: Option<String>,
+}
+
+impl From<&Record> for Entry {
+    fn from(record: &Record) -> Entry {
+        let mut entry = Entry {
+            t: record.offset.as_micros() as u64,
+            interface: record.interface.clone(),
+            line: None,
+            linktype: None,
+            frame: None,
+            signal: None,
+        };
+        match &record.recorded {
+            Recorded::Input(Input::Line(line)) => entry.line = Some(line.clone()),
+            Recorded::Input(Input::Frame { linktype, data }) => {
+                entry.linktype = Some(*linktype);
+                entry.frame = Some(hex(data));
+            }
+            Recorded::Signal(signal) => entry.signal = Some(signal.clone()),
+        }
+        entry
+    }
+}
+
+impl Entry {
+    fn into_record(self) -> Result<Record> {
+        let recorded = match (self.line, self.linktype, self.frame, self.signal) {
+            (Some(line), None, None, None) => Recorded::Input(Input::Line(line)),
+            (None, Some(linktype), Some(frame), None) => Recorded::Input(Input::Frame {
+                linktype,
+                data: unhex(&frame)?,
+            }),
+            (None, None, None, Some(signal)) => Recorded::Signal(signal),
+            _ => bail!("Recorded entry needs to be either a line, a frame or a signal"),
+        };
+        Ok(Record {
+            offset: Duration::from_micros(self.t),
+            interface: self.interface,
+            recorded,
+        })
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len() * 2);
+    for b in data {
+        write!(out, "{:02x}", b).ok();
+    }
+    out
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        bail!("Invalid hex in recorded frame");
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).context("Invalid hex in recorded frame"))
+        .collect()
+}
+
+struct Output {
+    encoder: GzEncoder<BufWriter<File>>,
+    written: u64,
+}
+
+/// Write captured input to a directory of compressed files, a new file is started every `max_size` bytes
+pub struct Recorder {
+    dir: PathBuf,
+    header: Header,
+    start: Instant,
+    max_size: u64,
+    max_files: usize,
+    files: VecDeque<PathBuf>,
+    output: Option<Output>,
+    counter: usize,
+    last_offset: Duration,
+    last_flush: Instant,
+}
+
+impl Recorder {
+    /// Start a recording in an empty or new directory, with `max_files` the oldest files are deleted
+    pub fn create<P: AsRef<Path>>(dir: P, format: Format, max_size: u64, max_files: usize) -> Result<Recorder> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)
+            .with_context(|| anyhow!("Failed to create recording directory {:?}", dir))?;
+        if !files(dir)?.is_empty() {
+            bail!("Directory {:?} already contains a recording", dir);
+        }
+
+        let now = Instant::now();
+        Ok(Recorder {
+            dir: dir.to_path_buf(),
+            header: Header {
+                recording: VERSION,
+                format,
+                started: Utc::now(),
+                signals: false,
+            },
+            start: now,
+            max_size: max_size.max(1),
+            max_files,
+            files: VecDeque::new(),
+            output: None,
+            counter: 0,
+            last_offset: Duration::ZERO,
+            last_flush: now,
+        })
+    }
+
+    /// Also record the signals of the hostapd hook, this has to be set before anything is written
+    pub fn with_signals(mut self) -> Recorder {
+        self.header.signals = true;
+        self
+    }
+
+    fn open(&mut self) -> Result<Output> {
+        self.counter += 1;
+        let path = self.dir.join(format!("{:04}{}", self.counter, EXTENSION));
+        debug!("Writing recording to {:?}", path);
+        let file = File::create(&path)
+            .with_context(|| anyhow!("Failed to create recording file {:?}", path))?;
+        self.files.push_back(path);
+
+        while self.max_files > 0 && self.files.len() > self.max_files {
+            if let Some(old) = self.files.pop_front() {
+                debug!("Deleting old recording file {:?}", old);
+                fs::remove_file(&old)
+                    .with_context(|| anyhow!("Failed to delete old recording file {:?}", old))?;
+            }
+        }
+
+        let mut output = Output {
+            encoder: GzEncoder::new(BufWriter::new(file), Compression::default()),
+            written: 0,
+        };
+        let mut header = serde_json::to_vec(&self.header)?;
+        header.push(b'\n');
+        output.encoder.write_all(&header)?;
+        output.written += header.len() as u64;
+        Ok(output)
+    }
+
+    fn close(output: Output) -> Result<()> {
+        output.encoder.finish()?.flush()?;
+        Ok(())
+    }
+
+    /// Record captured input with the time it was captured
+    pub fn write(&mut self, captured: &Captured) -> Result<()> {
+        let interface = captured.interface.as_deref().map(String::from);
+        self.append(captured.time, interface, Recorded::Input(captured.input.clone()))
+    }
+
+    /// Record a message of the hostapd hook, so the replay can start and end sessions at the same time
+    pub fn signal(&mut self, signal: &str, time: Instant) -> Result<()> {
+        self.append(time, None, Recorded::Signal(signal.to_string()))
+    }
+
+    /// Timestamps never go backwards, even if captures from different interfaces arrive out of order
+    fn append(&mut self, time: Instant, interface: Option<String>, recorded: Recorded) -> Result<()> {
+        let offset = time.saturating_duration_since(self.start).max(self.last_offset);
+        self.last_offset = offset;
+        let record = Record {
+            offset,
+            interface,
+            recorded,
+        };
+        let mut line = serde_json::to_vec(&Entry::from(&record))?;
+        line.push(b'\n');
+
+        let mut output = match self.output.take() {
+            Some(output) if output.written < self.max_size => output,
+            Some(output) => {
+                Recorder::close(output)?;
+                self.open()?
+            }
+            None => self.open()?,
+        };
+        output.encoder.write_all(&line)?;
+        output.written += line.len() as u64;
+
+        let now = Instant::now();
+        if now.duration_since(self.last_flush) >= FLUSH_INTERVAL {
+            output.encoder.flush()?;
+            self.last_flush = now;
+        }
+        self.output = Some(output);
+        Ok(())
+    }
+
+    /// Complete the current file, without this the end of the recording may be lost
+    pub fn finish(mut self) -> Result<()> {
+        if let Some(output) = self.output.take() {
+            Recorder::close(output)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the records of a single file
+pub struct Reader<R: Read> {
+    lines: io::Lines<BufReader<MultiGzDecoder<R>>>,
+    pub header: Header,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(reader: R) -> Result<Reader<R>> {
+        let mut lines = BufReader::new(MultiGzDecoder::new(reader)).lines();
+        let header = lines.next()
+            .context("Recording is empty")?
+            .context("Failed to read recording")?;
+        let header = serde_json::from_str::<Header>(&header)
+            .context("File is not a spytrap recording")?;
+        if header.recording != VERSION {
+            bail!("Recording has version {}, only {} is supported", header.recording, VERSION);
+        }
+        Ok(Reader {
+            lines,
+            header,
+        })
+    }
+
+    fn read(line: &str) -> Result<Record> {
+        let entry = serde_json::from_str::<Entry>(line)
+            .with_context(|| anyhow!("Invalid entry in recording: {:?}", line))?;
+        entry.into_record()
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<Record>;
+
+    /// A file that was cut off, e.g. by a power loss, is read up to the last complete line
+    fn next(&mut self) -> Option<Result<Record>> {
+        match self.lines.next()? {
+            Ok(line) => Some(Reader::<R>::read(&line)),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                warn!("Recording was cut off, stopping at the last complete entry");
+                None
+            }
+            Err(err) => Some(Err(err).context("Failed to read recording")),
+        }
+    }
+}
+
+/// The files of a recording in the order they were written, or just the file itself
+pub fn files(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path).with_context(|| anyhow!("Failed to list recording directory {:?}", path))? {
+        let path = entry?.path();
+        if path.to_str().is_some_and(|name| name.ends_with(EXTENSION)) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// A replayed record, captured input or a signal of the hostapd hook
+#[derive(Debug, PartialEq, Eq)]
+pub enum Replayed {
+    Captured(Captured),
+    Signal(String),
+}
+
+/// Feed a recording back with its original timing, `speed` times faster or as fast as possible if None.
+/// Captured times follow the recording, so time windows of the detections behave like in the live run
+pub struct Replay {
+    files: Vec<PathBuf>,
+    header: Header,
+}
+
+impl Replay {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Replay> {
+        let path = path.as_ref();
+        let files = files(path)?;
+        let first = files.first()
+            .with_context(|| anyhow!("No recording found in {:?}", path))?;
+        let file = File::open(first)
+            .with_context(|| anyhow!("Failed to open recording {:?}", first))?;
+        let header = Reader::new(file)
+            .with_context(|| anyhow!("Failed to read recording {:?}", first))?
+            .header;
+        Ok(Replay {
+            files,
+            header,
+        })
+    }
+
+    pub fn format(&self) -> Format {
+        self.header.format
+    }
+
+    /// Whether the hostapd hook was recorded, otherwise there are no session boundaries
+    pub fn signals(&self) -> bool {
+        self.header.signals
+    }
+
+    pub async fn run<S: Sink<Replayed> + Unpin>(self, speed: Option<f64>, mut sink: S) -> Result<()> {
+        let start = Instant::now();
+        let mut first = None;
+        for path in &self.files {
+            // files are small enough after rotation, decompressing from memory doesn't block on io
+            let data = tokio::fs::read(path).await
+                .with_context(|| anyhow!("Failed to read recording {:?}", path))?;
+            let reader = Reader::new(&data[..])
+                .with_context(|| anyhow!("Failed to read recording {:?}", path))?;
+            if reader.header.started != self.header.started {
+                bail!("File {:?} belongs to a different recording", path);
+            }
+
+            for record in reader {
+                let record = record?;
+                let first = *first.get_or_insert(record.offset);
+                let elapsed = record.offset.saturating_sub(first);
+                let time = start.checked_add(elapsed)
+                    .context("Recording timestamp is out of range")?;
+                if let Some(speed) = speed {
+                    let deadline = Duration::try_from_secs_f64(elapsed.as_secs_f64() / speed.max(MIN_SPEED))
+                        .ok()
+                        .and_then(|delay| start.checked_add(delay))
+                        .context("Recording timestamp is out of range")?;
+                    tokio::time::sleep_until(deadline.into()).await;
+                }
+                let replayed = match record.recorded {
+                    Recorded::Input(input) => Replayed::Captured(Captured {
+                        interface: record.interface.map(Arc::from),
+                        time,
+                        input,
+                    }),
+                    Recorded::Signal(signal) => Replayed::Signal(signal),
+                };
+                sink.send(replayed).await.map_err(|_| anyhow!("sink error"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run the detections on a replay like the daemon does. With `signals` every phone gets its own session
+/// and the detector starts from scratch for each of them, otherwise the recording is checked as one session.
+/// Everything is handled in the order it was recorded, regardless of the replay speed
+pub async fn detect<R, F, S>(mut rx: R, mut decode: F, mut detector: Detector, signals: bool, tx: &mut S) -> Result<()>
+where
+    R: Stream<Item=Replayed> + Unpin,
+    F: FnMut(Captured) -> Vec<Labeled>,
+    S: Sink<Event> + Unpin,
+{
+    let mut tracker = Tracker::new(session::IDLE_TIMEOUT);
+    let mut events = tracker.handle(Event::IocsLoaded(detector.provenance().clone()), Utc::now());
+    if !signals {
+        events.extend(tracker.start_session(Utc::now()));
+    }
+
+    loop {
+        for event in events.drain(..) {
+            tx.send(event).await.map_err(|_| anyhow!("sink error"))?;
+        }
+        let replayed = match rx.next().await {
+            Some(replayed) => replayed,
+            None => break,
+        };
+
+        let now = Utc::now();
+        events.extend(tracker.expire(now));
+        match replayed {
+            Replayed::Captured(captured) => {
+                for labeled in decode(captured) {
+                    for event in detector.process(&labeled) {
+                        events.extend(tracker.handle(event, now));
+                    }
+                }
+            }
+            // the hotspot gets a new password whenever a phone leaves, this ends the session
+            Replayed::Signal(signal) => match Signal::parse(&signal) {
+                Signal::Connected(mac) => {
+                    events.extend(tracker.handle(Event::ClientConnected { mac, session: None }, now));
+                    if events.iter().any(|event| matches!(event, Event::SessionStarted(_))) {
+                        detector.reset();
+                    }
+                }
+                Signal::Disconnected(mac) => {
+                    events.extend(tracker.handle(Event::ClientDisconnected { mac, session: None }, now));
+                    events.extend(tracker.end_session(EndReason::Reset, now));
+                }
+                Signal::Reset => events.extend(tracker.end_session(EndReason::Reset, now)),
+            },
+        }
+    }
+
+    if let Some(event) = tracker.end_session(EndReason::Shutdown, Utc::now()) {
+        tx.send(event).await.map_err(|_| anyhow!("sink error"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::{Flow, Proto};
+    use crate::observation::Observation;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("spytrap-recording-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn captured(recorder: &Recorder, ms: u64, input: Input) -> Captured {
+        Captured {
+            interface: Some(Arc::from("wlan0")),
+            time: recorder.start + Duration::from_millis(ms),
+            input,
+        }
+    }
+
+    fn line(line: &str) -> Recorded {
+        Recorded::Input(Input::Line(line.to_string()))
+    }
+
+    fn captured_only(replayed: Vec<Replayed>) -> Vec<Captured> {
+        replayed.into_iter()
+            .filter_map(|replayed| match replayed {
+                Replayed::Captured(captured) => Some(captured),
+                Replayed::Signal(_) => None,
+            })
+            .collect()
+    }
+
+    fn read_all(dir: &Path) -> Vec<Record> {
+        let mut records = Vec::new();
+        for path in files(dir).unwrap() {
+            let reader = Reader::new(File::open(path).unwrap()).unwrap();
+            assert_eq!(reader.header.format, Format::Sniffglue);
+            for record in reader {
+                records.push(record.unwrap());
+            }
+        }
+        records
+    }
+
+    #[test]
+    fn roundtrip() {
+        let dir = dir("roundtrip");
+        let mut recorder = Recorder::create(&dir, Format::Sniffglue, MAX_SIZE, 0).unwrap();
+        let frame = Input::Frame {
+            linktype: 1,
+            data: vec![0x00, 0x1b, 0xff, 0x42],
+        };
+        recorder.write(&captured(&recorder, 10, Input::Line("{\"a\":1}".to_string()))).unwrap();
+        recorder.write(&captured(&recorder, 25, frame.clone())).unwrap();
+        // arrived late from another interface
+        recorder.write(&captured(&recorder, 20, Input::Line("x".to_string()))).unwrap();
+        recorder.finish().unwrap();
+
+        let records = read_all(&dir);
+        let offsets = records.iter().map(|record| record.offset.as_millis()).collect::<Vec<_>>();
+        assert_eq!(offsets, vec![10, 25, 25]);
+        assert_eq!(records[1].recorded, Recorded::Input(frame));
+        assert_eq!(records[0].interface.as_deref(), Some("wlan0"));
+
+        assert!(Recorder::create(&dir, Format::Sniffglue, MAX_SIZE, 0).is_err());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn rotate_files() {
+        let dir = dir("rotate");
+        let mut recorder = Recorder::create(&dir, Format::Sniffglue, 200, 2).unwrap();
+        for i in 0..20 {
+            recorder.write(&captured(&recorder, i, Input::Line(format!("line {:040}", i)))).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        assert_eq!(files(&dir).unwrap().len(), 2);
+
+        // the oldest files are gone, the rest is still in order
+        let records = read_all(&dir);
+        assert_ne!(records[0].recorded, line(&format!("line {:040}", 0)));
+        assert_eq!(records.last().unwrap().recorded, line(&format!("line {:040}", 19)));
+        assert!(records.windows(2).all(|w| w[0].offset <= w[1].offset));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn truncated() {
+        let dir = dir("truncated");
+        let mut recorder = Recorder::create(&dir, Format::Sniffglue, MAX_SIZE, 0).unwrap();
+        recorder.write(&captured(&recorder, 1, Input::Line("first".to_string()))).unwrap();
+        recorder.write(&captured(&recorder, 2, Input::Line("second".to_string()))).unwrap();
+        recorder.finish().unwrap();
+        // the gzip trailer is missing if the process was killed
+        let path = files(&dir).unwrap().pop().unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 8]).unwrap();
+
+        let records = read_all(&dir);
+        assert_eq!(records[0].recorded, line("first"));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn replay_fast() {
+        let dir = dir("replay");
+        let mut recorder = Recorder::create(&dir, Format::Sniffglue, MAX_SIZE, 0).unwrap();
+        for ms in [5_000, 65_000, 125_000] {
+            recorder.write(&captured(&recorder, ms, Input::Line(ms.to_string()))).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let replay = Replay::open(&dir).unwrap();
+        assert_eq!(replay.format(), Format::Sniffglue);
+        let mut replayed = Vec::new();
+        let started = Instant::now();
+        replay.run(None, &mut replayed).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let captured = captured_only(replayed);
+
+        // the original spacing is kept, starting with the first record
+        let elapsed = captured.iter()
+            .map(|c| c.time.duration_since(captured[0].time).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(elapsed, vec![0, 60, 120]);
+        assert_eq!(captured[2].input, Input::Line("125000".to_string()));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn replay_tiny_speed() {
+        let dir = dir("replay-tiny-speed");
+        let mut recorder = Recorder::create(&dir, Format::Sniffglue, MAX_SIZE, 0).unwrap();
+        for ms in [0, 1] {
+            recorder.write(&captured(&recorder, ms, Input::Line(ms.to_string()))).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        // 1ms divided by the smallest positive float doesn't fit in a duration
+        let mut captured = Vec::new();
+        Replay::open(&dir).unwrap().run(Some(f64::MIN_POSITIVE), &mut captured).await.unwrap();
+        assert_eq!(captured.len(), 2);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn replay_sessions() {
+        let dir = dir("replay-sessions");
+        let mut recorder = Recorder::create(&dir, Format::Sniffglue, MAX_SIZE, 0).unwrap().with_signals();
+        let start = recorder.start;
+        let at = |ms| start + Duration::from_millis(ms);
+        recorder.signal("connected 02:00:00:00:00:01", at(0)).unwrap();
+        recorder.write(&captured(&recorder, 1_000, Input::Line("resolved 45.33.32.156".to_string()))).unwrap();
+        recorder.write(&captured(&recorder, 2_000, Input::Line("45.33.32.156:443".to_string()))).unwrap();
+        recorder.signal("disconnected 02:00:00:00:00:01", at(3_000)).unwrap();
+        // the next phone doesn't resolve the address itself
+        recorder.signal("connected 02:00:00:00:00:02", at(4_000)).unwrap();
+        recorder.write(&captured(&recorder, 5_000, Input::Line("45.33.32.156:443".to_string()))).unwrap();
+        recorder.signal("disconnected 02:00:00:00:00:02", at(6_000)).unwrap();
+        recorder.finish().unwrap();
+
+        let replay = Replay::open(&dir).unwrap();
+        assert!(replay.signals());
+        let signals = replay.signals();
+        let mut replayed = Vec::new();
+        replay.run(None, &mut replayed).await.unwrap();
+        assert_eq!(replayed.len(), 7);
+
+        let decode = |captured: Captured| {
+            let observation = match captured.input {
+                Input::Line(line) if line.starts_with("resolved ") => Observation::Resolved {
+                    name: "example.com".to_string(),
+                    addr: line["resolved ".len()..].parse().unwrap(),
+                },
+                Input::Line(line) => Observation::Connection(Flow {
+                    proto: Proto::TCP,
+                    src: "192.168.1.3:1337".parse().unwrap(),
+                    dst: line.parse().unwrap(),
+                }),
+                input => panic!("unexpected input: {:?}", input),
+            };
+            vec![Labeled {
+                interface: captured.interface,
+                time: Some(captured.time),
+                observation,
+            }]
+        };
+        let mut events = Vec::new();
+        detect(futures::stream::iter(replayed), decode, Detector::builder().build(), signals, &mut events).await.unwrap();
+
+        let sessions = events.iter()
+            .filter_map(|event| match event {
+                Event::SessionEnded(session) => Some(session),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sessions.len(), 2);
+        assert_ne!(sessions[0].id, sessions[1].id);
+        assert_eq!(sessions[0].clients, vec!["02:00:00:00:00:01"]);
+        assert_eq!(sessions[0].findings, vec![]);
+        // resolved in the first session, unexplained in the second
+        assert_eq!(sessions[1].clients, vec!["02:00:00:00:00:02"]);
+        assert_eq!(sessions[1].findings.len(), 1);
+        assert_eq!(sessions[1].findings[0].target.to_string(), "45.33.32.156:443");
+        fs::remove_dir_all(&dir).ok();
+    }
+}
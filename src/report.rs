@@ -0,0 +1,476 @@
+use crate::errors::*;
+use crate::event::{Detection, Level, Target};
+use crate::ioc::Section;
+use crate::pdf::{self, Style};
+use crate::session::{EndReason, Provenance, Session};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fmt::Write;
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Low,
+    Medium,
+    High,
+}
+
+impl Severity {
+    pub fn of(detection: &Detection) -> Severity {
+        match (detection.level, &detection.ioc) {
+            (Level::Detected, Some(ioc)) if ioc.section == Section::Website => Severity::Medium,
+            (Level::Detected, _) => Severity::High,
+            (Level::Suspicious, _) => Severity::Low,
+            (Level::Unexplained, _) => Severity::Info,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Low => "low",
+            Severity::Medium => "medium",
+            Severity::High => "high",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Clean,
+    Suspicious,
+    Detected,
+}
+
+impl Verdict {
+    fn from_severity(severity: Option<Severity>) -> Verdict {
+        match severity {
+            Some(Severity::High) => Verdict::Detected,
+            Some(Severity::Medium) | Some(Severity::Low) => Verdict::Suspicious,
+            Some(Severity::Info) | None => Verdict::Clean,
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            Verdict::Clean => "No stalkerware detected",
+            Verdict::Suspicious => "Suspicious activity, needs a closer look",
+            Verdict::Detected => "Stalkerware detected",
+        }
+    }
+
+    pub fn explanation(&self) -> &'static str {
+        match self {
+            Verdict::Clean => "No known stalkerware was seen while the phone was connected. This only covers the time of \
+                the scan and apps that are in the indicator list, it does not prove that the phone is free of monitoring software.",
+            Verdict::Suspicious => "Nothing definitive was found, but the phone showed behaviour that is worth a closer look. \
+                The findings below explain why each of them was reported.",
+            Verdict::Detected => "The phone contacted servers that are known to be used by stalkerware. It is very likely \
+                that an app on this phone monitors its user. Removing the app can alert the person who installed it, consider \
+                talking to a support organization about a safety plan before making changes to the phone.",
+        }
+    }
+}
+
+/// One or more identical detections
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub family: Option<String>,
+    pub category: String,
+    /// e.g. `dns` or `tls/fingerprint`
+    pub kind: String,
+    pub target: Target,
+    /// The phone's address, if several phones were scanned at once
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_seen: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<DateTime<Utc>>,
+    pub count: u64,
+    pub explanation: String,
+}
+
+impl Finding {
+    fn new(detection: &Detection) -> Finding {
+        Finding {
+            severity: Severity::of(detection),
+            family: detection.family().map(String::from),
+            category: category(detection),
+            kind: detection.kind.clone(),
+            target: detection.target.clone(),
+            client: detection.origin.client,
+            first_seen: detection.time,
+            last_seen: detection.last_seen.or(detection.time),
+            count: detection.count,
+            explanation: explain(detection),
+        }
+    }
+
+    /// Detections are already merged within a session, but older history may contain repeats
+    fn add(&mut self, detection: &Detection) {
+        self.count += detection.count;
+        if let Some(time) = detection.time {
+            self.first_seen = Some(self.first_seen.map_or(time, |first| first.min(time)));
+        }
+        if let Some(time) = detection.last_seen.or(detection.time) {
+            self.last_seen = Some(self.last_seen.map_or(time, |last| last.max(time)));
+        }
+    }
+}
+
+fn category(detection: &Detection) -> String {
+    if let Some(ioc) = &detection.ioc {
+        return format!("{} ({})", ioc.category, ioc.section.as_str());
+    }
+    match detection.level {
+        Level::Detected => "custom rule".to_string(),
+        Level::Suspicious => "dns tunnel".to_string(),
+        Level::Unexplained => "unexplained connection".to_string(),
+    }
+}
+
+fn explain(detection: &Detection) -> String {
+    if let Some(ioc) = &detection.ioc {
+        return match ioc.section {
+            Section::C2 => format!("The phone connected to a server that {} uses to upload collected data. \
+                This is a strong sign that {} is installed.", ioc.family, ioc.family),
+            Section::Distribution => format!("The phone contacted a server that distributes {}, \
+                the app may have been downloaded on this phone.", ioc.family),
+            Section::Website => format!("The phone contacted the website of {}. This happens when someone uses the \
+                app's web panel or bought it on this phone, but also when just reading about it.", ioc.family),
+        };
+    }
+    match (&detection.level, &detection.target) {
+        (Level::Detected, _) => format!("The traffic matched the custom rule {:?}.", detection.rule.as_deref().unwrap_or("?")),
+        (Level::Suspicious, Target::Tunnel { reason, .. }) => format!("Dns queries looked like data was hidden in them ({}). \
+            Some monitoring apps do this to avoid being noticed.", reason),
+        (Level::Suspicious, _) => "The traffic looked unusual.".to_string(),
+        (Level::Unexplained, _) => "The phone connected to an address without looking it up first. This is often \
+            harmless, but can be an app that hides which servers it talks to.".to_string(),
+    }
+}
+
+/// Everything a counsellor needs to hand out or file after a scan
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Report {
+    pub generated: DateTime<Utc>,
+    pub verdict: Verdict,
+    pub explanation: String,
+    pub session: String,
+    pub started: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ended: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<EndReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssid: Option<String>,
+    /// Mac addresses of the phones that were connected
+    pub devices: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+    /// The most severe ones first
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    pub fn new(session: &Session, generated: DateTime<Utc>) -> Report {
+        let mut findings: Vec<Finding> = Vec::new();
+        for detection in &session.findings {
+            let finding = findings.iter_mut().find(|finding| {
+                finding.kind == detection.kind
+                    && finding.target == detection.target
+                    && finding.family.as_deref() == detection.family()
+                    && finding.client == detection.origin.client
+            });
+            match finding {
+                Some(finding) => finding.add(detection),
+                None => findings.push(Finding::new(detection)),
+            }
+        }
+        // stable, so findings of the same severity stay in the order they were seen in
+        findings.sort_by_key(|finding| std::cmp::Reverse(finding.severity));
+
+        let verdict = Verdict::from_severity(findings.first().map(|finding| finding.severity));
+        Report {
+            generated,
+            verdict,
+            explanation: verdict.explanation().to_string(),
+            session: session.id.clone(),
+            started: session.started,
+            ended: session.ended,
+            reason: session.reason,
+            ssid: session.ssid.clone(),
+            devices: session.clients.clone(),
+            provenance: session.provenance.clone(),
+            findings,
+        }
+    }
+
+    /// The scan details as label and value, shared by html and pdf
+    fn details(&self) -> Vec<(&'static str, String)> {
+        let mut details = vec![
+            ("Session", self.session.clone()),
+            ("Started", self.started.format("%Y-%m-%d %H:%M:%S UTC").to_string()),
+        ];
+        if let Some(ended) = self.ended {
+            let reason = self.reason.map(|reason| format!(" ({})", reason.as_str())).unwrap_or_default();
+            details.push(("Ended", format!("{}{}", ended.format("%Y-%m-%d %H:%M:%S UTC"), reason)));
+        }
+        if let Some(ssid) = &self.ssid {
+            details.push(("Hotspot", ssid.clone()));
+        }
+        details.push(("Devices", self.devices.join(", ")));
+        match &self.provenance {
+            Some(provenance) => {
+                details.push(("Indicators", format!("{} iocs from {}", provenance.iocs, provenance.path)));
+                details.push(("Indicators sha256", provenance.sha256.clone()));
+                if let Some(rules) = &provenance.custom_rules {
+                    details.push(("Custom rules", rules.clone()));
+                }
+            }
+            None => details.push(("Indicators", "unknown".to_string())),
+        }
+        details.push(("Generated", self.generated.format("%Y-%m-%d %H:%M:%S UTC").to_string()));
+        details
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// A single html file without any external resources
+    pub fn to_html(&self) -> String {
+        let mut html = String::new();
+        html.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+        writeln!(html, "<title>spytrap report {}</title>", escape(&self.session)).ok();
+        html.push_str("<style>
+body { font-family: sans-serif; max-width: 50em; margin: 2em auto; padding: 0 1em; color: #222; }
+.verdict { padding: 1em; border-radius: 4px; }
+.detected { background: #f8d7da; } .suspicious { background: #fff3cd; } .clean { background: #d4edda; }
+table { border-collapse: collapse; width: 100%; }
+th, td { text-align: left; vertical-align: top; padding: 0.3em 0.5em; border-bottom: 1px solid #ddd; }
+.high { color: #a00; font-weight: bold; } .medium { color: #b60; font-weight: bold; }
+code { word-break: break-all; }
+</style>\n</head>\n<body>\n");
+
+        writeln!(html, "<h1>spytrap report</h1>").ok();
+        let verdict = match self.verdict {
+            Verdict::Clean => "clean",
+            Verdict::Suspicious => "suspicious",
+            Verdict::Detected => "detected",
+        };
+        writeln!(html, "<div class=\"verdict {}\">\n<h2>{}</h2>\n<p>{}</p>\n</div>", verdict, escape(self.verdict.title()), escape(&self.explanation)).ok();
+
+        html.push_str("<h2>Scan</h2>\n<table>\n");
+        for (label, value) in self.details() {
+            writeln!(html, "<tr><th>{}</th><td><code>{}</code></td></tr>", label, escape(&value)).ok();
+        }
+        html.push_str("</table>\n");
+
+        writeln!(html, "<h2>Findings ({})</h2>", self.findings.len()).ok();
+        if self.findings.is_empty() {
+            html.push_str("<p>Nothing was found.</p>\n");
+        }
+        for finding in &self.findings {
+            let severity = finding.severity.as_str();
+            writeln!(html, "<h3><span class=\"{}\">[{}]</span> {}</h3>", severity, severity, escape(finding.family.as_deref().unwrap_or(&finding.category))).ok();
+            writeln!(html, "<p>{}</p>", escape(&finding.explanation)).ok();
+            html.push_str("<table>\n");
+            writeln!(html, "<tr><th>Category</th><td>{}</td></tr>", escape(&finding.category)).ok();
+            writeln!(html, "<tr><th>Seen as</th><td><code>{}: {}</code></td></tr>", escape(&finding.kind), escape(&finding.target.to_string())).ok();
+            if let Some(client) = &finding.client {
+                writeln!(html, "<tr><th>Phone</th><td>{}</td></tr>", client).ok();
+            }
+            if let (Some(first), Some(last)) = (finding.first_seen, finding.last_seen) {
+                writeln!(html, "<tr><th>First seen</th><td>{}</td></tr>", first.format("%Y-%m-%d %H:%M:%S UTC")).ok();
+                writeln!(html, "<tr><th>Last seen</th><td>{}</td></tr>", last.format("%Y-%m-%d %H:%M:%S UTC")).ok();
+            }
+            writeln!(html, "<tr><th>Count</th><td>{}</td></tr>", finding.count).ok();
+            html.push_str("</table>\n");
+        }
+
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+
+    pub fn to_pdf(&self) -> Vec<u8> {
+        let mut doc = pdf::Document::new();
+        doc.push(Style::Title, "spytrap report");
+        doc.space();
+        doc.push(Style::Heading, self.verdict.title());
+        doc.push(Style::Text, &self.explanation);
+
+        doc.space();
+        doc.push(Style::Heading, "Scan");
+        for (label, value) in self.details() {
+            doc.push(Style::Text, &format!("{}: {}", label, value));
+        }
+
+        doc.space();
+        doc.push(Style::Heading, &format!("Findings ({})", self.findings.len()));
+        if self.findings.is_empty() {
+            doc.push(Style::Text, "Nothing was found.");
+        }
+        for finding in &self.findings {
+            doc.space();
+            doc.push(Style::Bold, &format!("[{}] {}", finding.severity.as_str(), finding.family.as_deref().unwrap_or(&finding.category)));
+            doc.push(Style::Text, &finding.explanation);
+            doc.push(Style::Text, &format!("Category: {}", finding.category));
+            doc.push(Style::Text, &format!("Seen as: {}: {}", finding.kind, finding.target));
+            if let Some(client) = &finding.client {
+                doc.push(Style::Text, &format!("Phone: {}", client));
+            }
+            if let (Some(first), Some(last)) = (finding.first_seen, finding.last_seen) {
+                doc.push(Style::Text, &format!("Seen {} times, first at {}, last at {}", finding.count,
+                    first.format("%Y-%m-%d %H:%M:%S UTC"), last.format("%Y-%m-%d %H:%M:%S UTC")));
+            } else {
+                doc.push(Style::Text, &format!("Seen {} times", finding.count));
+            }
+        }
+        doc.to_bytes()
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioc::Indicator;
+
+    fn time(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    fn detection(name: &str, section: Section, secs: i64) -> Detection {
+        let mut detection = Detection::new(Level::Detected, "dns", Target::Name(name.to_string())).ioc(&Indicator {
+            family: "OwnSpy".to_string(),
+            category: "stalkerware".to_string(),
+            section,
+        });
+        detection.time = Some(time(secs));
+        detection
+    }
+
+    fn session(findings: Vec<Detection>) -> Session {
+        Session {
+            id: "20231114-221320-0001".to_string(),
+            started: time(0),
+            ended: Some(time(600)),
+            reason: Some(EndReason::Disconnected),
+            ssid: Some("Starbucks WiFi".to_string()),
+            password: Some("abcdefghij".to_string()),
+            clients: vec!["aa:bb:cc:dd:ee:ff".to_string()],
+            provenance: None,
+            findings,
+        }
+    }
+
+    #[test]
+    fn group_findings() {
+        let report = Report::new(&session(vec![
+            detection("ownspy.com", Section::Website, 10),
+            detection("user.ownspy.es", Section::C2, 20),
+            detection("user.ownspy.es", Section::C2, 30),
+            Detection::new(Level::Unexplained, "tcp", Target::Addr("93.184.216.34:443".parse().unwrap())),
+        ]), time(700));
+
+        assert_eq!(report.verdict, Verdict::Detected);
+        assert_eq!(report.devices, vec!["aa:bb:cc:dd:ee:ff"]);
+        let findings = report.findings.iter()
+            .map(|finding| (finding.severity, finding.target.to_string(), finding.count))
+            .collect::<Vec<_>>();
+        assert_eq!(findings, vec![
+            (Severity::High, "\"user.ownspy.es\"".to_string(), 2),
+            (Severity::Medium, "\"ownspy.com\"".to_string(), 1),
+            (Severity::Info, "93.184.216.34:443".to_string(), 1),
+        ]);
+        let c2 = &report.findings[0];
+        assert_eq!((c2.first_seen, c2.last_seen), (Some(time(20)), Some(time(30))));
+        assert_eq!(c2.category, "stalkerware (c2)");
+        assert_eq!(c2.family.as_deref(), Some("OwnSpy"));
+    }
+
+    #[test]
+    fn findings_per_client() {
+        let from = |client: &str, secs| {
+            let mut detection = detection("user.ownspy.es", Section::C2, secs);
+            detection.origin.client = Some(client.parse().unwrap());
+            detection
+        };
+        let report = Report::new(&session(vec![
+            from("10.0.0.2", 10),
+            from("10.0.0.3", 20),
+            from("10.0.0.2", 30),
+        ]), time(700));
+        let findings = report.findings.iter()
+            .map(|finding| (finding.client.unwrap().to_string(), finding.count))
+            .collect::<Vec<_>>();
+        assert_eq!(findings, vec![("10.0.0.2".to_string(), 2), ("10.0.0.3".to_string(), 1)]);
+        assert!(report.to_html().contains("<tr><th>Phone</th><td>10.0.0.3</td></tr>"));
+    }
+
+    #[test]
+    fn merged_counts() {
+        let mut merged = detection("user.ownspy.es", Section::C2, 20);
+        merged.count = 5;
+        merged.last_seen = Some(time(90));
+        let report = Report::new(&session(vec![
+            merged,
+            detection("user.ownspy.es", Section::C2, 100),
+        ]), time(700));
+
+        let c2 = &report.findings[0];
+        assert_eq!(c2.count, 6);
+        assert_eq!((c2.first_seen, c2.last_seen), (Some(time(20)), Some(time(100))));
+    }
+
+    #[test]
+    fn verdicts() {
+        let report = Report::new(&session(vec![]), time(700));
+        assert_eq!(report.verdict, Verdict::Clean);
+        let report = Report::new(&session(vec![detection("ownspy.com", Section::Website, 10)]), time(700));
+        assert_eq!(report.verdict, Verdict::Suspicious);
+    }
+
+    #[test]
+    fn render() {
+        let mut detection = detection("<script>.example", Section::C2, 10);
+        detection.ioc.as_mut().unwrap().family = "A&B".to_string();
+        let report = Report::new(&session(vec![detection]), time(700));
+
+        let html = report.to_html();
+        assert!(html.contains("&lt;script&gt;.example"));
+        assert!(html.contains("A&amp;B"));
+        assert!(!html.contains("<script>"));
+        assert!(!html.contains("http"));
+
+        let json = serde_json::from_str::<serde_json::Value>(&report.to_json().unwrap()).unwrap();
+        assert_eq!(json["verdict"], "detected");
+        assert_eq!(json["findings"][0]["severity"], "high");
+        assert_eq!(json["findings"][0]["first_seen"], "2023-11-14T22:13:30Z");
+
+        let pdf = report.to_pdf();
+        assert!(pdf.starts_with(b"%PDF-"));
+        assert!(String::from_utf8_lossy(&pdf).contains("(Stalkerware detected) Tj"));
+    }
+}
@@ -0,0 +1,242 @@
+use crate::dns;
+use crate::errors::*;
+use crate::observation::Observation;
+use futures::{Sink, SinkExt};
+use std::net::IpAddr;
+use std::io::SeekFrom;
+use std::os::unix::fs::MetadataExt;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncSeekExt, BufReader};
+
+/// Journald json exports wrap the log line in a MESSAGE field
+fn message(line: &str) -> Option<String> {
+    if line.starts_with('{') {
+        let value = serde_json::from_str::<serde_json::Value>(line).ok()?;
+        Some(value.get("MESSAGE")?.as_str()?.to_string())
+    } else {
+        Some(line.to_string())
+    }
+}
+
+/// Parse a line of dnsmasq's `log-queries` output, this is also used by pihole.log
+///
+/// ```text
+/// dnsmasq[1234]: query[A] example.com from 10.38.73.100
+/// dnsmasq[1234]: 5 10.38.73.100/53022 query[A] example.com from 10.38.73.100
+/// dnsmasq[1234]: reply example.com is 93.184.216.34
+/// ```
+pub fn dnsmasq(line: &str) -> Option<Observation> {
+    let line = message(line)?;
+
+    if let Some(idx) = line.find("query[") {
+        let mut parts = line[idx + 6..].split_whitespace();
+        let qtype = parts.next()?.strip_suffix(']')?;
+        let name = parts.next()?;
+        if parts.next()? != "from" {
+            return None;
+        }
+        let client = parts.next()?.parse().ok()?;
+
+        // types that dnsmasq doesn't know are logged as type=65
+        let qtype = match qtype.strip_prefix("type=") {
+            Some(n) => dns::qtype_str(n.parse().ok()?),
+            None => qtype.to_string(),
+        };
+
+        return Some(Observation::Query {
+            client,
+            qtype,
+            name: name.to_string(),
+        });
+    }
+
+    for keyword in &[" reply ", " cached "] {
+        if let Some(idx) = line.find(keyword) {
+            let mut parts = line[idx + keyword.len()..].split_whitespace();
+            let name = parts.next()?;
+            if parts.next()? != "is" {
+                return None;
+            }
+            let addr = parts.next()?.parse().ok()?;
+            return Some(Observation::Resolved {
+                name: name.to_string(),
+                addr,
+            });
+        }
+    }
+
+    None
+}
+
+/// Parse a line of unbound's `log-queries` output
+///
+/// ```text
+/// [1682942400] unbound[1234:0] info: 10.38.73.100 example.com. A IN
+/// ```
+pub fn unbound(line: &str) -> Option<Observation> {
+    let line = message(line)?;
+    let idx = line.find(" info: ")?;
+
+    let parts = line[idx + 7..].split_whitespace().collect::<Vec<_>>();
+    // replies from `log-replies` have additional fields
+    let (client, name, qtype) = match parts[..] {
+        [client, name, qtype, _class] => (client, name, qtype),
+        _ => return None,
+    };
+    let client = client.parse::<IpAddr>().ok()?;
+    let name = name.strip_suffix('.').unwrap_or(name);
+
+    Some(Observation::Query {
+        client,
+        qtype: qtype.to_string(),
+        name: name.to_string(),
+    })
+}
+
+/// Follow a log file from its end, or from the start with `backfill`. The file is reopened if it's
+/// rotated or truncated, the new file is read from the start
+pub async fn follow<S: Sink<Observation> + Unpin>(path: String, parse: fn(&str) -> Option<Observation>, backfill: bool, mut sink: S) -> Result<()> {
+    let mut skip = !backfill;
+    loop {
+        let mut file = File::open(&path).await
+            .with_context(|| anyhow!("Failed to open log file {:?}", path))?;
+        let ino = file.metadata().await?.ino();
+        info!("Following {:?}", path);
+
+        let mut pos = 0;
+        if skip {
+            pos = file.seek(SeekFrom::End(0)).await?;
+            skip = false;
+        }
+        let mut reader = BufReader::new(file);
+        let mut line = Vec::new();
+        loop {
+            let n = reader.read_until(b'\n', &mut line).await?;
+            pos += n as u64;
+
+            if line.ends_with(b"\n") {
+                // a single broken line must not stop the input, it would continue at the end of the file
+                match std::str::from_utf8(&line) {
+                    Ok(line) => if let Some(obs) = parse(line.trim_end()) {
+                        sink.send(obs).await.map_err(|_| anyhow!("sink error"))?;
+                    },
+                    Err(_) => debug!("Skipping line that isn't valid utf-8 in {:?}", path),
+                }
+                line.clear();
+            } else if n == 0 {
+                // partial lines are kept until the rest was written
+                tokio::time::sleep(Duration::from_secs(1)).await;
+
+                match tokio::fs::metadata(&path).await {
+                    Ok(md) if md.ino() == ino && md.len() >= pos => (),
+                    _ => {
+                        debug!("Log file {:?} was rotated, reopening", path);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(client: &str, qtype: &str, name: &str) -> Option<Observation> {
+        Some(Observation::Query {
+            client: client.parse().unwrap(),
+            qtype: qtype.to_string(),
+            name: name.to_string(),
+        })
+    }
+
+    #[test]
+    fn parse_dnsmasq_query() {
+        let obs = dnsmasq("May  1 12:00:00 dnsmasq[1234]: query[A] example.com from 10.38.73.100");
+        assert_eq!(obs, query("10.38.73.100", "A", "example.com"));
+    }
+
+    #[test]
+    fn parse_dnsmasq_query_extra() {
+        let obs = dnsmasq("May  1 12:00:00 dnsmasq[1234]: 5 10.38.73.100/53022 query[TXT] example.com from 10.38.73.100");
+        assert_eq!(obs, query("10.38.73.100", "TXT", "example.com"));
+    }
+
+    #[test]
+    fn parse_dnsmasq_unknown_type() {
+        let obs = dnsmasq("dnsmasq[1234]: query[type=10] exfil.example.com from fd00::1337");
+        assert_eq!(obs, query("fd00::1337", "NULL", "exfil.example.com"));
+    }
+
+    #[test]
+    fn parse_dnsmasq_reply() {
+        let obs = dnsmasq("May  1 12:00:00 dnsmasq[1234]: reply example.com is 93.184.216.34");
+        assert_eq!(obs, Some(Observation::Resolved {
+            name: "example.com".to_string(),
+            addr: "93.184.216.34".parse().unwrap(),
+        }));
+        assert_eq!(dnsmasq("May  1 12:00:00 dnsmasq[1234]: reply example.com is <CNAME>"), None);
+        assert_eq!(dnsmasq("May  1 12:00:00 dnsmasq[1234]: reply nx.example.com is NXDOMAIN"), None);
+    }
+
+    #[test]
+    fn parse_dnsmasq_journald_json() {
+        let obs = dnsmasq(r#"{"__CURSOR":"s=1","SYSLOG_IDENTIFIER":"dnsmasq","MESSAGE":"query[AAAA] example.com from 10.38.73.100"}"#);
+        assert_eq!(obs, query("10.38.73.100", "AAAA", "example.com"));
+    }
+
+    #[test]
+    fn ignore_dnsmasq_forwarded() {
+        assert_eq!(dnsmasq("May  1 12:00:00 dnsmasq[1234]: forwarded example.com to 1.1.1.1"), None);
+    }
+
+    #[test]
+    fn parse_unbound_query() {
+        let obs = unbound("[1682942400] unbound[1234:0] info: 10.38.73.100 example.com. A IN");
+        assert_eq!(obs, query("10.38.73.100", "A", "example.com"));
+    }
+
+    #[test]
+    fn ignore_unbound_reply() {
+        assert_eq!(unbound("[1682942400] unbound[1234:0] info: 10.38.73.100 example.com. A IN NOERROR 0.000000 0 45"), None);
+        assert_eq!(unbound("[1682942400] unbound[1234:0] info: start of service (unbound 1.17.1)."), None);
+    }
+
+    #[tokio::test]
+    async fn follow_from_end() {
+        let path = std::env::temp_dir().join(format!("spytrap-resolver-{}.log", std::process::id()));
+        std::fs::write(&path, "dnsmasq[1234]: query[A] old.example.com from 10.38.73.100\n").unwrap();
+
+        let (tx, mut rx) = futures::channel::mpsc::channel(16);
+        let follow = tokio::spawn(follow(path.to_str().unwrap().to_string(), dnsmasq, false, tx));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut file, b"dnsmasq[1234]: query[A] new.example.com from 10.38.73.100\n").unwrap();
+
+        // queries from before the start are skipped
+        let obs = tokio::time::timeout(Duration::from_secs(5), futures::StreamExt::next(&mut rx)).await.unwrap();
+        assert_eq!(obs, query("10.38.73.100", "A", "new.example.com"));
+        follow.abort();
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn follow_invalid_utf8() {
+        let path = std::env::temp_dir().join(format!("spytrap-resolver-utf8-{}.log", std::process::id()));
+        let mut log = b"dnsmasq[1234]: query[A] \xff\xfe.example.com from 10.38.73.100\n".to_vec();
+        log.extend(b"dnsmasq[1234]: query[A] next.example.com from 10.38.73.100\n");
+        std::fs::write(&path, log).unwrap();
+
+        let (tx, mut rx) = futures::channel::mpsc::channel(16);
+        let follow = tokio::spawn(follow(path.to_str().unwrap().to_string(), dnsmasq, true, tx));
+
+        // the broken line is skipped, the input keeps going
+        let obs = tokio::time::timeout(Duration::from_secs(5), futures::StreamExt::next(&mut rx)).await.unwrap();
+        assert_eq!(obs, query("10.38.73.100", "A", "next.example.com"));
+        assert!(!follow.is_finished());
+        follow.abort();
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,330 @@
+use crate::errors::*;
+use crate::json::HTTP;
+use aho_corasick::AhoCorasick;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// Custom detection rules, in addition to the stalkerware-indicators iocs
+///
+/// ```yaml
+/// - name: ExampleSpy
+///   dns:
+///   - domain: exfil.example.com
+///     types: [TXT, NULL]
+///   http:
+///   - uri: /api/upload_sms.php
+///   - agent: ExampleSpy-Agent/
+///   ja3:
+///   - 6734f37431670b3ab4292b8f60f29984
+///   ja4:
+///   - t13d1516h2_8daaf6152771_e5627efa2ab1
+/// ```
+#[derive(Debug, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    http: HttpMatcher,
+    fingerprints: HashMap<String, usize>,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct Rule {
+    pub name: String,
+    #[serde(default)]
+    pub dns: Vec<DnsRule>,
+    #[serde(default)]
+    pub http: Vec<HttpRule>,
+    /// TLS ClientHello fingerprints
+    #[serde(default)]
+    pub ja3: Vec<String>,
+    #[serde(default)]
+    pub ja4: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct DnsRule {
+    pub domain: String,
+    /// Only match these query types, an empty list matches any type
+    #[serde(default)]
+    pub types: Vec<String>,
+}
+
+impl DnsRule {
+    pub fn matches(&self, qtype: &str, name: &str) -> bool {
+        let domain = self.domain.trim_end_matches('.');
+        let name = name.trim_end_matches('.');
+
+        let domain_matches = name == domain || name.strip_suffix(domain)
+            .map(|prefix| prefix.ends_with('.'))
+            .unwrap_or(false);
+        if !domain_matches {
+            return false;
+        }
+
+        self.types.is_empty() || self.types.iter().any(|t| t.eq_ignore_ascii_case(qtype))
+    }
+}
+
+/// If both fields are set, both need to match
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct HttpRule {
+    /// Substring of the request uri, case sensitive
+    pub uri: Option<String>,
+    /// Substring of the user agent, case insensitive
+    pub agent: Option<String>,
+}
+
+/// Match all uri and user agent patterns at once
+#[derive(Debug, Default)]
+struct HttpMatcher {
+    uri: Option<AhoCorasick>,
+    agent: Option<AhoCorasick>,
+    // for each pattern, the index of the http rule it belongs to
+    uri_owners: Vec<usize>,
+    agent_owners: Vec<usize>,
+    // for each http rule, the index of the rule and which fields are required
+    rules: Vec<(usize, bool, bool)>,
+}
+
+impl HttpMatcher {
+    fn new(rules: &[Rule]) -> Result<HttpMatcher> {
+        let mut matcher = HttpMatcher::default();
+        let mut uri_patterns = Vec::new();
+        let mut agent_patterns = Vec::new();
+
+        for (idx, rule) in rules.iter().enumerate() {
+            for http in &rule.http {
+                let owner = matcher.rules.len();
+                if http.uri.is_none() && http.agent.is_none() {
+                    bail!("Http rule in {:?} needs an uri or an agent", rule.name);
+                }
+                if let Some(uri) = &http.uri {
+                    uri_patterns.push(uri.as_str());
+                    matcher.uri_owners.push(owner);
+                }
+                if let Some(agent) = &http.agent {
+                    agent_patterns.push(agent.as_str());
+                    matcher.agent_owners.push(owner);
+                }
+                matcher.rules.push((idx, http.uri.is_some(), http.agent.is_some()));
+            }
+        }
+
+        if !uri_patterns.is_empty() {
+            matcher.uri = Some(AhoCorasick::new(uri_patterns)?);
+        }
+        if !agent_patterns.is_empty() {
+            matcher.agent = Some(AhoCorasick::builder()
+                .ascii_case_insensitive(true)
+                .build(agent_patterns)?);
+        }
+
+        Ok(matcher)
+    }
+
+    fn find(ac: &Option<AhoCorasick>, owners: &[usize], haystack: Option<&str>) -> HashSet<usize> {
+        match (ac, haystack) {
+            (Some(ac), Some(haystack)) => ac.find_overlapping_iter(haystack)
+                .map(|m| owners[m.pattern().as_usize()])
+                .collect(),
+            _ => HashSet::new(),
+        }
+    }
+
+    fn matches(&self, http: &HTTP) -> Option<usize> {
+        let uri = Self::find(&self.uri, &self.uri_owners, Some(&http.uri));
+        let agent = Self::find(&self.agent, &self.agent_owners, http.agent.as_deref());
+
+        self.rules.iter()
+            .enumerate()
+            .find(|(owner, (_, needs_uri, needs_agent))| {
+                (!needs_uri || uri.contains(owner)) && (!needs_agent || agent.contains(owner))
+            })
+            .map(|(_, (idx, _, _))| *idx)
+    }
+}
+
+impl Rules {
+    #[inline]
+    pub fn new() -> Rules {
+        Rules::default()
+    }
+
+    pub fn from_list(rules: Vec<Rule>) -> Result<Rules> {
+        let http = HttpMatcher::new(&rules)?;
+
+        let mut fingerprints = HashMap::new();
+        for (idx, rule) in rules.iter().enumerate() {
+            for fp in rule.ja3.iter().chain(rule.ja4.iter()) {
+                fingerprints.entry(fp.to_lowercase()).or_insert(idx);
+            }
+        }
+
+        Ok(Rules {
+            rules,
+            http,
+            fingerprints,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns the name of the first rule matching this dns query
+    pub fn match_dns(&self, qtype: &str, name: &str) -> Option<&str> {
+        self.rules.iter()
+            .find(|rule| rule.dns.iter().any(|dns| dns.matches(qtype, name)))
+            .map(|rule| rule.name.as_str())
+    }
+
+    /// Returns the name of the first rule matching this http request
+    pub fn match_http(&self, http: &HTTP) -> Option<&str> {
+        self.http.matches(http)
+            .map(|idx| self.rules[idx].name.as_str())
+    }
+
+    /// Lookup a ja3 or ja4 fingerprint
+    pub fn match_fingerprint(&self, fp: &str) -> Option<&str> {
+        self.fingerprints.get(fp)
+            .map(|idx| self.rules[*idx].name.as_str())
+    }
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Rules> {
+    let buf = fs::read(path)?;
+    parse(&buf)
+}
+
+/// Parse custom rules from yaml
+pub fn parse(buf: &[u8]) -> Result<Rules> {
+    let rules = serde_yaml::from_slice(buf)?;
+    Rules::from_list(rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> Rules {
+        parse(br#"---
+- name: ExampleSpy
+  dns:
+  - domain: exfil.example.com
+    types: [TXT, NULL]
+  - domain: c2.example.com
+- name: CheapSpy
+  http:
+  - uri: /api/upload_sms.php
+  - agent: CheapSpy-Agent/
+  - uri: /sync
+    agent: okhttp/
+  ja3:
+  - 6734F37431670B3AB4292B8F60F29984
+  ja4:
+  - t13d1516h2_8daaf6152771_e5627efa2ab1
+"#).unwrap()
+    }
+
+    fn http(uri: &str, agent: Option<&str>) -> HTTP {
+        HTTP {
+            method: "POST".to_string(),
+            uri: uri.to_string(),
+            version: "1.1".to_string(),
+            host: Some("example.com".to_string()),
+            agent: agent.map(String::from),
+            referer: None,
+            cookies: None,
+        }
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rules = rules();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules.rules[0], Rule {
+            name: "ExampleSpy".to_string(),
+            dns: vec![
+                DnsRule {
+                    domain: "exfil.example.com".to_string(),
+                    types: vec!["TXT".to_string(), "NULL".to_string()],
+                },
+                DnsRule {
+                    domain: "c2.example.com".to_string(),
+                    types: vec![],
+                },
+            ],
+            http: vec![],
+            ja3: vec![],
+            ja4: vec![],
+        });
+        assert_eq!(rules.rules[1].http[2], HttpRule {
+            uri: Some("/sync".to_string()),
+            agent: Some("okhttp/".to_string()),
+        });
+    }
+
+    #[test]
+    fn match_type() {
+        let rules = rules();
+        assert_eq!(rules.match_dns("TXT", "abc.exfil.example.com"), Some("ExampleSpy"));
+        assert_eq!(rules.match_dns("txt", "exfil.example.com"), Some("ExampleSpy"));
+        assert_eq!(rules.match_dns("A", "abc.exfil.example.com"), None);
+    }
+
+    #[test]
+    fn match_any_type() {
+        let rules = rules();
+        assert_eq!(rules.match_dns("A", "c2.example.com"), Some("ExampleSpy"));
+        assert_eq!(rules.match_dns("AAAA", "www.c2.example.com"), Some("ExampleSpy"));
+    }
+
+    #[test]
+    fn no_partial_label() {
+        let rules = rules();
+        assert_eq!(rules.match_dns("A", "notc2.example.com"), None);
+        assert_eq!(rules.match_dns("A", "example.com"), None);
+    }
+
+    #[test]
+    fn match_uri() {
+        let rules = rules();
+        assert_eq!(rules.match_http(&http("/api/upload_sms.php?id=1", None)), Some("CheapSpy"));
+        assert_eq!(rules.match_http(&http("/API/UPLOAD_SMS.PHP", None)), None);
+    }
+
+    #[test]
+    fn match_agent() {
+        let rules = rules();
+        assert_eq!(rules.match_http(&http("/", Some("cheapspy-agent/1.2"))), Some("CheapSpy"));
+        assert_eq!(rules.match_http(&http("/", Some("curl/7.72.0"))), None);
+        assert_eq!(rules.match_http(&http("/", None)), None);
+    }
+
+    #[test]
+    fn match_uri_and_agent() {
+        let rules = rules();
+        assert_eq!(rules.match_http(&http("/sync", Some("okhttp/4.9.0"))), Some("CheapSpy"));
+        assert_eq!(rules.match_http(&http("/sync", Some("curl/7.72.0"))), None);
+        assert_eq!(rules.match_http(&http("/", Some("okhttp/4.9.0"))), None);
+    }
+
+    #[test]
+    fn empty_http_rule() {
+        assert!(parse(b"[{name: Foo, http: [{}]}]").is_err());
+    }
+
+    #[test]
+    fn match_fingerprint() {
+        let rules = rules();
+        assert_eq!(rules.match_fingerprint("6734f37431670b3ab4292b8f60f29984"), Some("CheapSpy"));
+        assert_eq!(rules.match_fingerprint("t13d1516h2_8daaf6152771_e5627efa2ab1"), Some("CheapSpy"));
+        assert_eq!(rules.match_fingerprint("t13d1516h2_8daaf6152771_000000000000"), None);
+    }
+}
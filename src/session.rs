@@ -0,0 +1,526 @@
+use crate::errors::*;
+use crate::event::{Detection, Event, Target};
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::time::Duration;
+
+/// Sessions without any traffic are closed after this long
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Which iocs were used for a scan, findings are only meaningful together with the list they were checked against
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub path: String,
+    pub sha256: String,
+    pub iocs: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom_rules: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+    /// The last client left the hotspot
+    Disconnected,
+    /// The hotspot was restarted with a new password
+    Reset,
+    /// Nothing was seen for a while
+    Timeout,
+    Shutdown,
+}
+
+impl EndReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EndReason::Disconnected => "disconnected",
+            EndReason::Reset => "reset",
+            EndReason::Timeout => "timeout",
+            EndReason::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// A single scan, from the phone joining the hotspot until it leaves
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub started: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ended: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<EndReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// Every client that was connected during this session
+    pub clients: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+    pub findings: Vec<Detection>,
+}
+
+impl Session {
+    fn new(now: DateTime<Utc>) -> Session {
+        Session {
+            id: format!("{}-{:04x}", now.format("%Y%m%d-%H%M%S"), rand::random::<u16>()),
+            started: now,
+            ended: None,
+            reason: None,
+            ssid: None,
+            password: None,
+            clients: Vec::new(),
+            provenance: None,
+            findings: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "session {}", self.id)?;
+        if let Some(reason) = &self.reason {
+            write!(f, " ended ({}): {} findings", reason.as_str(), self.findings.len())
+        } else if self.clients.is_empty() {
+            write!(f, " started")
+        } else {
+            write!(f, " started: {}", self.clients.join(", "))
+        }
+    }
+}
+
+/// Detections with the same key are only reported once per session
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    family: Option<String>,
+    name: String,
+    kind: String,
+    /// The phone it was seen from, or the interface if the client is unknown
+    source: Option<String>,
+}
+
+impl Key {
+    fn new(detection: &Detection) -> Key {
+        let origin = &detection.origin;
+        let name = match &detection.target {
+            // the queries differ every time, the domain and heuristic are what's repeated
+            Target::Tunnel { parent, reason, .. } => format!("{:?} ({})", parent, reason),
+            target => target.to_string(),
+        };
+        Key {
+            family: detection.family().map(String::from),
+            name,
+            kind: detection.kind.clone(),
+            source: origin.client.map(|client| client.to_string())
+                .or_else(|| origin.interface.clone()),
+        }
+    }
+}
+
+/// Group events into sessions and tag every event with the session it belongs to
+#[derive(Debug)]
+pub struct Tracker {
+    timeout: Duration,
+    credentials: Option<(String, String)>,
+    provenance: Option<Provenance>,
+    current: Option<Session>,
+    connected: BTreeSet<String>,
+    last_seen: DateTime<Utc>,
+    /// Detections of the current session
+    findings: Vec<Detection>,
+    seen: HashMap<Key, usize>,
+}
+
+impl Tracker {
+    pub fn new(timeout: Duration) -> Tracker {
+        Tracker {
+            timeout,
+            credentials: None,
+            provenance: None,
+            current: None,
+            connected: BTreeSet::new(),
+            last_seen: DateTime::<Utc>::UNIX_EPOCH,
+            findings: Vec::new(),
+            seen: HashMap::new(),
+        }
+    }
+
+    pub fn current(&self) -> Option<&Session> {
+        self.current.as_ref()
+    }
+
+    fn open(&mut self, now: DateTime<Utc>) {
+        let mut session = Session::new(now);
+        if let Some((ssid, password)) = &self.credentials {
+            session.ssid = Some(ssid.clone());
+            session.password = Some(password.clone());
+        }
+        session.provenance = self.provenance.clone();
+        info!("Opened session {}", session.id);
+        self.current = Some(session);
+        self.last_seen = now;
+        self.findings.clear();
+        self.seen.clear();
+    }
+
+    fn close(&mut self, reason: EndReason, now: DateTime<Utc>) -> Option<Event> {
+        let mut session = self.current.take()?;
+        session.ended = Some(now);
+        session.reason = Some(reason);
+        session.findings = std::mem::take(&mut self.findings);
+        self.seen.clear();
+        self.connected.clear();
+        info!("Closed session {} ({})", session.id, reason.as_str());
+        Some(Event::SessionEnded(session))
+    }
+
+    /// Open a session without waiting for a client, e.g. for a recording that is checked as one scan
+    pub fn start_session(&mut self, now: DateTime<Utc>) -> Vec<Event> {
+        let mut events = Vec::new();
+        events.extend(self.close(EndReason::Reset, now));
+        self.open(now);
+        events.extend(self.current.clone().map(Event::SessionStarted));
+        events
+    }
+
+    /// Close the current session, e.g. when a replayed hostapd hook resets the hotspot
+    pub fn end_session(&mut self, reason: EndReason, now: DateTime<Utc>) -> Option<Event> {
+        self.close(reason, now)
+    }
+
+    /// Merge repeated detections into the first one, returns false if this isn't new
+    fn dedup(&mut self, detection: &Detection, now: DateTime<Utc>) -> bool {
+        let key = Key::new(detection);
+        if let Some(idx) = self.seen.get(&key) {
+            let first = &mut self.findings[*idx];
+            first.count += 1;
+            first.last_seen = Some(now);
+            false
+        } else {
+            self.seen.insert(key, self.findings.len());
+            self.findings.push(detection.clone());
+            true
+        }
+    }
+
+    /// Returns the event tagged with its session and time, surrounded by session starts and ends.
+    /// Detections that were already reported in this session are only counted, without a session
+    /// every detection is passed on
+    pub fn handle(&mut self, mut event: Event, now: DateTime<Utc>) -> Vec<Event> {
+        let mut events = Vec::new();
+        match &event {
+            Event::Credentials { ssid, password, .. } => {
+                events.extend(self.close(EndReason::Reset, now));
+                self.credentials = Some((ssid.clone(), password.clone()));
+            }
+            Event::IocsLoaded(provenance) => {
+                if let Some(session) = &mut self.current {
+                    session.provenance.get_or_insert_with(|| provenance.clone());
+                }
+                self.provenance = Some(provenance.clone());
+            }
+            Event::ClientConnected { mac, .. } => {
+                let opened = self.current.is_none();
+                if opened {
+                    self.open(now);
+                }
+                if let Some(session) = &mut self.current {
+                    if !session.clients.contains(mac) {
+                        session.clients.push(mac.clone());
+                    }
+                    if opened {
+                        events.push(Event::SessionStarted(session.clone()));
+                    }
+                }
+                self.connected.insert(mac.clone());
+            }
+            _ => (),
+        }
+
+        if let Event::Detection(detection) = &mut event {
+            detection.time = Some(now);
+        }
+        if let Some(session) = &self.current {
+            event.set_session(&session.id);
+            if matches!(event, Event::Detection(_) | Event::Observation(_) | Event::ClientConnected { .. }) {
+                self.last_seen = now;
+            }
+        }
+        if let Event::Detection(detection) = &event {
+            if self.current.is_some() && !self.dedup(detection, now) {
+                return events;
+            }
+        }
+
+        let disconnected = match &event {
+            Event::ClientDisconnected { mac, .. } => {
+                self.connected.remove(mac);
+                self.connected.is_empty()
+            }
+            _ => false,
+        };
+        events.push(event);
+        if disconnected {
+            events.extend(self.close(EndReason::Disconnected, now));
+        }
+        events
+    }
+
+    /// Close the current session if it has been idle for too long
+    pub fn expire(&mut self, now: DateTime<Utc>) -> Option<Event> {
+        let idle = now.signed_duration_since(self.last_seen).to_std().unwrap_or_default();
+        if self.current.is_some() && idle >= self.timeout {
+            self.close(EndReason::Timeout, now)
+        } else {
+            None
+        }
+    }
+}
+
+/// Tag a stream of events with sessions, an open session is closed once the stream ends
+pub fn track<R: Stream<Item=Event> + Unpin>(rx: R, tracker: Tracker) -> impl Stream<Item=Event> {
+    let interval = tokio::time::interval(Duration::from_secs(10));
+    let state = (rx.fuse(), tracker, interval, VecDeque::new(), false);
+    futures::stream::unfold(state, |(mut rx, mut tracker, mut interval, mut pending, mut done)| async move {
+        loop {
+            if let Some(event) = pending.pop_front() {
+                return Some((event, (rx, tracker, interval, pending, done)));
+            }
+            if done {
+                return None;
+            }
+            tokio::select! {
+                event = rx.next() => match event {
+                    Some(event) => pending.extend(tracker.handle(event, Utc::now())),
+                    None => {
+                        pending.extend(tracker.close(EndReason::Shutdown, Utc::now()));
+                        done = true;
+                    }
+                },
+                _ = interval.tick() => pending.extend(tracker.expire(Utc::now())),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{Level, Target};
+
+    fn time(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    fn detection() -> Event {
+        Event::from(Detection::new(Level::Detected, "dns", Target::Name("example.com".to_string())))
+    }
+
+    fn connected(mac: &str) -> Event {
+        Event::ClientConnected { mac: mac.to_string(), session: None }
+    }
+
+    fn disconnected(mac: &str) -> Event {
+        Event::ClientDisconnected { mac: mac.to_string(), session: None }
+    }
+
+    fn credentials() -> Event {
+        Event::Credentials {
+            ssid: "Starbucks WiFi".to_string(),
+            password: "abcdefghij".to_string(),
+            session: None,
+        }
+    }
+
+    fn ended(events: &[Event]) -> Option<&Session> {
+        events.iter().find_map(|event| match event {
+            Event::SessionEnded(session) => Some(session),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn session_lifecycle() {
+        let mut tracker = Tracker::new(IDLE_TIMEOUT);
+        assert_eq!(tracker.handle(credentials(), time(0)), vec![credentials()]);
+        // nothing to attach it to yet
+        let events = tracker.handle(detection(), time(1));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].session(), None);
+
+        let events = tracker.handle(connected("aa:bb"), time(2));
+        let session = match &events[..] {
+            [Event::SessionStarted(session), Event::ClientConnected { session: Some(id), .. }] => {
+                assert_eq!(&session.id, id);
+                session.clone()
+            }
+            _ => panic!("unexpected events: {:?}", events),
+        };
+        assert_eq!(session.clients, vec!["aa:bb"]);
+        assert_eq!(session.password.as_deref(), Some("abcdefghij"));
+
+        let events = tracker.handle(detection(), time(3));
+        assert_eq!(events[0].session(), Some(session.id.as_str()));
+        assert_eq!(events[0].detection().unwrap().time, Some(time(3)));
+
+        let events = tracker.handle(disconnected("aa:bb"), time(4));
+        assert_eq!(events[0].session(), Some(session.id.as_str()));
+        let ended = ended(&events).unwrap();
+        assert_eq!(ended.id, session.id);
+        assert_eq!(ended.reason, Some(EndReason::Disconnected));
+        assert_eq!(ended.ended, Some(time(4)));
+        assert_eq!(ended.findings.len(), 1);
+        assert!(tracker.current().is_none());
+    }
+
+    #[test]
+    fn merge_repeated_detections() {
+        let mut tracker = Tracker::new(IDLE_TIMEOUT);
+        tracker.handle(connected("aa:bb"), time(0));
+        assert_eq!(tracker.handle(detection(), time(1)).len(), 1);
+        assert_eq!(tracker.handle(detection(), time(2)), vec![]);
+        assert_eq!(tracker.handle(detection(), time(3)), vec![]);
+
+        // a different source is new information
+        let mut tls = detection();
+        if let Event::Detection(detection) = &mut tls {
+            detection.kind = "tls".to_string();
+        }
+        assert_eq!(tracker.handle(tls, time(4)).len(), 1);
+
+        let events = tracker.handle(disconnected("aa:bb"), time(5));
+        let findings = &ended(&events).unwrap().findings;
+        assert_eq!(findings.len(), 2);
+        assert_eq!((findings[0].count, findings[0].time, findings[0].last_seen), (3, Some(time(1)), Some(time(3))));
+        assert_eq!((findings[1].count, findings[1].last_seen), (1, None));
+
+        // every session starts over
+        tracker.handle(connected("aa:bb"), time(6));
+        assert_eq!(tracker.handle(detection(), time(7)).len(), 1);
+    }
+
+    #[test]
+    fn merge_per_client() {
+        let from = |client: Option<&str>, interface: Option<&str>| {
+            let mut event = detection();
+            if let Event::Detection(detection) = &mut event {
+                detection.origin.client = client.map(|client| client.parse().unwrap());
+                detection.origin.interface = interface.map(String::from);
+            }
+            event
+        };
+        let mut tracker = Tracker::new(IDLE_TIMEOUT);
+        tracker.handle(connected("aa:bb"), time(0));
+        tracker.handle(connected("cc:dd"), time(0));
+        assert_eq!(tracker.handle(from(Some("10.0.0.2"), Some("wlan0")), time(1)).len(), 1);
+        assert_eq!(tracker.handle(from(Some("10.0.0.2"), Some("wlan0")), time(2)), vec![]);
+        // the second phone gets its own finding
+        assert_eq!(tracker.handle(from(Some("10.0.0.3"), Some("wlan0")), time(3)).len(), 1);
+        // without a client the interface tells them apart
+        assert_eq!(tracker.handle(from(None, Some("wlan0")), time(4)).len(), 1);
+        assert_eq!(tracker.handle(from(None, Some("wlan1")), time(5)).len(), 1);
+        assert_eq!(tracker.handle(from(None, Some("wlan1")), time(6)), vec![]);
+
+        tracker.handle(disconnected("aa:bb"), time(7));
+        let events = tracker.handle(disconnected("cc:dd"), time(8));
+        let findings = &ended(&events).unwrap().findings;
+        assert_eq!(findings.iter().map(|finding| finding.count).collect::<Vec<_>>(), vec![2, 1, 1, 2]);
+    }
+
+    #[test]
+    fn merge_tunnel_queries() {
+        let tunnel = |name: &str| Event::from(Detection::new(Level::Suspicious, "dns", Target::Tunnel {
+            parent: "example.com".to_string(),
+            reason: "TXT query to unknown domain".to_string(),
+            names: vec![name.to_string()],
+        }));
+        let mut tracker = Tracker::new(IDLE_TIMEOUT);
+        tracker.start_session(time(0));
+        assert_eq!(tracker.handle(tunnel("a.example.com"), time(1)).len(), 1);
+        assert_eq!(tracker.handle(tunnel("b.example.com"), time(2)), vec![]);
+    }
+
+    #[test]
+    fn no_merge_without_session() {
+        let mut tracker = Tracker::new(IDLE_TIMEOUT);
+        assert_eq!(tracker.handle(detection(), time(0)).len(), 1);
+        assert_eq!(tracker.handle(detection(), time(1)).len(), 1);
+        assert!(tracker.findings.is_empty());
+
+        tracker.handle(connected("aa:bb"), time(2));
+        assert_eq!(tracker.handle(detection(), time(3)).len(), 1);
+        let events = tracker.handle(disconnected("aa:bb"), time(4));
+        assert_eq!(ended(&events).unwrap().findings[0].count, 1);
+    }
+
+    #[test]
+    fn start_session() {
+        let mut tracker = Tracker::new(IDLE_TIMEOUT);
+        let events = tracker.start_session(time(0));
+        match &events[..] {
+            [Event::SessionStarted(session)] => assert!(session.to_string().ends_with(" started")),
+            events => panic!("unexpected events: {:?}", events),
+        }
+        assert_eq!(tracker.handle(detection(), time(1)).len(), 1);
+        assert!(tracker.handle(detection(), time(2)).is_empty());
+
+        // starting again ends the previous one
+        let events = tracker.start_session(time(3));
+        assert_eq!(ended(&events).unwrap().findings[0].count, 2);
+        assert!(matches!(events[1], Event::SessionStarted(_)));
+    }
+
+    #[test]
+    fn multiple_clients() {
+        let mut tracker = Tracker::new(IDLE_TIMEOUT);
+        tracker.handle(connected("aa:bb"), time(0));
+        assert_eq!(tracker.handle(connected("cc:dd"), time(1)).len(), 1);
+        assert!(ended(&tracker.handle(disconnected("aa:bb"), time(2))).is_none());
+        let events = tracker.handle(disconnected("cc:dd"), time(3));
+        assert_eq!(ended(&events).unwrap().clients, vec!["aa:bb", "cc:dd"]);
+    }
+
+    #[test]
+    fn reset_closes_session() {
+        let mut tracker = Tracker::new(IDLE_TIMEOUT);
+        tracker.handle(connected("aa:bb"), time(0));
+        let events = tracker.handle(credentials(), time(1));
+        assert_eq!(ended(&events).unwrap().reason, Some(EndReason::Reset));
+        assert_eq!(events.last(), Some(&credentials()));
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let mut tracker = Tracker::new(Duration::from_secs(60));
+        tracker.handle(connected("aa:bb"), time(0));
+        tracker.handle(detection(), time(30));
+        assert_eq!(tracker.expire(time(60)), None);
+        // status updates don't keep a session alive
+        tracker.handle(Event::status("loaded 1 iocs"), time(80));
+        let session = match tracker.expire(time(90)) {
+            Some(Event::SessionEnded(session)) => session,
+            event => panic!("unexpected event: {:?}", event),
+        };
+        assert_eq!(session.reason, Some(EndReason::Timeout));
+    }
+
+    #[test]
+    fn provenance() {
+        let provenance = Provenance {
+            path: "ioc.yaml".to_string(),
+            sha256: "00".repeat(32),
+            iocs: 3,
+            custom_rules: None,
+        };
+        let mut tracker = Tracker::new(IDLE_TIMEOUT);
+        tracker.handle(Event::IocsLoaded(provenance.clone()), time(0));
+        let events = tracker.handle(connected("aa:bb"), time(1));
+        match &events[0] {
+            Event::SessionStarted(session) => assert_eq!(session.provenance.as_ref(), Some(&provenance)),
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+}
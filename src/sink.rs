@@ -0,0 +1,485 @@
+use crate::errors::*;
+use crate::event::Event;
+use crate::history::{self, History};
+use crate::report::Report;
+use chrono::Utc;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixListener;
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::process::{Child, ChildStdin, Command};
+
+/// Somewhere events are delivered to, e.g. the screen or a log file
+///
+/// Every sink runs in its own task, the next event is only sent once the previous one was handled.
+/// Returning an error stops the sink without affecting detections or other sinks.
+pub trait Sink: Send {
+    fn send<'a>(&'a mut self, event: &'a Event) -> BoxFuture<'a, Result<()>>;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// The same lines that are shown on the screen
+    #[default]
+    Text,
+    /// One json object per line
+    Json,
+}
+
+impl Format {
+    pub fn render(&self, event: &Event) -> Result<String> {
+        match self {
+            Format::Text => Ok(event.to_string()),
+            Format::Json => Ok(serde_json::to_string(event)?),
+        }
+    }
+}
+
+/// What happens to new events if a sink can't keep up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicy {
+    /// Discard the oldest queued event, the screen should show the latest findings
+    #[default]
+    Oldest,
+    /// Discard the new event, keeps the start of a scan
+    Newest,
+}
+
+fn default_buffer() -> usize {
+    64
+}
+
+/// A `[[sink]]` entry of the config file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SinkConfig {
+    /// `stdout`, `screen`, `file`, `socket`, `history`, `report` or any other registered sink
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Used in logs, defaults to the type
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Number of events that are queued for this sink
+    #[serde(default = "default_buffer")]
+    pub buffer: usize,
+    #[serde(default)]
+    pub drop: DropPolicy,
+    #[serde(default)]
+    pub format: Format,
+    /// Also send names that didn't match anything
+    #[serde(default)]
+    pub observations: bool,
+    /// Program for `screen`, events are written to its stdin
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// Path for `file`, `socket` and `history`, the directory for `report`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Group (name or id) that may connect to a `socket`, otherwise only the owner can
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+impl SinkConfig {
+    pub fn new(kind: &str) -> SinkConfig {
+        SinkConfig {
+            kind: kind.to_string(),
+            name: None,
+            buffer: default_buffer(),
+            drop: DropPolicy::default(),
+            format: Format::default(),
+            observations: false,
+            command: None,
+            path: None,
+            group: None,
+        }
+    }
+
+    /// The sink that is used if none are configured
+    pub fn screen(command: &str) -> SinkConfig {
+        SinkConfig {
+            command: Some(command.to_string()),
+            ..SinkConfig::new("screen")
+        }
+    }
+
+    pub fn history(path: &str) -> SinkConfig {
+        SinkConfig {
+            path: Some(path.to_string()),
+            // findings shouldn't be lost while the sd card is slow
+            buffer: 1024,
+            drop: DropPolicy::Newest,
+            ..SinkConfig::new("history")
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.kind)
+    }
+
+    fn command(&self) -> Result<&str> {
+        self.command.as_deref()
+            .with_context(|| anyhow!("Sink {:?} is missing a command", self.name()))
+    }
+
+    fn path(&self) -> Result<&str> {
+        self.path.as_deref()
+            .with_context(|| anyhow!("Sink {:?} is missing a path", self.name()))
+    }
+}
+
+pub type Factory = fn(&SinkConfig) -> Result<Box<dyn Sink>>;
+
+/// Known sink types, additional ones can be registered before the config is loaded
+pub struct Registry {
+    factories: HashMap<String, Factory>,
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        let mut registry = Registry {
+            factories: HashMap::new(),
+        };
+        registry.register("stdout", |config| Ok(Box::new(Stdout::new(config.format))));
+        registry.register("screen", |config| Ok(Box::new(Screen::spawn(config.command()?, config.format)?)));
+        registry.register("file", |config| Ok(Box::new(LogFile::new(config.path()?, config.format))));
+        registry.register("socket", |config| Ok(Box::new(Socket::bind(config.path()?, config.format, config.group.as_deref())?)));
+        registry.register("report", |config| Ok(Box::new(Reports::new(config.path()?))));
+        registry.register("history", |config| {
+            let path = config.path.as_deref().unwrap_or(history::DEFAULT_PATH);
+            Ok(Box::new(HistoryDb::open(path)?))
+        });
+        registry
+    }
+}
+
+impl Registry {
+    pub fn register(&mut self, kind: &str, factory: Factory) {
+        self.factories.insert(kind.to_string(), factory);
+    }
+
+    pub fn contains(&self, kind: &str) -> bool {
+        self.factories.contains_key(kind)
+    }
+
+    pub fn build(&self, config: &SinkConfig) -> Result<Box<dyn Sink>> {
+        let factory = self.factories.get(&config.kind)
+            .with_context(|| anyhow!("Unknown sink type: {:?}", config.kind))?;
+        factory(config)
+            .with_context(|| anyhow!("Failed to setup sink {:?}", config.name()))
+    }
+}
+
+pub struct Stdout {
+    format: Format,
+}
+
+impl Stdout {
+    pub fn new(format: Format) -> Stdout {
+        Stdout { format }
+    }
+}
+
+impl Sink for Stdout {
+    fn send<'a>(&'a mut self, event: &'a Event) -> BoxFuture<'a, Result<()>> {
+        async move {
+            println!("{}", self.format.render(event)?);
+            Ok(())
+        }.boxed()
+    }
+}
+
+/// A program that reads events from stdin, e.g. the e-paper script
+pub struct Screen {
+    child: Child,
+    stdin: ChildStdin,
+    format: Format,
+}
+
+impl Screen {
+    pub fn spawn(bin: &str, format: Format) -> Result<Screen> {
+        info!("Spawning screen: {:?}", bin);
+        let mut child = Command::new(bin)
+            .stdin(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| anyhow!("Failed to spawn {:?}", bin))?;
+        let stdin = child.stdin.take()
+            .context("Child did not have a handle to stdin")?;
+        Ok(Screen {
+            child,
+            stdin,
+            format,
+        })
+    }
+
+    pub async fn write_line(&mut self, line: &str) -> Result<()> {
+        info!("Sending to screen: {:?}", line);
+        if let Err(err) = self.stdin.write_all(format!("{}\n", line).as_bytes()).await {
+            if let Ok(Some(status)) = self.child.try_wait() {
+                bail!("Screen has exited: {}", status);
+            }
+            return Err(err.into());
+        }
+        Ok(())
+    }
+}
+
+impl Sink for Screen {
+    fn send<'a>(&'a mut self, event: &'a Event) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let line = self.format.render(event)?;
+            self.write_line(&line).await
+        }.boxed()
+    }
+}
+
+/// Append events to a file, it's opened lazily so the directory may be mounted later
+pub struct LogFile {
+    path: String,
+    file: Option<File>,
+    format: Format,
+}
+
+impl LogFile {
+    pub fn new(path: &str, format: Format) -> LogFile {
+        LogFile {
+            path: path.to_string(),
+            file: None,
+            format,
+        }
+    }
+}
+
+impl Sink for LogFile {
+    fn send<'a>(&'a mut self, event: &'a Event) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let line = self.format.render(event)?;
+            let file = match &mut self.file {
+                Some(file) => file,
+                None => {
+                    let file = OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&self.path).await
+                        .with_context(|| anyhow!("Failed to open log file {:?}", self.path))?;
+                    self.file.insert(file)
+                }
+            };
+            file.write_all(format!("{}\n", line).as_bytes()).await?;
+            file.flush().await?;
+            Ok(())
+        }.boxed()
+    }
+}
+
+/// Clients that don't read an event within this time are disconnected
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Look up a group id in /etc/group, numeric ids are used as they are
+pub(crate) fn group_id(group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let groups = std::fs::read_to_string("/etc/group")
+        .context("Failed to read /etc/group")?;
+    find_group(&groups, group)
+        .with_context(|| anyhow!("Unknown group: {:?}", group))
+}
+
+fn find_group(groups: &str, name: &str) -> Option<u32> {
+    groups.lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields[0] == name)
+        .and_then(|fields| fields.get(2)?.parse().ok())
+}
+
+/// A unix socket that any number of clients can connect to, e.g. with `socat - UNIX-CONNECT:events.sock`
+pub struct Socket {
+    clients: Arc<Mutex<Vec<OwnedWriteHalf>>>,
+    format: Format,
+}
+
+impl Socket {
+    pub fn bind(path: &str, format: Format, group: Option<&str>) -> Result<Socket> {
+        if Path::new(path).exists() {
+            std::fs::remove_file(path)
+                .context("Failed to remove old socket")?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| anyhow!("Failed to bind event socket {:?}", path))?;
+        // events include the hotspot password, only the owner and the group may read them
+        if let Some(group) = group {
+            std::os::unix::fs::chown(path, None, Some(group_id(group)?))
+                .with_context(|| anyhow!("Failed to change the group of {:?}", path))?;
+        }
+        std::fs::set_permissions(path, Permissions::from_mode(0o660))
+            .with_context(|| anyhow!("Failed to set permissions of {:?}", path))?;
+        let clients = Arc::new(Mutex::new(Vec::new()));
+
+        let path = path.to_string();
+        let accepted = clients.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _addr)) => {
+                        debug!("Client subscribed to {:?}", path);
+                        let (_, write) = stream.into_split();
+                        accepted.lock().unwrap().push(write);
+                    }
+                    Err(err) => {
+                        warn!("Failed to accept on {:?}: {:#}", path, err);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Socket {
+            clients,
+            format,
+        })
+    }
+}
+
+impl Sink for Socket {
+    fn send<'a>(&'a mut self, event: &'a Event) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let line = format!("{}\n", self.format.render(event)?);
+            let clients = std::mem::take(&mut *self.clients.lock().unwrap());
+
+            let mut connected = Vec::with_capacity(clients.len());
+            for mut client in clients {
+                // a client that went away or stopped reading doesn't affect the others
+                match tokio::time::timeout(CLIENT_TIMEOUT, client.write_all(line.as_bytes())).await {
+                    Ok(Ok(())) => connected.push(client),
+                    Ok(Err(err)) => debug!("Client disconnected: {:#}", err),
+                    Err(_) => warn!("Disconnecting client that didn't read events for {:?}", CLIENT_TIMEOUT),
+                }
+            }
+
+            // clients that connected in the meantime were added to the empty list
+            self.clients.lock().unwrap().extend(connected);
+            Ok(())
+        }.boxed()
+    }
+}
+
+/// Store sessions and detections in the sqlite history, with `observations` enabled names are counted too
+pub struct HistoryDb {
+    history: Arc<Mutex<History>>,
+}
+
+impl HistoryDb {
+    pub fn open(path: &str) -> Result<HistoryDb> {
+        let history = History::open(path)?;
+        Ok(HistoryDb {
+            history: Arc::new(Mutex::new(history)),
+        })
+    }
+}
+
+impl Sink for HistoryDb {
+    fn send<'a>(&'a mut self, event: &'a Event) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let history = self.history.clone();
+            let event = event.clone();
+            // every write is synced to disk, don't block the runtime while waiting for it
+            tokio::task::spawn_blocking(move || {
+                history.lock().unwrap().record(&event, Utc::now())
+            }).await?
+        }.boxed()
+    }
+}
+
+/// Write an html, json and pdf report into a directory once a session has ended
+pub struct Reports {
+    dir: PathBuf,
+}
+
+impl Reports {
+    pub fn new(dir: &str) -> Reports {
+        Reports {
+            dir: PathBuf::from(dir),
+        }
+    }
+}
+
+impl Sink for Reports {
+    fn send<'a>(&'a mut self, event: &'a Event) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let session = match event {
+                Event::SessionEnded(session) => session,
+                _ => return Ok(()),
+            };
+            let report = Report::new(session, Utc::now());
+            fs::create_dir_all(&self.dir).await
+                .with_context(|| anyhow!("Failed to create report directory {:?}", self.dir))?;
+
+            let files = [
+                ("html", report.to_html().into_bytes()),
+                ("json", report.to_json()?.into_bytes()),
+                ("pdf", report.to_pdf()),
+            ];
+            for (ext, data) in files {
+                let path = self.dir.join(format!("{}.{}", session.id, ext));
+                info!("Writing report to {:?}", path);
+                fs::write(&path, data).await
+                    .with_context(|| anyhow!("Failed to write report {:?}", path))?;
+            }
+            Ok(())
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{Detection, Level, Target};
+    use std::os::unix::fs::MetadataExt;
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn lookup_group() {
+        let groups = "root:x:0:\nnetdev:x:108:pi\nspytrap:x:1001:pi,www-data\n";
+        assert_eq!(find_group(groups, "spytrap"), Some(1001));
+        assert_eq!(find_group(groups, "root"), Some(0));
+        assert_eq!(find_group(groups, "pi"), None);
+        assert_eq!(group_id("1001").unwrap(), 1001);
+    }
+
+    #[tokio::test]
+    async fn socket_drops_slow_clients() {
+        let path = std::env::temp_dir().join(format!("spytrap-sink-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        let gid = std::fs::metadata(std::env::temp_dir()).unwrap().gid();
+        let mut socket = Socket::bind(path, Format::Json, Some(&gid.to_string())).unwrap();
+        assert_eq!(std::fs::metadata(path).unwrap().mode() & 0o777, 0o660);
+
+        // connected, but never reads
+        let _client = UnixStream::connect(path).await.unwrap();
+        while socket.clients.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let event = Event::from(Detection::new(Level::Detected, "dns", Target::Name("x".repeat(4096))));
+        let started = std::time::Instant::now();
+        while !socket.clients.lock().unwrap().is_empty() {
+            socket.send(&event).await.unwrap();
+            assert!(started.elapsed() < Duration::from_secs(10));
+        }
+        std::fs::remove_file(path).ok();
+    }
+}
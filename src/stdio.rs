@@ -1,5 +1,6 @@
 use futures::{Sink, SinkExt, Stream, StreamExt};
 use crate::errors::*;
+use std::fmt;
 use tokio::io::{BufReader, AsyncBufReadExt};
 
 pub async fn stdin<S: Sink<String> + Unpin>(mut sink: S) -> Result<()> {
@@ -14,7 +15,7 @@ pub async fn stdin<S: Sink<String> + Unpin>(mut sink: S) -> Result<()> {
     Ok(())
 }
 
-pub async fn stdout<S: Stream<Item=String> + Unpin>(mut stream: S) -> Result<()> {
+pub async fn stdout<T: fmt::Display, S: Stream<Item=T> + Unpin>(mut stream: S) -> Result<()> {
     while let Some(line) = stream.next().await {
         println!("{}", line);
     }
@@ -0,0 +1,269 @@
+use crate::errors::*;
+use crate::event::Event;
+use futures::channel::mpsc::Sender;
+use futures::SinkExt;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::{JoinError, JoinHandle};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    Running,
+    /// Failed and waiting to be restarted
+    Restarting,
+    /// Failed too often, this component is not restarted anymore
+    Failed,
+}
+
+/// How often and how fast a component is restarted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    /// Delay before the first restart, this doubles with every failure
+    pub initial: Duration,
+    pub max: Duration,
+    /// Give up after this many restarts within `window`
+    pub budget: usize,
+    pub window: Duration,
+    /// Running this long without failing resets the delay
+    pub healthy_after: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            budget: 10,
+            window: Duration::from_secs(600),
+            healthy_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Exponential backoff and the restart budget of a single component
+#[derive(Debug)]
+pub struct Restarts {
+    policy: Policy,
+    delay: Duration,
+    history: VecDeque<Instant>,
+}
+
+impl Restarts {
+    pub fn new(policy: Policy) -> Restarts {
+        Restarts {
+            policy,
+            delay: policy.initial,
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Returns how long to wait before the next restart, or None if the budget is used up
+    pub fn failed(&mut self, started: Instant, now: Instant) -> Option<Duration> {
+        if now.duration_since(started) >= self.policy.healthy_after {
+            self.delay = self.policy.initial;
+        }
+
+        while let Some(first) = self.history.front() {
+            if now.duration_since(*first) < self.policy.window {
+                break;
+            }
+            self.history.pop_front();
+        }
+        if self.history.len() >= self.policy.budget {
+            return None;
+        }
+        self.history.push_back(now);
+
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(self.policy.max);
+        Some(delay)
+    }
+}
+
+fn panic_message(err: JoinError) -> String {
+    match err.try_into_panic() {
+        Ok(panic) => {
+            if let Some(msg) = panic.downcast_ref::<&str>() {
+                msg.to_string()
+            } else if let Some(msg) = panic.downcast_ref::<String>() {
+                msg.clone()
+            } else {
+                "unknown panic".to_string()
+            }
+        }
+        Err(err) => err.to_string(),
+    }
+}
+
+/// Run components as tasks that are restarted if they fail or panic, instead of stopping everything
+pub struct Supervisor {
+    policy: Policy,
+    events: Sender<Event>,
+    health: Arc<Mutex<BTreeMap<String, Health>>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Supervisor {
+    /// Failures are reported as error events
+    pub fn new(events: Sender<Event>) -> Supervisor {
+        Supervisor {
+            policy: Policy::default(),
+            events,
+            health: Arc::new(Mutex::new(BTreeMap::new())),
+            tasks: Vec::new(),
+        }
+    }
+
+    pub fn policy(mut self, policy: Policy) -> Supervisor {
+        self.policy = policy;
+        self
+    }
+
+    /// Start a component, `factory` is called again for every restart
+    pub fn spawn<F, Fut>(&mut self, name: &str, mut factory: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output=Result<()>> + Send + 'static,
+    {
+        let name = name.to_string();
+        let mut restarts = Restarts::new(self.policy);
+        let mut events = self.events.clone();
+        let health = self.health.clone();
+
+        let task = tokio::spawn(async move {
+            loop {
+                health.lock().unwrap().insert(name.clone(), Health::Running);
+                let started = Instant::now();
+
+                // this is a separate task so panics are caught too
+                let err = match tokio::spawn(factory()).await {
+                    Ok(Ok(())) => anyhow!("Stopped unexpectedly"),
+                    Ok(Err(err)) => err,
+                    Err(err) => anyhow!("Panicked: {}", panic_message(err)),
+                };
+
+                match restarts.failed(started, Instant::now()) {
+                    Some(delay) => {
+                        error!("Component {:?} failed, restarting in {:?}: {:#}", name, delay, err);
+                        health.lock().unwrap().insert(name.clone(), Health::Restarting);
+                        let msg = format!("{} failed, restarting in {:?}: {:#}", name, delay, err);
+                        events.send(Event::error(msg)).await.ok();
+                        tokio::time::sleep(delay).await;
+                    }
+                    None => {
+                        error!("Component {:?} failed too often, giving up: {:#}", name, err);
+                        health.lock().unwrap().insert(name.clone(), Health::Failed);
+                        let msg = format!("{} failed too often, giving up: {:#}", name, err);
+                        events.send(Event::error(msg)).await.ok();
+                        break;
+                    }
+                }
+            }
+        });
+        self.tasks.push(task);
+    }
+
+    /// The current state of every component
+    pub fn health(&self) -> BTreeMap<String, Health> {
+        self.health.lock().unwrap().clone()
+    }
+
+    /// Wait until every component has failed permanently
+    pub async fn wait(self) -> Result<()> {
+        for task in self.tasks {
+            task.await?;
+        }
+        bail!("All components have failed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn policy() -> Policy {
+        Policy {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(4),
+            budget: 3,
+            window: Duration::from_secs(60),
+            healthy_after: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let mut restarts = Restarts::new(Policy {
+            budget: 100,
+            ..policy()
+        });
+        let now = Instant::now();
+        let delays = (0..5)
+            .map(|_| restarts.failed(now, now).unwrap().as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, vec![1, 2, 4, 4, 4]);
+
+        // running for a while resets the delay
+        let later = now + Duration::from_secs(30);
+        assert_eq!(restarts.failed(now, later), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn restart_budget() {
+        let mut restarts = Restarts::new(policy());
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(restarts.failed(now, now).is_some());
+        }
+        assert_eq!(restarts.failed(now, now), None);
+
+        // old failures leave the window
+        let later = now + Duration::from_secs(61);
+        assert!(restarts.failed(later, later).is_some());
+    }
+
+    #[tokio::test]
+    async fn restart_failing_component() {
+        let (tx, rx) = futures::channel::mpsc::channel(16);
+        let mut supervisor = Supervisor::new(tx).policy(Policy {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(1),
+            budget: 2,
+            ..policy()
+        });
+
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        supervisor.spawn("screen", move || {
+            let counter = counter.clone();
+            async move {
+                if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                    panic!("crashed");
+                }
+                bail!("exited")
+            }
+        });
+        supervisor.spawn("stream", futures::future::pending);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(supervisor.health().into_iter().collect::<Vec<_>>(), vec![
+            ("screen".to_string(), Health::Failed),
+            ("stream".to_string(), Health::Running),
+        ]);
+
+        drop(supervisor);
+        let events = rx.take(3).collect::<Vec<_>>().await;
+        assert_eq!(events, vec![
+            Event::error("screen failed, restarting in 1ms: Panicked: crashed"),
+            Event::error("screen failed, restarting in 1ms: exited"),
+            Event::error("screen failed too often, giving up: exited"),
+        ]);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::errors::*;
+use crate::json::HTTP;
+use crate::observation::{self, Observation};
+use serde::Deserialize;
+use std::net::IpAddr;
+
+#[derive(Debug, Deserialize)]
+struct Event {
+    event_type: String,
+    src_ip: Option<IpAddr>,
+    src_port: Option<u16>,
+    dest_ip: Option<IpAddr>,
+    dest_port: Option<u16>,
+    proto: Option<String>,
+    dns: Option<Dns>,
+    tls: Option<Tls>,
+    http: Option<Http>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Dns {
+    #[serde(rename = "type")]
+    typ: Option<String>,
+    rrname: Option<String>,
+    rrtype: Option<String>,
+    rdata: Option<String>,
+    /// eve version 3
+    #[serde(default)]
+    queries: Vec<Query>,
+    /// eve version 2 and 3
+    #[serde(default)]
+    answers: Vec<Answer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Query {
+    rrname: String,
+    rrtype: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Answer {
+    rrname: String,
+    rrtype: String,
+    rdata: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Tls {
+    sni: Option<String>,
+    ja3: Option<Ja3>,
+    ja4: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Ja3 {
+    hash: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Http {
+    hostname: Option<String>,
+    url: Option<String>,
+    http_user_agent: Option<String>,
+    http_method: Option<String>,
+    protocol: Option<String>,
+    http_refer: Option<String>,
+}
+
+fn resolved(rrname: &str, rrtype: &str, rdata: Option<&str>) -> Option<Observation> {
+    if rrtype != "A" && rrtype != "AAAA" {
+        return None;
+    }
+    let addr = rdata?.parse().ok()?;
+    Some(Observation::Resolved {
+        name: rrname.to_string(),
+        addr,
+    })
+}
+
+/// Parse a line of suricata's eve.json, only dns, tls and http events are used
+pub fn parse(line: &str) -> Result<Vec<Observation>> {
+    let event = serde_json::from_str::<Event>(line)?;
+    let flow = observation::flow(event.proto.as_deref(), event.src_ip, event.src_port, event.dest_ip, event.dest_port);
+
+    let mut observations = Vec::new();
+    match event.event_type.as_str() {
+        "dns" => {
+            let dns = event.dns.context("Dns event is missing dns object")?;
+            match dns.typ.as_deref() {
+                Some("query") | Some("request") => {
+                    if let (Some(rrname), Some(rrtype)) = (dns.rrname, dns.rrtype) {
+                        observations.push(Observation::Dns {
+                            flow,
+                            qtype: rrtype,
+                            name: rrname,
+                        });
+                    }
+                    for query in dns.queries {
+                        observations.push(Observation::Dns {
+                            flow,
+                            qtype: query.rrtype,
+                            name: query.rrname,
+                        });
+                    }
+                }
+                Some("answer") | Some("response") => {
+                    if let (Some(rrname), Some(rrtype)) = (&dns.rrname, &dns.rrtype) {
+                        observations.extend(resolved(rrname, rrtype, dns.rdata.as_deref()));
+                    }
+                    for answer in &dns.answers {
+                        observations.extend(resolved(&answer.rrname, &answer.rrtype, answer.rdata.as_deref()));
+                    }
+                }
+                _ => (),
+            }
+        }
+        "tls" => {
+            let tls = event.tls.context("Tls event is missing tls object")?;
+            observations.push(Observation::Tls {
+                flow,
+                sni: tls.sni,
+                ja3: tls.ja3.and_then(|ja3| ja3.hash),
+                ja4: tls.ja4,
+            });
+        }
+        "http" => {
+            let http = event.http.context("Http event is missing http object")?;
+            let version = http.protocol.as_deref()
+                .and_then(|p| p.strip_prefix("HTTP/"))
+                .unwrap_or("1.1")
+                .to_string();
+            observations.push(Observation::Http {
+                flow,
+                http: HTTP {
+                    method: http.http_method.unwrap_or_default(),
+                    uri: http.url.unwrap_or_default(),
+                    version,
+                    host: http.hostname,
+                    agent: http.http_user_agent,
+                    referer: http.http_refer,
+                    cookies: None,
+                },
+            });
+        }
+        _ => (),
+    }
+
+    Ok(observations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::{Flow, Proto};
+
+    fn flow(dst: &str) -> Option<Flow> {
+        Some(Flow {
+            proto: Proto::UDP,
+            src: "10.38.73.100:53022".parse().unwrap(),
+            dst: dst.parse().unwrap(),
+        })
+    }
+
+    #[test]
+    fn parse_dns_query() {
+        let line = r#"{"timestamp":"2023-05-01T12:00:00.000000+0000","flow_id":1,"in_iface":"wlan1","event_type":"dns","src_ip":"10.38.73.100","src_port":53022,"dest_ip":"10.38.73.1","dest_port":53,"proto":"UDP","dns":{"type":"query","id":1337,"rrname":"example.com","rrtype":"A","tx_id":0,"opcode":0}}"#;
+        assert_eq!(parse(line).unwrap(), vec![Observation::Dns {
+            flow: flow("10.38.73.1:53"),
+            qtype: "A".to_string(),
+            name: "example.com".to_string(),
+        }]);
+    }
+
+    #[test]
+    fn parse_dns_request_v3() {
+        let line = r#"{"event_type":"dns","src_ip":"10.38.73.100","src_port":53022,"dest_ip":"10.38.73.1","dest_port":53,"proto":"UDP","dns":{"version":3,"type":"request","tx_id":0,"id":1337,"queries":[{"rrname":"example.com","rrtype":"TXT"}]}}"#;
+        assert_eq!(parse(line).unwrap(), vec![Observation::Dns {
+            flow: flow("10.38.73.1:53"),
+            qtype: "TXT".to_string(),
+            name: "example.com".to_string(),
+        }]);
+    }
+
+    #[test]
+    fn parse_dns_answer() {
+        let line = r#"{"event_type":"dns","src_ip":"10.38.73.1","src_port":53,"dest_ip":"10.38.73.100","dest_port":53022,"proto":"UDP","dns":{"version":2,"type":"answer","id":1337,"rrname":"example.com","rrtype":"A","rcode":"NOERROR","answers":[{"rrname":"example.com","rrtype":"CNAME","ttl":300,"rdata":"www.example.com"},{"rrname":"www.example.com","rrtype":"A","ttl":300,"rdata":"93.184.216.34"}],"grouped":{"A":["93.184.216.34"]}}}"#;
+        assert_eq!(parse(line).unwrap(), vec![Observation::Resolved {
+            name: "www.example.com".to_string(),
+            addr: "93.184.216.34".parse().unwrap(),
+        }]);
+    }
+
+    #[test]
+    fn parse_tls() {
+        let line = r#"{"event_type":"tls","src_ip":"10.38.73.100","src_port":51234,"dest_ip":"93.184.216.34","dest_port":443,"proto":"TCP","tls":{"subject":"CN=example.com","version":"TLS 1.3","sni":"example.com","ja3":{"hash":"ada70206e40642a3e4461f35503241d5","string":"769,4-5-10"},"ja4":"t13d1516h2_8daaf6152771_e5627efa2ab1"}}"#;
+        assert_eq!(parse(line).unwrap(), vec![Observation::Tls {
+            flow: Some(Flow {
+                proto: Proto::TCP,
+                src: "10.38.73.100:51234".parse().unwrap(),
+                dst: "93.184.216.34:443".parse().unwrap(),
+            }),
+            sni: Some("example.com".to_string()),
+            ja3: Some("ada70206e40642a3e4461f35503241d5".to_string()),
+            ja4: Some("t13d1516h2_8daaf6152771_e5627efa2ab1".to_string()),
+        }]);
+    }
+
+    #[test]
+    fn parse_http() {
+        let line = r#"{"event_type":"http","src_ip":"10.38.73.100","src_port":51234,"dest_ip":"93.184.216.34","dest_port":80,"proto":"TCP","http":{"hostname":"example.com","url":"/upload.php","http_user_agent":"okhttp/3.12.1","http_content_type":"text/html","http_method":"POST","protocol":"HTTP/1.1","status":200,"length":12}}"#;
+        let observations = parse(line).unwrap();
+        match &observations[..] {
+            [Observation::Http { http, .. }] => {
+                assert_eq!(http.request_line(), "POST /upload.php HTTP/1.1");
+                assert_eq!(http.host.as_deref(), Some("example.com"));
+                assert_eq!(http.agent.as_deref(), Some("okhttp/3.12.1"));
+            }
+            _ => panic!("unexpected observations: {:?}", observations),
+        }
+    }
+
+    #[test]
+    fn ignore_other_events() {
+        let line = r#"{"event_type":"flow","src_ip":"10.38.73.100","src_port":51234,"dest_ip":"93.184.216.34","dest_port":80,"proto":"TCP","flow":{"pkts_toserver":3}}"#;
+        assert_eq!(parse(line).unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,433 @@
+use crate::errors::*;
+use crate::reader::Reader;
+use md5::Md5;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_SUPPORTED_GROUPS: u16 = 0x000a;
+const EXT_EC_POINT_FORMATS: u16 = 0x000b;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const EXT_ALPN: u16 = 0x0010;
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Transport {
+    TCP,
+    QUIC,
+}
+
+impl Transport {
+    fn ja4_prefix(&self) -> char {
+        match self {
+            Transport::TCP => 't',
+            Transport::QUIC => 'q',
+        }
+    }
+}
+
+/// The parts of a ClientHello that are used for fingerprinting
+#[derive(Debug, Default, PartialEq, Eq, Clone, Deserialize)]
+#[serde(default)]
+pub struct HelloParams {
+    pub legacy_version: u16,
+    pub cipher_suites: Vec<u16>,
+    /// Extension types, in the order they were sent
+    pub extensions: Vec<u16>,
+    pub supported_groups: Vec<u16>,
+    pub ec_point_formats: Vec<u8>,
+    pub signature_algorithms: Vec<u16>,
+    pub supported_versions: Vec<u16>,
+    pub alpn_protocols: Vec<String>,
+}
+
+/// Values like 0x0a0a, 0x1a1a, ... are sent randomly and need to be ignored
+#[inline]
+pub fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn join<T, F: Fn(&T) -> String>(list: &[T], sep: &str, f: F) -> String {
+    list.iter()
+        .map(f)
+        .collect::<Vec<_>>()
+        .join(sep)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        write!(out, "{:02x}", b).ok();
+    }
+    out
+}
+
+fn ja4_hash(s: &str) -> String {
+    if s.is_empty() {
+        return "000000000000".to_string();
+    }
+    let mut h = hex(&Sha256::digest(s.as_bytes()));
+    h.truncate(12);
+    h
+}
+
+fn ja4_version(version: u16) -> &'static str {
+    match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0x0002 => "s2",
+        0xfeff => "d1",
+        0xfefd => "d2",
+        0xfefc => "d3",
+        _ => "00",
+    }
+}
+
+fn ja4_alpn(alpn: Option<&String>) -> String {
+    let alpn = match alpn {
+        Some(alpn) if !alpn.is_empty() => alpn,
+        _ => return "00".to_string(),
+    };
+    let first = alpn.chars().next().unwrap();
+    let last = alpn.chars().last().unwrap();
+    if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+        format!("{}{}", first, last)
+    } else {
+        // use the first and last character of the hex representation instead
+        let h = hex(alpn.as_bytes());
+        format!("{}{}", &h[..1], &h[h.len() - 1..])
+    }
+}
+
+impl HelloParams {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.cipher_suites.is_empty()
+    }
+
+    pub fn ja3_string(&self) -> String {
+        let ciphers = self.cipher_suites.iter()
+            .copied()
+            .filter(|x| !is_grease(*x))
+            .collect::<Vec<_>>();
+        let extensions = self.extensions.iter()
+            .copied()
+            .filter(|x| !is_grease(*x))
+            .collect::<Vec<_>>();
+        let groups = self.supported_groups.iter()
+            .copied()
+            .filter(|x| !is_grease(*x))
+            .collect::<Vec<_>>();
+
+        format!("{},{},{},{},{}",
+            self.legacy_version,
+            join(&ciphers, "-", u16::to_string),
+            join(&extensions, "-", u16::to_string),
+            join(&groups, "-", u16::to_string),
+            join(&self.ec_point_formats, "-", u8::to_string),
+        )
+    }
+
+    pub fn ja3(&self) -> String {
+        hex(&Md5::digest(self.ja3_string().as_bytes()))
+    }
+
+    pub fn ja4(&self, transport: Transport) -> String {
+        let version = self.supported_versions.iter()
+            .copied()
+            .filter(|x| !is_grease(*x))
+            .max()
+            .unwrap_or(self.legacy_version);
+
+        let mut ciphers = self.cipher_suites.iter()
+            .copied()
+            .filter(|x| !is_grease(*x))
+            .collect::<Vec<_>>();
+        let extensions = self.extensions.iter()
+            .copied()
+            .filter(|x| !is_grease(*x))
+            .collect::<Vec<_>>();
+
+        let sni = if extensions.contains(&EXT_SERVER_NAME) { 'd' } else { 'i' };
+        let ja4_a = format!("{}{}{}{:02}{:02}{}",
+            transport.ja4_prefix(),
+            ja4_version(version),
+            sni,
+            ciphers.len().min(99),
+            extensions.len().min(99),
+            ja4_alpn(self.alpn_protocols.first()),
+        );
+
+        ciphers.sort_unstable();
+        let ja4_b = ja4_hash(&join(&ciphers, ",", |x| format!("{:04x}", x)));
+
+        let mut extensions = extensions.into_iter()
+            .filter(|x| *x != EXT_SERVER_NAME && *x != EXT_ALPN)
+            .collect::<Vec<_>>();
+        extensions.sort_unstable();
+        let mut ja4_c = join(&extensions, ",", |x| format!("{:04x}", x));
+        if !self.signature_algorithms.is_empty() {
+            ja4_c.push('_');
+            ja4_c.push_str(&join(&self.signature_algorithms, ",", |x| format!("{:04x}", x)));
+        }
+        let ja4_c = ja4_hash(&ja4_c);
+
+        format!("{}_{}_{}", ja4_a, ja4_b, ja4_c)
+    }
+}
+
+/// A ClientHello parsed from raw bytes
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct ClientHello {
+    pub sni: Option<String>,
+    pub params: HelloParams,
+}
+
+fn u16_list(r: &mut Reader) -> Result<Vec<u16>> {
+    let mut list = Vec::new();
+    while !r.is_empty() {
+        list.push(r.u16()?);
+    }
+    Ok(list)
+}
+
+fn parse_extension(hello: &mut ClientHello, ext: u16, mut r: Reader) -> Result<()> {
+    match ext {
+        EXT_SERVER_NAME => {
+            let mut list = r.prefixed(|r| Ok(r.u16()? as usize))?;
+            while !list.is_empty() {
+                let kind = list.u8()?;
+                let name = list.prefixed(|r| Ok(r.u16()? as usize))?;
+                // 0 is host_name, the only type that is defined
+                if kind == 0 && hello.sni.is_none() {
+                    let name = String::from_utf8(name.rest().to_vec())
+                        .context("Server name is not valid utf8")?;
+                    hello.sni = Some(name);
+                }
+            }
+        }
+        EXT_SUPPORTED_GROUPS => {
+            let mut list = r.prefixed(|r| Ok(r.u16()? as usize))?;
+            hello.params.supported_groups = u16_list(&mut list)?;
+        }
+        EXT_EC_POINT_FORMATS => {
+            let list = r.prefixed(|r| Ok(r.u8()? as usize))?;
+            hello.params.ec_point_formats = list.rest().to_vec();
+        }
+        EXT_SIGNATURE_ALGORITHMS => {
+            let mut list = r.prefixed(|r| Ok(r.u16()? as usize))?;
+            hello.params.signature_algorithms = u16_list(&mut list)?;
+        }
+        EXT_ALPN => {
+            let mut list = r.prefixed(|r| Ok(r.u16()? as usize))?;
+            while !list.is_empty() {
+                let proto = list.prefixed(|r| Ok(r.u8()? as usize))?;
+                hello.params.alpn_protocols.push(String::from_utf8_lossy(proto.rest()).into_owned());
+            }
+        }
+        EXT_SUPPORTED_VERSIONS => {
+            let mut list = r.prefixed(|r| Ok(r.u8()? as usize))?;
+            hello.params.supported_versions = u16_list(&mut list)?;
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn parse_client_hello(mut r: Reader) -> Result<ClientHello> {
+    let mut hello = ClientHello::default();
+    hello.params.legacy_version = r.u16()?;
+    // random
+    r.skip(32)?;
+    // session id
+    r.prefixed(|r| Ok(r.u8()? as usize))?;
+
+    let mut ciphers = r.prefixed(|r| Ok(r.u16()? as usize))?;
+    hello.params.cipher_suites = u16_list(&mut ciphers)?;
+
+    // compression methods
+    r.prefixed(|r| Ok(r.u8()? as usize))?;
+
+    // extensions are optional
+    if r.is_empty() {
+        return Ok(hello);
+    }
+
+    let mut extensions = r.prefixed(|r| Ok(r.u16()? as usize))?;
+    while !extensions.is_empty() {
+        let ext = extensions.u16()?;
+        let data = extensions.prefixed(|r| Ok(r.u16()? as usize))?;
+        hello.params.extensions.push(ext);
+        parse_extension(&mut hello, ext, data)
+            .with_context(|| anyhow!("Failed to parse tls extension {:#06x}", ext))?;
+    }
+
+    Ok(hello)
+}
+
+/// Parse a ClientHello handshake message, returns None if more data is needed
+pub fn parse_handshake(buf: &[u8]) -> Result<Option<ClientHello>> {
+    let mut r = Reader::new(buf);
+    if r.remaining() < 4 {
+        return Ok(None);
+    }
+
+    let kind = r.u8()?;
+    if kind != HANDSHAKE_CLIENT_HELLO {
+        bail!("Handshake message is not a ClientHello: {}", kind);
+    }
+
+    let len = r.u24()? as usize;
+    if r.remaining() < len {
+        return Ok(None);
+    }
+
+    let body = Reader::new(r.take(len)?);
+    parse_client_hello(body).map(Some)
+}
+
+/// Parse a ClientHello from a tls stream, possibly spanning multiple records
+///
+/// Returns None if more data is needed.
+pub fn parse_records(buf: &[u8]) -> Result<Option<ClientHello>> {
+    let mut r = Reader::new(buf);
+    let mut handshake = Vec::new();
+
+    while r.remaining() >= 5 {
+        let content_type = r.u8()?;
+        if content_type != CONTENT_TYPE_HANDSHAKE {
+            bail!("Tls record is not a handshake: {}", content_type);
+        }
+        // record version
+        r.skip(2)?;
+        let len = r.u16()? as usize;
+        if r.remaining() < len {
+            return Ok(None);
+        }
+        handshake.extend(r.take(len)?);
+
+        if let Some(hello) = parse_handshake(&handshake)? {
+            return Ok(Some(hello));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grease() {
+        assert!(is_grease(0x0a0a));
+        assert!(is_grease(0xfafa));
+        assert!(!is_grease(0x0a1a));
+        assert!(!is_grease(0x1301));
+    }
+
+    #[test]
+    fn ja3() {
+        // example from the ja3 readme
+        let params = HelloParams {
+            legacy_version: 769,
+            cipher_suites: vec![0x0a0a, 47, 53, 5, 10, 49161, 49162, 49171, 49172, 50, 56, 19, 4],
+            extensions: vec![0, 10, 11],
+            supported_groups: vec![23, 24, 25],
+            ec_point_formats: vec![0],
+            ..Default::default()
+        };
+        assert_eq!(params.ja3_string(), "769,47-53-5-10-49161-49162-49171-49172-50-56-19-4,0-10-11,23-24-25,0");
+        assert_eq!(params.ja3(), "ada70206e40642a3e4461f35503241d5");
+    }
+
+    #[test]
+    fn ja4() {
+        // example from the ja4 readme
+        let params = HelloParams {
+            legacy_version: 0x0303,
+            cipher_suites: vec![
+                0x2a2a, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030,
+                0xcca9, 0xcca8, 0xc013, 0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+            ],
+            extensions: vec![
+                0x5a5a, 0x0000, 0x0017, 0xff01, 0x000a, 0x000b, 0x0023, 0x0010, 0x0005,
+                0x000d, 0x0012, 0x0033, 0x002d, 0x002b, 0x001b, 0x0015, 0x4469, 0x3a3a,
+            ],
+            supported_groups: vec![0x1a1a, 0x001d, 0x0017, 0x0018],
+            ec_point_formats: vec![0],
+            signature_algorithms: vec![0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601],
+            supported_versions: vec![0x7a7a, 0x0304, 0x0303],
+            alpn_protocols: vec!["h2".to_string(), "http/1.1".to_string()],
+        };
+        assert_eq!(params.ja4(Transport::TCP), "t13d1516h2_8daaf6152771_e5627efa2ab1");
+        assert_eq!(params.ja4(Transport::QUIC), "q13d1516h2_8daaf6152771_e5627efa2ab1");
+    }
+
+    #[test]
+    fn ja4_no_sni_no_alpn() {
+        let params = HelloParams {
+            legacy_version: 0x0303,
+            cipher_suites: vec![0x1301],
+            extensions: vec![0x002b],
+            supported_versions: vec![0x0304],
+            ..Default::default()
+        };
+        assert!(params.ja4(Transport::TCP).starts_with("t13i010100_"));
+    }
+
+    #[test]
+    fn ja4_alpn_non_alphanumeric() {
+        assert_eq!(ja4_alpn(Some(&"\u{ab}".to_string())), "cb");
+        assert_eq!(ja4_alpn(None), "00");
+    }
+
+    #[test]
+    fn parse_client_hello_record() {
+        let buf = include_bytes!("../fixtures/tls/client_hello.bin");
+        let hello = parse_records(buf).unwrap().unwrap();
+        assert_eq!(hello.sni.as_deref(), Some("example.com"));
+        assert_eq!(hello.params.legacy_version, 0x0303);
+        assert_eq!(hello.params.alpn_protocols, vec!["h2".to_string(), "http/1.1".to_string()]);
+        assert!(hello.params.supported_versions.contains(&0x0304));
+        assert!(hello.params.cipher_suites.contains(&0x1301));
+        assert!(hello.params.extensions.contains(&EXT_SERVER_NAME));
+        assert!(!hello.params.supported_groups.is_empty());
+        assert!(!hello.params.signature_algorithms.is_empty());
+        assert!(hello.params.ja4(Transport::TCP).starts_with("t13d"));
+    }
+
+    #[test]
+    fn parse_incomplete_record() {
+        let buf = include_bytes!("../fixtures/tls/client_hello.bin");
+        for n in [0, 4, 5, 100, buf.len() - 1] {
+            assert_eq!(parse_records(&buf[..n]).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn parse_fragmented_records() {
+        // split the handshake message into two tls records
+        let buf = include_bytes!("../fixtures/tls/client_hello.bin");
+        let handshake = &buf[5..];
+        let mut split = Vec::new();
+        for chunk in handshake.chunks(300) {
+            split.extend(&[CONTENT_TYPE_HANDSHAKE, 0x03, 0x01]);
+            split.extend(&(chunk.len() as u16).to_be_bytes());
+            split.extend(chunk);
+        }
+        assert_eq!(parse_records(&split).unwrap(), parse_records(buf).unwrap());
+    }
+
+    #[test]
+    fn parse_not_handshake() {
+        assert!(parse_records(b"GET / HTTP/1.1\r\n").is_err());
+    }
+}
@@ -0,0 +1,367 @@
+use crate::suffix::SuffixTree;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Domains that are expected to receive TXT queries
+pub const KNOWN_TXT_DOMAINS: &[&str] = &[
+    "apple.com",
+    "icloud.com",
+    "google.com",
+    "googleapis.com",
+    "gstatic.com",
+    "microsoft.com",
+];
+
+/// Labels longer than this are very rare outside of encoded payloads
+const MAX_LABEL_LEN: usize = 52;
+/// Only calculate the entropy of labels that carry enough data
+const ENTROPY_MIN_LEN: usize = 24;
+/// Bits per character, random base32 is around 4.5, hex can't exceed 4.0
+const ENTROPY_THRESHOLD: f64 = 4.0;
+/// Number of unique subdomains of one parent within BURST_WINDOW
+const BURST_THRESHOLD: usize = 30;
+const BURST_WINDOW: Duration = Duration::from_secs(60);
+/// Parent domains that are tracked at once, random parents can't grow the state forever
+const MAX_WINDOWS: usize = 4096;
+/// Reports that are remembered, repeats after this are still merged by the session tracker
+const MAX_REPORTED: usize = 4096;
+
+#[derive(Debug, PartialEq)]
+pub enum Reason {
+    UnknownTxt { qtype: String },
+    LongLabel { len: usize },
+    HighEntropy { entropy: f64 },
+    Burst { unique: usize },
+}
+
+impl Reason {
+    fn kind(&self) -> &'static str {
+        match self {
+            Reason::UnknownTxt { .. } => "txt",
+            Reason::LongLabel { .. } => "long",
+            Reason::HighEntropy { .. } => "entropy",
+            Reason::Burst { .. } => "burst",
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reason::UnknownTxt { qtype } => write!(f, "{} query to unknown domain", qtype),
+            Reason::LongLabel { len } => write!(f, "label with {} characters", len),
+            Reason::HighEntropy { entropy } => write!(f, "label entropy {:.2} bits/char", entropy),
+            Reason::Burst { unique } => write!(f, "{} unique subdomains within {}s", unique, BURST_WINDOW.as_secs()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Suspicious {
+    pub parent: String,
+    pub reason: Reason,
+    /// The query that triggered it, or every subdomain of a burst
+    pub names: Vec<String>,
+}
+
+/// Common suffixes that are registered below, short domains like t.co or abc.io are not among them
+const SECOND_LEVEL_SUFFIXES: &[&str] = &[
+    "ac.uk", "co.uk", "gov.uk", "ltd.uk", "me.uk", "net.uk", "org.uk", "plc.uk",
+    "com.au", "net.au", "org.au", "edu.au", "gov.au",
+    "co.nz", "net.nz", "org.nz",
+    "co.jp", "ne.jp", "or.jp", "ac.jp", "go.jp",
+    "co.kr", "or.kr", "ne.kr",
+    "com.cn", "net.cn", "org.cn", "gov.cn",
+    "com.hk", "com.tw", "com.sg", "com.my", "co.id", "co.th", "co.in", "com.vn", "com.ph",
+    "com.br", "net.br", "org.br", "com.ar", "com.mx", "com.co", "com.pe",
+    "co.za", "com.ng", "co.ke", "com.eg",
+    "com.tr", "co.il", "com.sa", "com.ua", "com.pl", "co.at", "or.at",
+];
+
+/// Estimate the registered domain with a short list of known suffixes instead of a public suffix list
+fn parent(name: &str) -> String {
+    let labels = name.trim_end_matches('.').split('.').collect::<Vec<_>>();
+    let n = match labels.as_slice() {
+        [.., sld, tld] if SECOND_LEVEL_SUFFIXES.iter().any(|suffix| {
+            suffix.eq_ignore_ascii_case(&format!("{}.{}", sld, tld))
+        }) => 3,
+        _ => 2,
+    };
+    let start = labels.len().saturating_sub(n);
+    labels[start..].join(".")
+}
+
+fn entropy(label: &str) -> f64 {
+    let mut counts = HashMap::new();
+    for c in label.chars() {
+        *counts.entry(c.to_ascii_lowercase()).or_insert(0usize) += 1;
+    }
+    let len = label.chars().count() as f64;
+    counts.values()
+        .map(|n| {
+            let p = *n as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    names: HashSet<String>,
+}
+
+/// Heuristics for data exfiltration through dns queries
+#[derive(Debug)]
+pub struct TunnelDetector {
+    known: SuffixTree<String>,
+    windows: HashMap<String, Window>,
+    reported: HashSet<(String, &'static str)>,
+}
+
+impl Default for TunnelDetector {
+    fn default() -> TunnelDetector {
+        TunnelDetector {
+            known: KNOWN_TXT_DOMAINS.iter()
+                .map(|s| String::from(*s))
+                .collect(),
+            windows: HashMap::new(),
+            reported: HashSet::new(),
+        }
+    }
+}
+
+impl TunnelDetector {
+    #[inline]
+    pub fn new() -> TunnelDetector {
+        TunnelDetector::default()
+    }
+
+    /// Forget the query windows and what was reported, e.g. when the next phone is scanned
+    pub fn reset(&mut self) {
+        self.windows.clear();
+        self.reported.clear();
+    }
+
+    /// Returns every heuristic that triggered for the first time for this parent domain
+    pub fn check(&mut self, qtype: &str, name: &str, now: Instant) -> Vec<Suspicious> {
+        let name = name.trim_end_matches('.');
+        let parent = parent(name);
+        let mut reasons = Vec::new();
+
+        if (qtype.eq_ignore_ascii_case("TXT") || qtype.eq_ignore_ascii_case("NULL")) && !self.known.matches(name) {
+            reasons.push(Reason::UnknownTxt { qtype: qtype.to_uppercase() });
+        }
+
+        let subdomain = name.strip_suffix(&parent).unwrap_or("");
+        for label in subdomain.split('.') {
+            let len = label.chars().count();
+            if len > MAX_LABEL_LEN {
+                reasons.push(Reason::LongLabel { len });
+                break;
+            }
+            if len >= ENTROPY_MIN_LEN {
+                let entropy = entropy(label);
+                if entropy >= ENTROPY_THRESHOLD {
+                    reasons.push(Reason::HighEntropy { entropy });
+                    break;
+                }
+            }
+        }
+
+        if !self.windows.contains_key(&parent) && self.windows.len() >= MAX_WINDOWS {
+            self.evict(now);
+        }
+        let window = self.windows.entry(parent.clone())
+            .or_insert_with(|| Window {
+                start: now,
+                names: HashSet::new(),
+            });
+        if now.duration_since(window.start) > BURST_WINDOW {
+            window.start = now;
+            window.names.clear();
+        }
+        let mut burst = Vec::new();
+        if window.names.len() < BURST_THRESHOLD && name != parent {
+            window.names.insert(name.to_string());
+            if window.names.len() == BURST_THRESHOLD {
+                reasons.push(Reason::Burst { unique: BURST_THRESHOLD });
+                burst = window.names.iter().cloned().collect();
+                burst.sort();
+            }
+        }
+
+        if self.reported.len() + reasons.len() > MAX_REPORTED {
+            self.reported.clear();
+        }
+        reasons.into_iter()
+            .filter(|reason| self.reported.insert((parent.clone(), reason.kind())))
+            .map(|reason| {
+                let names = match reason {
+                    Reason::Burst { .. } => std::mem::take(&mut burst),
+                    _ => vec![name.to_string()],
+                };
+                Suspicious {
+                    parent: parent.clone(),
+                    reason,
+                    names,
+                }
+            })
+            .collect()
+    }
+
+    /// Drop the windows that expired, or the oldest one if all of them are still running
+    fn evict(&mut self, now: Instant) {
+        self.windows.retain(|_, window| now.duration_since(window.start) <= BURST_WINDOW);
+        if self.windows.len() >= MAX_WINDOWS {
+            let oldest = self.windows.iter()
+                .min_by_key(|(_, window)| window.start)
+                .map(|(parent, _)| parent.clone());
+            if let Some(parent) = oldest {
+                self.windows.remove(&parent);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parent_domain() {
+        assert_eq!(parent("a.b.example.com"), "example.com");
+        assert_eq!(parent("example.com."), "example.com");
+        assert_eq!(parent("www.example.co.uk"), "example.co.uk");
+        assert_eq!(parent("com"), "com");
+        // short domains below a country tld are no suffix
+        assert_eq!(parent("x.abc.io"), "abc.io");
+        assert_eq!(parent("x.t.co"), "t.co");
+        assert_eq!(parent("x.xyz.de"), "xyz.de");
+    }
+
+    #[test]
+    fn normal_query() {
+        let mut d = TunnelDetector::new();
+        assert_eq!(d.check("A", "www.github.com", Instant::now()), vec![]);
+        assert_eq!(d.check("AAAA", "e6858.dscx.akamaiedge.net", Instant::now()), vec![]);
+    }
+
+    #[test]
+    fn txt_known_domain() {
+        let mut d = TunnelDetector::new();
+        assert_eq!(d.check("TXT", "_spf.google.com", Instant::now()), vec![]);
+    }
+
+    #[test]
+    fn txt_unknown_domain() {
+        let mut d = TunnelDetector::new();
+        assert_eq!(d.check("TXT", "x.example.com", Instant::now()), vec![Suspicious {
+            parent: "example.com".to_string(),
+            reason: Reason::UnknownTxt { qtype: "TXT".to_string() },
+            names: vec!["x.example.com".to_string()],
+        }]);
+        // only reported once
+        assert_eq!(d.check("TXT", "y.example.com", Instant::now()), vec![]);
+        // until the next phone is scanned
+        d.reset();
+        assert_eq!(d.check("TXT", "y.example.com", Instant::now()).len(), 1);
+    }
+
+    #[test]
+    fn long_label() {
+        let mut d = TunnelDetector::new();
+        let name = format!("{}.example.com", "a".repeat(60));
+        assert_eq!(d.check("A", &name, Instant::now()), vec![Suspicious {
+            parent: "example.com".to_string(),
+            reason: Reason::LongLabel { len: 60 },
+            names: vec![name.clone()],
+        }]);
+    }
+
+    #[test]
+    fn high_entropy() {
+        let mut d = TunnelDetector::new();
+        let r = d.check("A", "mzxw6ytboi2dsnrtgq3tmobzhe4dqnzx.example.com", Instant::now());
+        assert_eq!(r.len(), 1);
+        assert!(matches!(r[0].reason, Reason::HighEntropy { .. }));
+    }
+
+    #[test]
+    fn short_cctld_domains() {
+        let mut d = TunnelDetector::new();
+        let label = "mzxw6ytboi2dsnrtgq3tmobzhe4dqnzxgezdgnbvgy3tqojqgeztgnjwg42tenr";
+        let r = d.check("A", &format!("{}.abc.io", label), Instant::now());
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].parent, "abc.io");
+        assert_eq!(r[0].reason, Reason::LongLabel { len: 63 });
+
+        let r = d.check("A", &format!("{}.t.co", &label[..32]), Instant::now());
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].parent, "t.co");
+        assert!(matches!(r[0].reason, Reason::HighEntropy { .. }));
+    }
+
+    #[test]
+    fn short_cctld_burst() {
+        let mut d = TunnelDetector::new();
+        let now = Instant::now();
+        for i in 0..BURST_THRESHOLD - 1 {
+            assert_eq!(d.check("A", &format!("{}.t.co", i), now), vec![]);
+        }
+        let r = d.check("A", "last.t.co", now);
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].parent, "t.co");
+        assert_eq!(r[0].reason, Reason::Burst { unique: BURST_THRESHOLD });
+        assert_eq!(d.windows.len(), 1);
+    }
+
+    #[test]
+    fn low_entropy() {
+        let mut d = TunnelDetector::new();
+        assert_eq!(d.check("A", "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.example.com", Instant::now()), vec![]);
+    }
+
+    #[test]
+    fn burst() {
+        let mut d = TunnelDetector::new();
+        let now = Instant::now();
+        for i in 0..BURST_THRESHOLD - 1 {
+            assert_eq!(d.check("A", &format!("{}.example.com", i), now), vec![]);
+        }
+        let r = d.check("A", "last.example.com", now);
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].reason, Reason::Burst { unique: BURST_THRESHOLD });
+        assert_eq!(r[0].names.len(), BURST_THRESHOLD);
+        assert!(r[0].names.contains(&"last.example.com".to_string()));
+    }
+
+    #[test]
+    fn bounded_windows() {
+        let mut d = TunnelDetector::new();
+        let start = Instant::now();
+        for i in 0..MAX_WINDOWS {
+            d.check("A", &format!("www.example{}.com", i), start);
+        }
+        // expired windows are dropped first
+        let later = start + BURST_WINDOW * 2;
+        d.check("A", "www.example.net", later);
+        assert_eq!(d.windows.len(), 1);
+
+        for i in 0..MAX_WINDOWS * 2 {
+            d.check("A", &format!("www.example{}.org", i), later);
+        }
+        assert_eq!(d.windows.len(), MAX_WINDOWS);
+    }
+
+    #[test]
+    fn slow_queries_are_no_burst() {
+        let mut d = TunnelDetector::new();
+        let mut now = Instant::now();
+        for i in 0..BURST_THRESHOLD * 2 {
+            now += Duration::from_secs(5);
+            assert_eq!(d.check("A", &format!("{}.example.com", i), now), vec![]);
+        }
+    }
+}
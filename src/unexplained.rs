@@ -0,0 +1,234 @@
+use crate::cidr::CidrSet;
+use crate::json::Flow;
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+/// Ranges that phones connect to without resolving them first
+pub const DEFAULT_ALLOWLIST: &[&str] = &[
+    // apple
+    "17.0.0.0/8",
+    "2620:149::/32",
+    "2a01:b740::/32",
+    // google
+    "8.8.8.8",
+    "8.8.4.4",
+    "74.125.0.0/16",
+    "142.250.0.0/15",
+    "172.217.0.0/16",
+    "173.194.0.0/16",
+    "216.58.192.0/19",
+    "2001:4860::/32",
+    "2404:6800::/32",
+    "2607:f8b0::/32",
+    "2a00:1450::/32",
+    // cloudflare and quad9 dns
+    "1.1.1.1",
+    "1.0.0.1",
+    "9.9.9.9",
+    "2606:4700:4700::1111",
+    "2606:4700:4700::1001",
+];
+
+/// Ranges the phones are in, networks with global ipv6 addresses need to add their prefix
+pub const DEFAULT_LOCAL_NETWORKS: &[&str] = &[
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    // shared address space (carrier-grade nat)
+    "100.64.0.0/10",
+    "169.254.0.0/16",
+    "127.0.0.0/8",
+    "fc00::/7",
+    "fe80::/10",
+    "::1/128",
+];
+
+pub fn default_allowlist() -> CidrSet {
+    let mut set = CidrSet::new();
+    for cidr in DEFAULT_ALLOWLIST {
+        set.insert(cidr.parse().expect("Invalid built-in allowlist entry"));
+    }
+    set
+}
+
+pub fn default_local_networks() -> CidrSet {
+    let mut set = CidrSet::new();
+    for cidr in DEFAULT_LOCAL_NETWORKS {
+        set.insert(cidr.parse().expect("Invalid built-in local network"));
+    }
+    set
+}
+
+pub fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // shared address space (carrier-grade nat)
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // unique local
+                || (first & 0xfe00) == 0xfc00
+                // link local
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Detect connections to public ips that were never returned by a dns query
+#[derive(Debug)]
+pub struct Unexplained {
+    allowlist: CidrSet,
+    local: CidrSet,
+    resolved: HashSet<IpAddr>,
+    reported: HashSet<IpAddr>,
+}
+
+impl Unexplained {
+    pub fn new(allowlist: CidrSet, local: CidrSet) -> Unexplained {
+        Unexplained {
+            allowlist,
+            local,
+            resolved: HashSet::new(),
+            reported: HashSet::new(),
+        }
+    }
+
+    /// The phone's side of a flow, responses are sent to it
+    pub fn local_addr(&self, flow: &Flow) -> Option<IpAddr> {
+        let (src, dst) = (flow.src.ip(), flow.dst.ip());
+        if self.local.contains(&src) {
+            Some(src)
+        } else if self.local.contains(&dst) {
+            Some(dst)
+        } else {
+            None
+        }
+    }
+
+    pub fn resolved(&mut self, addr: IpAddr) {
+        self.resolved.insert(addr);
+    }
+
+    /// Forget resolved and reported addresses, only dns answers of the current session explain a connection
+    pub fn reset(&mut self) {
+        self.resolved.clear();
+        self.reported.clear();
+    }
+
+    /// Returns true the first time an unexplained destination is contacted
+    pub fn check(&mut self, flow: &Flow) -> bool {
+        let src = flow.src.ip();
+        let dst = flow.dst.ip();
+
+        // only look at traffic from the phone to the internet
+        if !self.local.contains(&src) || self.local.contains(&dst) || !is_public(&dst) {
+            return false;
+        }
+
+        if self.resolved.contains(&dst) || self.allowlist.contains(&dst) {
+            return false;
+        }
+
+        self.reported.insert(dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::Proto;
+
+    fn unexplained(allowlist: CidrSet) -> Unexplained {
+        Unexplained::new(allowlist, default_local_networks())
+    }
+
+    fn flow(src: &str, dst: &str) -> Flow {
+        Flow {
+            proto: Proto::TCP,
+            src: src.parse().unwrap(),
+            dst: dst.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn unresolved() {
+        let mut u = unexplained(CidrSet::new());
+        assert!(u.check(&flow("10.38.73.100:1337", "93.184.216.34:443")));
+    }
+
+    #[test]
+    fn only_report_once() {
+        let mut u = unexplained(CidrSet::new());
+        assert!(u.check(&flow("10.38.73.100:1337", "93.184.216.34:443")));
+        assert!(!u.check(&flow("10.38.73.100:1338", "93.184.216.34:443")));
+    }
+
+    #[test]
+    fn reset() {
+        let mut u = unexplained(CidrSet::new());
+        u.resolved("93.184.216.34".parse().unwrap());
+        assert!(u.check(&flow("10.38.73.100:1337", "45.33.32.156:443")));
+        u.reset();
+        // neither an answer nor a report from an earlier session counts
+        assert!(u.check(&flow("10.38.73.100:1337", "93.184.216.34:443")));
+        assert!(u.check(&flow("10.38.73.100:1337", "45.33.32.156:443")));
+    }
+
+    #[test]
+    fn resolved() {
+        let mut u = unexplained(CidrSet::new());
+        u.resolved("93.184.216.34".parse().unwrap());
+        assert!(!u.check(&flow("10.38.73.100:1337", "93.184.216.34:443")));
+    }
+
+    #[test]
+    fn allowlisted() {
+        let mut u = unexplained(default_allowlist());
+        assert!(!u.check(&flow("10.38.73.100:1337", "17.253.144.10:443")));
+        assert!(!u.check(&flow("[fd00::3]:1337", "[2a00:1450:4001:82b::200e]:443")));
+    }
+
+    #[test]
+    fn ignore_inbound() {
+        let mut u = unexplained(CidrSet::new());
+        assert!(!u.check(&flow("93.184.216.34:443", "10.38.73.100:1337")));
+    }
+
+    #[test]
+    fn global_ipv6_client() {
+        let client = "[2a02:8070:1234:5600::23]:1337";
+        let server = "[2606:2800:220:1:248:1893:25c8:1946]:443";
+        // without its prefix the direction is unknown
+        let mut u = unexplained(CidrSet::new());
+        assert!(!u.check(&flow(client, server)));
+
+        let mut local = default_local_networks();
+        local.insert("2a02:8070:1234:5600::/56".parse().unwrap());
+        let mut u = Unexplained::new(CidrSet::new(), local);
+        assert_eq!(u.local_addr(&flow(server, client)), Some("2a02:8070:1234:5600::23".parse().unwrap()));
+        assert!(!u.check(&flow(server, client)));
+        assert!(u.check(&flow(client, server)));
+        // other phones of the lan are no destination
+        assert!(!u.check(&flow(client, "[2a02:8070:1234:5600::42]:8009")));
+    }
+
+    #[test]
+    fn ignore_local() {
+        let mut u = unexplained(CidrSet::new());
+        assert!(!u.check(&flow("10.38.73.100:1337", "10.38.73.1:53")));
+        assert!(!u.check(&flow("10.38.73.100:1337", "100.64.0.1:53")));
+        assert!(!u.check(&flow("[fe80::1]:1337", "[ff02::fb]:5353")));
+    }
+}
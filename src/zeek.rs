@@ -0,0 +1,368 @@
+use crate::errors::*;
+use crate::json::{Flow, HTTP};
+use crate::observation::{self, Observation};
+use serde_json::{Map, Value};
+use std::net::IpAddr;
+
+/// Decode `\xNN` escapes, used for the separator header and in field values
+fn unescape(s: &str) -> String {
+    if !s.contains("\\x") {
+        return s.to_string();
+    }
+
+    let mut out = Vec::with_capacity(s.len());
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && bytes.get(i + 1) == Some(&b'x') {
+            if let Some(b) = s.get(i + 2..i + 4).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                out.push(b);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// A zeek log entry, either read from json or from the tsv format
+struct Record(Map<String, Value>);
+
+impl Record {
+    fn str(&self, key: &str) -> Option<String> {
+        match self.0.get(key)? {
+            Value::String(s) => Some(s.to_string()),
+            Value::Number(n) => Some(n.to_string()),
+            _ => None,
+        }
+    }
+
+    fn list(&self, key: &str) -> Vec<String> {
+        match self.0.get(key) {
+            Some(Value::Array(values)) => values.iter()
+                .flat_map(|v| v.as_str())
+                .map(String::from)
+                .collect(),
+            Some(Value::String(s)) => vec![s.to_string()],
+            _ => Vec::new(),
+        }
+    }
+
+    fn ip(&self, key: &str) -> Option<IpAddr> {
+        self.str(key)?.parse().ok()
+    }
+
+    fn port(&self, key: &str) -> Option<u16> {
+        self.str(key)?.parse().ok()
+    }
+
+    /// The flow of the connection, ssl and http logs don't have a proto field
+    fn flow(&self, default_proto: Option<&str>) -> Option<Flow> {
+        let proto = self.str("proto");
+        observation::flow(proto.as_deref().or(default_proto),
+            self.ip("id.orig_h"), self.port("id.orig_p"),
+            self.ip("id.resp_h"), self.port("id.resp_p"))
+    }
+
+    /// Guess the log type of json entries that were written without `_path`
+    fn infer_path(&self) -> Option<&'static str> {
+        if self.0.contains_key("query") {
+            Some("dns")
+        } else if self.0.contains_key("server_name") || self.0.contains_key("ja3") || self.0.contains_key("cipher") {
+            Some("ssl")
+        } else if self.0.contains_key("uri") || self.0.contains_key("method") {
+            Some("http")
+        } else if self.0.contains_key("conn_state") {
+            Some("conn")
+        } else {
+            None
+        }
+    }
+}
+
+/// Parser for zeek logs, the tsv format is described by header lines
+/// so the parser needs to see the whole file in order
+#[derive(Debug)]
+pub struct Parser {
+    separator: String,
+    set_separator: String,
+    empty_field: String,
+    unset_field: String,
+    path: Option<String>,
+    fields: Vec<String>,
+    types: Vec<String>,
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser {
+            separator: "\t".to_string(),
+            set_separator: ",".to_string(),
+            empty_field: "(empty)".to_string(),
+            unset_field: "-".to_string(),
+            path: None,
+            fields: Vec::new(),
+            types: Vec::new(),
+        }
+    }
+}
+
+impl Parser {
+    pub fn new() -> Parser {
+        Parser::default()
+    }
+
+    fn header(&mut self, line: &str) {
+        if let Some(sep) = line.strip_prefix("#separator ") {
+            self.separator = unescape(sep);
+            return;
+        }
+
+        let mut values = line.split(self.separator.as_str());
+        let key = values.next().unwrap_or_default();
+        let values = values.map(String::from).collect::<Vec<_>>();
+        let value = values.first().cloned().unwrap_or_default();
+        match key {
+            "#set_separator" => self.set_separator = unescape(&value),
+            "#empty_field" => self.empty_field = value,
+            "#unset_field" => self.unset_field = value,
+            "#path" => self.path = Some(value),
+            "#fields" => self.fields = values,
+            "#types" => self.types = values,
+            _ => (),
+        }
+    }
+
+    fn tsv(&self, line: &str) -> Result<Record> {
+        if self.fields.is_empty() {
+            bail!("Missing #fields header");
+        }
+
+        let mut map = Map::new();
+        for (i, (key, value)) in self.fields.iter().zip(line.split(self.separator.as_str())).enumerate() {
+            if *value == self.unset_field {
+                continue;
+            }
+
+            let is_list = self.types.get(i)
+                .map(|t| t.starts_with("set[") || t.starts_with("vector["))
+                .unwrap_or(false);
+
+            let value = if *value == self.empty_field {
+                if is_list {
+                    Value::Array(Vec::new())
+                } else {
+                    Value::String(String::new())
+                }
+            } else if is_list {
+                Value::Array(value.split(self.set_separator.as_str())
+                    .map(|v| Value::String(unescape(v)))
+                    .collect())
+            } else {
+                Value::String(unescape(value))
+            };
+            map.insert(key.to_string(), value);
+        }
+
+        Ok(Record(map))
+    }
+
+    /// Parse a line of dns.log, ssl.log, http.log or conn.log, other logs are ignored
+    pub fn parse(&mut self, line: &str) -> Result<Vec<Observation>> {
+        let (record, path) = if line.starts_with('{') {
+            let record = Record(serde_json::from_str(line)?);
+            let path = match record.str("_path") {
+                Some(path) => Some(path),
+                None => record.infer_path().map(String::from),
+            };
+            (record, path)
+        } else if line.starts_with('#') {
+            self.header(line);
+            return Ok(Vec::new());
+        } else if line.is_empty() {
+            return Ok(Vec::new());
+        } else {
+            (self.tsv(line)?, self.path.clone())
+        };
+
+        let mut observations = Vec::new();
+        match path.as_deref() {
+            Some("dns") => {
+                let flow = record.flow(None);
+                if let Some(name) = record.str("query") {
+                    if let Some(qtype) = record.str("qtype_name") {
+                        observations.push(Observation::Dns {
+                            flow,
+                            qtype,
+                            name: name.clone(),
+                        });
+                    }
+                    for answer in record.list("answers") {
+                        if let Ok(addr) = answer.parse() {
+                            observations.push(Observation::Resolved {
+                                name: name.clone(),
+                                addr,
+                            });
+                        }
+                    }
+                }
+            }
+            Some("ssl") => {
+                observations.push(Observation::Tls {
+                    flow: record.flow(Some("tcp")),
+                    sni: record.str("server_name"),
+                    ja3: record.str("ja3"),
+                    ja4: record.str("ja4"),
+                });
+            }
+            Some("http") => {
+                observations.push(Observation::Http {
+                    flow: record.flow(Some("tcp")),
+                    http: HTTP {
+                        method: record.str("method").unwrap_or_default(),
+                        uri: record.str("uri").unwrap_or_default(),
+                        version: record.str("version").unwrap_or_else(|| "1.1".to_string()),
+                        host: record.str("host"),
+                        agent: record.str("user_agent"),
+                        referer: record.str("referrer"),
+                        cookies: None,
+                    },
+                });
+            }
+            Some("conn") => {
+                if let Some(flow) = record.flow(None) {
+                    observations.push(Observation::Connection(flow));
+                }
+            }
+            _ => (),
+        }
+
+        Ok(observations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::Proto;
+
+    fn flow(proto: Proto, src: &str, dst: &str) -> Option<Flow> {
+        Some(Flow {
+            proto,
+            src: src.parse().unwrap(),
+            dst: dst.parse().unwrap(),
+        })
+    }
+
+    fn parse_all(parser: &mut Parser, log: &str) -> Vec<Observation> {
+        log.lines()
+            .flat_map(|line| parser.parse(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parse_dns_tsv() {
+        let log = "#separator \\x09
+#set_separator\t,
+#empty_field\t(empty)
+#unset_field\t-
+#path\tdns
+#open\t2023-05-01-12-00-00
+#fields\tts\tuid\tid.orig_h\tid.orig_p\tid.resp_h\tid.resp_p\tproto\ttrans_id\tquery\tqtype_name\trcode_name\tanswers\tTTLs
+#types\ttime\tstring\taddr\tport\taddr\tport\tenum\tcount\tstring\tstring\tstring\tvector[string]\tvector[interval]
+1682942400.000000\tCjvDm83\t10.38.73.100\t53022\t10.38.73.1\t53\tudp\t1337\texample.com\tA\tNOERROR\twww.example.com,93.184.216.34\t300.000000,300.000000
+1682942401.000000\tCjvDm84\t10.38.73.100\t53023\t10.38.73.1\t53\tudp\t1338\tnx.example.com\tAAAA\tNXDOMAIN\t-\t-
+#close\t2023-05-01-13-00-00
+";
+        let mut parser = Parser::new();
+        assert_eq!(parse_all(&mut parser, log), vec![
+            Observation::Dns {
+                flow: flow(Proto::UDP, "10.38.73.100:53022", "10.38.73.1:53"),
+                qtype: "A".to_string(),
+                name: "example.com".to_string(),
+            },
+            Observation::Resolved {
+                name: "example.com".to_string(),
+                addr: "93.184.216.34".parse().unwrap(),
+            },
+            Observation::Dns {
+                flow: flow(Proto::UDP, "10.38.73.100:53023", "10.38.73.1:53"),
+                qtype: "AAAA".to_string(),
+                name: "nx.example.com".to_string(),
+            },
+        ]);
+    }
+
+    #[test]
+    fn parse_ssl_tsv() {
+        let log = "#separator \\x09
+#path\tssl
+#fields\tts\tuid\tid.orig_h\tid.orig_p\tid.resp_h\tid.resp_p\tversion\tcipher\tserver_name\tja3
+#types\ttime\tstring\taddr\tport\taddr\tport\tstring\tstring\tstring\tstring
+1682942400.000000\tCjvDm83\t10.38.73.100\t51234\t93.184.216.34\t443\tTLSv13\tTLS_AES_128_GCM_SHA256\texample.com\tada70206e40642a3e4461f35503241d5
+";
+        let mut parser = Parser::new();
+        assert_eq!(parse_all(&mut parser, log), vec![Observation::Tls {
+            flow: flow(Proto::TCP, "10.38.73.100:51234", "93.184.216.34:443"),
+            sni: Some("example.com".to_string()),
+            ja3: Some("ada70206e40642a3e4461f35503241d5".to_string()),
+            ja4: None,
+        }]);
+    }
+
+    #[test]
+    fn parse_http_tsv_escaped() {
+        let log = "#separator \\x09
+#path\thttp
+#fields\tts\tuid\tid.orig_h\tid.orig_p\tid.resp_h\tid.resp_p\ttrans_depth\tmethod\thost\turi\treferrer\tversion\tuser_agent
+#types\ttime\tstring\taddr\tport\taddr\tport\tcount\tstring\tstring\tstring\tstring\tstring\tstring
+1682942400.000000\tCjvDm83\t10.38.73.100\t51234\t93.184.216.34\t80\t1\tPOST\texample.com\t/upload.php?a=\\x09b\t-\t1.1\tokhttp/3.12.1
+";
+        let mut parser = Parser::new();
+        let observations = parse_all(&mut parser, log);
+        match &observations[..] {
+            [Observation::Http { flow: f, http }] => {
+                assert_eq!(*f, flow(Proto::TCP, "10.38.73.100:51234", "93.184.216.34:80"));
+                assert_eq!(http.request_line(), "POST /upload.php?a=\tb HTTP/1.1");
+                assert_eq!(http.host.as_deref(), Some("example.com"));
+                assert_eq!(http.agent.as_deref(), Some("okhttp/3.12.1"));
+                assert_eq!(http.referer, None);
+            }
+            _ => panic!("unexpected observations: {:?}", observations),
+        }
+    }
+
+    #[test]
+    fn parse_conn_json() {
+        let line = r#"{"_path":"conn","ts":1682942400.0,"uid":"CjvDm83","id.orig_h":"10.38.73.100","id.orig_p":51234,"id.resp_h":"93.184.216.34","id.resp_p":443,"proto":"tcp","conn_state":"SF"}"#;
+        let mut parser = Parser::new();
+        assert_eq!(parser.parse(line).unwrap(), vec![
+            Observation::Connection(flow(Proto::TCP, "10.38.73.100:51234", "93.184.216.34:443").unwrap()),
+        ]);
+    }
+
+    #[test]
+    fn parse_dns_json_without_path() {
+        let line = r#"{"ts":1682942400.0,"uid":"CjvDm83","id.orig_h":"10.38.73.100","id.orig_p":53022,"id.resp_h":"10.38.73.1","id.resp_p":53,"proto":"udp","query":"example.com","qtype_name":"AAAA","answers":["2606:2800:220:1:248:1893:25c8:1946"]}"#;
+        let mut parser = Parser::new();
+        assert_eq!(parser.parse(line).unwrap(), vec![
+            Observation::Dns {
+                flow: flow(Proto::UDP, "10.38.73.100:53022", "10.38.73.1:53"),
+                qtype: "AAAA".to_string(),
+                name: "example.com".to_string(),
+            },
+            Observation::Resolved {
+                name: "example.com".to_string(),
+                addr: "2606:2800:220:1:248:1893:25c8:1946".parse().unwrap(),
+            },
+        ]);
+    }
+
+    #[test]
+    fn tsv_without_header() {
+        let mut parser = Parser::new();
+        assert!(parser.parse("1682942400.000000\tCjvDm83").is_err());
+    }
+}